@@ -0,0 +1,98 @@
+//! 大きな譜面の解析の速さ
+//!
+//! `cargo bench --bench parse`で実行する
+//!
+//! `--features rayon`で並列に字句解析したときの速さを測る
+
+use bms_utils::RawBms;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use std::fmt::Write;
+use std::hint::black_box;
+
+/// 小節数
+///
+/// 小節番号は3桁なので、これが最大
+const MEASURES: usize = 1000;
+
+/// 1小節の1行に並べるオブジェクトの数
+const OBJECTS: usize = 32;
+
+fn id(n: usize) -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    [DIGITS[n / 36 % 36], DIGITS[n % 36]]
+        .iter()
+        .map(|&c| c as char)
+        .collect()
+}
+
+/// 全ての小節に鍵盤とBGMのオブジェクトを並べた、数MBの譜面
+fn large_chart() -> String {
+    let mut s = String::new();
+    for header in [
+        "#PLAYER 1",
+        "#GENRE ベンチマーク",
+        "#TITLE 大きな譜面",
+        "#ARTIST bms-utils",
+        "#BPM 150",
+        "#PLAYLEVEL 12",
+        "#RANK 2",
+        "#TOTAL 500",
+        "#LNOBJ ZZ",
+    ] {
+        writeln!(s, "{header}").unwrap();
+    }
+    for n in 1..36 * 36 {
+        writeln!(s, "#WAV{} sound{n}.wav", id(n)).unwrap();
+    }
+    for n in 1..100 {
+        writeln!(s, "#BMP{} image{n}.bmp", id(n)).unwrap();
+        writeln!(s, "#BPM{} {}", id(n), 100 + n).unwrap();
+        writeln!(s, "#STOP{} {}", id(n), n * 12).unwrap();
+    }
+    writeln!(s, "#RANDOM 2\n#IF 1\n#SUBTITLE [A]\n#ELSE\n#SUBTITLE [B]")
+        .unwrap();
+    writeln!(s, "#ENDIF\n#ENDRANDOM").unwrap();
+    let channels = [
+        "01", "01", "01", "01", "04", "08", "09", "11", "12", "13", "14", "15",
+        "18", "19", "16", "51", "52",
+    ];
+    for m in 0..MEASURES {
+        for (c, channel) in channels.iter().enumerate() {
+            let objects = (0..OBJECTS)
+                .map(|i| {
+                    let n = (m * 7 + i * 13 + c * 31) % (36 * 36 - 1) + 1;
+                    if (i + c) % 3 == 0 { id(n) } else { id(0) }
+                })
+                .collect::<String>();
+            writeln!(s, "#{m:03}{channel}:{objects}").unwrap();
+        }
+    }
+    s
+}
+
+fn parse(c: &mut Criterion) {
+    let chart = large_chart();
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(chart.len() as u64));
+    group.sample_size(20);
+    group.bench_function("parse", |b| {
+        b.iter(|| RawBms::parse(black_box(&chart)))
+    });
+    #[cfg(feature = "load")]
+    group.bench_function("parse_reader", |b| {
+        b.iter(|| {
+            RawBms::parse_reader(black_box(chart.as_bytes()), None).unwrap()
+        })
+    });
+    group.bench_function("parse_header", |b| {
+        b.iter(|| RawBms::parse_header(black_box(&chart)))
+    });
+    let raw = RawBms::parse(&chart);
+    group.bench_function("make_bms", |b| {
+        b.iter(|| black_box(&raw).make_bms_with(|_| 1).main_data.len())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
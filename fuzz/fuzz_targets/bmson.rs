@@ -0,0 +1,22 @@
+//! bmsonの読み込みとBMSへの変換
+//!
+//! `cargo +nightly fuzz run bmson`で実行する
+
+#![no_main]
+
+use bms_utils::{Bmson, RawBms};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|source: &str| {
+    let _ = Bmson::parse_info(source);
+    let Ok(bmson) = Bmson::parse(source)
+    else {
+        return;
+    };
+    if !bmson.validate().is_empty() {
+        return;
+    }
+    bmson.timeline();
+    let raw = RawBms::from_bmson(&bmson);
+    raw.make_bms_with(|_| 1).to_string();
+});
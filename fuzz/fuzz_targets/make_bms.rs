@@ -0,0 +1,27 @@
+//! 分岐を選んでBMSを生成し、検査と書き出しをする
+//!
+//! `cargo +nightly fuzz run make_bms`で実行する
+
+#![no_main]
+
+use bms_utils::RawBms;
+use bms_utils::bms::ParseLimits;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|source: &str| {
+    let limits = ParseLimits::default();
+    let Ok(raw) = RawBms::try_parse(source, &limits)
+    else {
+        return;
+    };
+    raw.make_bms(rand::rng());
+    let Ok(bms) = raw.try_make_bms_with(&limits, |max| max)
+    else {
+        return;
+    };
+    bms.check();
+    let timeline = bms.timeline();
+    bms.notes(&timeline);
+    bms.bgm(&timeline);
+    bms.to_string();
+});
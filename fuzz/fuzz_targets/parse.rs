@@ -0,0 +1,18 @@
+//! 字句解析と構文解析
+//!
+//! `cargo +nightly fuzz run parse`で実行する
+
+#![no_main]
+
+use bms_utils::RawBms;
+use bms_utils::bms::ParseLimits;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|source: &str| {
+    let raw = RawBms::parse(source);
+    raw.branches();
+    raw.random_combinations(16);
+    let _ = RawBms::parse_header(source);
+    let _ = RawBms::parse_reader(source.as_bytes(), None);
+    let _ = RawBms::try_parse(source, &ParseLimits::default());
+});
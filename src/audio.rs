@@ -0,0 +1,533 @@
+use crate::bms::{Bms, BmsNoteKind};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+mod decode;
+mod preview;
+pub use decode::Sound;
+#[cfg(feature = "bmson")]
+pub use preview::preview_bmson;
+pub use preview::{
+    PreviewOptions, PreviewSegment, densest_segment, find_preview_file,
+    preview_bms,
+};
+
+/// 音声の読み込みや書き込みのエラー
+#[derive(Debug)]
+pub enum AudioError {
+    Io(std::io::Error),
+    Wav(hound::Error),
+    Ogg(lewton::VorbisError),
+    Flac(claxon::Error),
+    /// 対応していない形式
+    UnsupportedFormat,
+}
+impl std::fmt::Display for AudioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioError::Io(e) => e.fmt(f),
+            AudioError::Wav(e) => e.fmt(f),
+            AudioError::Ogg(e) => e.fmt(f),
+            AudioError::Flac(e) => e.fmt(f),
+            AudioError::UnsupportedFormat => {
+                write!(f, "対応していない音声形式です")
+            }
+        }
+    }
+}
+impl std::error::Error for AudioError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AudioError::Io(e) => Some(e),
+            AudioError::Wav(e) => Some(e),
+            AudioError::Ogg(e) => Some(e),
+            AudioError::Flac(e) => Some(e),
+            AudioError::UnsupportedFormat => None,
+        }
+    }
+}
+impl From<std::io::Error> for AudioError {
+    fn from(e: std::io::Error) -> Self {
+        AudioError::Io(e)
+    }
+}
+impl From<hound::Error> for AudioError {
+    fn from(e: hound::Error) -> Self {
+        AudioError::Wav(e)
+    }
+}
+impl From<lewton::VorbisError> for AudioError {
+    fn from(e: lewton::VorbisError) -> Self {
+        AudioError::Ogg(e)
+    }
+}
+impl From<claxon::Error> for AudioError {
+    fn from(e: claxon::Error) -> Self {
+        AudioError::Flac(e)
+    }
+}
+
+/// ステレオのPCM
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pcm {
+    /// サンプリング周波数
+    pub sample_rate: u32,
+    /// 左右交互に並べたサンプル
+    pub samples: Vec<f32>,
+}
+impl Pcm {
+    /// フレーム数
+    pub fn frames(&self) -> usize {
+        self.samples.len() / 2
+    }
+    /// 長さ（ミリ秒）
+    pub fn duration_ms(&self) -> f64 {
+        self.frames() as f64 * 1000. / self.sample_rate as f64
+    }
+    /// 16bitのWAV形式で書き込む
+    ///
+    /// -1から1の範囲外のサンプルはクリップされる
+    pub fn write_wav<W: std::io::Write + std::io::Seek>(
+        &self,
+        writer: W,
+    ) -> Result<(), AudioError> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::new(writer, spec)?;
+        for s in &self.samples {
+            writer.write_sample((s.clamp(-1., 1.) * 32767.) as i16)?;
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+    /// 16bitのWAVファイルとして保存
+    pub fn save_wav(&self, path: impl AsRef<Path>) -> Result<(), AudioError> {
+        self.write_wav(std::io::BufWriter::new(std::fs::File::create(path)?))
+    }
+}
+
+/// 音声の書き出しの設定
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderOptions {
+    /// サンプリング周波数
+    pub sample_rate: u32,
+}
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions { sample_rate: 44100 }
+    }
+}
+
+/// 譜面から参照された名前を、フォルダからの相対パスにする
+///
+/// 絶対パスやフォルダの外を指す名前では`None`
+fn relative_path(name: &str) -> Option<PathBuf> {
+    let name = name.replace('\\', "/");
+    let mut path = PathBuf::new();
+    for component in Path::new(&name).components() {
+        match component {
+            Component::Normal(c) => path.push(c),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !path.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(path)
+}
+
+/// 音声ファイルを探す
+///
+/// 見つからなければ、大文字小文字を区別せずに拡張子違いのファイルを探す
+///
+/// 絶対パスや`dir`の外を指す名前では探さない
+pub fn find_sound_file(dir: &Path, name: &str) -> Option<PathBuf> {
+    // `load::SOUND_EXTENSIONS`と同じ
+    const EXTENSIONS: [&str; 3] = ["wav", "ogg", "flac"];
+    let name = relative_path(name)?;
+    name.file_name()?;
+    let path = dir.join(name);
+    if path.is_file() {
+        return Some(path);
+    }
+    let parent = path.parent()?;
+    let stem = path.file_stem()?.to_str()?;
+    std::fs::read_dir(parent)
+        .ok()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.file_stem()
+                .and_then(|s| s.to_str())
+                .is_some_and(|s| s.eq_ignore_ascii_case(stem))
+        })
+        .filter_map(|p| {
+            let ext = p.extension()?.to_str()?.to_ascii_lowercase();
+            let i = EXTENSIONS.iter().position(|e| *e == ext)?;
+            Some((i, p))
+        })
+        .min()
+        .map(|(_, p)| p)
+}
+
+/// 1回の再生
+#[derive(Clone, Debug, PartialEq)]
+struct Playback {
+    sound: usize,
+    /// 再生を始める時刻
+    start: f64,
+    /// 音声内の再生開始位置（ミリ秒）
+    offset: f64,
+    /// 再生を止める時刻
+    end: Option<f64>,
+    /// 再生速度
+    rate: f64,
+    /// 左右の音量
+    gain: [f32; 2],
+}
+
+fn mix(playbacks: &[Playback], sounds: &[Sound], sample_rate: u32) -> Pcm {
+    let out_rate = sample_rate as f64;
+    let mut samples = Vec::<f32>::new();
+    for p in playbacks {
+        let sound = &sounds[p.sound];
+        let frames = sound.frames();
+        let step = sound.sample_rate as f64 * p.rate / out_rate;
+        if frames == 0 || step <= 0. {
+            continue;
+        }
+        let start = (p.start * out_rate / 1000.).round().max(0.) as usize;
+        let end = p
+            .end
+            .map(|end| (end * out_rate / 1000.).round().max(0.) as usize)
+            .unwrap_or(usize::MAX);
+        let mut pos = p.offset * sound.sample_rate as f64 / 1000.;
+        let mut i = start;
+        while i < end && pos < frames as f64 {
+            let f = pos.floor() as usize;
+            let t = (pos - f as f64) as f32;
+            let [l0, r0] = sound.frame(f);
+            let [l1, r1] = sound.frame((f + 1).min(frames - 1));
+            if samples.len() < i * 2 + 2 {
+                samples.resize(i * 2 + 2, 0.);
+            }
+            samples[i * 2] += (l0 + (l1 - l0) * t) * p.gain[0];
+            samples[i * 2 + 1] += (r0 + (r1 - r0) * t) * p.gain[1];
+            pos += step;
+            i += 1;
+        }
+    }
+    Pcm {
+        sample_rate,
+        samples,
+    }
+}
+
+/// 読み込んだ音声ファイル
+///
+/// 同じファイルは1度だけ読み込む
+#[derive(Default)]
+struct SoundBank {
+    sounds: Vec<Sound>,
+    index: HashMap<String, Option<usize>>,
+}
+impl SoundBank {
+    fn load(&mut self, dir: &Path, name: &str) -> Option<usize> {
+        if let Some(i) = self.index.get(name) {
+            return *i;
+        }
+        let i = match find_sound_file(dir, name).map(Sound::open) {
+            Some(Ok(sound)) => {
+                self.sounds.push(sound);
+                Some(self.sounds.len() - 1)
+            }
+            Some(Err(e)) => {
+                log::warn!("{name}の読み込みに失敗しました");
+                log::debug!("{e}");
+                None
+            }
+            None => {
+                log::warn!("{name}が見つかりません");
+                None
+            }
+        };
+        self.index.insert(name.to_string(), i);
+        i
+    }
+}
+
+/// BMSのキー音とBGMを全て合成する
+///
+/// 不可視ノーツと地雷の音は鳴らさない
+///
+/// 同じidの音声を再度鳴らすと、前の再生は止まる
+///
+/// `#VOLWAV`・`#WAVCMD`・`#EXWAV`の音量・ピッチ・周波数・パン・再生時間を反映する
+///
+/// ピッチは0から127、周波数は100Hzから100000Hzの範囲に収める
+///
+/// `#PATH_WAV`が絶対パスや`dir`の外を指す場合は無視する
+pub fn render_bms(bms: &Bms, dir: &Path, options: &RenderOptions) -> Pcm {
+    #[derive(Clone)]
+    struct WavParam {
+        rate: f64,
+        volume: f64,
+        pan: f64,
+        duration: Option<f64>,
+        frequency: Option<f64>,
+    }
+    let dir = match bms.path_wav.map(|p| (p, relative_path(p))) {
+        Some((_, Some(path))) => dir.join(path),
+        Some((path, None)) => {
+            log::warn!("#PATH_WAV {path}は譜面のフォルダの外を指しています");
+            dir.to_path_buf()
+        }
+        None => dir.to_path_buf(),
+    };
+    let volume = bms.volume_wav.unwrap_or(100.) / 100.;
+    let default_param = WavParam {
+        rate: 1.,
+        volume,
+        pan: 0.,
+        duration: None,
+        frequency: None,
+    };
+
+    let mut params = HashMap::<usize, WavParam>::new();
+    for (id, (opt, _)) in &bms.ex_wav {
+        let p = params.entry(*id).or_insert_with(|| default_param.clone());
+        if let Some(pan) = opt[0] {
+            p.pan = (pan / 10000.).clamp(-1., 1.);
+        }
+        if let Some(v) = opt[1] {
+            p.volume *= 10f64.powf(v / 2000.);
+        }
+        p.frequency = opt[2].map(|f| f.clamp(100., 100000.));
+    }
+    for &(command, id, value) in &bms.wav_command {
+        let p = params.entry(id).or_insert_with(|| default_param.clone());
+        match command {
+            0 => p.rate = 2f64.powf((value.clamp(0., 127.) - 60.) / 12.),
+            1 => p.volume = volume * value / 100.,
+            2 if value > 0. => p.duration = Some(value),
+            _ => (),
+        }
+    }
+
+    let timeline = bms.timeline();
+    let mut triggers = bms
+        .bgm(&timeline)
+        .into_iter()
+        .map(|b| (b.time, b.wav))
+        .collect::<Vec<_>>();
+    for note in bms.notes(&timeline) {
+        match note.kind {
+            BmsNoteKind::Normal | BmsNoteKind::Long => {
+                triggers.push((note.time, note.wav));
+            }
+            _ => continue,
+        }
+        if let Some(end) = note.end
+            && end.wav != 0
+            && end.wav != note.wav
+        {
+            triggers.push((end.time, end.wav));
+        }
+    }
+    triggers.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut bank = SoundBank::default();
+    let mut playbacks = vec![];
+    let mut playing = HashMap::<usize, usize>::new();
+    for (time, id) in triggers {
+        let name = match (bms.ex_wav.get(&id), bms.wav.get(&id)) {
+            (Some((_, name)), _) | (None, Some(name)) => *name,
+            (None, None) => continue,
+        };
+        let Some(sound) = bank.load(&dir, name)
+        else {
+            continue;
+        };
+        if let Some(&i) = playing.get(&id) {
+            let p: &mut Playback = &mut playbacks[i];
+            p.end = Some(p.end.map_or(time, |end| end.min(time)));
+        }
+        let p = params.get(&id).unwrap_or(&default_param);
+        let rate = match p.frequency {
+            Some(f) => f / bank.sounds[sound].sample_rate as f64,
+            None => 1.,
+        } * p.rate;
+        playing.insert(id, playbacks.len());
+        playbacks.push(Playback {
+            sound,
+            start: time,
+            offset: 0.,
+            end: p.duration.map(|d| time + d),
+            rate,
+            gain: [
+                (p.volume * (1. - p.pan).min(1.)) as f32,
+                (p.volume * (1. + p.pan).min(1.)) as f32,
+            ],
+        });
+    }
+    mix(&playbacks, &bank.sounds, options.sample_rate)
+}
+
+/// Bmsonのサウンドチャンネルを全て合成する
+///
+/// 同じサウンドチャンネルの次のノートが鳴ると、前の再生は止まる
+///
+/// 続行フラグが立っているノートは、前のノートからの経過時間だけ進めた位置から再生する
+#[cfg(feature = "bmson")]
+pub fn render_bmson(
+    bmson: &crate::Bmson,
+    dir: &Path,
+    options: &RenderOptions,
+) -> Pcm {
+    let timeline = bmson.timeline();
+    let mut bank = SoundBank::default();
+    let mut playbacks = vec![];
+    for sc in bmson.sound_channels.iter().flatten() {
+        let Some(sound) = bank.load(dir, &sc.name)
+        else {
+            continue;
+        };
+        let mut times = sc
+            .notes
+            .iter()
+            .map(|n| (timeline.note_time(n), n.c))
+            .collect::<Vec<_>>();
+        times.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut last: Option<(f64, f64)> = None;
+        for (i, &(time, c)) in times.iter().enumerate() {
+            let offset = match last {
+                Some((last_time, last_offset)) if c => {
+                    last_offset + time - last_time
+                }
+                _ => 0.,
+            };
+            last = Some((time, offset));
+            let end =
+                times[i + 1..].iter().map(|(t, _)| *t).find(|t| time < *t);
+            playbacks.push(Playback {
+                sound,
+                start: time,
+                offset,
+                end,
+                rate: 1.,
+                gain: [1.; 2],
+            });
+        }
+    }
+    mix(&playbacks, &bank.sounds, options.sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawBms;
+
+    #[test]
+    fn render() {
+        let dir = std::env::temp_dir()
+            .join(format!("bms-utils-render-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 1000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer =
+            hound::WavWriter::create(dir.join("a.wav"), spec).unwrap();
+        for _ in 0..4000 {
+            writer.write_sample(16384i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        // 拡張子が違っても見つかる
+        let raw = RawBms::parse(
+            r"
+#BPM 120
+#WAV01 A.ogg
+#00101:01
+#00111:0001
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let options = RenderOptions { sample_rate: 1000 };
+        let pcm = render_bms(&bms, &dir, &options);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(pcm.samples[2000 * 2 - 2..2000 * 2], [0., 0.]);
+        assert_eq!(pcm.samples[2000 * 2..2000 * 2 + 2], [0.5, 0.5]);
+        // 同じidを再度鳴らすと前の再生は止まる
+        assert_eq!(pcm.samples[3000 * 2..3000 * 2 + 2], [0.5, 0.5]);
+        // 最後のフレームまで鳴らす
+        assert_eq!(pcm.frames(), 7000);
+        assert_eq!(pcm.samples[6999 * 2..], [0.5, 0.5]);
+    }
+
+    #[test]
+    fn extreme_pitch() {
+        let dir = std::env::temp_dir()
+            .join(format!("bms-utils-pitch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 1000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer =
+            hound::WavWriter::create(dir.join("a.wav"), spec).unwrap();
+        for _ in 0..100 {
+            writer.write_sample(16384i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        // ピッチ0（1/32倍速）に収める
+        let raw = RawBms::parse(
+            r"
+#BPM 120
+#WAV01 a.wav
+#WAVCMD 00 01 -1000000
+#00101:01
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let options = RenderOptions { sample_rate: 1000 };
+        let pcm = render_bms(&bms, &dir, &options);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(pcm.frames(), 2000 + 100 * 32);
+    }
+
+    #[test]
+    fn outside_dir() {
+        assert_eq!(relative_path("a\\..\\b.wav"), Some(PathBuf::from("b.wav")));
+        assert_eq!(relative_path("./a/b.wav"), Some(PathBuf::from("a/b.wav")));
+        assert_eq!(relative_path("../a.wav"), None);
+        assert_eq!(relative_path("a/../../a.wav"), None);
+        assert_eq!(relative_path("/etc/passwd"), None);
+
+        let dir = std::env::temp_dir()
+            .join(format!("bms-utils-outside-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("song")).unwrap();
+        std::fs::write(dir.join("a.wav"), b"").unwrap();
+        std::fs::write(dir.join("song/b.wav"), b"").unwrap();
+        let song = dir.join("song");
+        let found = (
+            find_sound_file(&song, "../a.wav"),
+            find_sound_file(&song, ".."),
+            find_sound_file(&song, "b.ogg"),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, (None, None, Some(song.join("b.wav"))));
+    }
+}
@@ -0,0 +1,105 @@
+use super::*;
+use std::io::Cursor;
+
+/// デコードされた音声
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sound {
+    /// サンプリング周波数
+    pub sample_rate: u32,
+    /// チャンネル数
+    pub channels: u16,
+    /// チャンネルごとに交互に並べたサンプル
+    ///
+    /// -1から1の範囲
+    pub samples: Vec<f32>,
+}
+
+impl Sound {
+    /// ファイルを読み込んでデコード
+    pub fn open(path: impl AsRef<Path>) -> Result<Sound, AudioError> {
+        Sound::decode(&std::fs::read(path)?)
+    }
+    /// WAV・OGG・FLACのデータをデコード
+    ///
+    /// 拡張子と中身が一致しないファイルが多いため、先頭のバイト列で形式を判定する
+    pub fn decode(bytes: &[u8]) -> Result<Sound, AudioError> {
+        match bytes.get(..4) {
+            Some(b"RIFF") => decode_wav(bytes),
+            Some(b"OggS") => decode_ogg(bytes),
+            Some(b"fLaC") => decode_flac(bytes),
+            _ => Err(AudioError::UnsupportedFormat),
+        }
+    }
+    /// フレーム数
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+    /// 長さ（ミリ秒）
+    pub fn duration_ms(&self) -> f64 {
+        self.frames() as f64 * 1000. / self.sample_rate as f64
+    }
+    /// フレームの左右のサンプル
+    ///
+    /// モノラルは左右に同じ値を返し、3チャンネル以上は最初の2チャンネルを使う
+    pub(crate) fn frame(&self, i: usize) -> [f32; 2] {
+        let channels = self.channels as usize;
+        let s = &self.samples[i * channels..];
+        if channels == 1 {
+            [s[0], s[0]]
+        }
+        else {
+            [s[0], s[1]]
+        }
+    }
+}
+
+fn decode_wav(bytes: &[u8]) -> Result<Sound, AudioError> {
+    let reader = hound::WavReader::new(Cursor::new(bytes))?;
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => {
+            reader.into_samples::<f32>().collect::<Result<_, _>>()?
+        }
+        hound::SampleFormat::Int => {
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    Ok(Sound {
+        sample_rate: spec.sample_rate,
+        channels: spec.channels,
+        samples,
+    })
+}
+
+fn decode_ogg(bytes: &[u8]) -> Result<Sound, AudioError> {
+    let mut reader =
+        lewton::inside_ogg::OggStreamReader::new(Cursor::new(bytes))?;
+    let mut samples = vec![];
+    while let Some(packet) = reader.read_dec_packet_itl()? {
+        samples.extend(packet.into_iter().map(|s| s as f32 / 32768.));
+    }
+    Ok(Sound {
+        sample_rate: reader.ident_hdr.audio_sample_rate,
+        channels: reader.ident_hdr.audio_channels as u16,
+        samples,
+    })
+}
+
+fn decode_flac(bytes: &[u8]) -> Result<Sound, AudioError> {
+    let mut reader = claxon::FlacReader::new(Cursor::new(bytes))?;
+    let info = reader.streaminfo();
+    let scale = (1u64 << (info.bits_per_sample - 1)) as f32;
+    let samples = reader
+        .samples()
+        .map(|s| s.map(|s| s as f32 / scale))
+        .collect::<Result<_, _>>()?;
+    Ok(Sound {
+        sample_rate: info.sample_rate,
+        channels: info.channels as u16,
+        samples,
+    })
+}
@@ -0,0 +1,298 @@
+use super::*;
+
+/// プレビューに使う区間の選び方
+#[derive(Clone, Debug, PartialEq)]
+pub enum PreviewSegment {
+    /// ノーツが最も多い区間
+    Densest,
+    /// 音量が最も大きい区間
+    Loudest,
+    /// 指定した小節の最初から
+    Measure(usize),
+}
+
+/// プレビューの書き出しの設定
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreviewOptions {
+    /// 区間の選び方
+    pub segment: PreviewSegment,
+    /// 長さ（ミリ秒）
+    pub duration: f64,
+    /// フェードインの長さ（ミリ秒）
+    pub fade_in: f64,
+    /// フェードアウトの長さ（ミリ秒）
+    pub fade_out: f64,
+    /// 合成の設定
+    pub render: RenderOptions,
+}
+impl Default for PreviewOptions {
+    fn default() -> Self {
+        PreviewOptions {
+            segment: PreviewSegment::Densest,
+            duration: 15000.,
+            fade_in: 500.,
+            fade_out: 1000.,
+            render: RenderOptions::default(),
+        }
+    }
+}
+
+impl Pcm {
+    /// 指定した区間を切り出す
+    ///
+    /// 範囲外は無音になる
+    pub fn slice(&self, start: f64, duration: f64) -> Pcm {
+        let rate = self.sample_rate as f64;
+        let start = (start * rate / 1000.).round().max(0.) as usize;
+        let len = (duration * rate / 1000.).round().max(0.) as usize;
+        let mut samples = vec![0.; len * 2];
+        if let Some(src) = self.samples.get(start * 2..) {
+            let n = src.len().min(samples.len());
+            samples[..n].copy_from_slice(&src[..n]);
+        }
+        Pcm {
+            sample_rate: self.sample_rate,
+            samples,
+        }
+    }
+    /// 最初と最後を線形にフェードする
+    pub fn fade(&mut self, fade_in: f64, fade_out: f64) {
+        let rate = self.sample_rate as f64;
+        let frames = self.frames();
+        let fade_in = (fade_in * rate / 1000.) as usize;
+        let fade_out = (fade_out * rate / 1000.) as usize;
+        for i in 0..frames {
+            let mut gain = 1.;
+            if i < fade_in {
+                gain *= i as f32 / fade_in as f32;
+            }
+            if frames - i <= fade_out {
+                gain *= (frames - i - 1) as f32 / fade_out as f32;
+            }
+            self.samples[i * 2] *= gain;
+            self.samples[i * 2 + 1] *= gain;
+        }
+    }
+    /// 二乗和が最も大きい区間の開始時刻
+    pub fn loudest_segment(&self, duration: f64) -> f64 {
+        let rate = self.sample_rate as f64;
+        let len = ((duration * rate / 1000.) as usize).max(1);
+        let energy = (0..self.frames())
+            .map(|i| {
+                let [l, r] = [self.samples[i * 2], self.samples[i * 2 + 1]];
+                (l * l + r * r) as f64
+            })
+            .collect::<Vec<_>>();
+        let mut sum = energy.iter().take(len).sum::<f64>();
+        let (mut best, mut best_start) = (sum, 0);
+        for i in len..energy.len() {
+            sum += energy[i] - energy[i - len];
+            if best < sum {
+                best = sum;
+                best_start = i + 1 - len;
+            }
+        }
+        best_start as f64 * 1000. / rate
+    }
+}
+
+/// 時刻が最も多く含まれる区間の開始時刻
+///
+/// 時刻は昇順に並んでいる必要がある
+pub fn densest_segment(times: &[f64], duration: f64) -> f64 {
+    let mut best = (0, 0.);
+    let mut end = 0;
+    for (i, &start) in times.iter().enumerate() {
+        while end < times.len() && times[end] < start + duration {
+            end += 1;
+        }
+        if best.0 < end - i {
+            best = (end - i, start);
+        }
+    }
+    best.1
+}
+
+/// プレビュー用の音声ファイルを探す
+///
+/// `#PREVIEW`などで指定されたファイルが無い場合は、
+/// 名前がpreviewから始まる音声ファイルを探す
+pub fn find_preview_file(dir: &Path, preview: Option<&str>) -> Option<PathBuf> {
+    if let Some(path) = preview.and_then(|name| find_sound_file(dir, name)) {
+        return Some(path);
+    }
+    let mut files = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            let name = |p: Option<&std::ffi::OsStr>| {
+                p.and_then(|s| s.to_str()).map(|s| s.to_ascii_lowercase())
+            };
+            name(p.file_stem()).is_some_and(|s| s.starts_with("preview"))
+                && name(p.extension()).is_some_and(|e| {
+                    ["wav", "ogg", "flac"].contains(&e.as_str())
+                })
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files.into_iter().next()
+}
+
+fn cut_preview(pcm: &Pcm, start: f64, options: &PreviewOptions) -> Pcm {
+    let mut preview = pcm.slice(start, options.duration);
+    preview.fade(options.fade_in, options.fade_out);
+    preview
+}
+
+/// 譜面に用意されたプレビューを読み込む
+///
+/// 見つからないか読み込めない場合は`None`
+fn load_preview(
+    dir: &Path,
+    preview: Option<&str>,
+    options: &PreviewOptions,
+) -> Option<Pcm> {
+    let path = find_preview_file(dir, preview)?;
+    let sound = match Sound::open(&path) {
+        Ok(sound) => sound,
+        Err(e) => {
+            log::warn!("{}の読み込みに失敗しました", path.display());
+            log::debug!("{e}");
+            return None;
+        }
+    };
+    let playback = Playback {
+        sound: 0,
+        start: 0.,
+        offset: 0.,
+        end: None,
+        rate: 1.,
+        gain: [1.; 2],
+    };
+    Some(mix(&[playback], &[sound], options.render.sample_rate))
+}
+
+/// BMSのプレビューを合成する
+///
+/// `#PREVIEW`や名前がpreviewから始まる音声ファイルがあれば、合成せずにそれを読み込む
+pub fn preview_bms(bms: &Bms, dir: &Path, options: &PreviewOptions) -> Pcm {
+    if let Some(pcm) = load_preview(dir, bms.preview, options) {
+        return pcm;
+    }
+    let pcm = render_bms(bms, dir, &options.render);
+    let timeline = bms.timeline();
+    let start = match options.segment {
+        PreviewSegment::Densest => {
+            let times = bms
+                .notes(&timeline)
+                .into_iter()
+                .filter(|n| {
+                    matches!(n.kind, BmsNoteKind::Normal | BmsNoteKind::Long)
+                })
+                .map(|n| n.time)
+                .collect::<Vec<_>>();
+            densest_segment(&times, options.duration)
+        }
+        PreviewSegment::Loudest => pcm.loudest_segment(options.duration),
+        PreviewSegment::Measure(m) => timeline.position_to_ms(m, 0.),
+    };
+    cut_preview(&pcm, start, options)
+}
+
+/// Bmsonのプレビューを合成する
+///
+/// `preview_music`や名前がpreviewから始まる音声ファイルがあれば、合成せずにそれを読み込む
+///
+/// 小節は小節線の位置で数える
+#[cfg(feature = "bmson")]
+pub fn preview_bmson(
+    bmson: &crate::Bmson,
+    dir: &Path,
+    options: &PreviewOptions,
+) -> Pcm {
+    let preview = bmson.info.preview_music.as_deref();
+    if let Some(pcm) = load_preview(dir, preview, options) {
+        return pcm;
+    }
+    let pcm = render_bmson(bmson, dir, &options.render);
+    let timeline = bmson.timeline();
+    let start = match options.segment {
+        PreviewSegment::Densest => {
+            let mut times = timeline
+                .notes(bmson)
+                .into_iter()
+                .filter(|n| n.note.x.is_some_and(|x| x != 0))
+                .map(|n| n.time)
+                .collect::<Vec<_>>();
+            times.sort_by(f64::total_cmp);
+            densest_segment(&times, options.duration)
+        }
+        PreviewSegment::Loudest => pcm.loudest_segment(options.duration),
+        PreviewSegment::Measure(m) => {
+            timeline.bar_lines(bmson).get(m).copied().unwrap_or(0.)
+        }
+    };
+    cut_preview(&pcm, start, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment() {
+        let times = [0., 100., 1000., 1100., 1200., 5000.];
+        assert_eq!(densest_segment(&times, 500.), 1000.);
+        assert_eq!(densest_segment(&[], 500.), 0.);
+
+        let mut samples = vec![0.; 20];
+        samples[12..16].fill(1.);
+        let pcm = Pcm {
+            sample_rate: 1000,
+            samples,
+        };
+        assert_eq!(pcm.loudest_segment(2.), 6.);
+
+        let mut slice = pcm.slice(6., 6.);
+        assert_eq!(slice.frames(), 6);
+        assert_eq!(slice.samples[..4], [1.; 4]);
+        assert_eq!(slice.samples[8..], [0.; 4]);
+        slice.fade(2., 3.);
+        assert_eq!(slice.samples[..4], [0., 0., 0.5, 0.5]);
+    }
+
+    #[test]
+    fn existing_preview() {
+        let dir = std::env::temp_dir()
+            .join(format!("bms-utils-preview-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 1000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer =
+            hound::WavWriter::create(dir.join("cut.wav"), spec).unwrap();
+        for _ in 0..300 {
+            writer.write_sample(16384i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        // 読み込めないmp3は選ばない
+        std::fs::write(dir.join("preview.mp3"), b"").unwrap();
+        let found = find_preview_file(&dir, None);
+
+        let raw = crate::RawBms::parse("#PREVIEW cut.wav\n");
+        let bms = raw.make_bms(rand::rng());
+        let options = PreviewOptions {
+            render: RenderOptions { sample_rate: 1000 },
+            ..Default::default()
+        };
+        let pcm = preview_bms(&bms, &dir, &options);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(found, None);
+        assert_eq!(pcm.frames(), 300);
+        assert_eq!(pcm.samples[..2], [0.5, 0.5]);
+    }
+}
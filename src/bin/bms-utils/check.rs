@@ -0,0 +1,282 @@
+use bms_utils::bms::BmsIssue;
+use bms_utils::load::{
+    ChartFile, ChartFormat, IMAGE_EXTENSIONS, SOUND_EXTENSIONS, TextEncoding,
+    find_file,
+};
+use bms_utils::{Bms, Bmson, RawBms};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// 譜面フォルダか譜面ファイル
+    ///
+    /// フォルダはサブフォルダも含めて検査する
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    /// JSONで出力する
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct Report {
+    path: String,
+    issues: Vec<Issue>,
+}
+
+#[derive(Serialize)]
+struct Issue {
+    level: Level,
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Level {
+    Error,
+    Warning,
+}
+
+impl Issue {
+    fn error(code: &'static str, message: impl ToString) -> Issue {
+        Issue {
+            level: Level::Error,
+            code,
+            message: message.to_string(),
+        }
+    }
+    fn warning(code: &'static str, message: impl ToString) -> Issue {
+        Issue {
+            level: Level::Warning,
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<BmsIssue> for Issue {
+    fn from(issue: BmsIssue) -> Self {
+        let code = match issue {
+            BmsIssue::UnparsedLine(_) => "unparsed-line",
+            BmsIssue::UnknownCommand(_) => "unknown-command",
+            BmsIssue::Undefined { .. } => "undefined",
+            BmsIssue::Unused { .. } => "unused",
+            BmsIssue::UnterminatedLongNote { .. } => "unterminated-long-note",
+            BmsIssue::UnmatchedLnObject { .. } => "unmatched-ln-object",
+            BmsIssue::OverlappingLongNote { .. } => "overlapping-long-note",
+        };
+        if issue.is_error() {
+            Issue::error(code, issue)
+        }
+        else {
+            Issue::warning(code, issue)
+        }
+    }
+}
+
+/// 参照されているファイル
+///
+/// (音声かどうか, ファイル名)
+type Resources = BTreeSet<(bool, String)>;
+
+pub fn run(args: Args) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let mut charts = vec![];
+    for path in &args.paths {
+        if path.is_dir() {
+            collect_charts(path, &mut charts)?;
+        }
+        else {
+            charts.push(path.clone());
+        }
+    }
+    let reports = charts.iter().map(|path| check(path)).collect::<Vec<_>>();
+    let count = |level| {
+        reports
+            .iter()
+            .flat_map(|r| &r.issues)
+            .filter(|i| i.level == level)
+            .count()
+    };
+    let (errors, warnings) = (count(Level::Error), count(Level::Warning));
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+    }
+    else {
+        for report in reports.iter().filter(|r| !r.issues.is_empty()) {
+            println!("{}", report.path);
+            for issue in &report.issues {
+                let level = match issue.level {
+                    Level::Error => "エラー",
+                    Level::Warning => "警告",
+                };
+                println!("  {level}: {}", issue.message);
+            }
+        }
+        println!(
+            "{}譜面: エラー {errors}件 / 警告 {warnings}件",
+            reports.len()
+        );
+    }
+    Ok(if 0 < errors {
+        ExitCode::FAILURE
+    }
+    else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// フォルダ内の譜面ファイルを名前順に集める
+///
+/// シンボリックリンクのフォルダはたどらない
+fn collect_charts(
+    dir: &Path,
+    charts: &mut Vec<PathBuf>,
+) -> std::io::Result<()> {
+    let sorted_entries = |dir: &Path| -> std::io::Result<Vec<PathBuf>> {
+        let mut entries = std::fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        // 後ろから取り出すので逆順
+        entries.sort_by(|a, b| b.cmp(a));
+        Ok(entries)
+    };
+    let mut stack = sorted_entries(dir)?;
+    while let Some(path) = stack.pop() {
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            stack.extend(sorted_entries(&path)?);
+        }
+        else if path.is_file() && ChartFormat::from_path(&path).is_some() {
+            charts.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn check(path: &Path) -> Report {
+    let mut report = Report {
+        path: path.display().to_string(),
+        issues: vec![],
+    };
+    let file = match ChartFile::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            report.issues.push(Issue::error("read", e));
+            return report;
+        }
+    };
+    if file.malformed {
+        report.issues.push(Issue::error(
+            "encoding",
+            format!("{}として不正なバイト列があります", file.encoding.name()),
+        ));
+    }
+    let resources = match file.format {
+        ChartFormat::Bms => {
+            if file.encoding == TextEncoding::Utf8 && !file.text.is_ascii() {
+                report.issues.push(Issue::warning(
+                    "encoding",
+                    "UTF-8で書かれています（Shift_JISとして読むプレイヤーでは文字化けします）",
+                ));
+            }
+            check_bms(&file.text, &mut report.issues)
+        }
+        ChartFormat::Bmson => match Bmson::parse(&file.text) {
+            Ok(bmson) => {
+                report.issues.extend(
+                    bmson
+                        .validate()
+                        .into_iter()
+                        .map(|i| Issue::error("invalid-bmson", i)),
+                );
+                bmson_resources(&bmson)
+            }
+            Err(e) => {
+                report.issues.push(Issue::error("invalid-bmson", e));
+                return report;
+            }
+        },
+    };
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    for (sound, name) in resources {
+        let extensions = if sound {
+            SOUND_EXTENSIONS
+        }
+        else {
+            IMAGE_EXTENSIONS
+        };
+        if find_file(dir, &name, extensions).is_none() {
+            report.issues.push(Issue::error(
+                "missing-file",
+                format!("{name}が見つかりません"),
+            ));
+        }
+    }
+    report
+}
+
+/// 全ての分岐の組み合わせを検査し、参照されているファイルを返す
+fn check_bms(text: &str, issues: &mut Vec<Issue>) -> Resources {
+    let raw = RawBms::parse(text);
+    let mut resources = Resources::new();
+    let found = raw.check_with(|bms| bms_resources(bms, &mut resources));
+    issues.extend(found.into_iter().map(Issue::from));
+    resources
+}
+
+fn bms_resources(bms: &Bms, resources: &mut Resources) {
+    let wav_dir = bms.path_wav.map(|p| p.replace('\\', "/"));
+    let sounds = bms
+        .wav
+        .values()
+        .copied()
+        .chain(bms.ex_wav.values().map(|(_, name)| *name));
+    for name in sounds {
+        let name = match &wav_dir {
+            Some(dir) => format!("{}/{name}", dir.trim_end_matches('/')),
+            None => name.to_string(),
+        };
+        resources.insert((true, name));
+    }
+    resources.extend(bms.preview.map(|name| (true, name.to_string())));
+    let images = bms
+        .bmp
+        .values()
+        .copied()
+        .chain(bms.ex_bmp.values().map(|(_, name)| *name))
+        .chain(bms.stage_file)
+        .chain(bms.banner)
+        .chain(bms.back_bmp);
+    resources.extend(images.map(|name| (false, name.to_string())));
+    resources.retain(|(_, name)| !name.is_empty());
+}
+
+fn bmson_resources(bmson: &Bmson) -> Resources {
+    let mut resources = Resources::new();
+    let sounds = bmson
+        .sound_channels
+        .iter()
+        .flatten()
+        .map(|c| &c.name)
+        .chain(bmson.key_channels.iter().flatten().map(|c| &c.name))
+        .chain(bmson.mine_channels.iter().flatten().map(|c| &c.name))
+        .chain(&bmson.info.preview_music);
+    resources.extend(sounds.map(|name| (true, name.clone())));
+    let info = &bmson.info;
+    let images = bmson
+        .bga
+        .bga_header
+        .iter()
+        .map(|h| &h.name)
+        .chain(&info.back_image)
+        .chain(&info.eyecatch_image)
+        .chain(&info.title_image)
+        .chain(&info.banner_image);
+    resources.extend(images.map(|name| (false, name.clone())));
+    resources.retain(|(_, name)| !name.is_empty());
+    resources
+}
@@ -0,0 +1,147 @@
+use bms_utils::load::{ChartFile, ChartFormat, TextEncoding};
+use bms_utils::{Bms, Bmson, RawBms};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// 全ての分岐を書き出すときのファイル数の上限
+const MAX_FILES: usize = 1000;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// 変換する譜面ファイル
+    input: PathBuf,
+    /// 出力先
+    ///
+    /// 拡張子で形式を決める。省略した場合は入力と別の形式にする
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Bmsonの分解能（四分音符1つ分のパルス数）
+    #[arg(long, default_value_t = 240)]
+    resolution: u32,
+    /// #RANDOM・#SWITCHで選ぶ値を出現順に並べたもの
+    ///
+    /// 省略した分岐はランダムに選ぶ
+    #[arg(long, value_delimiter = ',', conflicts_with = "all_branches")]
+    random: Vec<u128>,
+    /// 全ての分岐の組み合わせを別々のファイルに書き出す
+    ///
+    /// ファイル名には選んだ値が付く
+    #[arg(long)]
+    all_branches: bool,
+    /// BMSの文字コード
+    #[arg(long, value_enum, default_value_t = Encoding::ShiftJis)]
+    encoding: Encoding,
+    /// BMSのidを62進数で書き出す
+    ///
+    /// 36進数で表せないidがある場合は指定しなくても62進数になる
+    #[arg(long)]
+    base62: bool,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Encoding {
+    Utf8,
+    ShiftJis,
+}
+
+impl From<Encoding> for TextEncoding {
+    fn from(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Utf8 => TextEncoding::Utf8,
+            Encoding::ShiftJis => TextEncoding::ShiftJis,
+        }
+    }
+}
+
+pub fn run(args: Args) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let file = ChartFile::open(&args.input)?;
+    let raw = match file.format {
+        ChartFormat::Bms => RawBms::parse(&file.text),
+        ChartFormat::Bmson => RawBms::from_bmson(&Bmson::parse(&file.text)?),
+    };
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None => args.input.with_extension(match file.format {
+            ChartFormat::Bms => "bmson",
+            ChartFormat::Bmson => "bms",
+        }),
+    };
+    let Some(format) = ChartFormat::from_path(&output)
+    else {
+        return Err(format!(
+            "{}は譜面ファイルではありません",
+            output.display()
+        )
+        .into());
+    };
+
+    if args.all_branches {
+        let Some(combinations) = raw.random_combinations(MAX_FILES)
+        else {
+            return Err(format!(
+                "分岐の組み合わせが{MAX_FILES}個を超えています"
+            )
+            .into());
+        };
+        for values in combinations {
+            let mut values_iter = values.iter();
+            let bms = raw.make_bms_with(|_| *values_iter.next().unwrap());
+            let path = if values.is_empty() {
+                output.clone()
+            }
+            else {
+                branch_path(&output, &values)
+            };
+            write(&args, &bms, format, &path)?;
+        }
+    }
+    else {
+        let mut rng = rand::rng();
+        let mut values = args.random.iter();
+        let mut chosen = vec![];
+        let bms = raw.make_bms_with(|max| {
+            use rand::Rng;
+            // #RANDOM 0は1だけを選べるものとする
+            let max = max.max(1);
+            let value = match values.next() {
+                Some(&value) => value.clamp(1, max),
+                None => rng.random_range(1..=max),
+            };
+            chosen.push(value);
+            value
+        });
+        if !chosen.is_empty() {
+            let chosen =
+                chosen.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+            eprintln!("分岐: {}", chosen.join(","));
+        }
+        write(&args, &bms, format, &output)?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// 選んだ値をファイル名に付ける
+fn branch_path(path: &Path, values: &[u128]) -> PathBuf {
+    let values = values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{stem}.{}.{extension}", values.join("-")))
+}
+
+fn write(
+    args: &Args,
+    bms: &Bms,
+    format: ChartFormat,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes = match format {
+        ChartFormat::Bms => TextEncoding::from(args.encoding)
+            .encode(&bms.to_string_with_base(args.base62)),
+        ChartFormat::Bmson => Bmson::from_bms(bms, args.resolution)
+            .to_string_pretty()?
+            .into_bytes(),
+    };
+    std::fs::write(path, bytes)?;
+    println!("{}", path.display());
+    Ok(())
+}
@@ -0,0 +1,304 @@
+use bms_utils::bms::{BmsNoteKind, BranchInfo};
+use bms_utils::load::{ChartFile, ChartFormat};
+use bms_utils::transform::KeyMode;
+use bms_utils::{Bmson, RawBms};
+use rand::SeedableRng;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// 譜面ファイル
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// JSONで出力する
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct Info {
+    path: String,
+    format: &'static str,
+    encoding: &'static str,
+    md5: String,
+    sha256: String,
+    title: String,
+    subtitle: String,
+    artist: String,
+    subartist: String,
+    genre: String,
+    level: Option<i64>,
+    difficulty: Option<String>,
+    mode: String,
+    notes: Notes,
+    bpm: Bpm,
+    /// 最後のノーツかBGMまでの長さ（ミリ秒）
+    length: f64,
+    branches: Vec<Branch>,
+}
+
+#[derive(Default, Serialize)]
+struct Notes {
+    total: usize,
+    normal: usize,
+    long: usize,
+    invisible: usize,
+    landmine: usize,
+}
+
+#[derive(Serialize)]
+struct Bpm {
+    initial: f64,
+    min: f64,
+    max: f64,
+}
+
+#[derive(Serialize)]
+struct Branch {
+    switch: bool,
+    max: Option<String>,
+    set: Option<String>,
+    values: Vec<String>,
+    depth: usize,
+}
+
+impl From<BranchInfo> for Branch {
+    fn from(b: BranchInfo) -> Self {
+        // u128はJSONの数値に収まらないことがあるので文字列にする
+        Branch {
+            switch: b.switch,
+            max: b.max.map(|n| n.to_string()),
+            set: b.set.map(|n| n.to_string()),
+            values: b.values.iter().map(|n| n.to_string()).collect(),
+            depth: b.depth,
+        }
+    }
+}
+
+pub fn run(args: Args) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let mut infos = vec![];
+    let mut failed = false;
+    for path in &args.files {
+        match info(path) {
+            Ok(info) => infos.push(info),
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                failed = true;
+            }
+        }
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&infos)?);
+    }
+    else {
+        for info in &infos {
+            print(info);
+        }
+    }
+    Ok(if failed {
+        ExitCode::FAILURE
+    }
+    else {
+        ExitCode::SUCCESS
+    })
+}
+
+fn info(path: &PathBuf) -> Result<Info, Box<dyn std::error::Error>> {
+    let file = ChartFile::open(path)?;
+    let mut info = Info {
+        path: path.display().to_string(),
+        format: match file.format {
+            ChartFormat::Bms => "bms",
+            ChartFormat::Bmson => "bmson",
+        },
+        encoding: file.encoding.name(),
+        md5: file.md5,
+        sha256: file.sha256,
+        title: String::new(),
+        subtitle: String::new(),
+        artist: String::new(),
+        subartist: String::new(),
+        genre: String::new(),
+        level: None,
+        difficulty: None,
+        mode: String::new(),
+        notes: Notes::default(),
+        bpm: Bpm {
+            initial: 0.,
+            min: 0.,
+            max: 0.,
+        },
+        length: 0.,
+        branches: vec![],
+    };
+    match file.format {
+        ChartFormat::Bms => bms_info(&file.text, &mut info),
+        ChartFormat::Bmson => bmson_info(&file.text, &mut info)?,
+    }
+    Ok(info)
+}
+
+fn bms_info(text: &str, info: &mut Info) {
+    let raw = RawBms::parse(text);
+    info.branches = raw.branches().into_iter().map(Branch::from).collect();
+    // 分岐は固定のシードで選ぶ
+    let bms = raw.make_bms(rand::rngs::StdRng::seed_from_u64(0));
+    info.title = bms.title.unwrap_or_default().to_string();
+    info.subtitle = bms.sub_title.join(" ");
+    info.artist = bms.artist.unwrap_or_default().to_string();
+    info.subartist = bms.sub_artist.join(" ");
+    info.genre = bms.genre.unwrap_or_default().to_string();
+    info.level = bms.play_level.map(i64::from);
+    info.difficulty = bms.difficulty.map(|d| d.to_string());
+
+    let timeline = bms.timeline();
+    let notes = bms.notes(&timeline);
+    info.mode = KeyMode::detect(&notes).mode_hint().to_string();
+    for note in &notes {
+        match note.kind {
+            BmsNoteKind::Normal => info.notes.normal += 1,
+            BmsNoteKind::Long => info.notes.long += 1,
+            BmsNoteKind::Invisible => info.notes.invisible += 1,
+            BmsNoteKind::Landmine(_) => info.notes.landmine += 1,
+        }
+    }
+    info.notes.total = info.notes.normal + info.notes.long;
+
+    let bpms = timeline
+        .bpm_changes()
+        .into_iter()
+        .map(|c| c.2)
+        .collect::<Vec<_>>();
+    info.bpm = Bpm {
+        initial: timeline.bpm_at(0.),
+        min: bpms.iter().copied().fold(f64::INFINITY, f64::min),
+        max: bpms.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+    };
+    info.length = notes
+        .iter()
+        .map(|n| n.end.as_ref().map_or(n.time, |e| e.time))
+        .chain(bms.bgm(&timeline).iter().map(|b| b.time))
+        .fold(0., f64::max);
+}
+
+fn bmson_info(
+    text: &str,
+    info: &mut Info,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bmson = Bmson::parse(text)?;
+    info.title = bmson.info.title.clone();
+    info.subtitle = bmson.info.subtitle.clone();
+    info.artist = bmson.info.artist.clone();
+    info.subartist = bmson
+        .info
+        .subartists
+        .iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ");
+    info.genre = bmson.info.genre.clone();
+    info.level = Some(bmson.info.level.into());
+    info.difficulty =
+        Some(bmson.info.chart_name.clone()).filter(|name| !name.is_empty());
+    info.mode = bmson.info.mode_hint.clone();
+
+    let timeline = bmson.timeline();
+    let notes = timeline.notes(&bmson);
+    for note in notes.iter().filter(|n| n.note.x.is_some_and(|x| x != 0)) {
+        if 0 < note.note.l {
+            info.notes.long += 1;
+        }
+        else {
+            info.notes.normal += 1;
+        }
+    }
+    info.notes.total = info.notes.normal + info.notes.long;
+    info.notes.invisible = bmson
+        .key_channels
+        .iter()
+        .flatten()
+        .map(|c| c.notes.len())
+        .sum();
+    info.notes.landmine = bmson
+        .mine_channels
+        .iter()
+        .flatten()
+        .map(|c| c.notes.len())
+        .sum();
+
+    let bpms = std::iter::once(bmson.info.init_bpm)
+        .chain(bmson.bpm_events.iter().flatten().map(|e| e.bpm))
+        .collect::<Vec<_>>();
+    info.bpm = Bpm {
+        initial: bmson.info.init_bpm,
+        min: bpms.iter().copied().fold(f64::INFINITY, f64::min),
+        max: bpms.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+    };
+    info.length = notes.iter().map(|n| n.end_time).fold(0., f64::max);
+    Ok(())
+}
+
+fn print(info: &Info) {
+    println!("{}", info.path);
+    println!("  形式: {} ({})", info.format, info.encoding);
+    println!("  MD5: {}", info.md5);
+    println!("  SHA-256: {}", info.sha256);
+    println!("  タイトル: {}", join(&info.title, &info.subtitle));
+    println!("  アーティスト: {}", join(&info.artist, &info.subartist));
+    println!("  ジャンル: {}", info.genre);
+    if let Some(level) = info.level {
+        println!("  レベル: {level}");
+    }
+    if let Some(difficulty) = &info.difficulty {
+        println!("  難易度: {difficulty}");
+    }
+    println!("  配置: {}", info.mode);
+    let n = &info.notes;
+    println!(
+        "  ノーツ: {} (通常 {} / ロング {} / 不可視 {} / 地雷 {})",
+        n.total, n.normal, n.long, n.invisible, n.landmine
+    );
+    if info.bpm.min == info.bpm.max {
+        println!("  BPM: {}", info.bpm.initial);
+    }
+    else {
+        println!(
+            "  BPM: {} ({} - {})",
+            info.bpm.initial, info.bpm.min, info.bpm.max
+        );
+    }
+    let ms = info.length.round() as u64;
+    println!(
+        "  長さ: {}:{:02}.{:03}",
+        ms / 60000,
+        ms / 1000 % 60,
+        ms % 1000
+    );
+    for b in &info.branches {
+        let command = match (b.switch, &b.max) {
+            (false, Some(_)) => "#RANDOM",
+            (false, None) => "#SETRANDOM",
+            (true, Some(_)) => "#SWITCH",
+            (true, None) => "#SETSWITCH",
+        };
+        println!(
+            "  {}{} {} ({})",
+            "  ".repeat(b.depth),
+            command,
+            b.max.as_ref().or(b.set.as_ref()).unwrap(),
+            b.values.join(", ")
+        );
+    }
+}
+
+fn join(main: &str, sub: &str) -> String {
+    if sub.is_empty() {
+        main.to_string()
+    }
+    else {
+        format!("{main} {sub}")
+    }
+}
@@ -0,0 +1,40 @@
+use clap::{Parser, Subcommand};
+use std::process::ExitCode;
+
+mod check;
+mod convert;
+mod info;
+
+/// BMSのファイル(.bms .bme .bml .pms .bmson)を扱うツール
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 譜面の情報を表示する
+    Info(info::Args),
+    /// BMSとBmsonを相互に変換する
+    Convert(convert::Args),
+    /// 譜面フォルダを検査する
+    Check(check::Args),
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Info(args) => info::run(args),
+        Command::Convert(args) => convert::run(args),
+        Command::Check(args) => check::run(args),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("エラー: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
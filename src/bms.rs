@@ -1,6 +1,22 @@
+mod bga;
+mod check;
 pub(crate) mod lex;
+pub(crate) mod notes;
+mod option;
 pub(crate) mod parse;
+mod text;
+pub(crate) mod timeline;
 pub(crate) mod token;
+mod write;
+pub use bga::{
+    BgaImage, BgaLayer, BgaState, BgaTimeline, BgaVideo, PoorBgaMode,
+};
+pub use check::{BmsIssue, CHECK_COMBINATIONS, Definition};
+pub use notes::{BmsBgm, BmsNote, BmsNoteEnd, BmsNoteKind};
+pub use option::{BmsOption, BmsOptionChange, PlayOption};
+pub use parse::{DEFAULT_MAX_NESTING, MAX_NESTING, ParseError, ParseLimits};
+pub use text::{BmsText, to_lrc, to_srt};
+pub use timeline::{BmsTimeline, DEFAULT_BPM};
 pub use token::Channel;
 
 /// ファイルを解析したままのBMS
@@ -8,39 +24,40 @@ pub use token::Channel;
 /// ランダム要素を確定していない。
 /// [`RawBms::make_bms`]で疑似乱数生成器を指定してBMSを生成する
 #[derive(Debug, Clone, PartialEq, Default)]
-pub struct RawBms {
-    raw_bms: BmsBlock,
+pub struct RawBms<'a> {
+    raw_bms: BmsBlock<'a>,
     all_wav_files: HashSet<String>,
+    unparsed_lines: Vec<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq, Default)]
-pub(crate) struct BmsBlock(Vec<BmsElement>);
+pub(crate) struct BmsBlock<'a>(Vec<BmsElement<'a>>);
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) enum BmsElement {
-    Command(token::Command),
-    Random(BmsRandomBlock),
-    Switch(BmsSwitchBlock),
+pub(crate) enum BmsElement<'a> {
+    Command(token::Command<'a>),
+    Random(BmsRandomBlock<'a>),
+    Switch(BmsSwitchBlock<'a>),
 }
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct BmsRandomBlock(RandomValue, Vec<BmsRandomElement>);
+pub(crate) struct BmsRandomBlock<'a>(RandomValue, Vec<BmsRandomElement<'a>>);
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) enum BmsRandomElement {
-    Block(BmsBlock),
-    IfBlock(BmsIfBlock),
+pub(crate) enum BmsRandomElement<'a> {
+    Block(BmsBlock<'a>),
+    IfBlock(BmsIfBlock<'a>),
 }
 #[derive(Debug, Clone, Default, PartialEq)]
-pub(crate) struct BmsIfBlock {
-    pub(crate) r#if: Vec<(u128, BmsBlock)>,
-    pub(crate) r#else: Option<BmsBlock>,
+pub(crate) struct BmsIfBlock<'a> {
+    pub(crate) r#if: Vec<(u128, BmsBlock<'a>)>,
+    pub(crate) r#else: Option<BmsBlock<'a>>,
 }
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct BmsSwitchBlock(
+pub(crate) struct BmsSwitchBlock<'a>(
     RandomValue,
-    Vec<BmsCaseBlock>,
+    Vec<BmsCaseBlock<'a>>,
     std::collections::HashSet<u128>,
 );
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct BmsCaseBlock(SwitchLabel, BmsBlock, bool);
+pub(crate) struct BmsCaseBlock<'a>(SwitchLabel, BmsBlock<'a>, bool);
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) enum RandomValue {
     Max(u128),
@@ -51,149 +68,374 @@ pub(crate) enum SwitchLabel {
     Case(u128),
     Default,
 }
-impl BmsBlock {
-    pub(crate) fn get_token_vec<'a, Rng: rand::RngCore>(
-        &'a self,
-        output: &mut Vec<&'a token::Command>,
-        rng: &mut Rng,
-    ) {
-        for e in &self.0 {
-            e.get_token_vec(output, rng);
+impl RandomValue {
+    fn value(&self, choose: &mut impl FnMut(u128) -> u128) -> u128 {
+        match *self {
+            RandomValue::Max(n) => choose(n),
+            RandomValue::Set(n) => n,
         }
     }
 }
-impl BmsElement {
-    fn get_token_vec<'a, Rng: rand::RngCore>(
-        &'a self,
-        output: &mut Vec<&'a token::Command>,
-        rng: &mut Rng,
+impl<'a> BmsBlock<'a> {
+    /// 分岐を`choose`で選び、選ばれたコマンドを出現順に`output`へ追加する
+    ///
+    /// 深い入れ子でもスタックが溢れないように、再帰せずに辿る
+    pub(crate) fn get_token_vec<'b>(
+        &'b self,
+        output: &mut Vec<&'b token::Command<'a>>,
+        choose: &mut impl FnMut(u128) -> u128,
     ) {
-        match self {
-            BmsElement::Command(c) => {
-                output.push(c);
-            }
-            BmsElement::Random(rb) => {
-                rb.get_token_vec(output, rng);
-            }
-            BmsElement::Switch(sb) => {
-                sb.get_token_vec(output, rng);
+        // これから辿るブロックの残り
+        let mut stack = vec![self.0.iter()];
+        // 分岐で選ばれたブロック
+        let mut chosen: Vec<&'b BmsBlock<'a>> = vec![];
+        while let Some(elements) = stack.last_mut() {
+            let Some(e) = elements.next()
+            else {
+                stack.pop();
+                continue;
+            };
+            match e {
+                BmsElement::Command(c) => {
+                    output.push(c);
+                    continue;
+                }
+                BmsElement::Random(BmsRandomBlock(value, elements)) => {
+                    let n = value.value(choose);
+                    for e in elements {
+                        match e {
+                            BmsRandomElement::Block(b) => chosen.push(b),
+                            BmsRandomElement::IfBlock(ib) => chosen.extend(
+                                ib.r#if
+                                    .iter()
+                                    .find(|(i, _)| *i == n)
+                                    .map(|(_, b)| b)
+                                    .or(ib.r#else.as_ref()),
+                            ),
+                        }
+                    }
+                }
+                BmsElement::Switch(BmsSwitchBlock(value, cases, set)) => {
+                    let n = value.value(choose);
+                    let mut flag = false;
+                    for BmsCaseBlock(label, b, skip) in cases {
+                        flag |= match label {
+                            SwitchLabel::Case(i) => *i == n,
+                            SwitchLabel::Default => !set.contains(&n),
+                        };
+                        if flag {
+                            chosen.push(b);
+                            if *skip {
+                                break;
+                            }
+                        }
+                    }
+                }
             }
+            stack.extend(chosen.drain(..).rev().map(|b| b.0.iter()));
         }
     }
 }
-impl BmsRandomBlock {
-    fn get_token_vec<'a, Rng: rand::RngCore>(
-        &'a self,
-        output: &mut Vec<&'a token::Command>,
-        rng: &mut Rng,
-    ) {
-        use rand::Rng;
-        let n = match self.0 {
-            RandomValue::Max(n) => rng.random_range(1..=n),
-            RandomValue::Set(n) => n,
-        };
-        for e in &self.1 {
-            e.get_token_vec(output, rng, n);
-        }
-    }
+
+/// `#RANDOM`・`#SWITCH`による分岐の概要
+#[derive(Clone, Debug, PartialEq)]
+pub struct BranchInfo {
+    /// `#SWITCH`・`#SETSWITCH`による分岐
+    pub switch: bool,
+    /// `#RANDOM`・`#SWITCH`で指定された乱数の最大値
+    pub max: Option<u128>,
+    /// `#SETRANDOM`・`#SETSWITCH`で指定された値
+    pub set: Option<u128>,
+    /// `#IF`・`#ELSEIF`・`#CASE`の値
+    pub values: Vec<u128>,
+    /// 入れ子の深さ
+    ///
+    /// 一番外側の分岐は0
+    pub depth: usize,
 }
-impl BmsRandomElement {
-    fn get_token_vec<'a, Rng: rand::RngCore>(
-        &'a self,
-        output: &mut Vec<&'a token::Command>,
-        rng: &mut Rng,
-        n: u128,
-    ) {
-        match self {
-            BmsRandomElement::Block(b) => {
-                b.get_token_vec(output, rng);
-            }
-            BmsRandomElement::IfBlock(ib) => {
-                ib.get_token_vec(output, rng, n);
-            }
+impl BmsBlock<'_> {
+    /// 分岐を出現順に`output`へ追加する
+    ///
+    /// [`BmsBlock::get_token_vec`]と同じく、再帰せずに辿る
+    fn branches(&self, output: &mut Vec<BranchInfo>) {
+        // これから辿るブロックの残りと、その深さ
+        let mut stack = vec![(self.0.iter(), 0)];
+        // 分岐の中のブロック
+        let mut children: Vec<&BmsBlock> = vec![];
+        while let Some((elements, depth)) = stack.last_mut() {
+            let depth = *depth;
+            let Some(e) = elements.next()
+            else {
+                stack.pop();
+                continue;
+            };
+            let (switch, value, mut values) = match e {
+                BmsElement::Command(_) => continue,
+                BmsElement::Random(BmsRandomBlock(value, elements)) => {
+                    let mut values = vec![];
+                    for e in elements {
+                        match e {
+                            BmsRandomElement::Block(b) => children.push(b),
+                            BmsRandomElement::IfBlock(ib) => {
+                                for (n, b) in &ib.r#if {
+                                    values.push(*n);
+                                    children.push(b);
+                                }
+                                children.extend(&ib.r#else);
+                            }
+                        }
+                    }
+                    (false, value, values)
+                }
+                BmsElement::Switch(BmsSwitchBlock(value, cases, _)) => {
+                    let mut values = vec![];
+                    for BmsCaseBlock(label, b, _) in cases {
+                        if let SwitchLabel::Case(n) = label {
+                            values.push(*n);
+                        }
+                        children.push(b);
+                    }
+                    (true, value, values)
+                }
+            };
+            values.sort();
+            values.dedup();
+            output.push(BranchInfo {
+                switch,
+                max: match value {
+                    RandomValue::Max(n) => Some(*n),
+                    RandomValue::Set(_) => None,
+                },
+                set: match value {
+                    RandomValue::Max(_) => None,
+                    RandomValue::Set(n) => Some(*n),
+                },
+                values,
+                depth,
+            });
+            stack.extend(
+                children.drain(..).rev().map(|b| (b.0.iter(), depth + 1)),
+            );
         }
     }
 }
-impl BmsIfBlock {
-    fn get_token_vec<'a, Rng: rand::RngCore>(
-        &'a self,
-        output: &mut Vec<&'a token::Command>,
-        rng: &mut Rng,
-        n: u128,
-    ) {
-        for (i, b) in &self.r#if {
-            if *i == n {
-                b.get_token_vec(output, rng);
-                return;
-            }
-        }
-        if let Some(b) = &self.r#else {
-            b.get_token_vec(output, rng);
-        }
+
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "load")]
+impl RawBms<'static> {
+    /// 少しずつ読み込みながら解析する
+    ///
+    /// ファイル全体を文字列にせずに読むので、巨大なファイルに向いている。
+    /// `encoding`が`None`なら[`load::decode`](crate::load::decode)と同じく、
+    /// UTF-8として正しくなければShift_JISとして読む。
+    /// 不正なバイト列は置き換える
+    ///
+    /// `rayon`featureが有効なら、読み込んだ行をまとめて並列に字句解析する
+    pub fn parse_reader(
+        reader: impl std::io::BufRead,
+        encoding: Option<crate::load::TextEncoding>,
+    ) -> std::io::Result<RawBms<'static>> {
+        let (token_stream, unparsed_lines) = lex::lex_reader(reader, encoding)?;
+        Ok(RawBms::from_tokens(token_stream, unparsed_lines))
     }
 }
-impl BmsSwitchBlock {
-    fn get_token_vec<'a, Rng: rand::RngCore>(
-        &'a self,
-        output: &mut Vec<&'a token::Command>,
-        rng: &mut Rng,
-    ) {
-        use rand::Rng;
-        let n = match self.0 {
-            RandomValue::Max(n) => rng.random_range(1..=n),
-            RandomValue::Set(n) => n,
-        };
-        let mut flag = false;
-        for e in &self.1 {
-            if match &e.0 {
-                SwitchLabel::Case(i) => *i == n,
-                SwitchLabel::Default => !self.2.contains(&n),
-            } {
-                flag = true;
-            }
-            if flag {
-                e.1.get_token_vec(output, rng);
-            }
-            if flag && e.2 {
-                return;
-            }
+impl<'a> RawBms<'a> {
+    /// 解析する
+    ///
+    /// 入れ子が[`DEFAULT_MAX_NESTING`]より深い分岐は読み捨てる
+    pub fn parse(source: &'a str) -> RawBms<'a> {
+        let (token_stream, unparsed_lines) = lex::lex(source);
+        RawBms::from_tokens(token_stream, unparsed_lines)
+    }
+    /// 上限を確かめながら解析する
+    ///
+    /// 信頼できない譜面を解析するときに使う
+    pub fn try_parse(
+        source: &'a str,
+        limits: &ParseLimits,
+    ) -> Result<RawBms<'a>, ParseError> {
+        let (token_stream, unparsed_lines) =
+            lex::lex_with_limits(source, limits)?;
+        limits.check_events(
+            token_stream
+                .iter()
+                .map(|t| match t {
+                    token::Token::Command(c) => c.events(),
+                    _ => 0,
+                })
+                .sum(),
+        )?;
+        match RawBms::build(token_stream, unparsed_lines, limits.max_nesting) {
+            (raw, None) => Ok(raw),
+            (_, Some(e)) => Err(e),
         }
     }
-}
-
-use std::collections::{HashMap, HashSet};
-impl RawBms {
-    pub fn parse(source: &str) -> RawBms {
+    /// メインデータ（`#mmmcc:`の行）を読み飛ばして解析する
+    ///
+    /// タイトルやレベルなどのヘッダーだけが必要なときに使う
+    pub fn parse_header(source: &'a str) -> RawBms<'a> {
+        let (token_stream, unparsed_lines) = lex::lex_header(source);
+        RawBms::from_tokens(token_stream, unparsed_lines)
+    }
+    fn from_tokens(
+        token_stream: Vec<token::Token<'a>>,
+        unparsed_lines: Vec<usize>,
+    ) -> RawBms<'a> {
+        RawBms::build(token_stream, unparsed_lines, DEFAULT_MAX_NESTING).0
+    }
+    fn build(
+        token_stream: Vec<token::Token<'a>>,
+        unparsed_lines: Vec<usize>,
+        max_nesting: usize,
+    ) -> (RawBms<'a>, Option<ParseError>) {
         use token::*;
-        use winnow::prelude::*;
-        let token_stream = lex::lex(source);
         let all_wav_files = token_stream
             .iter()
             .filter_map(|t| {
                 if let Token::Command(Command::Wav(_, file)) = t {
-                    Some(file.clone())
+                    Some(file.to_string())
                 }
                 else {
                     None
                 }
             })
             .collect::<HashSet<_>>();
+        let (raw_bms, error) = parse::block(token_stream, max_nesting);
+        let raw = RawBms {
+            raw_bms,
+            all_wav_files,
+            unparsed_lines,
+        };
+        (raw, error)
+    }
+    /// 分岐の無いコマンドの列から作成
+    #[cfg(feature = "bmson")]
+    pub(crate) fn from_commands(
+        commands: Vec<token::Command<'a>>,
+    ) -> RawBms<'a> {
+        let all_wav_files = commands
+            .iter()
+            .filter_map(|c| {
+                if let token::Command::Wav(_, file) = c {
+                    Some(file.to_string())
+                }
+                else {
+                    None
+                }
+            })
+            .collect();
         RawBms {
-            raw_bms: parse::block
-                .parse_next(&mut token_stream.as_slice())
-                .unwrap(),
+            raw_bms: BmsBlock(
+                commands.into_iter().map(BmsElement::Command).collect(),
+            ),
             all_wav_files,
+            unparsed_lines: vec![],
         }
     }
     pub fn all_wav_files(&self) -> &HashSet<String> {
         &self.all_wav_files
     }
+    /// 解析できなかった行の行番号
+    ///
+    /// 行番号は1から始まる
+    pub fn unparsed_lines(&self) -> &[usize] {
+        &self.unparsed_lines
+    }
+    /// 全ての`#RANDOM`・`#SWITCH`の分岐を出現順に並べたもの
+    ///
+    /// 入れ子になった分岐も含む
+    pub fn branches(&self) -> Vec<BranchInfo> {
+        let mut branches = vec![];
+        self.raw_bms.branches(&mut branches);
+        branches
+    }
+    /// `#RANDOM`・`#SWITCH`で選ばれる値の全ての組み合わせ
+    ///
+    /// それぞれの組み合わせは、[`RawBms::make_bms_with`]へ出現順に渡す値になる
+    ///
+    /// 組み合わせが`limit`個を超える場合は`None`を返す
+    pub fn random_combinations(&self, limit: usize) -> Option<Vec<Vec<u128>>> {
+        let mut combinations = vec![];
+        let mut values: Vec<u128> = vec![];
+        loop {
+            if combinations.len() == limit {
+                return None;
+            }
+            let mut maxes = vec![];
+            self.raw_bms.get_token_vec(&mut vec![], &mut |max| {
+                let value = values.get(maxes.len()).copied().unwrap_or(1);
+                maxes.push(max);
+                value
+            });
+            values.resize(maxes.len(), 1);
+            combinations.push(values.clone());
+            // 最後の分岐から順に次の値へ進める
+            while let Some(value) = values.pop() {
+                if value < maxes[values.len()] {
+                    values.push(value + 1);
+                    break;
+                }
+            }
+            if values.is_empty() {
+                return Some(combinations);
+            }
+        }
+    }
+    /// `#RANDOM`・`#SWITCH`の値を`rng`で決めてBMSを生成する
+    ///
+    /// 最大値が0なら1を選ぶ
+    pub fn make_bms(&self, mut rng: impl rand::RngCore) -> Bms<'_> {
+        use rand::Rng;
+        self.make_bms_with(|max| rng.random_range(1..=max.max(1)))
+    }
+    /// `#RANDOM`・`#SWITCH`の値を`choose`で決めてBMSを生成する
+    ///
+    /// `choose`には乱数の最大値が分岐の出現順に渡され、1から最大値までの値を返す
+    pub fn make_bms_with(
+        &self,
+        mut choose: impl FnMut(u128) -> u128,
+    ) -> Bms<'_> {
+        let mut commands = vec![];
+        self.raw_bms.get_token_vec(&mut commands, &mut choose);
+        RawBms::make_bms_from(commands)
+    }
+    /// 上限を確かめながら[`RawBms::make_bms_with`]と同じようにBMSを生成する
+    ///
+    /// 最大値が`limits.max_random`より大きい分岐では`choose`を呼ばない
+    pub fn try_make_bms_with(
+        &self,
+        limits: &ParseLimits,
+        mut choose: impl FnMut(u128) -> u128,
+    ) -> Result<Bms<'_>, ParseError> {
+        let mut error = None;
+        let mut commands = vec![];
+        self.raw_bms
+            .get_token_vec(&mut commands, &mut |max| match limits
+                .check_random(max, None)
+            {
+                Ok(()) => choose(max),
+                Err(e) => {
+                    error.get_or_insert(e);
+                    1
+                }
+            });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        for c in &commands {
+            if let token::Command::MainData(measure, value) = c {
+                limits.check_measure(*measure, None)?;
+                if let token::MainDataValue::Length(length) = value {
+                    limits.check_measure_length(*length, None)?;
+                }
+            }
+        }
+        limits.check_events(commands.iter().map(|c| c.events()).sum())?;
+        Ok(RawBms::make_bms_from(commands))
+    }
     #[allow(deprecated)]
-    pub fn make_bms(&self, mut rng: impl rand::RngCore) -> Bms {
+    fn make_bms_from<'b>(commands: Vec<&'b token::Command<'a>>) -> Bms<'b> {
         use token::Command::*;
-        let mut commands = vec![];
-        self.raw_bms.get_token_vec(&mut commands, &mut rng);
-
         let base62 = commands.iter().any(|c| matches!(c, Base62));
 
         let mut bms = Bms {
@@ -201,12 +443,8 @@ impl RawBms {
             ..Default::default()
         };
 
-        let convert_channel_vec = |ch_vec: &[Channel]| {
-            ch_vec
-                .iter()
-                .map(|ch| ch.to_base_36_or_62(base62))
-                .collect::<Vec<_>>()
-        };
+        let convert_channel_vec =
+            |objects: &token::Objects| objects.ids(base62);
         for c in commands {
             match c {
                 MainData(measure, data) => {
@@ -326,8 +564,7 @@ impl RawBms {
                 OctFp => bms.oct_fp = true,
                 Option(opt) => bms.option.push(opt),
                 ChangeOption(ch, opt) => {
-                    bms.change_option
-                        .insert(ch.to_base_36_or_62(base62), opt);
+                    bms.change_option.insert(ch.to_base_36_or_62(base62), opt);
                 }
                 Wav(ch, s) => {
                     bms.wav.insert(ch.to_base_36_or_62(base62), s);
@@ -604,6 +841,7 @@ pub struct Bms<'a> {
     ///
     /// 試験的に追加された
     #[deprecated]
+    #[allow(clippy::type_complexity)]
     pub switch_bga:
         HashMap<usize, (f64, f64, usize, bool, &'a [u8; 4], &'a [Channel])>,
     /// ビデオの再生位置を調整
@@ -613,6 +851,10 @@ pub struct Bms<'a> {
     pub seek: HashMap<usize, f64>,
 
     /// その他のコマンド
+    ///
+    /// (コマンド名, 値)
+    ///
+    /// `#`で始まるコマンドは`#`を除いた名前、`%`で始まるコマンドは`%`を含めた名前
     pub other: Vec<(&'a str, &'a str)>,
 }
 
@@ -0,0 +1,388 @@
+use super::*;
+use timeline::objects;
+
+/// BGAのレイヤー
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BgaLayer {
+    /// BGA（チャンネル04・0B・A1）
+    Base,
+    /// BGA LAYER（チャンネル07・0C・A2）
+    Layer,
+    /// BGA LAYER2（チャンネル0A・0D・A3）
+    Layer2,
+    /// POOR BGA（チャンネル06・0E・A4）
+    Poor,
+}
+
+impl BgaLayer {
+    /// 全てのレイヤーを描画順に並べたもの
+    pub const ALL: [BgaLayer; 4] = [
+        BgaLayer::Base,
+        BgaLayer::Layer,
+        BgaLayer::Layer2,
+        BgaLayer::Poor,
+    ];
+    #[allow(clippy::type_complexity)]
+    fn rows<'b, 'a>(
+        self,
+        measure: &'b MainData<'a>,
+    ) -> (&'b [Vec<usize>], &'b [&'a [u8]], &'b [Vec<usize>]) {
+        match self {
+            BgaLayer::Base => {
+                (&measure.bga, &measure.bga_alpha, &measure.bga_argb)
+            }
+            BgaLayer::Layer => (
+                &measure.bga_layer,
+                &measure.bga_layer_alpha,
+                &measure.bga_layer_argb,
+            ),
+            BgaLayer::Layer2 => (
+                &measure.bga_layer2,
+                &measure.bga_layer2_alpha,
+                &measure.bga_layer2_argb,
+            ),
+            BgaLayer::Poor => (
+                &measure.bga_poor,
+                &measure.bga_poor_alpha,
+                &measure.bga_poor_argb,
+            ),
+        }
+    }
+}
+
+/// `#POORBGA`で指定されるPOOR BGAの表示方法
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum PoorBgaMode {
+    /// POORのときにBGAの代わりに表示する（0）
+    #[default]
+    Interrupt,
+    /// POORのときにBGAに重ねて表示する（1）
+    Overlay,
+    /// 表示しない（2）
+    Hidden,
+}
+
+impl PoorBgaMode {
+    /// `#POORBGA`の値から変換する
+    ///
+    /// 指定が無いか不明な値なら[`PoorBgaMode::Interrupt`]
+    pub fn new(poor_bga: Option<i32>) -> PoorBgaMode {
+        match poor_bga {
+            Some(1) => PoorBgaMode::Overlay,
+            Some(2) => PoorBgaMode::Hidden,
+            _ => PoorBgaMode::Interrupt,
+        }
+    }
+}
+
+/// 表示する画像
+#[derive(Clone, Debug, PartialEq)]
+pub struct BgaImage<'a> {
+    /// メインデータのid
+    pub id: usize,
+    /// `#BMP`・`#EXBMP`のid
+    ///
+    /// `#BGA`・`#@BGA`で定義されたidでは参照先のid
+    pub bmp: usize,
+    /// 画像または動画のファイル名
+    pub file: Option<&'a str>,
+    /// `#EXBMP`で指定された透過色
+    pub color_key: Option<[u8; 4]>,
+    /// 切り抜く範囲
+    ///
+    /// [[左上のx, 左上のy], [右下のx, 右下のy]]
+    ///
+    /// `None`なら画像全体
+    pub crop: Option<[[f64; 2]; 2]>,
+    /// 表示位置（左上）
+    pub position: [f64; 2],
+}
+
+/// ある時刻以降のレイヤーの状態
+#[derive(Clone, Debug, PartialEq)]
+pub struct BgaState<'a> {
+    /// 位置（拍数）
+    pub beat: f64,
+    /// 時刻
+    pub time: f64,
+    /// 表示する画像
+    pub image: Option<BgaImage<'a>>,
+    /// 画像に掛けるaRGB
+    ///
+    /// aRGBのチャンネルの値に、不透明度のチャンネルの値を掛けたもの
+    pub argb: [u8; 4],
+}
+
+/// 背景の動画
+#[derive(Clone, Debug, PartialEq)]
+pub struct BgaVideo<'a> {
+    /// ファイル名
+    pub file: &'a str,
+    /// 再生を始める時刻
+    ///
+    /// `#VIDEODELAY`のフレーム数を`#VIDEOf/s`で時間に変換したもの
+    pub start: f64,
+}
+
+/// BGAの各レイヤーの状態の変化を時刻順に並べたもの
+#[derive(Clone, Debug, PartialEq)]
+pub struct BgaTimeline<'a> {
+    layers: [Vec<BgaState<'a>>; 4],
+    video: Option<BgaVideo<'a>>,
+    poor_mode: PoorBgaMode,
+}
+
+impl<'a> BgaTimeline<'a> {
+    pub fn new(bms: &Bms<'a>, timeline: &BmsTimeline) -> BgaTimeline<'a> {
+        let poor_mode = PoorBgaMode::new(bms.poor_bga);
+        let layers = BgaLayer::ALL.map(|layer| match layer {
+            BgaLayer::Poor if poor_mode == PoorBgaMode::Hidden => vec![],
+            _ => states(bms, timeline, layer),
+        });
+        let video = bms.video_file.or(bms.movie).map(|file| BgaVideo {
+            file,
+            start: match (bms.video_delay, bms.video_fps) {
+                (Some(delay), Some(fps)) if 0. < fps => {
+                    delay as f64 * 1000. / fps
+                }
+                _ => 0.,
+            },
+        });
+        BgaTimeline {
+            layers,
+            video,
+            poor_mode,
+        }
+    }
+    /// レイヤーの状態が変化する時刻ごとの状態
+    ///
+    /// POOR BGAは`#BMP00`・`#EXBMP00`が定義されていれば最初から表示し、
+    /// [`PoorBgaMode::Hidden`]なら空
+    pub fn states(&self, layer: BgaLayer) -> &[BgaState<'a>] {
+        &self.layers[layer as usize]
+    }
+    /// その時刻のレイヤーの状態
+    ///
+    /// 最初の変化より前なら`None`
+    pub fn at(&self, layer: BgaLayer, time: f64) -> Option<&BgaState<'a>> {
+        let states = self.states(layer);
+        let i = states.partition_point(|s| s.time <= time);
+        i.checked_sub(1).map(|i| &states[i])
+    }
+    /// `#VIDEOFILE`か`#MOVIE`で指定された背景の動画
+    pub fn video(&self) -> Option<&BgaVideo<'a>> {
+        self.video.as_ref()
+    }
+    /// POOR BGAの表示方法
+    pub fn poor_mode(&self) -> PoorBgaMode {
+        self.poor_mode
+    }
+}
+
+/// メインデータのidから表示する画像を決める
+fn image<'a>(bms: &Bms<'a>, id: usize) -> BgaImage<'a> {
+    let (bmp, crop, position) = if let Some((bmp, p)) = bms.bga.get(&id) {
+        (*bmp, Some([p[0], p[1]]), p[2])
+    }
+    else if let Some((bmp, p)) = bms.at_bga.get(&id) {
+        // 左上の座標と幅・高さで指定される
+        let [[x, y], [w, h], position] = **p;
+        (*bmp, Some([[x, y], [x + w, y + h]]), position)
+    }
+    else {
+        (id, None, [0., 0.])
+    };
+    let ex_bmp = bms.ex_bmp.get(&bmp);
+    BgaImage {
+        id,
+        bmp,
+        file: bms.bmp.get(&bmp).copied().or(ex_bmp.map(|e| e.1)),
+        color_key: ex_bmp.map(|e| *e.0),
+        crop,
+        position,
+    }
+}
+
+fn states<'a>(
+    bms: &Bms<'a>,
+    timeline: &BmsTimeline,
+    layer: BgaLayer,
+) -> Vec<BgaState<'a>> {
+    enum Event {
+        Image(usize),
+        Alpha(u8),
+        Argb(usize),
+    }
+    let mut events = vec![];
+    // BMP00は既定のPOOR画像
+    if layer == BgaLayer::Poor
+        && (bms.bmp.contains_key(&0) || bms.ex_bmp.contains_key(&0))
+    {
+        events.push((0., Event::Image(0)));
+    }
+    for (m, measure) in bms.main_data.iter().enumerate() {
+        let (images, alphas, argbs) = layer.rows(measure);
+        for row in images {
+            for (f, id) in objects(row) {
+                events.push((timeline.beat(m, f), Event::Image(*id)));
+            }
+        }
+        for row in alphas {
+            for (f, alpha) in objects(row) {
+                events.push((timeline.beat(m, f), Event::Alpha(*alpha)));
+            }
+        }
+        for row in argbs {
+            for (f, id) in objects(row) {
+                events.push((timeline.beat(m, f), Event::Argb(*id)));
+            }
+        }
+    }
+    events.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut states: Vec<BgaState> = vec![];
+    let mut current = None;
+    let mut alpha = 255;
+    let mut argb = [255; 4];
+    for (beat, event) in events {
+        match event {
+            Event::Image(id) => current = Some(image(bms, id)),
+            Event::Alpha(a) => alpha = a,
+            Event::Argb(id) => match bms.argb.get(&id) {
+                Some(a) => argb = **a,
+                None => log::warn!("ARGB{}が定義されていません", id),
+            },
+        }
+        let mut effective = argb;
+        effective[0] = (argb[0] as u32 * alpha as u32 / 255) as u8;
+        let state = BgaState {
+            beat,
+            time: timeline.beat_to_ms(beat),
+            image: current.clone(),
+            argb: effective,
+        };
+        // 同じ位置の変化はまとめる
+        match states.last_mut() {
+            Some(last) if last.beat == beat => *last = state,
+            _ => states.push(state),
+        }
+    }
+    states
+}
+
+impl<'a> Bms<'a> {
+    /// BGAの各レイヤーの状態を時刻順に並べたもの
+    pub fn bga_timeline(&self, timeline: &BmsTimeline) -> BgaTimeline<'a> {
+        BgaTimeline::new(self, timeline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layers() {
+        let raw = RawBms::parse(
+            r"
+#BPM 120
+#BMP01 back.bmp
+#EXBMP02 255,0,0,0 layer.bmp
+#BGA03 01 10 20 110 120 5 6
+#@BGA04 02 10 20 100 100 0 0
+#ARGB01 255,128,64,32
+#VIDEOFILE movie.mpg
+#VIDEOf/s 30
+#VIDEODELAY 15
+#00004:0103
+#00007:0004
+#0000C:0080
+#000A2:0001
+#00106:02
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let timeline = bms.timeline();
+        let bga = bms.bga_timeline(&timeline);
+
+        let base = bga.states(BgaLayer::Base);
+        assert_eq!(base.len(), 2);
+        assert_eq!(base[0].time, 0.);
+        assert_eq!(base[0].image.as_ref().unwrap().file, Some("back.bmp"));
+        assert_eq!(base[0].image.as_ref().unwrap().crop, None);
+        let image = base[1].image.as_ref().unwrap();
+        assert_eq!(base[1].time, 1000.);
+        assert_eq!((image.id, image.bmp), (3, 1));
+        assert_eq!(image.crop, Some([[10., 20.], [110., 120.]]));
+        assert_eq!(image.position, [5., 6.]);
+        assert_eq!(bga.at(BgaLayer::Base, 999.), Some(&base[0]));
+        assert_eq!(bga.at(BgaLayer::Base, 1000.), Some(&base[1]));
+
+        // 画像・不透明度・aRGBが同じ位置で変化する
+        let layer = bga.at(BgaLayer::Layer, 1500.).unwrap();
+        let image = layer.image.as_ref().unwrap();
+        assert_eq!(image.file, Some("layer.bmp"));
+        assert_eq!(image.color_key, Some([255, 0, 0, 0]));
+        assert_eq!(image.crop, Some([[10., 20.], [110., 120.]]));
+        assert_eq!(layer.argb, [128, 128, 64, 32]);
+        assert_eq!(bga.at(BgaLayer::Layer, 500.), None);
+
+        let poor = bga.states(BgaLayer::Poor);
+        assert_eq!(poor.len(), 1);
+        assert_eq!(poor[0].time, 2000.);
+        assert_eq!(poor[0].argb, [255; 4]);
+        assert!(bga.states(BgaLayer::Layer2).is_empty());
+
+        assert_eq!(
+            bga.video(),
+            Some(&BgaVideo {
+                file: "movie.mpg",
+                start: 500.
+            })
+        );
+    }
+
+    #[test]
+    fn poor() {
+        // BMP00はチャンネル06が無くても最初から表示する
+        let raw = RawBms::parse("#BPM 120\n#BMP00 miss.bmp\n#BMP01 a.bmp");
+        let bms = raw.make_bms(rand::rng());
+        let bga = bms.bga_timeline(&bms.timeline());
+        assert_eq!(bga.poor_mode(), PoorBgaMode::Interrupt);
+        let poor = bga.states(BgaLayer::Poor);
+        assert_eq!(poor.len(), 1);
+        assert_eq!(poor[0].time, 0.);
+        assert_eq!(poor[0].image.as_ref().unwrap().file, Some("miss.bmp"));
+
+        // チャンネル06で切り替わる
+        let raw = RawBms::parse(
+            "#BPM 120\n#POORBGA 1\n#EXBMP00 0,0,0,0 miss.bmp\n#BMP01 a.bmp\n#00106:01",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let bga = bms.bga_timeline(&bms.timeline());
+        assert_eq!(bga.poor_mode(), PoorBgaMode::Overlay);
+        let poor = bga.states(BgaLayer::Poor);
+        assert_eq!(poor.len(), 2);
+        assert_eq!(poor[0].image.as_ref().unwrap().file, Some("miss.bmp"));
+        assert_eq!(poor[1].time, 2000.);
+        assert_eq!(poor[1].image.as_ref().unwrap().file, Some("a.bmp"));
+
+        // 同じ位置ならチャンネル06の画像
+        let raw = RawBms::parse("#BMP00 miss.bmp\n#BMP01 a.bmp\n#00006:01");
+        let bms = raw.make_bms(rand::rng());
+        let bga = bms.bga_timeline(&bms.timeline());
+        let poor = bga.states(BgaLayer::Poor);
+        assert_eq!(poor.len(), 1);
+        assert_eq!(poor[0].image.as_ref().unwrap().file, Some("a.bmp"));
+
+        // POORBGA 2では表示しない
+        let raw = RawBms::parse(
+            "#POORBGA 2\n#BMP00 miss.bmp\n#BMP01 a.bmp\n#00106:01",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let bga = bms.bga_timeline(&bms.timeline());
+        assert_eq!(bga.poor_mode(), PoorBgaMode::Hidden);
+        assert!(bga.states(BgaLayer::Poor).is_empty());
+        assert_eq!(bga.at(BgaLayer::Poor, 3000.), None);
+    }
+}
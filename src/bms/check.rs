@@ -0,0 +1,411 @@
+use super::*;
+use notes::{LONG_OFFSET, NOTE_CHANNELS};
+use std::collections::BTreeSet;
+use std::fmt;
+use timeline::objects;
+
+/// [`RawBms::check`]で全ての分岐の組み合わせを調べる上限
+pub const CHECK_COMBINATIONS: usize = 256;
+
+/// idで定義するものの種類
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Definition {
+    Wav,
+    Bmp,
+    Bpm,
+    Stop,
+    Scroll,
+    Speed,
+    Text,
+}
+
+impl Definition {
+    /// 定義するコマンドの名前
+    pub fn command(self) -> &'static str {
+        match self {
+            Definition::Wav => "WAV",
+            Definition::Bmp => "BMP",
+            Definition::Bpm => "BPM",
+            Definition::Stop => "STOP",
+            Definition::Scroll => "SCROLL",
+            Definition::Speed => "SPEED",
+            Definition::Text => "TEXT",
+        }
+    }
+}
+
+/// [`Bms::check`]・[`RawBms::check`]で見つかった問題
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BmsIssue {
+    /// 解析できなかった行
+    ///
+    /// 1から始まる行番号
+    UnparsedLine(usize),
+    /// 対応していないか、値を解釈できなかったコマンド
+    UnknownCommand(String),
+    /// 定義されていないidの参照
+    Undefined {
+        definition: Definition,
+        id: usize,
+        measure: usize,
+    },
+    /// 使われていない定義
+    Unused { definition: Definition, id: usize },
+    /// 終点の無いロングノート
+    UnterminatedLongNote { channel: usize, measure: usize },
+    /// 対応する始点の無いLNOBJ
+    UnmatchedLnObject { channel: usize, measure: usize },
+    /// ロングノートに重なっているノーツ
+    OverlappingLongNote { channel: usize, measure: usize },
+}
+
+impl BmsIssue {
+    /// 譜面が正しく再生されない問題かどうか
+    ///
+    /// 解析できなかった行・コマンドと使われていない定義は`false`
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            BmsIssue::UnparsedLine(_)
+                | BmsIssue::UnknownCommand(_)
+                | BmsIssue::Unused { .. }
+        )
+    }
+}
+
+impl fmt::Display for BmsIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use BmsIssue::*;
+        let id = |n: usize| write::id(n, 36 * 36 <= n);
+        let channel = |n: usize| write::id(n, false);
+        match self {
+            UnparsedLine(line) => write!(f, "{line}行目を解析できません"),
+            UnknownCommand(command) => {
+                write!(f, "{}{command}を解釈できません", write::prefix(command))
+            }
+            Undefined {
+                definition,
+                id: n,
+                measure,
+            } => write!(
+                f,
+                "{measure:03}小節の{}{}は定義されていません",
+                definition.command(),
+                id(*n)
+            ),
+            Unused { definition, id: n } => {
+                write!(
+                    f,
+                    "{}{}は使われていません",
+                    definition.command(),
+                    id(*n)
+                )
+            }
+            UnterminatedLongNote {
+                channel: c,
+                measure,
+            } => write!(
+                f,
+                "{measure:03}小節の{}チャンネルのロングノートに終点がありません",
+                channel(*c + LONG_OFFSET)
+            ),
+            UnmatchedLnObject {
+                channel: c,
+                measure,
+            } => write!(
+                f,
+                "{measure:03}小節の{}チャンネルのLNOBJに始点がありません",
+                channel(*c)
+            ),
+            OverlappingLongNote {
+                channel: c,
+                measure,
+            } => write!(
+                f,
+                "{measure:03}小節の{}チャンネルのノーツがロングノートに重なっています",
+                channel(*c)
+            ),
+        }
+    }
+}
+
+/// 順番を保ったまま同じ問題を1つにまとめる
+fn dedup(issues: &mut Vec<BmsIssue>) {
+    let mut seen = HashSet::new();
+    issues.retain(|issue| seen.insert(issue.clone()));
+}
+
+impl Bms<'_> {
+    /// 解釈できなかったコマンド、idの定義と参照、ロングノートを検査する
+    ///
+    /// 同じ問題は1つにまとめる
+    pub fn check(&self) -> Vec<BmsIssue> {
+        let mut issues = vec![];
+        for (command, _) in &self.other {
+            issues.push(BmsIssue::UnknownCommand(command.to_string()));
+        }
+        self.check_references(&mut issues);
+        self.check_long_notes(&mut issues);
+        dedup(&mut issues);
+        issues
+    }
+    fn defined(&self, definition: Definition, id: usize) -> bool {
+        match definition {
+            Definition::Wav => {
+                self.wav.contains_key(&id) || self.ex_wav.contains_key(&id)
+            }
+            Definition::Bmp => {
+                self.bmp.contains_key(&id)
+                    || self.ex_bmp.contains_key(&id)
+                    || self.bga.contains_key(&id)
+                    || self.at_bga.contains_key(&id)
+            }
+            Definition::Bpm => self.ex_bpm.contains_key(&id),
+            Definition::Stop => self.stop.contains_key(&id),
+            Definition::Scroll => self.scroll.contains_key(&id),
+            Definition::Speed => self.speed.contains_key(&id),
+            Definition::Text => self.text.contains_key(&id),
+        }
+    }
+    fn check_references(&self, issues: &mut Vec<BmsIssue>) {
+        use Definition::*;
+        let mut used = HashSet::new();
+        for (m, measure) in self.main_data.iter().enumerate() {
+            let mut rows = vec![
+                (Wav, &measure.bgm),
+                (Bmp, &measure.bga),
+                (Bmp, &measure.bga_layer),
+                (Bmp, &measure.bga_layer2),
+                (Bmp, &measure.bga_poor),
+                (Bpm, &measure.ex_bpm),
+                (Stop, &measure.stop),
+                (Scroll, &measure.scroll),
+                (Speed, &measure.speed),
+                (Text, &measure.text),
+            ];
+            for map in [
+                &measure.notes,
+                &measure.invisible_notes,
+                &measure.long_notes,
+            ] {
+                rows.extend(map.values().map(|rows| (Wav, rows)));
+            }
+            for (definition, rows) in rows {
+                for row in rows {
+                    for (_, &id) in objects(row) {
+                        used.insert((definition, id));
+                        // LNOBJのidは音声が無くてもよい
+                        if definition == Wav && self.ln_object.contains(&id) {
+                            continue;
+                        }
+                        if !self.defined(definition, id) {
+                            issues.push(BmsIssue::Undefined {
+                                definition,
+                                id,
+                                measure: m,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        // #BGAで使われる画像
+        for (bmp, _) in self.bga.values().chain(self.at_bga.values()) {
+            used.insert((Bmp, *bmp));
+        }
+
+        let mut defined = BTreeSet::new();
+        defined.extend(self.wav.keys().map(|id| (Wav, *id)));
+        defined.extend(self.ex_wav.keys().map(|id| (Wav, *id)));
+        defined.extend(self.bmp.keys().map(|id| (Bmp, *id)));
+        defined.extend(self.ex_bmp.keys().map(|id| (Bmp, *id)));
+        defined.extend(self.ex_bpm.keys().map(|id| (Bpm, *id)));
+        defined.extend(self.stop.keys().map(|id| (Stop, *id)));
+        defined.extend(self.scroll.keys().map(|id| (Scroll, *id)));
+        defined.extend(self.speed.keys().map(|id| (Speed, *id)));
+        defined.extend(self.text.keys().map(|id| (Text, *id)));
+        for (definition, id) in defined {
+            // WAV00は地雷、BMP00はPOORのときに使われる
+            if id == 0 && matches!(definition, Wav | Bmp) {
+                continue;
+            }
+            if !used.contains(&(definition, id)) {
+                issues.push(BmsIssue::Unused { definition, id });
+            }
+        }
+    }
+    fn check_long_notes(&self, issues: &mut Vec<BmsIssue>) {
+        let timeline = self.timeline();
+        for channel in NOTE_CHANNELS {
+            if self.ln_type != Some(2) {
+                let long = self.lane_objects(&timeline, |m| {
+                    m.long_notes.get(&(channel + LONG_OFFSET))
+                });
+                if long.len() % 2 == 1 {
+                    let (beat, _) = long[long.len() - 1];
+                    issues.push(BmsIssue::UnterminatedLongNote {
+                        channel,
+                        measure: timeline.measure_at(beat),
+                    });
+                }
+            }
+            if !self.ln_object.is_empty() {
+                let mut start = false;
+                for (beat, id) in
+                    self.lane_objects(&timeline, |m| m.notes.get(&channel))
+                {
+                    let end = self.ln_object.contains(&id);
+                    if end && !start {
+                        issues.push(BmsIssue::UnmatchedLnObject {
+                            channel,
+                            measure: timeline.measure_at(beat),
+                        });
+                    }
+                    start = !end;
+                }
+            }
+        }
+
+        let mut notes = self
+            .notes(&timeline)
+            .into_iter()
+            .filter(|n| {
+                matches!(n.kind, BmsNoteKind::Normal | BmsNoteKind::Long)
+            })
+            .collect::<Vec<_>>();
+        notes.sort_by(|a, b| {
+            a.channel.cmp(&b.channel).then(a.beat.total_cmp(&b.beat))
+        });
+        for pair in notes.windows(2) {
+            if let [a, b] = pair
+                && a.channel == b.channel
+                && let Some(end) = &a.end
+                && b.beat <= end.beat
+            {
+                issues.push(BmsIssue::OverlappingLongNote {
+                    channel: b.channel,
+                    measure: timeline.measure_at(b.beat),
+                });
+            }
+        }
+    }
+}
+
+impl RawBms<'_> {
+    /// 解析できなかった行と、全ての分岐の組み合わせの[`Bms::check`]の結果
+    ///
+    /// 組み合わせが[`CHECK_COMBINATIONS`]個を超える場合は、
+    /// 全ての分岐で1を選んだものだけを検査する
+    ///
+    /// 使われていない定義は、どの組み合わせでも使われていないものだけを返す
+    pub fn check(&self) -> Vec<BmsIssue> {
+        self.check_with(|_| ())
+    }
+    /// [`RawBms::check`]と同じ検査をし、検査した組み合わせのBMSごとに`visit`を呼ぶ
+    ///
+    /// 検査と同時に参照されているファイルなどを集めるのに使う
+    pub fn check_with(&self, mut visit: impl FnMut(&Bms)) -> Vec<BmsIssue> {
+        let mut issues = self
+            .unparsed_lines
+            .iter()
+            .map(|&line| BmsIssue::UnparsedLine(line))
+            .collect::<Vec<_>>();
+        let combinations = self
+            .random_combinations(CHECK_COMBINATIONS)
+            .unwrap_or_else(|| vec![vec![]]);
+        let mut unused: Option<Vec<BmsIssue>> = None;
+        for values in combinations {
+            let mut values = values.into_iter();
+            let bms = self.make_bms_with(|_| values.next().unwrap_or(1));
+            let (new_unused, others): (Vec<_>, Vec<_>) = bms
+                .check()
+                .into_iter()
+                .partition(|i| matches!(i, BmsIssue::Unused { .. }));
+            issues.extend(others);
+            unused = Some(match unused {
+                Some(unused) => {
+                    let new_unused =
+                        new_unused.into_iter().collect::<HashSet<_>>();
+                    unused
+                        .into_iter()
+                        .filter(|i| new_unused.contains(i))
+                        .collect()
+                }
+                None => new_unused,
+            });
+            visit(&bms);
+        }
+        issues.extend(unused.into_iter().flatten());
+        dedup(&mut issues);
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let raw = RawBms::parse(
+            r"
+#WAV01 a.wav
+#WAV02 b.wav
+#WAV03 c.wav
+#BPM01 240
+#LNOBJ ZZ
+#BPM
+#RANDOM 2
+#IF 1
+#00111:03
+#ENDIF
+#ENDRANDOM
+#00111:0102
+#00112:ZZ
+#00108:02
+#00151:01000001
+#00252:01
+",
+        );
+        assert_eq!(
+            raw.check(),
+            vec![
+                BmsIssue::UnknownCommand("BPM".to_string()),
+                BmsIssue::Undefined {
+                    definition: Definition::Bpm,
+                    id: 2,
+                    measure: 1,
+                },
+                BmsIssue::UnterminatedLongNote {
+                    channel: Channel::new("12").to_base_36(),
+                    measure: 2,
+                },
+                BmsIssue::UnmatchedLnObject {
+                    channel: Channel::new("12").to_base_36(),
+                    measure: 1,
+                },
+                BmsIssue::OverlappingLongNote {
+                    channel: Channel::new("11").to_base_36(),
+                    measure: 1,
+                },
+                BmsIssue::Unused {
+                    definition: Definition::Bpm,
+                    id: 1,
+                },
+            ]
+        );
+        // 検査した組み合わせごとにBMSを受け取る
+        let mut visited = 0;
+        let issues = raw.check_with(|_| visited += 1);
+        assert_eq!(issues, raw.check());
+        assert_eq!(visited, 2);
+        assert_eq!(
+            BmsIssue::Unused {
+                definition: Definition::Wav,
+                id: 36,
+            }
+            .to_string(),
+            "WAV10は使われていません"
+        );
+    }
+}
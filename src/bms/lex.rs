@@ -1,27 +1,28 @@
+use super::parse::{ParseError, ParseLimits};
 use super::token::*;
 use Command::*;
 use ControlFlow::*;
+use std::borrow::Cow;
 use winnow::{
     ascii::{Caseless, alphanumeric1, digit1, float},
-    combinator::{alt, dispatch, empty, opt, preceded, repeat, separated},
+    combinator::{
+        alt, dispatch, empty, opt, peek, preceded, repeat, separated,
+    },
     error::ParserError,
     prelude::*,
     stream::AsChar,
     token::{any, rest, take_while},
 };
 
-fn quoted_string(input: &mut &str) -> ModalResult<String> {
-    let str = rest_string.parse_next(input)?;
-    let trim = str.trim();
-    if trim.starts_with('"') && trim.ends_with('"') {
-        Ok(trim[1..trim.len() - 1].to_string())
-    }
-    else {
-        Err(ParserError::from_input(input))
+fn quoted_string<'a>(input: &mut &'a str) -> ModalResult<Cow<'a, str>> {
+    let str: &str = rest.parse_next(input)?;
+    match unquote(str.trim()) {
+        Some(s) => Ok(Cow::Borrowed(s)),
+        None => Err(ParserError::from_input(input)),
     }
 }
-fn rest_string(input: &mut &str) -> ModalResult<String> {
-    rest.map(|s: &str| s.to_string()).parse_next(input)
+fn rest_string<'a>(input: &mut &'a str) -> ModalResult<Cow<'a, str>> {
+    rest.map(Cow::Borrowed).parse_next(input)
 }
 fn one_of_space(input: &mut &str) -> ModalResult<char> {
     any.verify(|c: &char| c.is_whitespace()).parse_next(input)
@@ -49,20 +50,211 @@ fn uint<N: num_traits::Num>(input: &mut &str) -> ModalResult<N> {
         .verify_map(|s: &str| N::from_str_radix(s, 10).ok())
         .parse_next(input)
 }
-fn quoted_or_no_quote(input: &mut &str) -> ModalResult<String> {
-    let mut str = rest_string.parse_next(input)?;
-    let trim = str.trim();
-    if trim.starts_with('"') && trim.ends_with('"') {
-        str = trim[1..trim.len() - 1].to_string();
+fn quoted_or_no_quote<'a>(input: &mut &'a str) -> ModalResult<Cow<'a, str>> {
+    let str: &str = rest.parse_next(input)?;
+    Ok(Cow::Borrowed(unquote(str.trim()).unwrap_or(str)))
+}
+/// `"`で囲まれた文字列の中身
+///
+/// `"`1文字だけなら囲まれていない
+fn unquote(s: &str) -> std::option::Option<&str> {
+    s.strip_prefix('"')?.strip_suffix('"')
+}
+/// 空白で区切られてもよい、2文字ずつ並んだオブジェクトの列
+///
+/// 2文字に満たない部分より後は読まない
+fn objects<'a>(input: &mut &'a str) -> ModalResult<Objects<'a>> {
+    let start = *input;
+    let mut end = 0;
+    let mut rest = start;
+    loop {
+        let trimmed = rest.trim_start();
+        match trimmed.as_bytes() {
+            [a, b, ..]
+                if a.is_ascii_alphanumeric() && b.is_ascii_alphanumeric() =>
+            {
+                rest = &trimmed[2..];
+                end = start.len() - rest.len();
+            }
+            _ => break,
+        }
+    }
+    *input = &start[end..];
+    Ok(Objects::from(&start[..end]))
+}
+/// 字句解析の結果と、解析できなかった行の行番号（1から）
+pub(crate) type Lexed<'a> = (Vec<Token<'a>>, Vec<usize>);
+
+pub(crate) fn lex(input: &str) -> Lexed<'_> {
+    lex_lines(input, |_| true, None).unwrap_or_else(|_| unreachable!())
+}
+/// メインデータの行を読み飛ばして字句解析する
+pub(crate) fn lex_header(input: &str) -> Lexed<'_> {
+    lex_lines(input, |line| !is_main_data_line(line), None)
+        .unwrap_or_else(|_| unreachable!())
+}
+/// 1行ずつ上限を確かめながら字句解析する
+pub(crate) fn lex_with_limits<'a>(
+    input: &'a str,
+    limits: &ParseLimits,
+) -> Result<Lexed<'a>, ParseError> {
+    lex_lines(input, |_| true, Some(limits))
+}
+/// `#mmmcc:`で始まる行かどうか
+fn is_main_data_line(line: &str) -> bool {
+    let b = line.trim_start().as_bytes();
+    7 <= b.len()
+        && b[0] == b'#'
+        && b[1..4].iter().all(u8::is_ascii_digit)
+        && b[4..6].iter().all(u8::is_ascii_alphanumeric)
+        && b[6] == b':'
+}
+
+/// [`lex_reader`]で一度に読み込む行数
+#[cfg(feature = "load")]
+const READ_LINES: usize = 16384;
+/// 並列に字句解析するときに1つのタスクで扱う行数
+#[cfg(feature = "rayon")]
+const PARALLEL_LINES: usize = 1024;
+
+/// 読み込んだ行をまとめて文字列にする
+///
+/// `encoding`が`None`なら、UTF-8として正しくない部分が見つかってから後は
+/// Shift_JISとして読み、`encoding`をShift_JISにする
+#[cfg(feature = "load")]
+fn decode_chunk<'b>(
+    bytes: &'b [u8],
+    encoding: &mut std::option::Option<crate::load::TextEncoding>,
+) -> Cow<'b, str> {
+    use crate::load::TextEncoding;
+    if *encoding != Some(TextEncoding::ShiftJis) {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => return Cow::Borrowed(text),
+            Err(_) if encoding.is_none() => {
+                *encoding = Some(TextEncoding::ShiftJis);
+            }
+            Err(_) => return String::from_utf8_lossy(bytes),
+        }
     }
-    Ok(str)
+    encoding_rs::SHIFT_JIS.decode_without_bom_handling(bytes).0
 }
 
-pub(crate) fn lex(input: &str) -> Vec<Token> {
+/// 少しずつ読み込みながら字句解析する
+///
+/// 文字コードについては[`decode_chunk`]を参照
+///
+/// UTF-8のBOMは取り除き、不正なバイト列は置き換える
+#[cfg(feature = "load")]
+pub(crate) fn lex_reader(
+    mut reader: impl std::io::BufRead,
+    mut encoding: std::option::Option<crate::load::TextEncoding>,
+) -> std::io::Result<Lexed<'static>> {
     let mut r = vec![];
-    for (line, mut input) in input.lines().enumerate() {
+    let mut failed = vec![];
+    let mut first = 0;
+    let mut buf = vec![];
+    loop {
+        // 行の途中で区切らないように、1行ずつ読んでまとめる
+        buf.clear();
+        let mut eof = false;
+        for _ in 0..READ_LINES {
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                eof = true;
+                break;
+            }
+        }
+        let bytes = match first {
+            0 => buf.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(&buf),
+            _ => &buf,
+        };
+        let text = decode_chunk(bytes, &mut encoding);
+        let lines = text.lines().collect::<Vec<_>>();
+        let (tokens, chunk_failed) = lex_chunk(&lines, first, &|_| true, None)
+            .unwrap_or_else(|_| unreachable!());
+        r.extend(tokens.into_iter().map(Token::into_owned));
+        failed.extend(chunk_failed);
+        first += lines.len();
+        if eof {
+            break;
+        }
+    }
+    Ok((r, failed))
+}
+
+/// `limits`が`None`なら失敗しない
+fn lex_lines<'a>(
+    input: &'a str,
+    filter: impl Fn(&str) -> bool + Sync,
+    limits: std::option::Option<&ParseLimits>,
+) -> Result<Lexed<'a>, ParseError> {
+    #[cfg(feature = "rayon")]
+    if 1 < rayon::current_num_threads() {
+        let lines = input.lines().collect::<Vec<_>>();
+        return lex_chunk(&lines, 0, &filter, limits);
+    }
+    lex_iter(input.lines().enumerate(), &filter, limits)
+}
+/// 行の列を字句解析する
+///
+/// `first`は最初の行の0から始まる行番号
+///
+/// `rayon`featureが有効で複数のスレッドを使えるなら、
+/// [`PARALLEL_LINES`]行ずつ並列に解析する
+#[cfg(any(feature = "load", feature = "rayon"))]
+fn lex_chunk<'a>(
+    lines: &[&'a str],
+    first: usize,
+    filter: &(impl Fn(&str) -> bool + Sync),
+    limits: std::option::Option<&ParseLimits>,
+) -> Result<Lexed<'a>, ParseError> {
+    #[cfg(feature = "rayon")]
+    if PARALLEL_LINES < lines.len() && 1 < rayon::current_num_threads() {
+        use rayon::prelude::*;
+        let chunks = lines
+            .par_chunks(PARALLEL_LINES)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let first = first + i * PARALLEL_LINES;
+                lex_iter(
+                    chunk.iter().enumerate().map(|(j, &l)| (first + j, l)),
+                    filter,
+                    limits,
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut r = vec![];
+        let mut failed = vec![];
+        // 最初に見つかったエラーを返す
+        for chunk in chunks {
+            let (tokens, chunk_failed) = chunk?;
+            r.extend(tokens);
+            failed.extend(chunk_failed);
+        }
+        return Ok((r, failed));
+    }
+    lex_iter(
+        lines.iter().enumerate().map(|(i, &l)| (first + i, l)),
+        filter,
+        limits,
+    )
+}
+/// (0から始まる行番号, 行)の列を字句解析する
+fn lex_iter<'a>(
+    lines: impl Iterator<Item = (usize, &'a str)>,
+    filter: &impl Fn(&str) -> bool,
+    limits: std::option::Option<&ParseLimits>,
+) -> Result<Lexed<'a>, ParseError> {
+    let mut r = vec![];
+    let mut failed = vec![];
+    for (line, mut input) in lines {
+        if !filter(input) {
+            continue;
+        }
         match preceded(space0, command).parse_next(&mut input) {
             Ok(t) => {
+                if let Some(limits) = limits {
+                    check_limits(&t, line + 1, limits)?;
+                }
                 if t != Token::Comment {
                     r.push(t);
                 }
@@ -70,108 +262,159 @@ pub(crate) fn lex(input: &str) -> Vec<Token> {
             Err(e) => {
                 log::warn!("{}行の解析に失敗しました", line + 1);
                 log::debug!("{e}");
+                failed.push(line + 1);
             }
         }
     }
-    r
+    Ok((r, failed))
+}
+/// 1行で分かる上限を確かめる
+///
+/// `line`は1から始まる行番号
+fn check_limits(
+    token: &Token,
+    line: usize,
+    limits: &ParseLimits,
+) -> Result<(), ParseError> {
+    match token {
+        Token::Command(MainData(measure, value)) => {
+            limits.check_measure(*measure, Some(line))?;
+            if let MainDataValue::Length(length) = value {
+                limits.check_measure_length(*length, Some(line))?;
+            }
+            if limits.max_objects_per_line < value.len() {
+                return Err(ParseError::TooManyObjects {
+                    line,
+                    limit: limits.max_objects_per_line,
+                });
+            }
+            Ok(())
+        }
+        Token::ControlFlow(Random(max) | Switch(max)) => {
+            limits.check_random(*max, Some(line))
+        }
+        _ => Ok(()),
+    }
 }
-fn command(input: &mut &str) -> ModalResult<Token> {
+fn command<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     if input.is_empty() {
         return Ok(Token::Comment);
     }
-    dispatch! {any;
+    dispatch! {peek(any);
         '%' => percent_command,
-        '#' => sharp_command,
+        '#' => preceded('#', sharp_command),
         _ => empty.value(Token::Comment),
     }
     .parse_next(input)
 }
-fn percent_command(input: &mut &str) -> ModalResult<Token> {
-    alt((url, email, other)).parse_next(input)
-}
-fn sharp_command(input: &mut &str) -> ModalResult<Token> {
-    let parsers = [
-        main_data,
-        player,
-        rank,
-        def_ex_rank,
-        ex_rank,
-        total,
-        volume_wav,
-        stage_file,
-        banner,
-        back_bmp,
-        character_file,
-        play_level,
-        difficulty,
-        title,
-        sub_title,
-        artist,
-        sub_artist,
-        maker,
-        genre,
-        comment,
-        text_song,
-        path_wav,
-        bpm,
-        ex_bpm,
-        base_bpm,
-        stop,
-        stp,
-        ln_mode,
-        ln_type,
-        ln_object,
-        oct_fp,
-        option,
-        change_option,
-        wav,
-        wav_command,
-        ex_wav,
-        cdda,
-        midi_file,
-        bmp,
-        ex_bmp,
-        bga,
-        at_bga,
-        poor_bga,
-        switch_bga,
-        argb,
-        video_file,
-        video_fps,
-        video_colors,
-        video_delay,
-        movie,
-        seek,
-        ex_character,
-        scroll,
-        speed,
-        preview,
-        base62,
-        random,
-        set_random,
-        end_random,
-        r#if,
-        else_if,
-        r#else,
-        end_if,
-        switch,
-        set_switch,
-        end_switch,
-        case,
-        skip,
-        default,
-    ];
-    for parser in parsers {
-        if let Some(t) = opt(parser).parse_next(input)? {
+/// `%`で始まるコマンド
+///
+/// 解釈できないコマンドは`%`を含めた名前にする
+fn percent_command<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
+    alt((preceded('%', alt((url, email))), other)).parse_next(input)
+}
+type CommandParser = for<'a> fn(&mut &'a str) -> ModalResult<Token<'a>>;
+
+/// コマンド名の先頭と、そのコマンドを解析する関数
+///
+/// コマンド名が一致するものを前から順に試す
+const COMMANDS: &[(&str, CommandParser)] = &[
+    ("PLAYER", player),
+    ("RANK", rank),
+    ("DEFEXRANK", def_ex_rank),
+    ("EXRANK", ex_rank),
+    ("TOTAL", total),
+    ("VOLWAV", volume_wav),
+    ("STAGEFILE", stage_file),
+    ("BANNER", banner),
+    ("BACKBMP", back_bmp),
+    ("CHARFILE", character_file),
+    ("PLAYLEVEL", play_level),
+    ("DIFFICULTY", difficulty),
+    ("TITLE", title),
+    ("SUBTITLE", sub_title),
+    ("ARTIST", artist),
+    ("SUBARTIST", sub_artist),
+    ("MAKER", maker),
+    ("GENRE", genre),
+    ("COMMENT", comment),
+    ("TEXT", text_song),
+    ("SONG", text_song),
+    ("PATH_WAV", path_wav),
+    ("BPM", bpm),
+    ("BPM", ex_bpm),
+    ("EXBPM", ex_bpm),
+    ("BASEBPM", base_bpm),
+    ("STOP", stop),
+    ("STP", stp),
+    ("LNMODE", ln_mode),
+    ("LNTYPE", ln_type),
+    ("LNOBJ", ln_object),
+    ("OCT/FP", oct_fp),
+    ("OPTION", option),
+    ("CHANGEOPTION", change_option),
+    ("WAV", wav),
+    ("WAVCMD", wav_command),
+    ("EXWAV", ex_wav),
+    ("CDDA", cdda),
+    ("MIDIFILE", midi_file),
+    ("BMP", bmp),
+    ("EXBMP", ex_bmp),
+    ("BGA", bga),
+    ("@BGA", at_bga),
+    ("POORBGA", poor_bga),
+    ("SWBGA", switch_bga),
+    ("ARGB", argb),
+    ("VIDEOFILE", video_file),
+    ("VIDEOF/S", video_fps),
+    ("VIDEOCOLORS", video_colors),
+    ("VIDEODELAY", video_delay),
+    ("MOVIE", movie),
+    ("SEEK", seek),
+    ("EXTCHR", ex_character),
+    ("SCROLL", scroll),
+    ("SPEED", speed),
+    ("PREVIEW", preview),
+    ("BASE", base62),
+    ("RANDOM", random),
+    ("SETRANDOM", set_random),
+    ("ENDRANDOM", end_random),
+    ("IF", r#if),
+    ("ELSEIF", else_if),
+    ("ELSE", r#else),
+    ("ENDIF", end_if),
+    ("SWITCH", switch),
+    ("SETSWITCH", set_switch),
+    ("ENDSW", end_switch),
+    ("CASE", case),
+    ("SKIP", skip),
+    ("DEF", default),
+];
+
+fn sharp_command<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
+    if input.starts_with(|c: char| c.is_ascii_digit()) {
+        if let Some(t) = opt(main_data).parse_next(input)? {
             return Ok(t);
         }
     }
+    else {
+        let name = input.as_bytes();
+        for (keyword, parser) in COMMANDS {
+            if name.len() >= keyword.len()
+                && name[..keyword.len()]
+                    .eq_ignore_ascii_case(keyword.as_bytes())
+                && let Some(t) = opt(*parser).parse_next(input)?
+            {
+                return Ok(t);
+            }
+        }
+    }
     other.parse_next(input)
 }
 const fn base36(s: &str) -> usize {
     Channel::new(s).to_base_36()
 }
-fn main_data(input: &mut &str) -> ModalResult<Token> {
+fn main_data<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (n, ch, _) = (
         take_while(3, AsChar::is_dec_digit)
             .map(|s: &str| s.parse::<usize>().unwrap()),
@@ -180,7 +423,7 @@ fn main_data(input: &mut &str) -> ModalResult<Token> {
     )
         .parse_next(input)?;
 
-    let mut ch_vec = repeat(0.., preceded(space0, channel));
+    let mut ch_vec = objects;
     let mut hex_vec = repeat(
         0..,
         preceded(
@@ -270,7 +513,7 @@ fn main_data(input: &mut &str) -> ModalResult<Token> {
     };
     Ok(Token::Command(MainData(n, data)))
 }
-fn player(input: &mut &str) -> ModalResult<Token> {
+fn player<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (
         Caseless("PLAYER"),
         space1,
@@ -279,92 +522,92 @@ fn player(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(Player(n)))
 }
-fn rank(input: &mut &str) -> ModalResult<Token> {
+fn rank<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("RANK"), space1, int).parse_next(input)?;
     Ok(Token::Command(Rank(n)))
 }
-fn def_ex_rank(input: &mut &str) -> ModalResult<Token> {
+fn def_ex_rank<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("DEFEXRANK"), space1, float).parse_next(input)?;
     Ok(Token::Command(DefExRank(n)))
 }
-fn ex_rank(input: &mut &str) -> ModalResult<Token> {
+fn ex_rank<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, n) =
         (Caseless("EXRANK"), channel, space1, float).parse_next(input)?;
     Ok(Token::Command(ExRank(ch, n)))
 }
-fn total(input: &mut &str) -> ModalResult<Token> {
+fn total<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("TOTAL"), space1, float).parse_next(input)?;
     Ok(Token::Command(Total(n)))
 }
 
-fn volume_wav(input: &mut &str) -> ModalResult<Token> {
+fn volume_wav<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("VOLWAV"), space1, float).parse_next(input)?;
     Ok(Token::Command(VolumeWav(n)))
 }
-fn stage_file(input: &mut &str) -> ModalResult<Token> {
+fn stage_file<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("STAGEFILE"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(StageFile(s)))
 }
-fn banner(input: &mut &str) -> ModalResult<Token> {
+fn banner<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("BANNER"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(Banner(s)))
 }
-fn back_bmp(input: &mut &str) -> ModalResult<Token> {
+fn back_bmp<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("BACKBMP"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(BackBmp(s)))
 }
-fn character_file(input: &mut &str) -> ModalResult<Token> {
+fn character_file<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("CHARFILE"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(CharacterFile(s)))
 }
-fn play_level(input: &mut &str) -> ModalResult<Token> {
+fn play_level<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("PLAYLEVEL"), space1, int).parse_next(input)?;
     Ok(Token::Command(PlayLevel(n)))
 }
-fn difficulty(input: &mut &str) -> ModalResult<Token> {
+fn difficulty<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("DIFFICULTY"), space1, int).parse_next(input)?;
     Ok(Token::Command(Difficulty(n)))
 }
-fn title(input: &mut &str) -> ModalResult<Token> {
+fn title<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) = (Caseless("TITLE"), one_of_space, quoted_or_no_quote)
         .parse_next(input)?;
     Ok(Token::Command(Title(s)))
 }
-fn sub_title(input: &mut &str) -> ModalResult<Token> {
+fn sub_title<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("SUBTITLE"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(SubTitle(s)))
 }
-fn artist(input: &mut &str) -> ModalResult<Token> {
+fn artist<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("ARTIST"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(Artist(s)))
 }
-fn sub_artist(input: &mut &str) -> ModalResult<Token> {
+fn sub_artist<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("SUBARTIST"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(SubArtist(s)))
 }
-fn maker(input: &mut &str) -> ModalResult<Token> {
+fn maker<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("MAKER"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(Maker(s)))
 }
-fn genre(input: &mut &str) -> ModalResult<Token> {
+fn genre<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("GENRE"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(Genre(s)))
 }
-fn comment(input: &mut &str) -> ModalResult<Token> {
+fn comment<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) = (Caseless("COMMENT"), one_of_space, quoted_or_no_quote)
         .parse_next(input)?;
     Ok(Token::Command(Comment(s)))
 }
-fn text_song(input: &mut &str) -> ModalResult<Token> {
+fn text_song<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, s) = (
         alt((Caseless("TEXT"), Caseless("SONG"))),
         channel,
@@ -374,16 +617,16 @@ fn text_song(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(Text(ch, s)))
 }
-fn path_wav(input: &mut &str) -> ModalResult<Token> {
+fn path_wav<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("PATH_WAV"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(PathWav(s)))
 }
-fn bpm(input: &mut &str) -> ModalResult<Token> {
+fn bpm<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("BPM"), space1, float).parse_next(input)?;
     Ok(Token::Command(Bpm(n)))
 }
-fn ex_bpm(input: &mut &str) -> ModalResult<Token> {
+fn ex_bpm<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, n) = (
         alt((Caseless("BPM"), Caseless("EXBPM"))),
         channel,
@@ -393,16 +636,16 @@ fn ex_bpm(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(ExBpm(ch, n)))
 }
-fn base_bpm(input: &mut &str) -> ModalResult<Token> {
+fn base_bpm<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("BASEBPM"), space1, float).parse_next(input)?;
     Ok(Token::Command(BaseBpm(n)))
 }
-fn stop(input: &mut &str) -> ModalResult<Token> {
+fn stop<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, n) =
         (Caseless("STOP"), channel, space1, float).parse_next(input)?;
     Ok(Token::Command(Stop(ch, n)))
 }
-fn stp(input: &mut &str) -> ModalResult<Token> {
+fn stp<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, x, _, y, _, z) = (
         Caseless("STP"),
         space1,
@@ -415,7 +658,7 @@ fn stp(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(Stp(x, y, z)))
 }
-fn ln_mode(input: &mut &str) -> ModalResult<Token> {
+fn ln_mode<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (
         Caseless("LNMODE"),
         space1,
@@ -424,7 +667,7 @@ fn ln_mode(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(LnMode(n)))
 }
-fn ln_type(input: &mut &str) -> ModalResult<Token> {
+fn ln_type<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (
         Caseless("LNTYPE"),
         space1,
@@ -433,31 +676,31 @@ fn ln_type(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(LnType(n)))
 }
-fn ln_object(input: &mut &str) -> ModalResult<Token> {
+fn ln_object<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, ch) = (Caseless("LNOBJ"), space1, channel).parse_next(input)?;
     Ok(Token::Command(LnObject(ch)))
 }
-fn oct_fp(input: &mut &str) -> ModalResult<Token> {
+fn oct_fp<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let _ = (Caseless("OCT/FP")).parse_next(input)?;
     Ok(Token::Command(OctFp))
 }
-fn option(input: &mut &str) -> ModalResult<Token> {
+fn option<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, option) =
         (Caseless("OPTION"), space1, rest_string).parse_next(input)?;
     Ok(Token::Command(Option(option)))
 }
-fn change_option(input: &mut &str) -> ModalResult<Token> {
+fn change_option<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, option) =
         (Caseless("CHANGEOPTION"), channel, space1, rest_string)
             .parse_next(input)?;
     Ok(Token::Command(ChangeOption(ch, option)))
 }
-fn wav(input: &mut &str) -> ModalResult<Token> {
+fn wav<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, s) =
         (Caseless("WAV"), channel, space1, rest_string).parse_next(input)?;
     Ok(Token::Command(Wav(ch, s)))
 }
-fn wav_command(input: &mut &str) -> ModalResult<Token> {
+fn wav_command<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, id, _, ch, _, val) = (
         Caseless("WAVCMD"),
         space1,
@@ -470,7 +713,7 @@ fn wav_command(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(WavCommand(id, ch, val)))
 }
-fn ex_wav(input: &mut &str) -> ModalResult<Token> {
+fn ex_wav<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, opt_str) =
         (Caseless("EXWAV"), channel, space1, alphanumeric1)
             .parse_next(input)?;
@@ -512,21 +755,21 @@ fn ex_wav(input: &mut &str) -> ModalResult<Token> {
     let name = preceded(space1, rest_string).parse_next(input)?;
     Ok(Token::Command(ExWav(ch, option, name)))
 }
-fn cdda(input: &mut &str) -> ModalResult<Token> {
+fn cdda<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("CDDA"), space1, uint).parse_next(input)?;
     Ok(Token::Command(Cdda(n)))
 }
-fn midi_file(input: &mut &str) -> ModalResult<Token> {
+fn midi_file<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("MIDIFILE"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(MidiFile(s)))
 }
-fn bmp(input: &mut &str) -> ModalResult<Token> {
+fn bmp<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, s) =
         (Caseless("BMP"), channel, space1, rest_string).parse_next(input)?;
     Ok(Token::Command(Bmp(ch, s)))
 }
-fn ex_bmp(input: &mut &str) -> ModalResult<Token> {
+fn ex_bmp<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, color, _, s): (_, _, _, Vec<u8>, _, _) = (
         Caseless("EXBMP"),
         channel,
@@ -538,7 +781,7 @@ fn ex_bmp(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(ExBmp(ch, color.try_into().unwrap(), s)))
 }
-fn bga(input: &mut &str) -> ModalResult<Token> {
+fn bga<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, ch_bmp) =
         (Caseless("BGA"), channel, space1, channel).parse_next(input)?;
     let p: Vec<f64> =
@@ -549,7 +792,7 @@ fn bga(input: &mut &str) -> ModalResult<Token> {
         [[p[0], p[1]], [p[2], p[3]], [p[4], p[5]]],
     )))
 }
-fn at_bga(input: &mut &str) -> ModalResult<Token> {
+fn at_bga<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, ch_bmp) =
         (Caseless("@BGA"), channel, space1, channel).parse_next(input)?;
     let p: Vec<f64> =
@@ -560,7 +803,7 @@ fn at_bga(input: &mut &str) -> ModalResult<Token> {
         [[p[0], p[1]], [p[2], p[3]], [p[4], p[5]]],
     )))
 }
-fn poor_bga(input: &mut &str) -> ModalResult<Token> {
+fn poor_bga<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (
         Caseless("POORBGA"),
         space1,
@@ -569,7 +812,7 @@ fn poor_bga(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(PoorBga(n)))
 }
-fn switch_bga(input: &mut &str) -> ModalResult<Token> {
+fn switch_bga<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, frame, _, time, _, line, _, r#loop, _, argb, _, pattern) = (
         Caseless("SWBGA"),
         channel,
@@ -592,7 +835,7 @@ fn switch_bga(input: &mut &str) -> ModalResult<Token> {
         ch, frame, time, line, r#loop, argb, pattern,
     )))
 }
-fn argb(input: &mut &str) -> ModalResult<Token> {
+fn argb<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, argb) = (
         Caseless("ARGB"),
         channel,
@@ -603,35 +846,35 @@ fn argb(input: &mut &str) -> ModalResult<Token> {
         .parse_next(input)?;
     Ok(Token::Command(Argb(ch, argb)))
 }
-fn video_file(input: &mut &str) -> ModalResult<Token> {
+fn video_file<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("VIDEOFILE"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(VideoFile(s)))
 }
-fn video_fps(input: &mut &str) -> ModalResult<Token> {
+fn video_fps<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("VIDEOf/s"), space1, float).parse_next(input)?;
     Ok(Token::Command(VideoFps(n)))
 }
-fn video_colors(input: &mut &str) -> ModalResult<Token> {
+fn video_colors<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) =
         (Caseless("VIDEOCOLORS"), space1, uint).parse_next(input)?;
     Ok(Token::Command(VideoColors(n)))
 }
-fn video_delay(input: &mut &str) -> ModalResult<Token> {
+fn video_delay<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("VIDEODELAY"), space1, uint).parse_next(input)?;
     Ok(Token::Command(VideoDelay(n)))
 }
-fn movie(input: &mut &str) -> ModalResult<Token> {
+fn movie<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("MOVIE"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(Movie(s)))
 }
-fn seek(input: &mut &str) -> ModalResult<Token> {
+fn seek<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, n) =
         (Caseless("SEEK"), channel, space1, float).parse_next(input)?;
     Ok(Token::Command(Seek(ch, n)))
 }
-fn ex_character(input: &mut &str) -> ModalResult<Token> {
+fn ex_character<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, spri_n, _, bmp_n, _, trim) = (
         Caseless("ExtChr"),
         space1,
@@ -656,97 +899,97 @@ fn ex_character(input: &mut &str) -> ModalResult<Token> {
         abs,
     )))
 }
-fn url(input: &mut &str) -> ModalResult<Token> {
+fn url<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("URL"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(Url(s)))
 }
-fn email(input: &mut &str) -> ModalResult<Token> {
+fn email<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("EMAIL"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(Email(s)))
 }
-fn scroll(input: &mut &str) -> ModalResult<Token> {
+fn scroll<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, n) =
         (Caseless("SCROLL"), channel, space1, float).parse_next(input)?;
     Ok(Token::Command(Scroll(ch, n)))
 }
-fn speed(input: &mut &str) -> ModalResult<Token> {
+fn speed<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, ch, _, n) =
         (Caseless("SPEED"), channel, space1, float).parse_next(input)?;
     Ok(Token::Command(Speed(ch, n)))
 }
-fn preview(input: &mut &str) -> ModalResult<Token> {
+fn preview<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, s) =
         (Caseless("PREVIEW"), one_of_space, rest_string).parse_next(input)?;
     Ok(Token::Command(Preview(s)))
 }
-fn base62(input: &mut &str) -> ModalResult<Token> {
+fn base62<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, _): (_, _, i32) =
         (Caseless("BASE"), space1, int.verify(|&n| n == 62))
             .parse_next(input)?;
     Ok(Token::Command(Base62))
 }
-fn random(input: &mut &str) -> ModalResult<Token> {
+fn random<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("RANDOM"), space1, uint).parse_next(input)?;
     Ok(Token::ControlFlow(Random(n)))
 }
-fn set_random(input: &mut &str) -> ModalResult<Token> {
+fn set_random<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("SETRANDOM"), space1, uint).parse_next(input)?;
     Ok(Token::ControlFlow(SetRandom(n)))
 }
-fn end_random(input: &mut &str) -> ModalResult<Token> {
+fn end_random<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let _ = Caseless("ENDRANDOM").parse_next(input)?;
     Ok(Token::ControlFlow(EndRandom))
 }
-fn r#if(input: &mut &str) -> ModalResult<Token> {
+fn r#if<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("IF"), space1, uint).parse_next(input)?;
     Ok(Token::ControlFlow(If(n)))
 }
-fn else_if(input: &mut &str) -> ModalResult<Token> {
+fn else_if<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("ELSEIF"), space1, uint).parse_next(input)?;
     Ok(Token::ControlFlow(ElseIf(n)))
 }
-fn r#else(input: &mut &str) -> ModalResult<Token> {
+fn r#else<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let _ = Caseless("ELSE").parse_next(input)?;
     Ok(Token::ControlFlow(Else))
 }
-fn end_if(input: &mut &str) -> ModalResult<Token> {
+fn end_if<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let _ = Caseless("ENDIF").parse_next(input)?;
     Ok(Token::ControlFlow(EndIf))
 }
-fn switch(input: &mut &str) -> ModalResult<Token> {
+fn switch<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("SWITCH"), space1, uint).parse_next(input)?;
     Ok(Token::ControlFlow(Switch(n)))
 }
-fn set_switch(input: &mut &str) -> ModalResult<Token> {
+fn set_switch<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("SETSWITCH"), space1, uint).parse_next(input)?;
     Ok(Token::ControlFlow(SetSwitch(n)))
 }
-fn end_switch(input: &mut &str) -> ModalResult<Token> {
+fn end_switch<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let _ = Caseless("ENDSW").parse_next(input)?;
     Ok(Token::ControlFlow(EndSwitch))
 }
-fn case(input: &mut &str) -> ModalResult<Token> {
+fn case<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let (_, _, n) = (Caseless("CASE"), space1, uint).parse_next(input)?;
     Ok(Token::ControlFlow(Case(n)))
 }
-fn skip(input: &mut &str) -> ModalResult<Token> {
+fn skip<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let _ = Caseless("SKIP").parse_next(input)?;
     Ok(Token::ControlFlow(Skip))
 }
-fn default(input: &mut &str) -> ModalResult<Token> {
+fn default<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
     let _ = alt((Caseless("DEFAULT"), Caseless("DEF"))).parse_next(input)?;
     Ok(Token::ControlFlow(Default))
 }
-fn other(input: &mut &str) -> ModalResult<Token> {
-    let (command, _, value) = (
-        take_while(0.., |c: char| !c.is_whitespace()),
-        space0,
-        repeat(0.., any),
-    )
-        .parse_next(input)?;
-    Ok(Token::Command(Other(command.to_string(), value)))
+fn other<'a>(input: &mut &'a str) -> ModalResult<Token<'a>> {
+    let (command, _, value) =
+        (take_while(0.., |c: char| !c.is_whitespace()), space0, rest)
+            .parse_next(input)?;
+    Ok(Token::Command(Other(
+        Cow::Borrowed(command),
+        Cow::Borrowed(value),
+    )))
 }
 
 #[cfg(test)]
@@ -787,16 +1030,42 @@ mod tests {
         assert!(channel.parse_peek("てすと").is_err());
     }
 
+    #[test]
+    fn objects_test() {
+        let mut input = "01 02　0304 5?";
+        let objects = objects.parse_next(&mut input).unwrap();
+        assert_eq!(input, " 5?");
+        assert_eq!(
+            objects.iter().collect::<Vec<_>>(),
+            ["01", "02", "03", "04"].map(Channel::from)
+        );
+        assert_eq!(
+            command.parse_peek("#00111:0A00zz"),
+            Ok((
+                "",
+                Token::Command(MainData(
+                    1,
+                    MainDataValue::Note(base36("11"), Objects::from("0A00zz"))
+                ))
+            ))
+        );
+    }
+
     #[test]
     fn quoted_or_no_quote_test() {
         assert_eq!(
             quoted_or_no_quote.parse_peek(r#""Test""#),
-            Ok(("", String::from("Test")))
+            Ok(("", Cow::from("Test")))
         );
         assert_eq!(
             quoted_or_no_quote.parse_peek(r#"Test"#),
-            Ok(("", String::from("Test")))
+            Ok(("", Cow::from("Test")))
+        );
+        assert_eq!(
+            quoted_or_no_quote.parse_peek(r#"""#),
+            Ok(("", Cow::from(r#"""#)))
         );
+        assert!(quoted_string.parse_peek(r#" " "#).is_err());
     }
 
     #[test]
@@ -814,20 +1083,14 @@ mod tests {
             command.parse_peek("#PLAYER 0"),
             Ok((
                 "",
-                Token::Command(Other(
-                    String::from("PLAYER"),
-                    String::from("0")
-                ))
+                Token::Command(Other(Cow::from("PLAYER"), Cow::from("0")))
             ))
         );
         assert_eq!(
             command.parse_peek("#Player 5"),
             Ok((
                 "",
-                Token::Command(Other(
-                    String::from("Player"),
-                    String::from("5")
-                ))
+                Token::Command(Other(Cow::from("Player"), Cow::from("5")))
             ))
         );
         // RANK
@@ -890,45 +1153,43 @@ mod tests {
         // STAGEFILE
         assert_eq!(
             command.parse_peek("#STAGEFILE image.bmp"),
-            Ok(("", Token::Command(StageFile(String::from("image.bmp")))))
+            Ok(("", Token::Command(StageFile(Cow::from("image.bmp")))))
         );
         assert_eq!(
             command.parse_peek("#stagefile 画像.png"),
-            Ok(("", Token::Command(StageFile(String::from("画像.png")))))
+            Ok(("", Token::Command(StageFile(Cow::from("画像.png")))))
         );
         // BANNER
         assert_eq!(
             command.parse_peek("#BANNER banner.jpg"),
-            Ok(("", Token::Command(Banner(String::from("banner.jpg")))))
+            Ok(("", Token::Command(Banner(Cow::from("banner.jpg")))))
         );
         assert_eq!(
             command.parse_peek("#banner ばなー.bmp"),
-            Ok(("", Token::Command(Banner(String::from("ばなー.bmp")))))
+            Ok(("", Token::Command(Banner(Cow::from("ばなー.bmp")))))
         );
         // BACKBMP
         assert_eq!(
             command.parse_peek("#BACKBMP back.png"),
-            Ok(("", Token::Command(BackBmp(String::from("back.png")))))
+            Ok(("", Token::Command(BackBmp(Cow::from("back.png")))))
         );
         assert_eq!(
             command.parse_peek("#backbmp 背景.jpg"),
-            Ok(("", Token::Command(BackBmp(String::from("背景.jpg")))))
+            Ok(("", Token::Command(BackBmp(Cow::from("背景.jpg")))))
         );
         // CHARFILE
         assert_eq!(
             command.parse_peek("#CHARFILE character.chp"),
             Ok((
                 "",
-                Token::Command(CharacterFile(String::from("character.chp")))
+                Token::Command(CharacterFile(Cow::from("character.chp")))
             ))
         );
         assert_eq!(
             command.parse_peek("#charfile キャラファイル.chp"),
             Ok((
                 "",
-                Token::Command(CharacterFile(String::from(
-                    "キャラファイル.chp"
-                )))
+                Token::Command(CharacterFile(Cow::from("キャラファイル.chp")))
             ))
         );
         // PLAYLEVEL
@@ -952,94 +1213,91 @@ mod tests {
         // TITLE
         assert_eq!(
             command.parse_peek("#TITLE \"title\""),
-            Ok(("", Token::Command(Title(String::from("title")))))
+            Ok(("", Token::Command(Title(Cow::from("title")))))
         );
         assert_eq!(
             command.parse_peek("#title タイトル"),
-            Ok(("", Token::Command(Title(String::from("タイトル")))))
+            Ok(("", Token::Command(Title(Cow::from("タイトル")))))
         );
         assert_eq!(
             command.parse_peek("#Title  　ABC　 "),
-            Ok(("", Token::Command(Title(String::from(" 　ABC　 ")))))
+            Ok(("", Token::Command(Title(Cow::from(" 　ABC　 ")))))
         );
         // SUBTITLE
         assert_eq!(
             command.parse_peek("#SUBTITLE sub_title"),
-            Ok(("", Token::Command(SubTitle(String::from("sub_title")))))
+            Ok(("", Token::Command(SubTitle(Cow::from("sub_title")))))
         );
         assert_eq!(
             command.parse_peek("#subtitle サブタイトル"),
-            Ok(("", Token::Command(SubTitle(String::from("サブタイトル")))))
+            Ok(("", Token::Command(SubTitle(Cow::from("サブタイトル")))))
         );
         assert_eq!(
             command.parse_peek("#SubTitle \tLOVE♡SHINE\t"),
-            Ok(("", Token::Command(SubTitle(String::from("\tLOVE♡SHINE\t")))))
+            Ok(("", Token::Command(SubTitle(Cow::from("\tLOVE♡SHINE\t")))))
         );
         // ARTIST
         assert_eq!(
             command.parse_peek("#ARTIST artist"),
-            Ok(("", Token::Command(Artist(String::from("artist")))))
+            Ok(("", Token::Command(Artist(Cow::from("artist")))))
         );
         assert_eq!(
             command.parse_peek("#artist アーティスト"),
-            Ok(("", Token::Command(Artist(String::from("アーティスト")))))
+            Ok(("", Token::Command(Artist(Cow::from("アーティスト")))))
         );
         // SUBARTIST
         assert_eq!(
             command.parse_peek("#SUBARTIST sub_artist"),
-            Ok(("", Token::Command(SubArtist(String::from("sub_artist")))))
+            Ok(("", Token::Command(SubArtist(Cow::from("sub_artist")))))
         );
         assert_eq!(
             command.parse_peek("#subartist サブアーティスト"),
-            Ok((
-                "",
-                Token::Command(SubArtist(String::from("サブアーティスト")))
-            ))
+            Ok(("", Token::Command(SubArtist(Cow::from("サブアーティスト")))))
         );
         // MAKER
         assert_eq!(
             command.parse_peek("#MAKER maker"),
-            Ok(("", Token::Command(Maker(String::from("maker")))))
+            Ok(("", Token::Command(Maker(Cow::from("maker")))))
         );
         assert_eq!(
             command.parse_peek("#maker 譜面制作者"),
-            Ok(("", Token::Command(Maker(String::from("譜面制作者")))))
+            Ok(("", Token::Command(Maker(Cow::from("譜面制作者")))))
         );
         // GENRE
         assert_eq!(
             command.parse_peek("#GENRE genre"),
-            Ok(("", Token::Command(Genre(String::from("genre")))))
+            Ok(("", Token::Command(Genre(Cow::from("genre")))))
         );
         assert_eq!(
             command.parse_peek("#genre ジャンル"),
-            Ok(("", Token::Command(Genre(String::from("ジャンル")))))
+            Ok(("", Token::Command(Genre(Cow::from("ジャンル")))))
         );
         // COMMENT
         assert_eq!(
             command.parse_peek("#COMMENT \"comment\""),
-            Ok(("", Token::Command(Comment(String::from("comment")))))
+            Ok(("", Token::Command(Comment(Cow::from("comment")))))
         );
         assert_eq!(
             command.parse_peek("#comment コメント"),
-            Ok(("", Token::Command(Comment(String::from("コメント")))))
+            Ok(("", Token::Command(Comment(Cow::from("コメント")))))
         );
         assert_eq!(
             command.parse_peek("#Comment \"𠮷野家\""),
-            Ok(("", Token::Command(Comment(String::from("𠮷野家")))))
+            Ok(("", Token::Command(Comment(Cow::from("𠮷野家")))))
         );
         // TEXT
         assert_eq!(
             command.parse_peek("#TEXT01 \"歌詞\""),
             Ok((
                 "",
-                Token::Command(Text(Channel::from("01"), String::from("歌詞")))
+                Token::Command(Text(Channel::from("01"), Cow::from("歌詞")))
             ))
         );
         assert_eq!(
             command.parse_peek("#textzz \"瑕疵\""),
             Ok((
                 "",
-                Token::Command(Text(Channel::from("zz"), String::from("瑕疵")))
+                Token::Command(Text(Channel::from("zz"), Cow::from("瑕疵")))
             ))
         );
         // SONG
@@ -1047,24 +1305,24 @@ mod tests {
             command.parse_peek("#SONG01 \"歌詞\""),
             Ok((
                 "",
-                Token::Command(Text(Channel::from("01"), String::from("歌詞")))
+                Token::Command(Text(Channel::from("01"), Cow::from("歌詞")))
             ))
         );
         assert_eq!(
             command.parse_peek("#songzz \"瑕疵\""),
             Ok((
                 "",
-                Token::Command(Text(Channel::from("zz"), String::from("瑕疵")))
+                Token::Command(Text(Channel::from("zz"), Cow::from("瑕疵")))
             ))
         );
         // PATH_WAV
         assert_eq!(
             command.parse_peek("#PATH_WAV C:/path/to/wav"),
-            Ok(("", Token::Command(PathWav(String::from("C:/path/to/wav")))))
+            Ok(("", Token::Command(PathWav(Cow::from("C:/path/to/wav")))))
         );
         assert_eq!(
             command.parse_peek("#path_wav local/path"),
-            Ok(("", Token::Command(PathWav(String::from("local/path")))))
+            Ok(("", Token::Command(PathWav(Cow::from("local/path")))))
         );
         // BPM
         assert_eq!(
@@ -1124,8 +1382,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("stp"),
-                    String::from("500.1000 500")
+                    Cow::from("stp"),
+                    Cow::from("500.1000 500")
                 ))
             ))
         );
@@ -1142,20 +1400,14 @@ mod tests {
             command.parse_peek("#LnType 0"),
             Ok((
                 "",
-                Token::Command(Other(
-                    String::from("LnType"),
-                    String::from("0")
-                ))
+                Token::Command(Other(Cow::from("LnType"), Cow::from("0")))
             ))
         );
         assert_eq!(
             command.parse_peek("#LNTYPE 3"),
             Ok((
                 "",
-                Token::Command(Other(
-                    String::from("LNTYPE"),
-                    String::from("3")
-                ))
+                Token::Command(Other(Cow::from("LNTYPE"), Cow::from("3")))
             ))
         );
         // LNOBJ
@@ -1179,17 +1431,11 @@ mod tests {
         // OPTION
         assert_eq!(
             command.parse_peek("#option GameName:OptionStr"),
-            Ok((
-                "",
-                Token::Command(Option(String::from("GameName:OptionStr")))
-            ))
+            Ok(("", Token::Command(Option(Cow::from("GameName:OptionStr")))))
         );
         assert_eq!(
             command.parse_peek("#OPTION 774:HI-SPEED_x99.75"),
-            Ok((
-                "",
-                Token::Command(Option(String::from("774:HI-SPEED_x99.75")))
-            ))
+            Ok(("", Token::Command(Option(Cow::from("774:HI-SPEED_x99.75")))))
         );
         // CHANGEOPTION
         assert_eq!(
@@ -1198,7 +1444,7 @@ mod tests {
                 "",
                 Token::Command(ChangeOption(
                     Channel::from("01"),
-                    String::from("charatbeatHDX:LONGMODE 0")
+                    Cow::from("charatbeatHDX:LONGMODE 0")
                 ))
             ))
         );
@@ -1208,7 +1454,7 @@ mod tests {
                 "",
                 Token::Command(ChangeOption(
                     Channel::from("zz"),
-                    String::from("774:RANDOM_MIRROR")
+                    Cow::from("774:RANDOM_MIRROR")
                 ))
             ))
         );
@@ -1217,20 +1463,14 @@ mod tests {
             command.parse_peek("#WAV01 base.wav"),
             Ok((
                 "",
-                Token::Command(Wav(
-                    Channel::from("01"),
-                    String::from("base.wav")
-                ))
+                Token::Command(Wav(Channel::from("01"), Cow::from("base.wav")))
             ))
         );
         assert_eq!(
             command.parse_peek("#WAVzz kick.ogg"),
             Ok((
                 "",
-                Token::Command(Wav(
-                    Channel::from("zz"),
-                    String::from("kick.ogg")
-                ))
+                Token::Command(Wav(Channel::from("zz"), Cow::from("kick.ogg")))
             ))
         );
         // WAVCMD
@@ -1250,8 +1490,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("WavCmd"),
-                    String::from("03 zz 2000.")
+                    Cow::from("WavCmd"),
+                    Cow::from("03 zz 2000.")
                 ))
             ))
         );
@@ -1263,7 +1503,7 @@ mod tests {
                 Token::Command(ExWav(
                     Channel::from("01"),
                     [Some(-10000.), Some(-50.), Some(100.)],
-                    String::from("aaa.wav")
+                    Cow::from("aaa.wav")
                 ))
             ))
         );
@@ -1274,7 +1514,7 @@ mod tests {
                 Token::Command(ExWav(
                     Channel::from("zz"),
                     [Some(500.), None, None],
-                    String::from("aaa.wav")
+                    Cow::from("aaa.wav")
                 ))
             ))
         );
@@ -1283,8 +1523,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ExWavFF"),
-                    String::from("p 10000.001 aaa.wav")
+                    Cow::from("ExWavFF"),
+                    Cow::from("p 10000.001 aaa.wav")
                 ))
             ))
         );
@@ -1293,8 +1533,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ExWavFF"),
-                    String::from("p -10000.001 aaa.wav")
+                    Cow::from("ExWavFF"),
+                    Cow::from("p -10000.001 aaa.wav")
                 ))
             ))
         );
@@ -1303,8 +1543,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ExWavFF"),
-                    String::from("v 0.001 aaa.wav")
+                    Cow::from("ExWavFF"),
+                    Cow::from("v 0.001 aaa.wav")
                 ))
             ))
         );
@@ -1313,8 +1553,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ExWavFF"),
-                    String::from("v -10000.001 aaa.wav")
+                    Cow::from("ExWavFF"),
+                    Cow::from("v -10000.001 aaa.wav")
                 ))
             ))
         );
@@ -1323,8 +1563,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ExWavFF"),
-                    String::from("f 100000.001 aaa.wav")
+                    Cow::from("ExWavFF"),
+                    Cow::from("f 100000.001 aaa.wav")
                 ))
             ))
         );
@@ -1333,8 +1573,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ExWavFF"),
-                    String::from("f 99.999 aaa.wav")
+                    Cow::from("ExWavFF"),
+                    Cow::from("f 99.999 aaa.wav")
                 ))
             ))
         );
@@ -1350,31 +1590,25 @@ mod tests {
         // MIDIFILE
         assert_eq!(
             command.parse_peek("#MIDIFILE piano.mid"),
-            Ok(("", Token::Command(MidiFile(String::from("piano.mid")))))
+            Ok(("", Token::Command(MidiFile(Cow::from("piano.mid")))))
         );
         assert_eq!(
             command.parse_peek("#midifile base.mid"),
-            Ok(("", Token::Command(MidiFile(String::from("base.mid")))))
+            Ok(("", Token::Command(MidiFile(Cow::from("base.mid")))))
         );
         // BMP
         assert_eq!(
             command.parse_peek("#BMP00 miss.bmp"),
             Ok((
                 "",
-                Token::Command(Bmp(
-                    Channel::from("00"),
-                    String::from("miss.bmp")
-                ))
+                Token::Command(Bmp(Channel::from("00"), Cow::from("miss.bmp")))
             ))
         );
         assert_eq!(
             command.parse_peek("#bmpzz bga.mp4"),
             Ok((
                 "",
-                Token::Command(Bmp(
-                    Channel::from("zz"),
-                    String::from("bga.mp4")
-                ))
+                Token::Command(Bmp(Channel::from("zz"), Cow::from("bga.mp4")))
             ))
         );
         // EXBMP
@@ -1385,7 +1619,7 @@ mod tests {
                 Token::Command(ExBmp(
                     Channel::from("00"),
                     [0; 4],
-                    String::from("miss.avi")
+                    Cow::from("miss.avi")
                 ))
             ))
         );
@@ -1396,7 +1630,7 @@ mod tests {
                 Token::Command(ExBmp(
                     Channel::from("zz"),
                     [255; 4],
-                    String::from("bga.webm")
+                    Cow::from("bga.webm")
                 ))
             ))
         );
@@ -1405,8 +1639,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ExBmpFF"),
-                    String::from("256,0,0,0 movie.mov")
+                    Cow::from("ExBmpFF"),
+                    Cow::from("256,0,0,0 movie.mov")
                 ))
             ))
         );
@@ -1469,10 +1703,7 @@ mod tests {
             command.parse_peek("#PoorBga 3"),
             Ok((
                 "",
-                Token::Command(Other(
-                    String::from("PoorBga"),
-                    String::from("3")
-                ))
+                Token::Command(Other(Cow::from("PoorBga"), Cow::from("3")))
             ))
         );
         // SWBGA
@@ -1517,8 +1748,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("SWBGA01"),
-                    String::from("100:400:16:0:255,255,255,256 01")
+                    Cow::from("SWBGA01"),
+                    Cow::from("100:400:16:0:255,255,255,256 01")
                 ))
             ))
         );
@@ -1536,19 +1767,19 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ArgbFF"),
-                    String::from("255,255,255,256")
+                    Cow::from("ArgbFF"),
+                    Cow::from("255,255,255,256")
                 ))
             ))
         );
         // VIDEOFILE
         assert_eq!(
             command.parse_peek("#VIDEOFILE video.mp4"),
-            Ok(("", Token::Command(VideoFile(String::from("video.mp4")))))
+            Ok(("", Token::Command(VideoFile(Cow::from("video.mp4")))))
         );
         assert_eq!(
             command.parse_peek("#videofile bga.avi"),
-            Ok(("", Token::Command(VideoFile(String::from("bga.avi")))))
+            Ok(("", Token::Command(VideoFile(Cow::from("bga.avi")))))
         );
         // VIDEOf/p
         assert_eq!(
@@ -1580,11 +1811,11 @@ mod tests {
         // MOVIE
         assert_eq!(
             command.parse_peek("#MOVIE movie.mp4"),
-            Ok(("", Token::Command(Movie(String::from("movie.mp4")))))
+            Ok(("", Token::Command(Movie(Cow::from("movie.mp4")))))
         );
         assert_eq!(
             command.parse_peek("#movie bga.avi"),
-            Ok(("", Token::Command(Movie(String::from("bga.avi")))))
+            Ok(("", Token::Command(Movie(Cow::from("bga.avi")))))
         );
         // SEEK
         assert_eq!(
@@ -1634,8 +1865,8 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ExtChr"),
-                    String::from("1024 0 0 0 0 0")
+                    Cow::from("ExtChr"),
+                    Cow::from("1024 0 0 0 0 0")
                 ))
             ))
         );
@@ -1644,36 +1875,34 @@ mod tests {
             Ok((
                 "",
                 Token::Command(Other(
-                    String::from("ExtChr"),
-                    String::from("0 256 0 0 0 0")
+                    Cow::from("ExtChr"),
+                    Cow::from("0 256 0 0 0 0")
                 ))
             ))
         );
         // URL
         assert_eq!(
             command.parse_peek("%URL https://home-page.net"),
-            Ok((
-                "",
-                Token::Command(Url(String::from("https://home-page.net")))
-            ))
+            Ok(("", Token::Command(Url(Cow::from("https://home-page.net")))))
         );
         assert_eq!(
             command.parse_peek("%url https://foo.com"),
-            Ok(("", Token::Command(Url(String::from("https://foo.com")))))
+            Ok(("", Token::Command(Url(Cow::from("https://foo.com")))))
         );
         // EMAIL
         assert_eq!(
             command.parse_peek("%EMAIL name@some.mail.com"),
-            Ok((
-                "",
-                Token::Command(Email(String::from("name@some.mail.com")))
-            ))
+            Ok(("", Token::Command(Email(Cow::from("name@some.mail.com")))))
         );
         assert_eq!(
             command.parse_peek("%email foo@some.mail.co.jp"),
+            Ok(("", Token::Command(Email(Cow::from("foo@some.mail.co.jp")))))
+        );
+        assert_eq!(
+            command.parse_peek("%CUSTOM x"),
             Ok((
                 "",
-                Token::Command(Email(String::from("foo@some.mail.co.jp")))
+                Token::Command(Other(Cow::from("%CUSTOM"), Cow::from("x")))
             ))
         );
         // SCROLL
@@ -1697,11 +1926,11 @@ mod tests {
         // PREVIEW
         assert_eq!(
             command.parse_peek("#PREVIEW preview.wav"),
-            Ok(("", Token::Command(Preview(String::from("preview.wav")))))
+            Ok(("", Token::Command(Preview(Cow::from("preview.wav")))))
         );
         assert_eq!(
             command.parse_peek("#preview プレビュー.ogg"),
-            Ok(("", Token::Command(Preview(String::from("プレビュー.ogg")))))
+            Ok(("", Token::Command(Preview(Cow::from("プレビュー.ogg")))))
         );
         // BASE62
         assert_eq!(
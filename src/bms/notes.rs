@@ -0,0 +1,394 @@
+use super::*;
+use timeline::objects;
+
+/// 時刻が確定したノーツ
+#[derive(Clone, Debug, PartialEq)]
+pub struct BmsNote {
+    /// 演奏するキーのチャンネル
+    ///
+    /// 11から1Zは1P、21から2Zは2Pで、36進数で解釈した値
+    ///
+    /// 不可視ノーツ・ロングノーツ・地雷もこの範囲のチャンネルに変換される
+    pub channel: usize,
+    /// ノーツの種類
+    pub kind: BmsNoteKind,
+    /// 音声ファイルのid
+    pub wav: usize,
+    /// 位置（拍数）
+    pub beat: f64,
+    /// 時刻
+    pub time: f64,
+    /// ロングノートの終端
+    pub end: Option<BmsNoteEnd>,
+}
+
+/// ノーツの種類
+#[derive(Clone, Debug, PartialEq)]
+pub enum BmsNoteKind {
+    /// 普通のノート
+    Normal,
+    /// ロングノート
+    Long,
+    /// 不可視ノート
+    Invisible,
+    /// 地雷
+    ///
+    /// ダメージ（%）
+    Landmine(f64),
+}
+
+/// ロングノートの終端
+#[derive(Clone, Debug, PartialEq)]
+pub struct BmsNoteEnd {
+    /// 音声ファイルのid
+    ///
+    /// LNOBJで指定された終端では、そのid
+    pub wav: usize,
+    /// 位置（拍数）
+    pub beat: f64,
+    /// 時刻
+    pub time: f64,
+}
+
+/// 時刻が確定したBGM
+#[derive(Clone, Debug, PartialEq)]
+pub struct BmsBgm {
+    /// 音声ファイルのid
+    pub wav: usize,
+    /// 位置（拍数）
+    pub beat: f64,
+    /// 時刻
+    pub time: f64,
+}
+
+pub(crate) const NOTE_CHANNELS: std::ops::RangeInclusive<usize> =
+    Channel::new("11").to_base_36()..=Channel::new("2Z").to_base_36();
+pub(crate) const INVISIBLE_OFFSET: usize =
+    Channel::new("31").to_base_36() - Channel::new("11").to_base_36();
+pub(crate) const LONG_OFFSET: usize =
+    Channel::new("51").to_base_36() - Channel::new("11").to_base_36();
+pub(crate) const LANDMINE_OFFSET: usize =
+    Channel::new("D1").to_base_36() - Channel::new("11").to_base_36();
+
+impl Bms<'_> {
+    /// 全てのノーツを時刻順に並べたもの
+    ///
+    /// LNTYPE・LNOBJに従ってロングノートを解析する
+    ///
+    /// 同じ小節、同じチャンネルの複数行で同じ位置にあるノーツは、後の行を優先する
+    pub fn notes(&self, timeline: &BmsTimeline) -> Vec<BmsNote> {
+        let mut notes = vec![];
+        for channel in NOTE_CHANNELS {
+            self.visible_notes(timeline, channel, &mut notes);
+            self.long_notes(timeline, channel, &mut notes);
+            for (beat, wav) in self.lane_objects(timeline, |m| {
+                m.invisible_notes.get(&(channel + INVISIBLE_OFFSET))
+            }) {
+                notes.push(BmsNote {
+                    channel,
+                    kind: BmsNoteKind::Invisible,
+                    wav,
+                    beat,
+                    time: timeline.beat_to_ms(beat),
+                    end: None,
+                });
+            }
+            let mut landmines = vec![];
+            for (m, measure) in self.main_data.iter().enumerate() {
+                for row in measure
+                    .landmine
+                    .get(&(channel + LANDMINE_OFFSET))
+                    .into_iter()
+                    .flatten()
+                {
+                    for (f, damage) in objects(row) {
+                        landmines.push((timeline.beat(m, f), *damage));
+                    }
+                }
+            }
+            for (beat, damage) in dedup_by_beat(landmines) {
+                notes.push(BmsNote {
+                    channel,
+                    kind: BmsNoteKind::Landmine(damage),
+                    wav: 0,
+                    beat,
+                    time: timeline.beat_to_ms(beat),
+                    end: None,
+                });
+            }
+        }
+        notes.sort_by(|a, b| {
+            a.beat.total_cmp(&b.beat).then(a.channel.cmp(&b.channel))
+        });
+        notes
+    }
+    /// 全てのBGMを時刻順に並べたもの
+    pub fn bgm(&self, timeline: &BmsTimeline) -> Vec<BmsBgm> {
+        let mut bgm = vec![];
+        for (m, measure) in self.main_data.iter().enumerate() {
+            for row in &measure.bgm {
+                for (f, wav) in objects(row) {
+                    let beat = timeline.beat(m, f);
+                    bgm.push(BmsBgm {
+                        wav: *wav,
+                        beat,
+                        time: timeline.beat_to_ms(beat),
+                    });
+                }
+            }
+        }
+        bgm.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+        bgm
+    }
+    pub(super) fn lane_objects<'b>(
+        &'b self,
+        timeline: &BmsTimeline,
+        rows: impl Fn(&'b MainData) -> Option<&'b Vec<Vec<usize>>>,
+    ) -> Vec<(f64, usize)> {
+        let mut objs = vec![];
+        for (m, measure) in self.main_data.iter().enumerate() {
+            for row in rows(measure).into_iter().flatten() {
+                for (f, id) in objects(row) {
+                    objs.push((timeline.beat(m, f), *id));
+                }
+            }
+        }
+        dedup_by_beat(objs)
+    }
+    fn visible_notes(
+        &self,
+        timeline: &BmsTimeline,
+        channel: usize,
+        notes: &mut Vec<BmsNote>,
+    ) {
+        let mut last: Option<usize> = None;
+        for (beat, wav) in
+            self.lane_objects(timeline, |m| m.notes.get(&channel))
+        {
+            let time = timeline.beat_to_ms(beat);
+            if self.ln_object.contains(&wav) {
+                match last.map(|i| &mut notes[i]) {
+                    Some(note) if note.kind == BmsNoteKind::Normal => {
+                        note.kind = BmsNoteKind::Long;
+                        note.end = Some(BmsNoteEnd { wav, beat, time });
+                    }
+                    _ => log::warn!(
+                        "{}拍目のLNOBJに対応する始点がありません",
+                        beat
+                    ),
+                }
+                last = None;
+                continue;
+            }
+            last = Some(notes.len());
+            notes.push(BmsNote {
+                channel,
+                kind: BmsNoteKind::Normal,
+                wav,
+                beat,
+                time,
+                end: None,
+            });
+        }
+    }
+    fn long_notes(
+        &self,
+        timeline: &BmsTimeline,
+        channel: usize,
+        notes: &mut Vec<BmsNote>,
+    ) {
+        let long_channel = channel + LONG_OFFSET;
+        let mut push = |(beat, wav): (f64, usize), end: Option<BmsNoteEnd>| {
+            notes.push(BmsNote {
+                channel,
+                kind: if end.is_some() {
+                    BmsNoteKind::Long
+                }
+                else {
+                    BmsNoteKind::Normal
+                },
+                wav,
+                beat,
+                time: timeline.beat_to_ms(beat),
+                end,
+            });
+        };
+        if self.ln_type == Some(2) {
+            // 0以外のidが続く部分をロングノートとする
+            let mut start = None;
+            for (m, measure) in self.main_data.iter().enumerate() {
+                let row = measure
+                    .long_notes
+                    .get(&long_channel)
+                    .map(|rows| merge_rows(rows))
+                    .unwrap_or_default();
+                if row.is_empty()
+                    && let Some(s) = start.take()
+                {
+                    let beat = timeline.measure_start(m);
+                    push(s, Some(end(timeline, beat, 0)));
+                }
+                for (i, &id) in row.iter().enumerate() {
+                    let beat = timeline.beat(m, i as f64 / row.len() as f64);
+                    match (id, start) {
+                        (0, Some(s)) => {
+                            push(s, Some(end(timeline, beat, 0)));
+                            start = None;
+                        }
+                        (1.., None) => start = Some((beat, id)),
+                        _ => (),
+                    }
+                }
+            }
+            if let Some(s) = start {
+                let beat = timeline.measure_start(self.main_data.len());
+                push(s, Some(end(timeline, beat, 0)));
+            }
+        }
+        else {
+            // 0以外のidを始点と終点の繰り返しとする
+            let mut start = None;
+            for (beat, id) in
+                self.lane_objects(timeline, |m| m.long_notes.get(&long_channel))
+            {
+                match start.take() {
+                    Some(s) => push(s, Some(end(timeline, beat, id))),
+                    None => start = Some((beat, id)),
+                }
+            }
+            if let Some(s) = start {
+                log::warn!("{}拍目のロングノートに終点がありません", s.0);
+                push(s, None);
+            }
+        }
+    }
+}
+
+fn end(timeline: &BmsTimeline, beat: f64, wav: usize) -> BmsNoteEnd {
+    BmsNoteEnd {
+        wav,
+        beat,
+        time: timeline.beat_to_ms(beat),
+    }
+}
+
+/// 位置順に並べ、同じ位置のオブジェクトは後のものを残す
+fn dedup_by_beat<T>(mut objs: Vec<(f64, T)>) -> Vec<(f64, T)> {
+    objs.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut result: Vec<(f64, T)> = Vec::with_capacity(objs.len());
+    for o in objs {
+        match result.last_mut() {
+            Some(last) if last.0 == o.0 => *last = o,
+            _ => result.push(o),
+        }
+    }
+    result
+}
+
+/// 同じ小節、同じチャンネルの複数行を1行にまとめる
+///
+/// 同じ位置にオブジェクトがある場合は後の行を優先する
+pub(crate) fn merge_rows(rows: &[Vec<usize>]) -> Vec<usize> {
+    fn gcd(a: usize, b: usize) -> usize {
+        if b == 0 { a } else { gcd(b, a % b) }
+    }
+    const MAX_LEN: usize = 192 * 100;
+    let rows = rows.iter().filter(|r| !r.is_empty()).collect::<Vec<_>>();
+    match rows.as_slice() {
+        [] => return vec![],
+        [row] => return row.to_vec(),
+        _ => (),
+    }
+    let mut len = 1;
+    for row in &rows {
+        len = len / gcd(len, row.len()) * row.len();
+        if MAX_LEN < len {
+            len = rows.iter().map(|r| r.len()).max().unwrap();
+            break;
+        }
+    }
+    let mut merged = vec![0; len];
+    for row in rows {
+        for (i, &id) in row.iter().enumerate() {
+            if id != 0 {
+                merged[i * len / row.len()] = id;
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_notes() {
+        let raw = RawBms::parse(
+            r"
+#BPM 120
+#LNOBJ ZZ
+#00111:0100ZZ00
+#00112:01
+#00112:0002
+#00151:03000003
+#00131:04
+#001D1:0A
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let timeline = bms.timeline();
+        let notes = bms.notes(&timeline);
+        let summary = notes
+            .iter()
+            .map(|n| {
+                (
+                    n.channel,
+                    n.kind.clone(),
+                    n.wav,
+                    n.beat,
+                    n.end.as_ref().map(|e| (e.wav, e.beat)),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (37, BmsNoteKind::Long, 1, 4., Some((1295, 6.))),
+                (37, BmsNoteKind::Long, 3, 4., Some((3, 7.))),
+                (37, BmsNoteKind::Invisible, 4, 4., None),
+                (37, BmsNoteKind::Landmine(5.), 0, 4., None),
+                (38, BmsNoteKind::Normal, 1, 4., None),
+                (38, BmsNoteKind::Normal, 2, 6., None),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_note_type_2() {
+        let raw = RawBms::parse(
+            r"
+#LNTYPE 2
+#00151:00010101
+#00251:0101000001
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let timeline = bms.timeline();
+        let notes = bms.notes(&timeline);
+        let summary = notes
+            .iter()
+            .map(|n| (n.beat, n.end.as_ref().map(|e| e.beat)))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![(5., Some(9.6)), (11.2, Some(12.))]);
+    }
+
+    #[test]
+    fn merge() {
+        assert_eq!(
+            merge_rows(&[vec![1, 0], vec![0, 2, 0]]),
+            vec![1, 0, 2, 0, 0, 0]
+        );
+        assert_eq!(merge_rows(&[vec![1, 0], vec![3]]), vec![3, 0]);
+    }
+}
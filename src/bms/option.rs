@@ -0,0 +1,217 @@
+use super::*;
+use crate::transform::LaneOption;
+use timeline::objects;
+
+/// `#OPTION`・`#CHANGEOPTION`で指定されたオプション
+///
+/// "ゲーム名:オプション"の形式で、オプションは`_`か空白で区切る
+#[derive(Clone, Debug, PartialEq)]
+pub struct BmsOption<'a> {
+    /// 対象のゲーム名
+    pub game: Option<&'a str>,
+    /// オプション
+    pub options: Vec<PlayOption<'a>>,
+}
+
+/// プレイオプション
+#[derive(Clone, Debug, PartialEq)]
+pub enum PlayOption<'a> {
+    /// ハイスピード
+    ///
+    /// `HI-SPEED_x2.5`のように倍率が指定されることがある
+    HighSpeed(Option<f64>),
+    /// ノーツを下側で隠す
+    Hidden,
+    /// ノーツを上側で隠す
+    Sudden,
+    /// 判定ラインを上げる
+    Lift,
+    /// ミラー
+    Mirror,
+    /// ランダム
+    Random,
+    /// Rランダム
+    RRandom,
+    /// Sランダム
+    SRandom,
+    /// Hランダム
+    HRandom,
+    /// オールスクラッチ
+    AllScratch,
+    /// 1Pと2Pの入れ替え
+    Flip,
+    /// 解釈できないオプション
+    Other(&'a str),
+}
+
+impl<'a> BmsOption<'a> {
+    /// オプションの文字列を解釈する
+    pub fn parse(s: &'a str) -> BmsOption<'a> {
+        let (game, rest) = match s.split_once(':') {
+            Some((game, rest)) if !game.contains(char::is_whitespace) => {
+                (Some(game), rest)
+            }
+            _ => (None, s),
+        };
+        let mut options = vec![];
+        for word in rest
+            .split(|c: char| c == '_' || c.is_whitespace())
+            .filter(|w| !w.is_empty())
+        {
+            // 直前のハイスピードの倍率
+            if let Some(PlayOption::HighSpeed(rate @ None)) = options.last_mut()
+                && let Some(r) =
+                    word.strip_prefix(['x', 'X']).and_then(|r| r.parse().ok())
+            {
+                *rate = Some(r);
+                continue;
+            }
+            options.push(PlayOption::parse(word));
+        }
+        BmsOption { game, options }
+    }
+}
+
+impl<'a> PlayOption<'a> {
+    /// 1つのオプションを解釈する
+    ///
+    /// 大文字と小文字は区別しない
+    pub fn parse(s: &'a str) -> PlayOption<'a> {
+        use PlayOption::*;
+        match s.to_ascii_uppercase().as_str() {
+            "HIGH-SPEED" | "HI-SPEED" => HighSpeed(None),
+            "HIDDEN" => Hidden,
+            "SUDDEN" => Sudden,
+            "LIFT" => Lift,
+            "MIRROR" => Mirror,
+            "RANDOM" => Random,
+            "R-RANDOM" => RRandom,
+            "S-RANDOM" => SRandom,
+            "H-RANDOM" => HRandom,
+            "ALL-SCRATCH" | "ALLSCR" => AllScratch,
+            "FLIP" => Flip,
+            _ => Other(s),
+        }
+    }
+    /// 対応するレーンオプション
+    pub fn lane_option(&self) -> Option<LaneOption> {
+        Some(match self {
+            PlayOption::Mirror => LaneOption::Mirror,
+            PlayOption::Random => LaneOption::Random,
+            PlayOption::RRandom => LaneOption::RRandom,
+            PlayOption::SRandom => LaneOption::SRandom,
+            PlayOption::HRandom => LaneOption::HRandom,
+            PlayOption::AllScratch => LaneOption::AllScratch,
+            _ => return None,
+        })
+    }
+}
+
+/// 時刻が確定したオプションの変更
+#[derive(Clone, Debug, PartialEq)]
+pub struct BmsOptionChange<'a> {
+    /// `#CHANGEOPTION`のid
+    pub id: usize,
+    /// 変更するオプションの文字列
+    pub text: &'a str,
+    /// 解釈したオプション
+    pub option: BmsOption<'a>,
+    /// 位置（拍数）
+    pub beat: f64,
+    /// 時刻
+    pub time: f64,
+}
+
+impl<'a> Bms<'a> {
+    /// `#OPTION`で指定されたオプション
+    pub fn options(&self) -> Vec<BmsOption<'a>> {
+        self.option.iter().map(|o| BmsOption::parse(o)).collect()
+    }
+    /// チャンネルA6のオプションの変更を時刻順に並べたもの
+    ///
+    /// 同じ位置に複数の変更がある場合は全て残す
+    pub fn option_changes(
+        &self,
+        timeline: &BmsTimeline,
+    ) -> Vec<BmsOptionChange<'a>> {
+        let mut changes = vec![];
+        for (m, measure) in self.main_data.iter().enumerate() {
+            for row in &measure.option {
+                for (f, id) in objects(row) {
+                    let Some(text) = self.change_option.get(id)
+                    else {
+                        log::warn!("CHANGEOPTION{}が定義されていません", id);
+                        continue;
+                    };
+                    let beat = timeline.beat(m, f);
+                    changes.push(BmsOptionChange {
+                        id: *id,
+                        text,
+                        option: BmsOption::parse(text),
+                        beat,
+                        time: timeline.beat_to_ms(beat),
+                    });
+                }
+            }
+        }
+        changes.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        use PlayOption::*;
+        assert_eq!(
+            BmsOption::parse("774:HI-SPEED_x99.75"),
+            BmsOption {
+                game: Some("774"),
+                options: vec![HighSpeed(Some(99.75))],
+            }
+        );
+        assert_eq!(
+            BmsOption::parse("774:RANDOM_MIRROR").options,
+            vec![Random, Mirror]
+        );
+        assert_eq!(
+            BmsOption::parse("charatbeatHDX:LONGMODE 0").options,
+            vec![Other("LONGMODE"), Other("0")]
+        );
+        assert_eq!(
+            BmsOption::parse("hidden sudden"),
+            BmsOption {
+                game: None,
+                options: vec![Hidden, Sudden],
+            }
+        );
+        assert_eq!(SRandom.lane_option(), Some(LaneOption::SRandom));
+        assert_eq!(Hidden.lane_option(), None);
+
+        let raw = RawBms::parse(
+            r"
+#BPM 120
+#OPTION 774:HIGH-SPEED
+#CHANGEOPTION01 774:SUDDEN
+#CHANGEOPTION02 774:MIRROR
+#001A6:0102
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        assert_eq!(bms.options()[0].options, vec![HighSpeed(None)]);
+        let timeline = bms.timeline();
+        assert_eq!(
+            bms.option_changes(&timeline)
+                .iter()
+                .map(|c| (c.time, c.text, c.option.options.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (2000., "774:SUDDEN", vec![Sudden]),
+                (3000., "774:MIRROR", vec![Mirror]),
+            ]
+        );
+    }
+}
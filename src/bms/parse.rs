@@ -1,132 +1,442 @@
-#![allow(dead_code)]
-
-use super::token::{ControlFlow::*, Token, Token::ControlFlow};
+use super::token::{ControlFlow::*, Token};
 use super::*;
-use winnow::combinator::{opt, preceded};
-use winnow::token::one_of;
-use winnow::{
-    combinator::{alt, repeat},
-    prelude::*,
-};
+use std::fmt;
 
-pub(crate) fn block(input: &mut &[Token]) -> ModalResult<BmsBlock> {
-    Ok(BmsBlock(
-        repeat(
-            0..,
-            alt((
-                one_of(|t| matches!(t, Token::Command(_)))
-                    .map(|t| match t {
-                        Token::Command(c) => c,
-                        _ => unreachable!(),
-                    })
-                    .map(BmsElement::Command),
-                random_block.map(BmsElement::Random),
-                switch_block.map(BmsElement::Switch),
-            )),
-        )
-        .parse_next(input)?,
-    ))
+/// 分岐の入れ子の深さの上限の既定値
+pub const DEFAULT_MAX_NESTING: usize = 64;
+/// 分岐の入れ子の深さの上限に指定できる最大値
+///
+/// 分岐の木の複製・比較・破棄は再帰するため、これより深い木は作らない
+pub const MAX_NESTING: usize = 512;
+
+/// 信頼できない譜面を解析するときの上限
+///
+/// [`RawBms::try_parse`]では字句解析と構文解析で、
+/// [`RawBms::try_make_bms_with`]では選ばれた分岐の内容で確かめる
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseLimits {
+    /// 小節の数
+    ///
+    /// 小節番号がこれ以上ならエラー
+    pub max_measures: usize,
+    /// メインデータの1行に並ぶオブジェクトの数（`00`も含む）
+    pub max_objects_per_line: usize,
+    /// 全ての`00`以外のオブジェクトの数
+    ///
+    /// [`RawBms::try_parse`]では選ばれない分岐の中のものも数える
+    pub max_events: usize,
+    /// `#RANDOM`・`#SWITCH`の最大値
+    pub max_random: u128,
+    /// `#RANDOM`・`#IF`・`#SWITCH`の入れ子の深さ
+    ///
+    /// [`MAX_NESTING`]より大きい値は[`MAX_NESTING`]として扱う
+    pub max_nesting: usize,
+    /// 小節の長さ（チャンネル02）の最小値
+    ///
+    /// これより短いか有限でない長さならエラー
+    pub min_measure_length: f64,
 }
-fn random_block(input: &mut &[Token]) -> ModalResult<BmsRandomBlock> {
-    let (n, e, _) = (
-        one_of(|t| matches!(t, ControlFlow(Random(_) | SetRandom(_)))).map(
-            |t| match t {
-                ControlFlow(Random(n)) => RandomValue::Max(n),
-                ControlFlow(SetRandom(n)) => RandomValue::Set(n),
-                _ => unreachable!(),
-            },
-        ),
-        repeat(
-            0..,
-            alt((
-                if_block.map(BmsRandomElement::IfBlock),
-                block
-                    .verify(|b| !b.0.is_empty())
-                    .map(BmsRandomElement::Block),
-            )),
-        ),
-        opt(one_of(ControlFlow(EndRandom))),
-    )
-        .parse_next(input)?;
-    Ok(BmsRandomBlock(n, e))
+impl std::default::Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_measures: 1000,
+            max_objects_per_line: 4096,
+            max_events: 1_000_000,
+            max_random: 65536,
+            max_nesting: DEFAULT_MAX_NESTING,
+            min_measure_length: 1. / 1024.,
+        }
+    }
 }
-fn if_block(input: &mut &[Token]) -> ModalResult<BmsIfBlock> {
-    let mut if_block = BmsIfBlock::default();
-    if_block.r#if.push(
-        (
-            one_of(|t| matches!(t, ControlFlow(If(_)))).map(|t| match t {
-                ControlFlow(If(n)) => n,
-                _ => unreachable!(),
-            }),
-            block,
-        )
-            .parse_next(input)?,
-    );
-    if_block.r#if.extend::<Vec<_>>(
-        repeat(
-            0..,
-            (
-                one_of(|t| matches!(t, ControlFlow(ElseIf(_)))).map(
-                    |t| match t {
-                        ControlFlow(ElseIf(n)) => n,
-                        _ => unreachable!(),
-                    },
-                ),
-                block,
-            ),
-        )
-        .parse_next(input)?,
-    );
-    if_block.r#else =
-        opt(preceded(one_of(ControlFlow(Else)), block)).parse_next(input)?;
-    one_of(ControlFlow(EndIf)).parse_next(input)?;
-    Ok(if_block)
+impl ParseLimits {
+    pub(crate) fn check_measure(
+        &self,
+        measure: usize,
+        line: Option<usize>,
+    ) -> Result<(), ParseError> {
+        if self.max_measures <= measure {
+            return Err(ParseError::TooManyMeasures {
+                line,
+                limit: self.max_measures,
+            });
+        }
+        Ok(())
+    }
+    pub(crate) fn check_measure_length(
+        &self,
+        length: f64,
+        line: Option<usize>,
+    ) -> Result<(), ParseError> {
+        if !(self.min_measure_length <= length && length.is_finite()) {
+            return Err(ParseError::InvalidMeasureLength {
+                line,
+                limit: self.min_measure_length,
+            });
+        }
+        Ok(())
+    }
+    pub(crate) fn check_random(
+        &self,
+        max: u128,
+        line: Option<usize>,
+    ) -> Result<(), ParseError> {
+        if self.max_random < max {
+            return Err(ParseError::RandomRangeTooLarge {
+                line,
+                limit: self.max_random,
+            });
+        }
+        Ok(())
+    }
+    pub(crate) fn check_events(&self, events: usize) -> Result<(), ParseError> {
+        if self.max_events < events {
+            return Err(ParseError::TooManyEvents {
+                limit: self.max_events,
+            });
+        }
+        Ok(())
+    }
 }
-fn switch_block(input: &mut &[Token]) -> ModalResult<BmsSwitchBlock> {
-    let (n, b, _): (_, Vec<_>, _) = (
-        one_of(|t| matches!(t, ControlFlow(Switch(_) | SetSwitch(_)))).map(
-            |t| match t {
-                ControlFlow(Switch(n)) => RandomValue::Max(n),
-                ControlFlow(SetSwitch(n)) => RandomValue::Set(n),
-                _ => unreachable!(),
-            },
-        ),
-        repeat(0.., case_block),
-        one_of(ControlFlow(EndSwitch)),
-    )
-        .parse_next(input)?;
-    let default_set = b
-        .iter()
-        .filter_map(|BmsCaseBlock(l, _, _)| {
-            if let SwitchLabel::Case(n) = l {
-                Some(*n)
+
+/// 構文解析の失敗と、[`ParseLimits`]を超えた入力
+///
+/// `line`は字句解析で見つかったときの1から始まる行番号
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// `#RANDOM`・`#IF`・`#SWITCH`の入れ子が上限より深い
+    NestingTooDeep { limit: usize },
+    /// 小節番号が上限以上
+    TooManyMeasures { line: Option<usize>, limit: usize },
+    /// メインデータの1行のオブジェクトが上限より多い
+    TooManyObjects { line: usize, limit: usize },
+    /// オブジェクトの総数が上限より多い
+    TooManyEvents { limit: usize },
+    /// `#RANDOM`・`#SWITCH`の最大値が上限より大きい
+    RandomRangeTooLarge { line: Option<usize>, limit: u128 },
+    /// 小節の長さが下限より短いか有限でない
+    InvalidMeasureLength { line: Option<usize>, limit: f64 },
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ParseError::*;
+        let line = match self {
+            TooManyMeasures { line, .. }
+            | RandomRangeTooLarge { line, .. }
+            | InvalidMeasureLength { line, .. } => *line,
+            TooManyObjects { line, .. } => Some(*line),
+            NestingTooDeep { .. } | TooManyEvents { .. } => None,
+        };
+        if let Some(line) = line {
+            write!(f, "{line}行目: ")?;
+        }
+        match self {
+            NestingTooDeep { limit } => {
+                write!(f, "分岐の入れ子が上限（{limit}）より深いです")
+            }
+            TooManyMeasures { limit, .. } => {
+                write!(f, "小節番号が上限（{limit}）以上です")
+            }
+            TooManyObjects { limit, .. } => {
+                write!(f, "1行のオブジェクトが上限（{limit}）より多いです")
             }
-            else {
-                None
+            TooManyEvents { limit } => {
+                write!(f, "オブジェクトの総数が上限（{limit}）より多いです")
             }
-        })
-        .collect();
-    Ok(BmsSwitchBlock(n, b, default_set))
+            RandomRangeTooLarge { limit, .. } => {
+                write!(f, "乱数の最大値が上限（{limit}）より大きいです")
+            }
+            InvalidMeasureLength { limit, .. } => {
+                write!(
+                    f,
+                    "小節の長さが下限（{limit}）より短いか有限ではありません"
+                )
+            }
+        }
+    }
 }
-fn case_block(input: &mut &[Token]) -> ModalResult<BmsCaseBlock> {
-    let (l, b, s) = (
-        one_of(|t| matches!(t, ControlFlow(Case(_) | Default))).map(
-            |t| match t {
-                ControlFlow(Case(n)) => SwitchLabel::Case(n),
-                ControlFlow(Default) => SwitchLabel::Default,
-                _ => unreachable!(),
+impl std::error::Error for ParseError {}
+
+/// 閉じていない分岐
+enum Frame<'a> {
+    Random {
+        value: RandomValue,
+        elements: Vec<BmsRandomElement<'a>>,
+        /// `#IF`の外に書かれたコマンド
+        block: BmsBlock<'a>,
+    },
+    If {
+        if_block: BmsIfBlock<'a>,
+        /// 今読んでいる`#IF`・`#ELSEIF`の値
+        ///
+        /// `#ELSE`なら`None`
+        label: Option<u128>,
+        block: BmsBlock<'a>,
+    },
+    Switch {
+        value: RandomValue,
+        cases: Vec<BmsCaseBlock<'a>>,
+        case: Option<(SwitchLabel, BmsBlock<'a>)>,
+    },
+    /// 入れ子が深すぎるため読み捨てている分岐
+    Skipped(Kind),
+}
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Random,
+    If,
+    Switch,
+}
+impl Frame<'_> {
+    fn random(value: RandomValue) -> Self {
+        Frame::Random {
+            value,
+            elements: vec![],
+            block: BmsBlock::default(),
+        }
+    }
+    fn switch(value: RandomValue) -> Self {
+        Frame::Switch {
+            value,
+            cases: vec![],
+            case: None,
+        }
+    }
+    fn kind(&self) -> Kind {
+        match self {
+            Frame::Random { .. } => Kind::Random,
+            Frame::If { .. } => Kind::If,
+            Frame::Switch { .. } => Kind::Switch,
+            Frame::Skipped(kind) => *kind,
+        }
+    }
+}
+
+struct Parser<'a> {
+    root: BmsBlock<'a>,
+    stack: Vec<Frame<'a>>,
+    max_nesting: usize,
+    error: Option<ParseError>,
+}
+impl<'a> Parser<'a> {
+    /// コマンドや閉じた分岐を追加する先
+    ///
+    /// `#SWITCH`の`#CASE`の外や、読み捨てている分岐の中なら`None`
+    fn current_block(&mut self) -> Option<&mut BmsBlock<'a>> {
+        match self.stack.last_mut() {
+            None => Some(&mut self.root),
+            Some(Frame::Random { block, .. } | Frame::If { block, .. }) => {
+                Some(block)
+            }
+            Some(Frame::Switch { case, .. }) => {
+                case.as_mut().map(|(_, block)| block)
+            }
+            Some(Frame::Skipped(_)) => None,
+        }
+    }
+    fn push(&mut self, element: BmsElement<'a>) {
+        if let Some(block) = self.current_block() {
+            block.0.push(element);
+        }
+    }
+    fn open(&mut self, frame: Frame<'a>) {
+        if self.stack.len() < self.max_nesting {
+            self.stack.push(frame);
+            return;
+        }
+        if self.error.is_none() {
+            log::warn!("分岐の入れ子が深すぎるため読み捨てます");
+            self.error = Some(ParseError::NestingTooDeep {
+                limit: self.max_nesting,
+            });
+        }
+        self.stack.push(Frame::Skipped(frame.kind()));
+    }
+    /// 一番内側の`kind`の分岐より内側の分岐を閉じる
+    ///
+    /// `kind`の分岐が無ければ何もせずに`false`を返す
+    fn close_to(&mut self, kind: Kind) -> bool {
+        let Some(i) = self.stack.iter().rposition(|f| f.kind() == kind)
+        else {
+            return false;
+        };
+        while i + 1 < self.stack.len() {
+            self.close();
+        }
+        true
+    }
+    /// 一番内側の分岐を閉じる
+    fn close(&mut self) {
+        let Some(frame) = self.stack.pop()
+        else {
+            return;
+        };
+        match frame {
+            Frame::Random {
+                value,
+                mut elements,
+                block,
+            } => {
+                if !block.0.is_empty() {
+                    elements.push(BmsRandomElement::Block(block));
+                }
+                self.push(BmsElement::Random(BmsRandomBlock(value, elements)));
+            }
+            Frame::If {
+                mut if_block,
+                label,
+                block,
+            } => {
+                match label {
+                    Some(n) => if_block.r#if.push((n, block)),
+                    None => if_block.r#else = Some(block),
+                }
+                // #IFは#RANDOMの直下でだけ開く
+                if let Some(Frame::Random { elements, .. }) =
+                    self.stack.last_mut()
+                {
+                    elements.push(BmsRandomElement::IfBlock(if_block));
+                }
+            }
+            Frame::Switch {
+                value,
+                mut cases,
+                case,
+            } => {
+                if let Some((label, block)) = case {
+                    cases.push(BmsCaseBlock(label, block, false));
+                }
+                let default_set = cases
+                    .iter()
+                    .filter_map(|BmsCaseBlock(l, _, _)| match l {
+                        SwitchLabel::Case(n) => Some(*n),
+                        SwitchLabel::Default => None,
+                    })
+                    .collect();
+                self.push(BmsElement::Switch(BmsSwitchBlock(
+                    value,
+                    cases,
+                    default_set,
+                )));
+            }
+            Frame::Skipped(_) => (),
+        }
+    }
+    fn control_flow(&mut self, flow: token::ControlFlow) {
+        match flow {
+            Random(n) => self.open(Frame::random(RandomValue::Max(n))),
+            SetRandom(n) => self.open(Frame::random(RandomValue::Set(n))),
+            Switch(n) => self.open(Frame::switch(RandomValue::Max(n))),
+            SetSwitch(n) => self.open(Frame::switch(RandomValue::Set(n))),
+            If(n) => match self.stack.last_mut() {
+                Some(Frame::Random {
+                    elements, block, ..
+                }) => {
+                    if !block.0.is_empty() {
+                        let block = std::mem::take(block);
+                        elements.push(BmsRandomElement::Block(block));
+                    }
+                    self.open(Frame::If {
+                        if_block: BmsIfBlock::default(),
+                        label: Some(n),
+                        block: BmsBlock::default(),
+                    });
+                }
+                Some(Frame::Skipped(Kind::Random)) => {
+                    self.stack.push(Frame::Skipped(Kind::If));
+                }
+                // #RANDOMの外の#IFは無視する
+                _ => (),
             },
-        ),
-        block,
-        opt(one_of(ControlFlow(Skip))).map(|t| t.is_some()),
-    )
-        .parse_next(input)?;
-    Ok(BmsCaseBlock(l, b, s))
+            ElseIf(_) | Else => {
+                // #ELSEの後の#ELSEIF・#ELSEは無視する
+                if self.close_to(Kind::If)
+                    && let Some(Frame::If {
+                        if_block,
+                        label,
+                        block,
+                    }) = self.stack.last_mut()
+                    && let Some(n) = *label
+                {
+                    if_block.r#if.push((n, std::mem::take(block)));
+                    *label = match flow {
+                        ElseIf(n) => Some(n),
+                        _ => None,
+                    };
+                }
+            }
+            EndIf => {
+                if self.close_to(Kind::If) {
+                    self.close();
+                }
+            }
+            EndRandom => {
+                if self.close_to(Kind::Random) {
+                    self.close();
+                }
+            }
+            Case(_) | Default | Skip => {
+                if self.close_to(Kind::Switch)
+                    && let Some(Frame::Switch { cases, case, .. }) =
+                        self.stack.last_mut()
+                {
+                    if let Some((label, block)) = case.take() {
+                        cases.push(BmsCaseBlock(label, block, flow == Skip));
+                    }
+                    *case = match flow {
+                        Case(n) => {
+                            Some((SwitchLabel::Case(n), BmsBlock::default()))
+                        }
+                        Default => {
+                            Some((SwitchLabel::Default, BmsBlock::default()))
+                        }
+                        _ => None,
+                    };
+                }
+            }
+            EndSwitch => {
+                if self.close_to(Kind::Switch) {
+                    self.close();
+                }
+            }
+        }
+    }
+}
+
+/// トークン列から分岐の木を組み立てる
+///
+/// 再帰せずにスタックで入れ子を管理するので、深い入れ子でもスタックが溢れない。
+/// 入れ子が`max_nesting`（[`MAX_NESTING`]まで）より深い分岐は読み捨て、エラーを返す
+///
+/// 閉じていない分岐はファイルの終わりか、外側の分岐の終わりで閉じる
+pub(crate) fn block<'a>(
+    tokens: impl IntoIterator<Item = Token<'a>>,
+    max_nesting: usize,
+) -> (BmsBlock<'a>, Option<ParseError>) {
+    let mut parser = Parser {
+        root: BmsBlock::default(),
+        stack: vec![],
+        max_nesting: max_nesting.min(MAX_NESTING),
+        error: None,
+    };
+    for token in tokens {
+        match token {
+            Token::Command(command) => {
+                parser.push(BmsElement::Command(command));
+            }
+            Token::ControlFlow(flow) => parser.control_flow(flow),
+            Token::Comment => (),
+        }
+    }
+    while !parser.stack.is_empty() {
+        parser.close();
+    }
+    (parser.root, parser.error)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn simple() {
@@ -136,22 +446,20 @@ mod tests {
         };
         let empty_token_stream = vec![];
         assert_eq!(
-            block
-                .parse_next(&mut empty_token_stream.as_slice())
-                .unwrap(),
+            block(empty_token_stream, DEFAULT_MAX_NESTING).0,
             BmsBlock(vec![])
         );
         let token_stream = vec![
             Command(Player(1)),
-            Command(Genre("ジャンル".to_string())),
-            Command(Title("タイトル".to_string())),
+            Command(Genre("ジャンル".into())),
+            Command(Title("タイトル".into())),
         ];
         assert_eq!(
-            block.parse_next(&mut token_stream.as_slice()).unwrap(),
+            block(token_stream, DEFAULT_MAX_NESTING).0,
             BmsBlock(vec![
                 BmsElement::Command(Player(1)),
-                BmsElement::Command(Genre("ジャンル".to_string())),
-                BmsElement::Command(Title("タイトル".to_string())),
+                BmsElement::Command(Genre("ジャンル".into())),
+                BmsElement::Command(Title("タイトル".into())),
             ])
         );
     }
@@ -168,19 +476,19 @@ mod tests {
         let token_stream = vec![
             Command(PlayLevel(12)),
             ControlFlow(Random(10)),
-            Command(Genre("ジャンル".to_string())),
+            Command(Genre("ジャンル".into())),
             ControlFlow(If(1)),
-            Command(Title("タイトル1".to_string())),
+            Command(Title("タイトル1".into())),
             ControlFlow(ElseIf(2)),
-            Command(Title("タイトル2".to_string())),
+            Command(Title("タイトル2".into())),
             ControlFlow(ElseIf(4)),
-            Command(Title("タイトル4".to_string())),
+            Command(Title("タイトル4".into())),
             ControlFlow(Else),
-            Command(Title("タイトル*".to_string())),
+            Command(Title("タイトル*".into())),
             ControlFlow(Random(100)),
             ControlFlow(EndRandom),
             ControlFlow(EndIf),
-            Command(Artist("アーティスト".to_string())),
+            Command(Artist("アーティスト".into())),
             ControlFlow(If(1)),
             ControlFlow(EndIf),
             ControlFlow(If(2)),
@@ -194,40 +502,38 @@ mod tests {
             ControlFlow(SetRandom(123456789012345678901234567890)),
         ];
         assert_eq!(
-            block.parse_next(&mut token_stream.as_slice()).unwrap(),
+            block(token_stream, DEFAULT_MAX_NESTING).0,
             BmsBlock(vec![
                 BmsElement::Command(PlayLevel(12)),
                 BmsElement::Random(BmsRandomBlock(
                     RandomValue::Max(10),
                     vec![
                         BmsRandomElement::Block(BmsBlock(vec![
-                            BmsElement::Command(Genre("ジャンル".to_string()))
+                            BmsElement::Command(Genre("ジャンル".into()))
                         ])),
                         BmsRandomElement::IfBlock(BmsIfBlock {
                             r#if: vec![
                                 (
                                     1,
                                     BmsBlock(vec![BmsElement::Command(Title(
-                                        "タイトル1".to_string()
+                                        "タイトル1".into()
                                     ))])
                                 ),
                                 (
                                     2,
                                     BmsBlock(vec![BmsElement::Command(Title(
-                                        "タイトル2".to_string()
+                                        "タイトル2".into()
                                     ))])
                                 ),
                                 (
                                     4,
                                     BmsBlock(vec![BmsElement::Command(Title(
-                                        "タイトル4".to_string()
+                                        "タイトル4".into()
                                     )),])
                                 ),
                             ],
                             r#else: Some(BmsBlock(vec![
-                                BmsElement::Command(Title(
-                                    "タイトル*".to_string()
-                                )),
+                                BmsElement::Command(Title("タイトル*".into())),
                                 BmsElement::Random(BmsRandomBlock(
                                     RandomValue::Max(100),
                                     vec![]
@@ -235,9 +541,7 @@ mod tests {
                             ])),
                         }),
                         BmsRandomElement::Block(BmsBlock(vec![
-                            BmsElement::Command(Artist(
-                                "アーティスト".to_string()
-                            ))
+                            BmsElement::Command(Artist("アーティスト".into()))
                         ])),
                         BmsRandomElement::IfBlock(BmsIfBlock {
                             r#if: vec![(1, BmsBlock(vec![])),],
@@ -276,15 +580,15 @@ mod tests {
             Command(PlayLevel(12)),
             ControlFlow(Switch(10)),
             ControlFlow(Case(1)),
-            Command(Title("タイトル1".to_string())),
+            Command(Title("タイトル1".into())),
             ControlFlow(Case(2)),
-            Command(Title("タイトル2".to_string())),
+            Command(Title("タイトル2".into())),
             ControlFlow(Skip),
             ControlFlow(Case(4)),
-            Command(Title("タイトル4".to_string())),
+            Command(Title("タイトル4".into())),
             ControlFlow(Skip),
             ControlFlow(Default),
-            Command(Title("タイトル*".to_string())),
+            Command(Title("タイトル*".into())),
             ControlFlow(Switch(100)),
             ControlFlow(EndSwitch),
             ControlFlow(EndSwitch),
@@ -296,7 +600,7 @@ mod tests {
             ControlFlow(EndSwitch),
         ];
         assert_eq!(
-            block.parse_next(&mut token_stream.as_slice()).unwrap(),
+            block(token_stream, DEFAULT_MAX_NESTING).0,
             BmsBlock(vec![
                 BmsElement::Command(PlayLevel(12)),
                 BmsElement::Switch(BmsSwitchBlock(
@@ -305,30 +609,28 @@ mod tests {
                         BmsCaseBlock(
                             SwitchLabel::Case(1),
                             BmsBlock(vec![BmsElement::Command(Title(
-                                "タイトル1".to_string()
+                                "タイトル1".into()
                             ))]),
                             false
                         ),
                         BmsCaseBlock(
                             SwitchLabel::Case(2),
                             BmsBlock(vec![BmsElement::Command(Title(
-                                "タイトル2".to_string()
+                                "タイトル2".into()
                             ))]),
                             true
                         ),
                         BmsCaseBlock(
                             SwitchLabel::Case(4),
                             BmsBlock(vec![BmsElement::Command(Title(
-                                "タイトル4".to_string()
+                                "タイトル4".into()
                             ))]),
                             true
                         ),
                         BmsCaseBlock(
                             SwitchLabel::Default,
                             BmsBlock(vec![
-                                BmsElement::Command(Title(
-                                    "タイトル*".to_string()
-                                )),
+                                BmsElement::Command(Title("タイトル*".into())),
                                 BmsElement::Switch(BmsSwitchBlock(
                                     RandomValue::Max(100),
                                     vec![],
@@ -365,27 +667,340 @@ mod tests {
         );
     }
 
-    //#[test]
+    #[test]
+    fn branches() {
+        let raw = RawBms::parse(
+            r"
+#RANDOM 3
+#IF 1
+#SWITCH 2
+#CASE 1
+#SKIP
+#CASE 2
+#SKIP
+#ENDSW
+#ELSEIF 3
+#ENDIF
+#ENDRANDOM
+#SETRANDOM 2
+#IF 2
+#ENDIF
+",
+        );
+        assert_eq!(
+            raw.branches(),
+            vec![
+                BranchInfo {
+                    switch: false,
+                    max: Some(3),
+                    set: None,
+                    values: vec![1, 3],
+                    depth: 0,
+                },
+                BranchInfo {
+                    switch: true,
+                    max: Some(2),
+                    set: None,
+                    values: vec![1, 2],
+                    depth: 1,
+                },
+                BranchInfo {
+                    switch: false,
+                    max: None,
+                    set: Some(2),
+                    values: vec![2],
+                    depth: 0,
+                },
+            ]
+        );
+        // 1を選んだときだけ入れ子のSWITCHが現れる
+        assert_eq!(
+            raw.random_combinations(10),
+            Some(vec![vec![1, 1], vec![1, 2], vec![2], vec![3]])
+        );
+        assert_eq!(raw.random_combinations(3), None);
+    }
+
+    #[test]
+    fn header() {
+        let source = r"
+#TITLE タイトル
+#RANDOM 2
+#IF 2
+#PLAYLEVEL 12
+#00111:01
+#ENDIF
+#ENDRANDOM
+#00211:0101
+ #003SC:01
+#WAV01 a.wav
+";
+        let raw = RawBms::parse_header(source);
+        let bms = raw.make_bms_with(|_| 2);
+        assert_eq!(bms.title, Some("タイトル"));
+        assert_eq!(bms.play_level, Some(12));
+        assert_eq!(bms.wav.get(&1), Some(&"a.wav"));
+        assert!(bms.main_data.is_empty());
+        assert!(bms.other.is_empty());
+        assert_eq!(raw.branches(), RawBms::parse(source).branches());
+    }
+
+    #[test]
+    #[cfg(feature = "load")]
+    fn reader() {
+        use crate::load::TextEncoding;
+        let mut source =
+            "#TITLE タイトル\r\n#RANDOM 2\r\n#IF 1\r\n".to_string();
+        for m in 0..20000 {
+            source.push_str(&format!("#{:03}11:01 02\r\n", m % 1000));
+        }
+        source.push_str("#ENDIF\n#ENDRANDOM\n#WAV01 a.wav");
+        let raw = RawBms::parse_reader(source.as_bytes(), None).unwrap();
+        assert_eq!(raw, RawBms::parse(&source));
+
+        // Shift_JIS
+        let sjis =
+            TextEncoding::ShiftJis.encode("#TITLE タイトル\n#WAV01 音.wav");
+        let raw = RawBms::parse_reader(&sjis[..], None).unwrap();
+        let bms = raw.make_bms_with(|_| 1);
+        assert_eq!(bms.title, Some("タイトル"));
+        assert_eq!(bms.wav.get(&1), Some(&"音.wav"));
+        let raw = RawBms::parse_reader(&sjis[..], Some(TextEncoding::ShiftJis))
+            .unwrap();
+        assert_eq!(raw.make_bms_with(|_| 1).title, Some("タイトル"));
+        // 前の部分がASCIIだけなら、途中からShift_JISでも読める
+        let mut source = "#TITLE a\n".repeat(20000).into_bytes();
+        source.extend(TextEncoding::ShiftJis.encode("#ARTIST 制作者\n"));
+        let raw = RawBms::parse_reader(&source[..], None).unwrap();
+        assert_eq!(raw.make_bms_with(|_| 1).artist, Some("制作者"));
+
+        let utf8 = b"\xEF\xBB\xBF#TITLE \xE3\x81\x82\n";
+        let raw = RawBms::parse_reader(&utf8[..], None).unwrap();
+        assert_eq!(raw.make_bms_with(|_| 1).title, Some("あ"));
+        let raw =
+            RawBms::parse_reader(&sjis[..], Some(TextEncoding::Utf8)).unwrap();
+        assert!(raw.make_bms_with(|_| 1).title.unwrap().contains('\u{FFFD}'));
+    }
+
+    #[test]
     fn nest_test() {
         use token::{
+            Command::Title,
             ControlFlow::{
-                Case, EndIf, EndRandom, EndSwitch, If, Random, Switch,
+                Case, EndIf, EndRandom, EndSwitch, If, SetRandom, SetSwitch,
             },
-            Token::ControlFlow,
+            Token::{Command, ControlFlow},
         };
         let mut token_stream = vec![];
         for i in 0..100 {
-            token_stream.push(ControlFlow(Random(i)));
+            token_stream.push(ControlFlow(SetRandom(i)));
             token_stream.push(ControlFlow(If(i)));
-            token_stream.push(ControlFlow(Switch(i)));
+            token_stream.push(ControlFlow(SetSwitch(i)));
             token_stream.push(ControlFlow(Case(i)));
         }
+        token_stream.push(Command(Title("タイトル".into())));
         for _ in 0..100 {
             token_stream.push(ControlFlow(EndSwitch));
             token_stream.push(ControlFlow(EndIf));
             token_stream.push(ControlFlow(EndRandom));
         }
-        let b = block.parse_next(&mut token_stream.as_slice()).unwrap();
-        println!("{b:?}");
+        let commands = |b: &BmsBlock| {
+            let mut output = vec![];
+            b.get_token_vec(&mut output, &mut |_| 1);
+            output.len()
+        };
+        let (b, error) = block(token_stream.clone(), 300);
+        assert_eq!(error, None);
+        assert_eq!(commands(&b), 1);
+        let (b, error) = block(token_stream, DEFAULT_MAX_NESTING);
+        assert_eq!(
+            error,
+            Some(ParseError::NestingTooDeep {
+                limit: DEFAULT_MAX_NESTING
+            })
+        );
+        assert_eq!(commands(&b), 0);
+
+        // スタックが溢れずに、深すぎる分岐だけを読み捨てる
+        let source = "#RANDOM 1\n#IF 1\n".repeat(100000)
+            + "#TITLE タイトル\n"
+            + &"#ENDIF\n#ENDRANDOM\n".repeat(100000)
+            + "#ARTIST アーティスト\n";
+        assert_eq!(
+            RawBms::try_parse(&source, &ParseLimits::default()),
+            Err(ParseError::NestingTooDeep {
+                limit: DEFAULT_MAX_NESTING
+            })
+        );
+        let raw = RawBms::parse(&source);
+        let bms = raw.make_bms_with(|_| 1);
+        assert_eq!(bms.title, None);
+        assert_eq!(bms.artist, Some("アーティスト"));
+
+        // 上限はMAX_NESTINGまで
+        let limits = ParseLimits {
+            max_nesting: 1_000_000,
+            ..ParseLimits::default()
+        };
+        assert_eq!(
+            RawBms::try_parse(&source, &limits),
+            Err(ParseError::NestingTooDeep { limit: MAX_NESTING })
+        );
+        let source =
+            "#RANDOM 1\n#IF 1\n".repeat(MAX_NESTING / 2) + "#TITLE タイトル\n";
+        let raw = RawBms::try_parse(&source, &limits).unwrap();
+        let branches = raw.branches();
+        assert_eq!(branches.len(), MAX_NESTING / 2);
+        assert_eq!(branches.last().unwrap().depth, MAX_NESTING / 2 - 1);
+        assert_eq!(raw.clone(), raw);
+        assert_eq!(raw.make_bms_with(|_| 1).title, Some("タイトル"));
+    }
+
+    #[test]
+    fn limits() {
+        let limits = ParseLimits {
+            max_measures: 10,
+            max_objects_per_line: 4,
+            max_events: 5,
+            max_random: 10,
+            max_nesting: 4,
+            min_measure_length: 0.125,
+        };
+        let parse = |source: &str| RawBms::try_parse(source, &limits).err();
+        assert_eq!(parse("#00911:01010101\n#00111:0001"), None);
+        assert_eq!(
+            parse("#TITLE a\n#01011:01"),
+            Some(ParseError::TooManyMeasures {
+                line: Some(2),
+                limit: 10,
+            })
+        );
+        assert_eq!(
+            parse("#00111:01 01 01 01 01"),
+            Some(ParseError::TooManyObjects { line: 1, limit: 4 })
+        );
+        assert_eq!(
+            parse("#00111:01010101\n#00211:00000001\n#00311:01"),
+            Some(ParseError::TooManyEvents { limit: 5 })
+        );
+        assert_eq!(
+            parse("#RANDOM 11\n#ENDRANDOM"),
+            Some(ParseError::RandomRangeTooLarge {
+                line: Some(1),
+                limit: 10,
+            })
+        );
+        assert_eq!(parse("#SETRANDOM 11\n#ENDRANDOM"), None);
+        assert_eq!(parse("#00102:0.125"), None);
+        for length in ["0.1", "0", "-1", "inf", "NaN", "1e-300"] {
+            assert_eq!(
+                parse(&format!("#TITLE a\n#00102:{length}")),
+                Some(ParseError::InvalidMeasureLength {
+                    line: Some(2),
+                    limit: 0.125,
+                })
+            );
+        }
+
+        // 選ばれた分岐の内容を確かめる
+        let raw = RawBms::parse("#RANDOM 20\n#IF 1\n#01011:01\n#ENDIF");
+        assert_eq!(
+            raw.try_make_bms_with(&limits, |_| 1).err(),
+            Some(ParseError::RandomRangeTooLarge {
+                line: None,
+                limit: 10,
+            })
+        );
+        let limits = ParseLimits {
+            max_random: 20,
+            ..limits
+        };
+        assert_eq!(
+            raw.try_make_bms_with(&limits, |_| 1).err(),
+            Some(ParseError::TooManyMeasures {
+                line: None,
+                limit: 10,
+            })
+        );
+        assert!(raw.try_make_bms_with(&limits, |_| 2).is_ok());
+        let raw = RawBms::parse("#RANDOM 2\n#IF 1\n#00102:inf\n#ENDIF");
+        assert_eq!(
+            raw.try_make_bms_with(&limits, |_| 1).err(),
+            Some(ParseError::InvalidMeasureLength {
+                line: None,
+                limit: 0.125,
+            })
+        );
+        assert!(raw.try_make_bms_with(&limits, |_| 2).is_ok());
+        assert_eq!(
+            ParseError::TooManyObjects { line: 3, limit: 4 }.to_string(),
+            "3行目: 1行のオブジェクトが上限（4）より多いです"
+        );
+    }
+
+    #[test]
+    fn unclosed() {
+        let raw = RawBms::parse(
+            r"
+#ENDIF
+#TITLE タイトル
+#RANDOM 2
+#IF 1
+#RANDOM 2
+#IF 2
+#ARTIST アーティスト
+#ELSE
+#GENRE ジャンル
+#ENDIF
+#ELSE
+#ELSEIF 2
+#PLAYLEVEL 12
+#ENDRANDOM
+#SUBTITLE サブタイトル
+",
+        );
+        assert!(raw.unparsed_lines().is_empty());
+        let bms = raw.make_bms_with(|_| 1);
+        assert_eq!(bms.title, Some("タイトル"));
+        assert_eq!(bms.genre, Some("ジャンル"));
+        assert_eq!(bms.play_level, None);
+        assert_eq!(bms.sub_title, vec!["サブタイトル"]);
+        let bms = raw.make_bms_with(|_| 2);
+        assert_eq!(bms.artist, None);
+        assert_eq!(bms.play_level, Some(12));
+    }
+
+    /// BMSらしい行
+    fn line() -> impl Strategy<Value = String> {
+        prop_oneof![
+            ("[0-9]{3}", "[0-9A-Za-z]{2}", "[0-9A-Za-z. ]{0,16}")
+                .prop_map(|(m, c, v)| format!("#{m}{c}:{v}")),
+            (
+                "(SET)?(RANDOM|SWITCH)|(ELSE)?IF|ELSE|END(IF|RANDOM|SW)|CASE|SKIP|DEF",
+                "[0-9]{0,3}",
+            )
+                .prop_map(|(c, n)| format!("#{c} {n}")),
+            (
+                "WAV|BMP|BPM|STOP|EXBMP|EXWAV|ARGB|@?BGA|SWBGA|STP|LNOBJ|TEXT|BASE|SCROLL|SPEED|EXRANK|CHANGEOPTION|WAVCMD|EXCHARACTER|SEEK|LNTYPE|PLAYER|TOTAL|TITLE|COMMENT",
+                "[0-9A-Za-z]{0,2}",
+                "[ 0-9A-Za-z,.\"-]{0,24}",
+            )
+                .prop_map(|(c, id, v)| format!("#{c}{id} {v}")),
+            any::<String>(),
+        ]
+    }
+
+    proptest! {
+        // どんな行が並んでいても、解析から書き出しまでパニックしない
+        #[test]
+        fn never_panics(lines in proptest::collection::vec(line(), 0..40)) {
+            let source = lines.join("\n");
+            let raw = RawBms::parse(&source);
+            raw.make_bms(rand::rng());
+            let bms = raw.make_bms_with(|max| max);
+            bms.check();
+            bms.to_string();
+            let _ = RawBms::try_parse(&source, &ParseLimits::default());
+        }
     }
 }
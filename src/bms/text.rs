@@ -0,0 +1,156 @@
+use super::*;
+use std::fmt::Write;
+use timeline::objects;
+
+/// 時刻が確定したテキスト
+#[derive(Clone, Debug, PartialEq)]
+pub struct BmsText<'a> {
+    /// `#TEXT`のid
+    pub id: usize,
+    /// 表示する文字列
+    pub text: &'a str,
+    /// 位置（拍数）
+    pub beat: f64,
+    /// 表示を始める時刻
+    pub time: f64,
+    /// 表示を終える時刻
+    ///
+    /// 次のテキストの時刻で、最後のテキストでは譜面の終わりの時刻
+    pub end: f64,
+}
+
+impl<'a> Bms<'a> {
+    /// チャンネル99のテキストを時刻順に並べたもの
+    ///
+    /// 同じ位置に複数のテキストがある場合は後のものを残す
+    pub fn texts(&self, timeline: &BmsTimeline) -> Vec<BmsText<'a>> {
+        let mut objs = vec![];
+        for (m, measure) in self.main_data.iter().enumerate() {
+            for row in &measure.text {
+                for (f, id) in objects(row) {
+                    objs.push((timeline.beat(m, f), *id));
+                }
+            }
+        }
+        objs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut texts: Vec<BmsText> = vec![];
+        for (beat, id) in objs {
+            let Some(text) = self.text.get(&id)
+            else {
+                log::warn!("TEXT{}が定義されていません", id);
+                continue;
+            };
+            let text = BmsText {
+                id,
+                text,
+                beat,
+                time: timeline.beat_to_ms(beat),
+                end: 0.,
+            };
+            match texts.last_mut() {
+                Some(last) if last.beat == beat => *last = text,
+                _ => texts.push(text),
+            }
+        }
+        let last =
+            timeline.beat_to_ms(timeline.measure_start(self.main_data.len()));
+        let ends = texts
+            .iter()
+            .skip(1)
+            .map(|t| t.time)
+            .chain([last])
+            .collect::<Vec<_>>();
+        for (text, end) in texts.iter_mut().zip(ends) {
+            text.end = end.max(text.time);
+        }
+        texts
+    }
+}
+
+/// テキストをSRT形式の字幕にする
+///
+/// 空の文字列のテキストは、それまでの字幕を消すだけで出力しない
+pub fn to_srt(texts: &[BmsText]) -> String {
+    fn time(ms: f64) -> String {
+        let ms = ms.max(0.).round() as u64;
+        format!(
+            "{:02}:{:02}:{:02},{:03}",
+            ms / 3600000,
+            ms / 60000 % 60,
+            ms / 1000 % 60,
+            ms % 1000
+        )
+    }
+    let mut srt = String::new();
+    for (i, text) in texts.iter().filter(|t| !t.text.is_empty()).enumerate() {
+        writeln!(srt, "{}", i + 1).unwrap();
+        writeln!(srt, "{} --> {}", time(text.time), time(text.end)).unwrap();
+        writeln!(srt, "{}", text.text).unwrap();
+        writeln!(srt).unwrap();
+    }
+    srt
+}
+
+/// テキストをLRC形式の歌詞にする
+///
+/// 最後のテキストの終わりには空の行を加える
+pub fn to_lrc(texts: &[BmsText]) -> String {
+    fn time(ms: f64) -> String {
+        let cs = (ms.max(0.) / 10.).round() as u64;
+        format!("[{:02}:{:02}.{:02}]", cs / 6000, cs / 100 % 60, cs % 100)
+    }
+    let mut lrc = String::new();
+    for text in texts {
+        writeln!(lrc, "{}{}", time(text.time), text.text).unwrap();
+    }
+    if let Some(last) = texts.last()
+        && !last.text.is_empty()
+    {
+        writeln!(lrc, "{}", time(last.end)).unwrap();
+    }
+    lrc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lyrics() {
+        let raw = RawBms::parse(
+            r#"
+#BPM 120
+#TEXT01 "first line"
+#TEXT02 "second line"
+#TEXT03 ""
+#00199:0102
+#00299:03
+#00399:04
+"#,
+        );
+        let bms = raw.make_bms(rand::rng());
+        let timeline = bms.timeline();
+        let texts = bms.texts(&timeline);
+        assert_eq!(
+            texts
+                .iter()
+                .map(|t| (t.text, t.time, t.end))
+                .collect::<Vec<_>>(),
+            vec![
+                ("first line", 2000., 3000.),
+                ("second line", 3000., 4000.),
+                ("", 4000., 8000.),
+            ]
+        );
+        assert_eq!(
+            to_srt(&texts),
+            "1\n00:00:02,000 --> 00:00:03,000\nfirst line\n\n\
+             2\n00:00:03,000 --> 00:00:04,000\nsecond line\n\n"
+        );
+        assert_eq!(
+            to_lrc(&texts),
+            "[00:02.00]first line\n[00:03.00]second line\n[00:04.00]\n"
+        );
+    }
+}
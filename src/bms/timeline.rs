@@ -0,0 +1,311 @@
+use super::*;
+
+/// 譜面上の位置と時刻を相互に変換するタイムライン
+///
+/// 位置は譜面の最初からの拍数（四分音符の数）で表し、
+/// 長さ1の小節を4拍とする
+///
+/// 時刻はすべてミリ秒で、譜面の最初を0msとする
+#[derive(Clone, Debug, PartialEq)]
+pub struct BmsTimeline {
+    /// 各小節の開始位置
+    measure_starts: Vec<f64>,
+    points: Vec<TimingPoint>,
+}
+
+/// BPMか停止が変化する位置
+#[derive(Clone, Debug, PartialEq)]
+struct TimingPoint {
+    /// 位置（拍数）
+    beat: f64,
+    /// この位置に到達した時刻
+    time: f64,
+    /// この位置以降のBPM
+    bpm: f64,
+    /// この位置での停止時間
+    stop: f64,
+}
+
+/// BPMが指定されていない場合のBPM
+pub const DEFAULT_BPM: f64 = 130.;
+
+impl BmsTimeline {
+    pub fn new(bms: &Bms) -> BmsTimeline {
+        let mut measure_starts = Vec::with_capacity(bms.main_data.len() + 1);
+        let mut beat = 0.;
+        for measure in &bms.main_data {
+            measure_starts.push(beat);
+            beat += measure.length * 4.;
+        }
+        measure_starts.push(beat);
+
+        enum Event {
+            Bpm(f64),
+            /// 192分音符単位の停止
+            Stop(f64),
+            /// ミリ秒単位の停止
+            Stp(f64),
+        }
+        let mut events = vec![];
+        let position = |m: usize, f: f64| {
+            measure_starts[m] + f * 4. * bms.main_data[m].length
+        };
+        for (m, measure) in bms.main_data.iter().enumerate() {
+            for row in &measure.bpm {
+                for (f, bpm) in objects(row) {
+                    if let Some(bpm) = bpm {
+                        events.push((position(m, f), Event::Bpm(*bpm)));
+                    }
+                }
+            }
+            for row in &measure.ex_bpm {
+                for (f, id) in objects(row) {
+                    match bms.ex_bpm.get(id) {
+                        Some(bpm) => {
+                            events.push((position(m, f), Event::Bpm(*bpm)))
+                        }
+                        None => {
+                            log::warn!("{m}小節のBPM{id}が定義されていません")
+                        }
+                    }
+                }
+            }
+            for row in &measure.stop {
+                for (f, id) in objects(row) {
+                    match bms.stop.get(id) {
+                        Some(stop) => {
+                            events.push((position(m, f), Event::Stop(*stop)))
+                        }
+                        None => {
+                            log::warn!("{m}小節のSTOP{id}が定義されていません")
+                        }
+                    }
+                }
+            }
+        }
+        for &(m, f, ms) in &bms.stp {
+            let beat = if m < bms.main_data.len() {
+                position(m, f as f64 / 1000.)
+            }
+            else {
+                *measure_starts.last().unwrap()
+                    + (m - bms.main_data.len()) as f64 * 4.
+                    + f as f64 / 250.
+            };
+            events.push((beat, Event::Stp(ms)));
+        }
+        // 同じ位置ではBPM変化を先に適用する
+        events.sort_by(|(a, ea), (b, eb)| {
+            let is_bpm = |e: &Event| matches!(e, Event::Bpm(_));
+            a.total_cmp(b).then(is_bpm(eb).cmp(&is_bpm(ea)))
+        });
+
+        let init_bpm = match bms.bpm {
+            Some(bpm) if bpm > 0. => bpm,
+            _ => DEFAULT_BPM,
+        };
+        let mut points = vec![TimingPoint {
+            beat: 0.,
+            time: 0.,
+            bpm: init_bpm,
+            stop: 0.,
+        }];
+        for (beat, e) in events {
+            if let Event::Bpm(bpm) = e
+                && bpm <= 0.
+            {
+                log::warn!("0以下のBPMを無視しました");
+                continue;
+            }
+            let last = points.last().unwrap();
+            if last.beat < beat {
+                let time = last.time
+                    + last.stop
+                    + beats_to_ms(beat - last.beat, last.bpm);
+                let bpm = last.bpm;
+                points.push(TimingPoint {
+                    beat,
+                    time,
+                    bpm,
+                    stop: 0.,
+                });
+            }
+            let last = points.last_mut().unwrap();
+            match e {
+                Event::Bpm(bpm) => last.bpm = bpm,
+                Event::Stop(n) => last.stop += beats_to_ms(n / 48., last.bpm),
+                Event::Stp(ms) => last.stop += ms,
+            }
+        }
+        BmsTimeline {
+            measure_starts,
+            points,
+        }
+    }
+    /// 小節数
+    pub fn measure_count(&self) -> usize {
+        self.measure_starts.len() - 1
+    }
+    /// 小節の開始位置
+    ///
+    /// メインデータの範囲外の小節は長さ1として扱う
+    pub fn measure_start(&self, measure: usize) -> f64 {
+        let count = self.measure_count();
+        if measure <= count {
+            self.measure_starts[measure]
+        }
+        else {
+            self.measure_starts[count] + (measure - count) as f64 * 4.
+        }
+    }
+    /// 小節と小節内の位置（0以上1未満）から位置（拍数）へ変換
+    pub fn beat(&self, measure: usize, fraction: f64) -> f64 {
+        let start = self.measure_start(measure);
+        let length = self.measure_start(measure + 1) - start;
+        start + fraction * length
+    }
+    /// 位置を含む小節
+    pub fn measure_at(&self, beat: f64) -> usize {
+        let i = self.measure_starts.partition_point(|&s| s <= beat);
+        if i < self.measure_starts.len() {
+            i.saturating_sub(1)
+        }
+        else {
+            let count = self.measure_count();
+            count + ((beat - self.measure_starts[count]) / 4.) as usize
+        }
+    }
+    fn point_at_beat(&self, beat: f64) -> &TimingPoint {
+        let i = self.points.partition_point(|p| p.beat <= beat);
+        &self.points[i.saturating_sub(1)]
+    }
+    /// 位置から時刻へ変換
+    ///
+    /// 停止位置では停止が始まる時刻を返す
+    pub fn beat_to_ms(&self, beat: f64) -> f64 {
+        let p = self.point_at_beat(beat);
+        let stop = if p.beat < beat { p.stop } else { 0. };
+        p.time + stop + beats_to_ms(beat - p.beat, p.bpm)
+    }
+    /// 時刻から位置へ変換
+    ///
+    /// 停止中の時刻では停止位置を返す
+    pub fn ms_to_beat(&self, ms: f64) -> f64 {
+        let i = self.points.partition_point(|p| p.time <= ms);
+        let p = &self.points[i.saturating_sub(1)];
+        let elapsed = if ms < p.time {
+            ms - p.time
+        }
+        else {
+            (ms - p.time - p.stop).max(0.)
+        };
+        p.beat + elapsed * p.bpm / 60000.
+    }
+    /// 小節と小節内の位置から時刻へ変換
+    pub fn position_to_ms(&self, measure: usize, fraction: f64) -> f64 {
+        self.beat_to_ms(self.beat(measure, fraction))
+    }
+    /// その位置のBPM
+    pub fn bpm_at(&self, beat: f64) -> f64 {
+        self.point_at_beat(beat).bpm
+    }
+    /// BPMが変化する位置
+    ///
+    /// (位置, 時刻, BPM)
+    pub fn bpm_changes(&self) -> Vec<(f64, f64, f64)> {
+        let mut changes = vec![];
+        let mut last = None;
+        for p in &self.points {
+            if last != Some(p.bpm) {
+                changes.push((p.beat, p.time, p.bpm));
+                last = Some(p.bpm);
+            }
+        }
+        changes
+    }
+    /// 停止する位置
+    ///
+    /// (位置, 時刻, 停止時間)
+    pub fn stops(&self) -> Vec<(f64, f64, f64)> {
+        self.points
+            .iter()
+            .filter(|p| p.stop > 0.)
+            .map(|p| (p.beat, p.time, p.stop))
+            .collect()
+    }
+}
+
+/// 1行のメインデータのうち、0以外のオブジェクト
+///
+/// (小節内の位置, オブジェクト)
+pub(crate) fn objects<T: Default + PartialEq>(
+    row: &[T],
+) -> impl Iterator<Item = (f64, &T)> {
+    let len = row.len() as f64;
+    let zero = T::default();
+    row.iter()
+        .enumerate()
+        .filter(move |(_, o)| **o != zero)
+        .map(move |(i, o)| (i as f64 / len, o))
+}
+
+fn beats_to_ms(beats: f64, bpm: f64) -> f64 {
+    beats * 60000. / bpm
+}
+
+impl Bms<'_> {
+    /// 位置と時刻を変換するタイムラインを作成
+    pub fn timeline(&self) -> BmsTimeline {
+        BmsTimeline::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timing() {
+        let raw = RawBms::parse(
+            r"
+#BPM 120
+#BPM01 240
+#STOP01 48
+#STP 004.500 1000
+#00102:0.5
+#00203:00780000
+#00308:0001
+#00309:01
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let timeline = bms.timeline();
+
+        assert_eq!(timeline.beat(1, 0.), 4.);
+        assert_eq!(timeline.beat(2, 0.), 6.);
+        assert_eq!(timeline.measure_at(5.), 1);
+        assert_eq!(timeline.measure_at(14.), 4);
+
+        // 0小節 4拍 120BPM 2000ms
+        // 1小節 2拍 120BPM 1000ms
+        assert_eq!(timeline.position_to_ms(2, 0.), 3000.);
+        // 2小節の1/4の位置でBPM 0x78 = 120 (変化なし)
+        assert_eq!(timeline.position_to_ms(3, 0.), 5000.);
+        // 3小節の最初で1拍停止 (120BPMで500ms)
+        assert_eq!(timeline.position_to_ms(3, 0.5), 6500.);
+        // 3小節の途中でBPM240
+        assert_eq!(timeline.position_to_ms(3, 0.75), 6750.);
+        assert_eq!(timeline.position_to_ms(4, 0.), 7000.);
+        // 4小節の途中で1000ms停止
+        assert_eq!(timeline.position_to_ms(4, 1.), 9000.);
+        assert_eq!(timeline.ms_to_beat(6500.), 12.);
+        assert_eq!(timeline.ms_to_beat(8000.), 16.);
+        assert_eq!(timeline.ms_to_beat(9000.), 18.);
+
+        assert_eq!(timeline.bpm_at(14.), 240.);
+        assert_eq!(
+            timeline.stops(),
+            vec![(10., 5000., 500.), (16., 7500., 1000.)]
+        );
+    }
+}
@@ -1,46 +1,49 @@
+use std::borrow::Cow;
+
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) enum Token {
-    Command(Command),
+pub(crate) enum Token<'a> {
+    Command(Command<'a>),
     ControlFlow(ControlFlow),
     Comment,
 }
-impl winnow::stream::ContainsToken<Token> for Token {
+impl<'a> winnow::stream::ContainsToken<Token<'a>> for Token<'a> {
     #[inline(always)]
-    fn contains_token(&self, token: Token) -> bool {
+    fn contains_token(&self, token: Token<'a>) -> bool {
         *self == token
     }
 }
-impl winnow::stream::ContainsToken<Token> for &[Token] {
+impl<'a> winnow::stream::ContainsToken<Token<'a>> for &[Token<'a>] {
     #[inline]
-    fn contains_token(&self, token: Token) -> bool {
-        self.iter().any(|t| *t == token)
+    fn contains_token(&self, token: Token<'a>) -> bool {
+        self.contains(&token)
     }
 }
 
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) enum Command {
-    MainData(usize, MainDataValue),
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum Command<'a> {
+    MainData(usize, MainDataValue<'a>),
     Player(i32),
     Rank(i32),
     DefExRank(f64),
     ExRank(Channel, f64),
     Total(f64),
     VolumeWav(f64),
-    StageFile(String),
-    Banner(String),
-    BackBmp(String),
-    CharacterFile(String),
+    StageFile(Cow<'a, str>),
+    Banner(Cow<'a, str>),
+    BackBmp(Cow<'a, str>),
+    CharacterFile(Cow<'a, str>),
     PlayLevel(i32),
     Difficulty(i32),
-    Title(String),
-    SubTitle(String),
-    Artist(String),
-    SubArtist(String),
-    Maker(String),
-    Genre(String),
-    Comment(String),
-    Text(Channel, String),
-    PathWav(String),
+    Title(Cow<'a, str>),
+    SubTitle(Cow<'a, str>),
+    Artist(Cow<'a, str>),
+    SubArtist(Cow<'a, str>),
+    Maker(Cow<'a, str>),
+    Genre(Cow<'a, str>),
+    Comment(Cow<'a, str>),
+    Text(Channel, Cow<'a, str>),
+    PathWav(Cow<'a, str>),
     Bpm(f64),
     ExBpm(Channel, f64),
     BaseBpm(f64),
@@ -50,25 +53,25 @@ pub(crate) enum Command {
     LnType(i32),
     LnObject(Channel),
     OctFp,
-    Option(String),
-    ChangeOption(Channel, String),
-    Wav(Channel, String),
+    Option(Cow<'a, str>),
+    ChangeOption(Channel, Cow<'a, str>),
+    Wav(Channel, Cow<'a, str>),
     WavCommand(i32, Channel, f64),
-    ExWav(Channel, [Option<f64>; 3], String),
+    ExWav(Channel, [Option<f64>; 3], Cow<'a, str>),
     Cdda(u32),
-    MidiFile(String),
-    Bmp(Channel, String),
-    ExBmp(Channel, [u8; 4], String),
+    MidiFile(Cow<'a, str>),
+    Bmp(Channel, Cow<'a, str>),
+    ExBmp(Channel, [u8; 4], Cow<'a, str>),
     Bga(Channel, Channel, [[f64; 2]; 3]),
     AtBga(Channel, Channel, [[f64; 2]; 3]),
     PoorBga(i32),
     SwitchBga(Channel, f64, f64, Channel, bool, [u8; 4], Vec<Channel>),
     Argb(Channel, [u8; 4]),
-    VideoFile(String),
+    VideoFile(Cow<'a, str>),
     VideoFps(f64),
     VideoColors(u32),
     VideoDelay(u32),
-    Movie(String),
+    Movie(Cow<'a, str>),
     Seek(Channel, f64),
     ExCharacter(
         u32,
@@ -77,47 +80,231 @@ pub(crate) enum Command {
         Option<[f64; 2]>,
         Option<[f64; 2]>,
     ),
-    Url(String),
-    Email(String),
+    Url(Cow<'a, str>),
+    Email(Cow<'a, str>),
     Scroll(Channel, f64),
     Speed(Channel, f64),
-    Preview(String),
+    Preview(Cow<'a, str>),
     /// 旧型BPM変更( 16進数 )と地雷( 36進数 )以外の
     /// WAV BMP BPM STOP SCROLLのチャンネルの指定と参照を62進数で解釈する
     Base62,
     /// その他のコマンド
-    Other(String, String),
+    Other(Cow<'a, str>, Cow<'a, str>),
 }
 #[derive(Clone, Debug, PartialEq)]
-pub(crate) enum MainDataValue {
-    Bgm(Vec<Channel>),
+pub(crate) enum MainDataValue<'a> {
+    Bgm(Objects<'a>),
     Length(f64),
-    Bga(Vec<Channel>),
+    Bga(Objects<'a>),
     Bpm(Vec<Option<f64>>),
-    BgaPoor(Vec<Channel>),
-    BgaLayer(Vec<Channel>),
-    ExBpm(Vec<Channel>),
-    Stop(Vec<Channel>),
-    BgaLayer2(Vec<Channel>),
-    ExRank(Vec<Channel>),
+    BgaPoor(Objects<'a>),
+    BgaLayer(Objects<'a>),
+    ExBpm(Objects<'a>),
+    Stop(Objects<'a>),
+    BgaLayer2(Objects<'a>),
+    ExRank(Objects<'a>),
     BgaAlpha(Vec<u8>),
     BgaLayerAlpha(Vec<u8>),
     BgaLayer2Alpha(Vec<u8>),
     BgaPoorAlpha(Vec<u8>),
-    Note(usize, Vec<Channel>),
-    InvisibleNote(usize, Vec<Channel>),
-    LongNote(usize, Vec<Channel>),
-    Text(Vec<Channel>),
-    BgaArgb(Vec<Channel>),
-    BgaLayerArgb(Vec<Channel>),
-    BgaLayer2Argb(Vec<Channel>),
-    BgaPoorArgb(Vec<Channel>),
-    SwitchBga(Vec<Channel>),
-    Option(Vec<Channel>),
+    Note(usize, Objects<'a>),
+    InvisibleNote(usize, Objects<'a>),
+    LongNote(usize, Objects<'a>),
+    Text(Objects<'a>),
+    BgaArgb(Objects<'a>),
+    BgaLayerArgb(Objects<'a>),
+    BgaLayer2Argb(Objects<'a>),
+    BgaPoorArgb(Objects<'a>),
+    SwitchBga(Objects<'a>),
+    Option(Objects<'a>),
     Landmine(usize, Vec<f64>),
-    Scroll(Vec<Channel>),
-    Speed(Vec<Channel>),
-    Other(usize, String),
+    Scroll(Objects<'a>),
+    Speed(Objects<'a>),
+    Other(usize, Cow<'a, str>),
+}
+impl Token<'_> {
+    /// 入力を借用しないトークンにする
+    #[cfg(feature = "load")]
+    pub(crate) fn into_owned(self) -> Token<'static> {
+        match self {
+            Token::Command(command) => Token::Command(command.into_owned()),
+            Token::ControlFlow(flow) => Token::ControlFlow(flow),
+            Token::Comment => Token::Comment,
+        }
+    }
+}
+impl Command<'_> {
+    /// 入力を借用しないコマンドにする
+    #[cfg(feature = "load")]
+    pub(crate) fn into_owned(self) -> Command<'static> {
+        use Command::*;
+        fn own(s: Cow<'_, str>) -> Cow<'static, str> {
+            Cow::Owned(s.into_owned())
+        }
+        match self {
+            MainData(measure, value) => MainData(measure, value.into_owned()),
+            Player(n) => Player(n),
+            Rank(n) => Rank(n),
+            DefExRank(n) => DefExRank(n),
+            ExRank(ch, n) => ExRank(ch, n),
+            Total(n) => Total(n),
+            VolumeWav(n) => VolumeWav(n),
+            StageFile(s) => StageFile(own(s)),
+            Banner(s) => Banner(own(s)),
+            BackBmp(s) => BackBmp(own(s)),
+            CharacterFile(s) => CharacterFile(own(s)),
+            PlayLevel(n) => PlayLevel(n),
+            Difficulty(n) => Difficulty(n),
+            Title(s) => Title(own(s)),
+            SubTitle(s) => SubTitle(own(s)),
+            Artist(s) => Artist(own(s)),
+            SubArtist(s) => SubArtist(own(s)),
+            Maker(s) => Maker(own(s)),
+            Genre(s) => Genre(own(s)),
+            Comment(s) => Comment(own(s)),
+            Text(ch, s) => Text(ch, own(s)),
+            PathWav(s) => PathWav(own(s)),
+            Bpm(n) => Bpm(n),
+            ExBpm(ch, n) => ExBpm(ch, n),
+            BaseBpm(n) => BaseBpm(n),
+            Stop(ch, n) => Stop(ch, n),
+            Stp(measure, ms, n) => Stp(measure, ms, n),
+            LnMode(n) => LnMode(n),
+            LnType(n) => LnType(n),
+            LnObject(ch) => LnObject(ch),
+            OctFp => OctFp,
+            Option(s) => Option(own(s)),
+            ChangeOption(ch, s) => ChangeOption(ch, own(s)),
+            Wav(ch, s) => Wav(ch, own(s)),
+            WavCommand(n, ch, v) => WavCommand(n, ch, v),
+            ExWav(ch, params, s) => ExWav(ch, params, own(s)),
+            Cdda(n) => Cdda(n),
+            MidiFile(s) => MidiFile(own(s)),
+            Bmp(ch, s) => Bmp(ch, own(s)),
+            ExBmp(ch, argb, s) => ExBmp(ch, argb, own(s)),
+            Bga(ch, bmp, pos) => Bga(ch, bmp, pos),
+            AtBga(ch, bmp, pos) => AtBga(ch, bmp, pos),
+            PoorBga(n) => PoorBga(n),
+            SwitchBga(ch, fr, time, line, r#loop, argb, pattern) => {
+                SwitchBga(ch, fr, time, line, r#loop, argb, pattern)
+            }
+            Argb(ch, argb) => Argb(ch, argb),
+            VideoFile(s) => VideoFile(own(s)),
+            VideoFps(n) => VideoFps(n),
+            VideoColors(n) => VideoColors(n),
+            VideoDelay(n) => VideoDelay(n),
+            Movie(s) => Movie(own(s)),
+            Seek(ch, n) => Seek(ch, n),
+            ExCharacter(sprite, bmp, rect, offset, abs) => {
+                ExCharacter(sprite, bmp, rect, offset, abs)
+            }
+            Url(s) => Url(own(s)),
+            Email(s) => Email(own(s)),
+            Scroll(ch, n) => Scroll(ch, n),
+            Speed(ch, n) => Speed(ch, n),
+            Preview(s) => Preview(own(s)),
+            Base62 => Base62,
+            Other(command, value) => Other(own(command), own(value)),
+        }
+    }
+}
+impl Command<'_> {
+    /// `00`以外のオブジェクトの数
+    pub(crate) fn events(&self) -> usize {
+        match self {
+            Command::MainData(_, value) => value.events(),
+            _ => 0,
+        }
+    }
+}
+impl MainDataValue<'_> {
+    fn objects(&self) -> Option<&Objects<'_>> {
+        use MainDataValue::*;
+        match self {
+            Bgm(o)
+            | Bga(o)
+            | BgaPoor(o)
+            | BgaLayer(o)
+            | ExBpm(o)
+            | Stop(o)
+            | BgaLayer2(o)
+            | ExRank(o)
+            | Note(_, o)
+            | InvisibleNote(_, o)
+            | LongNote(_, o)
+            | Text(o)
+            | BgaArgb(o)
+            | BgaLayerArgb(o)
+            | BgaLayer2Argb(o)
+            | BgaPoorArgb(o)
+            | SwitchBga(o)
+            | Option(o)
+            | Scroll(o)
+            | Speed(o) => Some(o),
+            _ => None,
+        }
+    }
+    /// 並んでいるオブジェクトの数
+    ///
+    /// `00`も含む
+    pub(crate) fn len(&self) -> usize {
+        use MainDataValue::*;
+        match self {
+            Bpm(v) => v.len(),
+            BgaAlpha(v) | BgaLayerAlpha(v) | BgaLayer2Alpha(v)
+            | BgaPoorAlpha(v) => v.len(),
+            Landmine(_, v) => v.len(),
+            Length(_) | Other(..) => 0,
+            _ => self.objects().map_or(0, Objects::len),
+        }
+    }
+    /// `00`以外のオブジェクトの数
+    pub(crate) fn events(&self) -> usize {
+        use MainDataValue::*;
+        match self {
+            Bpm(v) => v.iter().flatten().count(),
+            BgaAlpha(v) | BgaLayerAlpha(v) | BgaLayer2Alpha(v)
+            | BgaPoorAlpha(v) => v.iter().filter(|&&a| a != 0).count(),
+            Landmine(_, v) => v.iter().filter(|&&d| d != 0.).count(),
+            Length(_) | Other(..) => 0,
+            _ => self.objects().map_or(0, Objects::events),
+        }
+    }
+    /// 入力を借用しない値にする
+    #[cfg(feature = "load")]
+    pub(crate) fn into_owned(self) -> MainDataValue<'static> {
+        use MainDataValue::*;
+        match self {
+            Bgm(o) => Bgm(o.into_owned()),
+            Length(n) => Length(n),
+            Bga(o) => Bga(o.into_owned()),
+            Bpm(v) => Bpm(v),
+            BgaPoor(o) => BgaPoor(o.into_owned()),
+            BgaLayer(o) => BgaLayer(o.into_owned()),
+            ExBpm(o) => ExBpm(o.into_owned()),
+            Stop(o) => Stop(o.into_owned()),
+            BgaLayer2(o) => BgaLayer2(o.into_owned()),
+            ExRank(o) => ExRank(o.into_owned()),
+            BgaAlpha(v) => BgaAlpha(v),
+            BgaLayerAlpha(v) => BgaLayerAlpha(v),
+            BgaLayer2Alpha(v) => BgaLayer2Alpha(v),
+            BgaPoorAlpha(v) => BgaPoorAlpha(v),
+            Note(ch, o) => Note(ch, o.into_owned()),
+            InvisibleNote(ch, o) => InvisibleNote(ch, o.into_owned()),
+            LongNote(ch, o) => LongNote(ch, o.into_owned()),
+            Text(o) => Text(o.into_owned()),
+            BgaArgb(o) => BgaArgb(o.into_owned()),
+            BgaLayerArgb(o) => BgaLayerArgb(o.into_owned()),
+            BgaLayer2Argb(o) => BgaLayer2Argb(o.into_owned()),
+            BgaPoorArgb(o) => BgaPoorArgb(o.into_owned()),
+            SwitchBga(o) => SwitchBga(o.into_owned()),
+            Option(o) => Option(o.into_owned()),
+            Landmine(ch, v) => Landmine(ch, v),
+            Scroll(o) => Scroll(o.into_owned()),
+            Speed(o) => Speed(o.into_owned()),
+            Other(ch, s) => Other(ch, Cow::Owned(s.into_owned())),
+        }
+    }
 }
 #[derive(Clone, Debug, PartialEq)]
 pub(crate) enum ControlFlow {
@@ -166,11 +353,18 @@ impl Channel {
         match s.len() {
             0 => Channel([0, 0]),
             1 => Channel([0, Self::parse_base_62(s[0])]),
-            2.. => {
-                Channel([Self::parse_base_62(s[0]), Self::parse_base_62(s[1])])
-            }
+            2.. => Self::from_pair(s[0], s[1]),
         }
     }
+    const fn from_pair(a: u8, b: u8) -> Channel {
+        Channel([Self::parse_base_62(a), Self::parse_base_62(b)])
+    }
+    /// 36進数か62進数の値から作成
+    #[cfg(feature = "bmson")]
+    pub(crate) const fn from_number(n: usize, base62: bool) -> Channel {
+        let base = if base62 { 62 } else { 36 };
+        Channel([(n / base % base) as i32, (n % base) as i32])
+    }
     pub const fn to_base_36_or_62(&self, flag: bool) -> usize {
         if flag {
             self.to_base_62()
@@ -191,12 +385,102 @@ impl Channel {
     }
 }
 
+/// メインデータの2文字ずつ並んだオブジェクトの列
+///
+/// 字句解析では入力の範囲を保持するだけで、[`Objects::iter`]で読むときに変換する
+///
+/// 2文字の間以外には空白を含んでもよい
+#[derive(Clone, Debug, PartialEq, Default)]
+pub(crate) struct Objects<'a>(pub(crate) Cow<'a, str>);
+impl<'a> From<&'a str> for Objects<'a> {
+    fn from(value: &'a str) -> Self {
+        Objects(Cow::Borrowed(value))
+    }
+}
+impl Objects<'_> {
+    /// idの列から作成
+    #[cfg(feature = "bmson")]
+    pub(crate) fn from_ids(ids: &[usize], base62: bool) -> Objects<'static> {
+        Objects(Cow::Owned(
+            ids.iter().map(|&id| super::write::id(id, base62)).collect(),
+        ))
+    }
+    /// 36進数か62進数の値にする
+    pub(crate) fn ids(&self, base62: bool) -> Vec<usize> {
+        const fn table(base62: bool) -> [u8; 256] {
+            let mut table = [0; 256];
+            let mut c = 0;
+            while c < 256 {
+                let ch = Channel::from_pair(b'0', c as u8);
+                table[c] = ch.to_base_36_or_62(base62) as u8;
+                c += 1;
+            }
+            table
+        }
+        const BASE36: [u8; 256] = table(false);
+        const BASE62: [u8; 256] = table(true);
+        let (table, base) = if base62 { (&BASE62, 62) } else { (&BASE36, 36) };
+
+        let bytes = self.0.as_bytes();
+        let mut ids = Vec::with_capacity(bytes.len() / 2);
+        // 空白が無ければ2文字ずつ区切るだけでよい
+        if bytes.iter().all(u8::is_ascii_alphanumeric) {
+            ids.extend(bytes.chunks_exact(2).map(|pair| {
+                base * table[pair[0] as usize] as usize
+                    + table[pair[1] as usize] as usize
+            }));
+        }
+        else {
+            ids.extend(self.iter().map(|ch| ch.to_base_36_or_62(base62)));
+        }
+        ids
+    }
+    /// 入力を借用しない列にする
+    #[cfg(feature = "load")]
+    pub(crate) fn into_owned(self) -> Objects<'static> {
+        Objects(Cow::Owned(self.0.into_owned()))
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Channel> + '_ {
+        self.pairs().map(|[a, b]| Channel::from_pair(a, b))
+    }
+    fn pairs(&self) -> impl Iterator<Item = [u8; 2]> + '_ {
+        let mut bytes = self.0.bytes().filter(|c| c.is_ascii_alphanumeric());
+        std::iter::from_fn(move || Some([bytes.next()?, bytes.next()?]))
+    }
+    /// オブジェクトの数
+    ///
+    /// `00`も含む
+    pub(crate) fn len(&self) -> usize {
+        self.pairs().count()
+    }
+    /// `00`以外のオブジェクトの数
+    pub(crate) fn events(&self) -> usize {
+        self.pairs().filter(|pair| pair != b"00").count()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::super::write::id;
+    use super::Channel;
+    use proptest::prelude::*;
+
+    proptest! {
+        // 2文字と数値が1対1に対応する
+        #[test]
+        fn channel_base_36(s in "[0-9A-Z]{2}", n in 0..36usize * 36) {
+            prop_assert_eq!(id(Channel::new(&s).to_base_36(), false), s);
+            prop_assert_eq!(Channel::new(&id(n, false)).to_base_36(), n);
+        }
+        #[test]
+        fn channel_base_62(s in "[0-9A-Za-z]{2}", n in 0..62usize * 62) {
+            prop_assert_eq!(id(Channel::new(&s).to_base_62(), true), s);
+            prop_assert_eq!(Channel::new(&id(n, true)).to_base_62(), n);
+        }
+    }
+
     #[test]
     fn channel_to_number() {
-        use super::Channel;
-
         let zero1 = Channel::from("0");
         let zero2 = Channel::from("00");
         let one = Channel::from("1");
@@ -0,0 +1,489 @@
+use super::*;
+use std::fmt::{self, Write};
+
+/// 36進数か62進数の2文字
+pub(super) fn id(n: usize, base62: bool) -> String {
+    const DIGITS: &[u8] =
+        b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let base = if base62 { 62 } else { 36 };
+    let n = n.min(base * base - 1);
+    [DIGITS[n / base], DIGITS[n % base]]
+        .iter()
+        .map(|&c| c as char)
+        .collect()
+}
+
+/// 解釈できなかったコマンドの前に付ける記号
+///
+/// `%`で始まるコマンドは`%`を含めた名前になっている
+pub(super) fn prefix(command: &str) -> &'static str {
+    if command.starts_with('%') { "" } else { "#" }
+}
+
+fn sorted<K: Ord + Copy, V>(map: &HashMap<K, V>) -> Vec<(K, &V)> {
+    let mut v = map.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    v.sort_by_key(|(k, _)| *k);
+    v
+}
+
+fn numbers(numbers: impl IntoIterator<Item = f64>, separator: &str) -> String {
+    numbers
+        .into_iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+impl Bms<'_> {
+    /// 62進数でないと表せないidがあるかどうか
+    fn needs_base62(&self) -> bool {
+        const MAX: usize = 36 * 36 - 1;
+        let keys = [
+            self.ex_bpm.keys().max(),
+            self.stop.keys().max(),
+            self.wav.keys().max(),
+            self.bmp.keys().max(),
+            self.ex_wav.keys().max(),
+            self.ex_bmp.keys().max(),
+            self.scroll.keys().max(),
+            self.speed.keys().max(),
+            self.ln_object.iter().max(),
+        ];
+        keys.into_iter().flatten().any(|&k| MAX < k)
+            || self.main_data.iter().any(|m| {
+                m.notes
+                    .values()
+                    .chain(m.invisible_notes.values())
+                    .chain(m.long_notes.values())
+                    .chain([&m.bgm, &m.bga, &m.bga_layer, &m.bga_poor])
+                    .flatten()
+                    .flatten()
+                    .any(|&id| MAX < id)
+            })
+    }
+    #[allow(deprecated)]
+    fn write_header(&self, f: &mut impl Write, base62: bool) -> fmt::Result {
+        if let Some(player) = &self.player {
+            let n = match player {
+                PlayType::SinglePlay => 1,
+                PlayType::CouplePlay => 2,
+                PlayType::DoublePlay => 3,
+                PlayType::BattlePlay => 4,
+            };
+            writeln!(f, "#PLAYER {n}")?;
+        }
+        macro_rules! write_option {
+            ($($command:literal => $field:expr,)*) => {
+                $(
+                    if let Some(v) = &$field {
+                        writeln!(f, concat!("#", $command, " {}"), v)?;
+                    }
+                )*
+            };
+        }
+        write_option! {
+            "GENRE" => self.genre,
+            "TITLE" => self.title,
+        }
+        for s in &self.sub_title {
+            writeln!(f, "#SUBTITLE {s}")?;
+        }
+        write_option! {
+            "ARTIST" => self.artist,
+        }
+        for s in &self.sub_artist {
+            writeln!(f, "#SUBARTIST {s}")?;
+        }
+        write_option! {
+            "MAKER" => self.maker,
+            "BPM" => self.bpm,
+            "BASEBPM" => self.base_bpm,
+            "PLAYLEVEL" => self.play_level,
+            "DIFFICULTY" => self.difficulty,
+            "RANK" => self.rank,
+            "DEFEXRANK" => self.def_ex_rank,
+            "TOTAL" => self.total,
+            "VOLWAV" => self.volume_wav,
+            "STAGEFILE" => self.stage_file,
+            "BANNER" => self.banner,
+            "BACKBMP" => self.back_bmp,
+            "PREVIEW" => self.preview,
+            "CHARFILE" => self.character_file,
+            "PATH_WAV" => self.path_wav,
+            "LNTYPE" => self.ln_type,
+            "LNMODE" => self.ln_mode,
+            "CDDA" => self.cdda,
+            "MIDIFILE" => self.midi_file,
+            "POORBGA" => self.poor_bga,
+            "VIDEOFILE" => self.video_file,
+            "VIDEOf/s" => self.video_fps,
+            "VIDEOCOLORS" => self.video_colors,
+            "VIDEODELAY" => self.video_delay,
+            "MOVIE" => self.movie,
+        }
+        if let Some(url) = self.url {
+            writeln!(f, "%URL {url}")?;
+        }
+        if let Some(email) = self.email {
+            writeln!(f, "%EMAIL {email}")?;
+        }
+        for s in &self.comment {
+            writeln!(f, "#COMMENT \"{s}\"")?;
+        }
+        if self.oct_fp {
+            writeln!(f, "#OCT/FP")?;
+        }
+        for s in &self.option {
+            writeln!(f, "#OPTION {s}")?;
+        }
+        if base62 {
+            writeln!(f, "#BASE 62")?;
+        }
+        let mut ln_object = self.ln_object.iter().collect::<Vec<_>>();
+        ln_object.sort();
+        for n in ln_object {
+            writeln!(f, "#LNOBJ {}", id(*n, base62))?;
+        }
+        for &(m, pos, ms) in &self.stp {
+            writeln!(f, "#STP {m:03}.{pos:03} {ms}")?;
+        }
+        Ok(())
+    }
+    #[allow(deprecated)]
+    fn write_definitions(
+        &self,
+        f: &mut impl Write,
+        base62: bool,
+    ) -> fmt::Result {
+        let id = |n| id(n, base62);
+        for (n, s) in sorted(&self.wav) {
+            writeln!(f, "#WAV{} {s}", id(n))?;
+        }
+        for (n, (opt, s)) in sorted(&self.ex_wav) {
+            let mut flags = String::new();
+            let mut values = vec![];
+            for (c, v) in ['p', 'v', 'f'].into_iter().zip(opt.iter()) {
+                if let Some(v) = v {
+                    flags.push(c);
+                    values.push(*v);
+                }
+            }
+            writeln!(
+                f,
+                "#EXWAV{} {flags} {} {s}",
+                id(n),
+                numbers(values, " ")
+            )?;
+        }
+        for &(command, n, value) in &self.wav_command {
+            writeln!(f, "#WAVCMD {command:02} {} {value}", id(n))?;
+        }
+        for (n, s) in sorted(&self.bmp) {
+            writeln!(f, "#BMP{} {s}", id(n))?;
+        }
+        for (n, (argb, s)) in sorted(&self.ex_bmp) {
+            let argb = argb.map(|c| c.to_string()).join(",");
+            writeln!(f, "#EXBMP{} {argb} {s}", id(n))?;
+        }
+        for (command, map) in [("BGA", &self.bga), ("@BGA", &self.at_bga)] {
+            for (n, (bmp, pos)) in sorted(map) {
+                let pos = numbers(pos.iter().flatten().copied(), " ");
+                writeln!(f, "#{command}{} {} {pos}", id(n), id(*bmp))?;
+            }
+        }
+        for (n, argb) in sorted(&self.argb) {
+            let argb = argb.map(|c| c.to_string()).join(",");
+            writeln!(f, "#ARGB{} {argb}", id(n))?;
+        }
+        for (n, (frame, time, line, r#loop, argb, pattern)) in
+            sorted(&self.switch_bga)
+        {
+            let argb = argb.map(|c| c.to_string()).join(",");
+            let pattern = pattern
+                .iter()
+                .map(|c| id(c.to_base_36_or_62(base62)))
+                .collect::<String>();
+            writeln!(
+                f,
+                "#SWBGA{} {frame}:{time}:{}:{}:{argb} {pattern}",
+                id(n),
+                self::id(*line, false),
+                *r#loop as u8,
+            )?;
+        }
+        if let Some(c) = &self.ex_character {
+            write!(
+                f,
+                "#ExtChr {} {} {}",
+                c.sprite_num,
+                c.bmp,
+                numbers(c.trim_rect.iter().flatten().copied(), " ")
+            )?;
+            for p in [c.offset, c.abs_pos].into_iter().flatten() {
+                write!(f, " {}", numbers(*p, " "))?;
+            }
+            writeln!(f)?;
+        }
+        macro_rules! write_map {
+            ($($command:literal => $field:expr,)*) => {
+                $(
+                    for (n, v) in sorted(&$field) {
+                        writeln!(f, concat!("#", $command, "{} {}"), id(n), v)?;
+                    }
+                )*
+            };
+        }
+        write_map! {
+            "BPM" => self.ex_bpm,
+            "STOP" => self.stop,
+            "SCROLL" => self.scroll,
+            "SPEED" => self.speed,
+            "EXRANK" => self.ex_rank,
+            "SEEK" => self.seek,
+            "CHANGEOPTION" => self.change_option,
+        }
+        for (n, s) in sorted(&self.text) {
+            writeln!(f, "#TEXT{} \"{s}\"", id(n))?;
+        }
+        for (command, value) in &self.other {
+            writeln!(f, "{}{command} {value}", prefix(command))?;
+        }
+        Ok(())
+    }
+    fn write_main_data(&self, f: &mut impl Write, base62: bool) -> fmt::Result {
+        let ids = |row: &[usize]| {
+            row.iter().map(|&n| id(n, base62)).collect::<String>()
+        };
+        let hex = |row: &[u8]| {
+            row.iter().map(|n| format!("{n:02X}")).collect::<String>()
+        };
+        for (m, measure) in self.main_data.iter().enumerate() {
+            let mut line = |channel: &str, data: String| {
+                if data.is_empty() {
+                    return Ok(());
+                }
+                writeln!(f, "#{m:03}{channel}:{data}")
+            };
+            if measure.length != 1. {
+                line("02", measure.length.to_string())?;
+            }
+            for row in &measure.bpm {
+                let row = row
+                    .iter()
+                    .map(|n| n.map_or(0, |n| n as u8))
+                    .collect::<Vec<_>>();
+                line("03", hex(&row))?;
+            }
+            for (channel, rows) in [
+                ("01", &measure.bgm),
+                ("04", &measure.bga),
+                ("06", &measure.bga_poor),
+                ("07", &measure.bga_layer),
+                ("08", &measure.ex_bpm),
+                ("09", &measure.stop),
+                ("0A", &measure.bga_layer2),
+                ("99", &measure.text),
+                ("A0", &measure.ex_rank),
+                ("A1", &measure.bga_argb),
+                ("A2", &measure.bga_layer_argb),
+                ("A3", &measure.bga_layer2_argb),
+                ("A4", &measure.bga_poor_argb),
+                ("A5", &measure.switch_bga),
+                ("A6", &measure.option),
+                ("SC", &measure.scroll),
+                ("SP", &measure.speed),
+            ] {
+                for row in rows {
+                    line(channel, ids(row))?;
+                }
+            }
+            for (channel, rows) in [
+                ("0B", &measure.bga_alpha),
+                ("0C", &measure.bga_layer_alpha),
+                ("0D", &measure.bga_layer2_alpha),
+                ("0E", &measure.bga_poor_alpha),
+            ] {
+                for row in rows {
+                    line(channel, hex(row))?;
+                }
+            }
+            for map in [
+                &measure.notes,
+                &measure.invisible_notes,
+                &measure.long_notes,
+            ] {
+                for (channel, rows) in sorted(map) {
+                    for row in rows {
+                        line(&id(channel, false), ids(row))?;
+                    }
+                }
+            }
+            for (channel, rows) in sorted(&measure.landmine) {
+                for row in rows {
+                    let row = row
+                        .iter()
+                        .map(|d| id((d * 2.).round() as usize, false))
+                        .collect::<String>();
+                    line(&id(channel, false), row)?;
+                }
+            }
+            for (channel, data) in &measure.other {
+                line(&id(*channel, false), data.to_string())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bms<'_> {
+    fn write(&self, f: &mut impl Write, base62: bool) -> fmt::Result {
+        self.write_header(f, base62)?;
+        writeln!(f)?;
+        self.write_definitions(f, base62)?;
+        writeln!(f)?;
+        self.write_main_data(f, base62)
+    }
+    /// 36進数か62進数を指定してBMS形式の文字列にする
+    ///
+    /// 36進数を指定しても、36進数で表せないidがあれば62進数で書き出す
+    pub fn to_string_with_base(&self, base62: bool) -> String {
+        let mut s = String::new();
+        self.write(&mut s, base62 || self.needs_base62()).unwrap();
+        s
+    }
+}
+
+/// BMS形式の文字列として書き出す
+///
+/// ランダム要素は確定したものが書き出される
+///
+/// 36進数で表せないidがあれば`#BASE 62`を付けて62進数で書き出す
+impl fmt::Display for Bms<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, self.needs_base62())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn round_trip() {
+        let source = r#"
+#PLAYER 1
+#GENRE ジャンル
+#TITLE タイトル
+#SUBTITLE サブタイトル
+#ARTIST 制作者
+#BPM 150
+#PLAYLEVEL 12
+#RANK 3
+#TOTAL 300
+#LNTYPE 1
+#COMMENT "コメント"
+#LNOBJ ZZ
+#STP 002.500 1000
+#WAV01 a.wav
+#EXWAV02 pvf 100 -500 8000 b.wav
+#WAVCMD 00 01 72
+#BMP01 a.bmp
+#BGA02 01 0 0 256 256 0 0
+#BPM01 300.5
+#STOP01 96
+#SCROLL01 0.5
+#TEXT01 "テキスト"
+#VENDORCMD hello
+%CUSTOM x
+#00102:0.75
+#00103:0096
+#00101:01000001
+#00101:0001
+#00108:01
+#00109:0001
+#0010B:00FF
+#00111:0100ZZ00
+#00151:0101
+#001D1:0A
+#001SC:01
+#00299:01
+"#;
+        let raw = RawBms::parse(source);
+        let bms = raw.make_bms(rand::rng());
+        let written = bms.to_string();
+        let raw_written = RawBms::parse(&written);
+        assert_eq!(raw_written.make_bms(rand::rng()), bms);
+        assert_eq!(bms.other, vec![("VENDORCMD", "hello"), ("%CUSTOM", "x")]);
+        assert!(written.contains("#VENDORCMD hello\n%CUSTOM x\n"));
+
+        // 62進数
+        let raw = RawBms::parse("#BASE 62\n#WAVzz a.wav\n#00111:zz");
+        let bms = raw.make_bms(rand::rng());
+        let written = bms.to_string();
+        assert!(written.contains("#BASE 62"));
+        assert!(written.contains("#WAVzz a.wav"));
+        assert_eq!(RawBms::parse(&written).make_bms(rand::rng()), bms);
+
+        let raw = RawBms::parse("#WAV0Z a.wav\n#00111:0Z");
+        let bms = raw.make_bms(rand::rng());
+        let written = bms.to_string_with_base(true);
+        assert!(written.contains("#WAV0Z a.wav"));
+        assert!(written.contains("#00111:0Z"));
+        assert_eq!(RawBms::parse(&written).make_bms(rand::rng()), bms);
+    }
+
+    /// 書き出して読み直せるBMS
+    fn source() -> impl Strategy<Value = String> {
+        use proptest::collection::{btree_map, vec};
+        use proptest::sample::select;
+        let header = ("[a-zA-Z0-9ぁ-ん]{1,10}", 1..=300u32, 1..=12i32);
+        // 解釈できないコマンド
+        let other = vec("[#%]VENDOR[A-Z]{0,4} [a-z]{0,8}", 0..3);
+        let wav = btree_map(1..36usize * 36, "[a-z]{1,8}", 0..20);
+        let lengths =
+            btree_map(0..20usize, select(vec![0.25, 0.5, 0.75, 1.5, 2.]), 0..4);
+        let channels = vec![
+            "01", "04", "06", "07", "08", "09", "11", "12", "16", "19", "31",
+            "51", "52", "99", "D1", "SC",
+        ];
+        let line = (0..20usize, select(channels), vec(0..36usize * 36, 1..16));
+        (header, other, wav, lengths, vec(line, 0..30)).prop_map(
+            |((title, bpm, level), other, wav, lengths, lines)| {
+                let mut s =
+                    format!("#TITLE {title}\n#BPM {bpm}\n#PLAYLEVEL {level}\n");
+                for line in other {
+                    writeln!(s, "{line}").unwrap();
+                }
+                for (n, name) in wav {
+                    writeln!(s, "#WAV{} {name}.wav", id(n, false)).unwrap();
+                }
+                for (m, length) in lengths {
+                    writeln!(s, "#{m:03}02:{length}").unwrap();
+                }
+                for (m, channel, objects) in lines {
+                    let objects = objects
+                        .into_iter()
+                        .map(|n| id(n, false))
+                        .collect::<String>();
+                    writeln!(s, "#{m:03}{channel}:{objects}").unwrap();
+                }
+                s
+            },
+        )
+    }
+
+    proptest! {
+        // 読んで書き出したものを読み直すと同じになる
+        #[test]
+        fn round_trip_generated(source in source()) {
+            let raw = RawBms::parse(&source);
+            let bms = raw.make_bms_with(|_| 1);
+            let written = bms.to_string();
+            let raw_written = RawBms::parse(&written);
+            let bms_written = raw_written.make_bms_with(|_| 1);
+            prop_assert_eq!(&bms_written, &bms);
+            prop_assert_eq!(bms_written.to_string(), written);
+        }
+    }
+}
@@ -1,15 +1,49 @@
 use serde::{Deserialize, Serialize};
 
+mod convert;
+mod legacy;
+mod timeline;
+mod validate;
+pub use legacy::{
+    LegacyBga, LegacyBgaHeader, LegacyBmson, LegacyBmsonInfo, LegacyEventNote,
+    LegacySoundChannel, LegacySoundNote,
+};
+pub use timeline::{BmsonTimeline, TimedNote};
+pub use validate::{
+    BmsonError, MAX_GENERIC_KEYS, SUPPORTED_VERSIONS, ValidationIssue,
+};
+
 impl std::str::FromStr for Bmson {
     type Err = serde_json::Error;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        serde_json::from_str(s)
+        Bmson::parse(s)
     }
 }
 impl Bmson {
     /// 文字列からBmsonを解析
+    ///
+    /// バージョン0.21以前のBmsonは現在のBmsonに変換する
     pub fn parse(source: &str) -> serde_json::Result<Bmson> {
-        serde_json::from_str(source)
+        serde_json::from_str(source).or_else(|e| {
+            match serde_json::from_str::<serde_json::Value>(source) {
+                Ok(value) if LegacyBmson::detect(&value) => {
+                    Ok(serde_json::from_value::<LegacyBmson>(value)?.upgrade())
+                }
+                _ => Err(e),
+            }
+        })
+    }
+    /// `info`だけを解析する
+    ///
+    /// ノーツなどの配列は読み飛ばすので、[`Bmson::parse`]より速い
+    pub fn parse_info(source: &str) -> serde_json::Result<BmsonInfo> {
+        #[derive(Deserialize)]
+        struct InfoOnly {
+            info: BmsonInfo,
+        }
+        serde_json::from_str::<InfoOnly>(source)
+            .map(|b| b.info)
+            .or_else(|e| Bmson::parse(source).map(|b| b.info).map_err(|_| e))
     }
     /// BmsonからJson形式の文字列に変換
     pub fn to_string(&self) -> serde_json::Result<String> {
@@ -21,6 +55,10 @@ impl Bmson {
     pub fn to_string_pretty(&self) -> serde_json::Result<String> {
         serde_json::to_string_pretty(self)
     }
+    /// パルス数と時刻を変換するタイムラインを作成
+    pub fn timeline(&self) -> BmsonTimeline {
+        BmsonTimeline::new(self)
+    }
 }
 
 /// Bmson本体
@@ -49,6 +87,11 @@ pub struct Bmson {
     /// 不可視ノートチャンネル（beatoraja拡張）
     #[serde(skip_serializing_if = "Option::is_none")]
     pub key_channels: Option<Vec<KeyChannel>>,
+    /// その他のキー
+    ///
+    /// 解析時に保存され、書き込み時にそのまま出力される
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 /// ヘッダー
@@ -112,6 +155,11 @@ pub struct BmsonInfo {
     /// ロングノートの種類（beatoraja拡張）
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ln_type: Option<LongNoteType>,
+    /// その他のキー
+    ///
+    /// 解析時に保存され、書き込み時にそのまま出力される
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 fn default_mode_hint() -> String {
     "beat-7k".to_string()
@@ -146,6 +194,7 @@ impl Default for BmsonInfo {
             preview_music: None,
             resolution: 240,
             ln_type: None,
+            extra: serde_json::Map::new(),
         }
     }
 }
@@ -164,6 +213,11 @@ pub struct SoundChannel {
     pub name: String,
     /// ノーツ
     pub notes: Vec<Note>,
+    /// その他のキー
+    ///
+    /// 解析時に保存され、書き込み時にそのまま出力される
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 /// サウンドノート
@@ -191,6 +245,11 @@ pub struct Note {
     /// trueでかつロングノートの終点に配置される場合、終端音として鳴らす
     #[serde(skip_serializing_if = "Option::is_none")]
     pub up: Option<bool>,
+    /// その他のキー
+    ///
+    /// 解析時に保存され、書き込み時にそのまま出力される
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 /// BPM変化イベント
@@ -222,6 +281,11 @@ pub struct Bga {
     pub layer_events: Vec<BgaEvent>,
     /// POORイベント
     pub poor_events: Vec<BgaEvent>,
+    /// その他のキー
+    ///
+    /// 解析時に保存され、書き込み時にそのまま出力される
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 /// 画像ファイル
@@ -328,3 +392,58 @@ pub struct KeyNote {
     /// 演奏時刻（パルス数）
     pub y: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preserve_unknown_keys() {
+        let source = r#"
+        {
+          "version": "1.0.0",
+          "vendor": { "tool": "editor" },
+          "info": {
+            "title": "タイトル",
+            "artist": "制作者",
+            "genre": "ジャンル",
+            "chart_name": "",
+            "level": 1,
+            "init_bpm": 120.0,
+            "info_extension": 1
+          },
+          "lines": null,
+          "bpm_events": null,
+          "stop_events": null,
+          "sound_channels": [
+            {
+              "name": "a.wav",
+              "notes": [
+                { "x": 1, "y": 0, "l": 0, "c": false, "color": "red" }
+              ],
+              "volume": 0.5
+            }
+          ],
+          "bga": {
+            "bga_header": [],
+            "bga_events": [],
+            "layer_events": [],
+            "poor_events": [],
+            "layer2_events": []
+          }
+        }
+        "#;
+        let mut bmson = Bmson::parse(source).unwrap();
+        assert_eq!(bmson.extra["vendor"]["tool"], "editor");
+        assert_eq!(bmson.info.extra["info_extension"], 1);
+        let sc = &bmson.sound_channels.as_ref().unwrap()[0];
+        assert_eq!(sc.extra["volume"], 0.5);
+        assert_eq!(sc.notes[0].extra["color"], "red");
+        assert!(bmson.bga.extra.contains_key("layer2_events"));
+        assert_eq!(Bmson::parse_info(source).unwrap(), bmson.info);
+
+        bmson.info.title = "新しいタイトル".to_string();
+        let edited = Bmson::parse(&bmson.to_string_pretty().unwrap()).unwrap();
+        assert_eq!(edited, bmson);
+    }
+}
@@ -0,0 +1,804 @@
+use super::*;
+use crate::RawBms;
+use crate::bms::notes::{INVISIBLE_OFFSET, LANDMINE_OFFSET, LONG_OFFSET};
+use crate::bms::timeline::objects;
+use crate::bms::token::{Channel, Command, MainDataValue, Objects};
+use crate::bms::{Bms, BmsNoteKind, BmsTimeline, MainData};
+use crate::transform::KeyMode;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// `#DIFFICULTY`の値と`chart_name`の対応
+const CHART_NAMES: [(i32, &str); 5] = [
+    (1, "BEGINNER"),
+    (2, "NORMAL"),
+    (3, "HYPER"),
+    (4, "ANOTHER"),
+    (5, "INSANE"),
+];
+
+/// `#RANK`の0から4に対応する`judge_rank`
+const JUDGE_RANKS: [f64; 5] = [25., 50., 75., 100., 125.];
+
+/// 36進数の2文字で表せるidの最大値
+const MAX_BASE_36: usize = 36 * 36 - 1;
+
+/// BMSからの変換で使う分解能の最大値
+///
+/// 4分の4拍子の1000小節分のパルス数がu32に収まる
+const MAX_RESOLUTION: u32 = u32::MAX / (4 * 1000);
+
+fn pulse(beat: f64, resolution: u32) -> u32 {
+    (beat * resolution as f64).round().max(0.) as u32
+}
+
+/// チャンネルのオブジェクトを(パルス数, id)にして位置順に並べる
+fn events<'a>(
+    bms: &Bms<'a>,
+    timeline: &BmsTimeline,
+    resolution: u32,
+    rows: impl for<'b> Fn(&'b MainData<'a>) -> &'b Vec<Vec<usize>>,
+) -> Vec<(u32, usize)> {
+    let mut events = vec![];
+    for (m, measure) in bms.main_data.iter().enumerate() {
+        for row in rows(measure) {
+            for (f, id) in objects(row) {
+                events.push((pulse(timeline.beat(m, f), resolution), *id));
+            }
+        }
+    }
+    events.sort();
+    events
+}
+
+impl Bmson {
+    /// BMSからBmsonへ変換
+    ///
+    /// 位置は`resolution`を四分音符1つ分のパルス数として丸める
+    ///
+    /// `resolution`は1から`u32::MAX / 4000`の範囲に収める
+    ///
+    /// 鍵盤の配置は使われているチャンネルから推測し、
+    /// 配置に含まれないチャンネルのノーツはBGMにする
+    pub fn from_bms(bms: &Bms, resolution: u32) -> Bmson {
+        let resolution = resolution.clamp(1, MAX_RESOLUTION);
+        let pulse = |beat| pulse(beat, resolution);
+        let timeline = bms.timeline();
+        let notes = bms.notes(&timeline);
+        let mode = KeyMode::detect(&notes);
+        let name = |wav: usize| bms.wav.get(&wav).map_or("", |s| s).to_string();
+        let note = |x, y, l, up| Note {
+            x,
+            y,
+            l,
+            c: false,
+            t: None,
+            up,
+            extra: Default::default(),
+        };
+
+        let mut sounds = BTreeMap::<usize, Vec<Note>>::new();
+        let mut keys = BTreeMap::<usize, Vec<KeyNote>>::new();
+        let mut mines = vec![];
+        for n in &notes {
+            let x = mode.bms_lane(n.channel);
+            let y = pulse(n.beat);
+            match n.kind {
+                BmsNoteKind::Normal => {
+                    sounds.entry(n.wav).or_default().push(note(x, y, 0, None))
+                }
+                BmsNoteKind::Long => {
+                    let end = n.end.as_ref().map_or(y, |e| pulse(e.beat));
+                    let l = if x.is_some() { end - y } else { 0 };
+                    sounds.entry(n.wav).or_default().push(note(x, y, l, None));
+                    // 始点と違う終端音は終端フラグのノートにする
+                    if let Some(e) = &n.end
+                        && x.is_some()
+                        && e.wav != n.wav
+                        && bms.wav.contains_key(&e.wav)
+                    {
+                        sounds.entry(e.wav).or_default().push(note(
+                            x,
+                            end,
+                            0,
+                            Some(true),
+                        ));
+                    }
+                }
+                BmsNoteKind::Invisible => {
+                    keys.entry(n.wav).or_default().push(KeyNote { x, y })
+                }
+                BmsNoteKind::Landmine(damage) => {
+                    mines.push(MineNote { x, y, damage })
+                }
+            }
+        }
+        for b in bms.bgm(&timeline) {
+            sounds.entry(b.wav).or_default().push(note(
+                None,
+                pulse(b.beat),
+                0,
+                None,
+            ));
+        }
+
+        let bga_events = |events: Vec<(u32, usize)>| {
+            events
+                .into_iter()
+                .map(|(y, id)| BgaEvent { y, id: id as u32 })
+                .collect()
+        };
+        let mut bga_header = bms
+            .bmp
+            .iter()
+            .map(|(id, name)| BgaHeader {
+                id: *id as u32,
+                name: name.to_string(),
+            })
+            .collect::<Vec<_>>();
+        bga_header.sort_by_key(|h| h.id);
+
+        let info = BmsonInfo {
+            title: bms.title.unwrap_or_default().to_string(),
+            subtitle: bms.sub_title.join(" "),
+            artist: bms.artist.unwrap_or_default().to_string(),
+            subartists: Some(
+                bms.sub_artist.iter().map(|s| s.to_string()).collect(),
+            ),
+            genre: bms.genre.unwrap_or_default().to_string(),
+            mode_hint: mode.mode_hint().to_string(),
+            chart_name: CHART_NAMES
+                .iter()
+                .find(|(n, _)| Some(*n) == bms.difficulty)
+                .map_or("", |(_, name)| name)
+                .to_string(),
+            level: bms.play_level.unwrap_or(0).max(0) as u32,
+            init_bpm: timeline.bpm_at(0.),
+            judge_rank: bms
+                .def_ex_rank
+                .or_else(|| {
+                    JUDGE_RANKS.get(usize::try_from(bms.rank?).ok()?).copied()
+                })
+                .unwrap_or(BmsonInfo::default().judge_rank),
+            total: bms.total.unwrap_or(BmsonInfo::default().total),
+            eyecatch_image: bms.stage_file.map(str::to_string),
+            title_image: bms.back_bmp.map(str::to_string),
+            banner_image: bms.banner.map(str::to_string),
+            preview_music: bms.preview.map(str::to_string),
+            resolution,
+            ln_type: match bms.ln_mode {
+                Some(1) => Some(LongNoteType::LongNote),
+                Some(2) => Some(LongNoteType::ChargeNote),
+                Some(3) => Some(LongNoteType::HellChargeNote),
+                _ => None,
+            },
+            ..Default::default()
+        };
+        Bmson {
+            version: validate::SUPPORTED_VERSIONS[0].to_string(),
+            info,
+            lines: Some(
+                (0..=timeline.measure_count())
+                    .map(|m| BarLine {
+                        y: pulse(timeline.measure_start(m)),
+                    })
+                    .collect(),
+            ),
+            bpm_events: Some(
+                timeline
+                    .bpm_changes()
+                    .into_iter()
+                    .skip(1)
+                    .map(|(beat, _, bpm)| BpmEvent {
+                        y: pulse(beat),
+                        bpm,
+                    })
+                    .collect(),
+            ),
+            stop_events: Some(
+                timeline
+                    .stops()
+                    .into_iter()
+                    .map(|(beat, _, ms)| StopEvent {
+                        y: pulse(beat),
+                        duration: (ms * timeline.bpm_at(beat) / 60000.
+                            * resolution as f64)
+                            .round() as u32,
+                    })
+                    .collect(),
+            ),
+            sound_channels: Some(
+                sounds
+                    .into_iter()
+                    .map(|(wav, mut notes)| {
+                        notes.sort_by_key(|n| n.y);
+                        SoundChannel {
+                            name: name(wav),
+                            notes,
+                            extra: Default::default(),
+                        }
+                    })
+                    .collect(),
+            ),
+            bga: Bga {
+                bga_header,
+                bga_events: bga_events(events(
+                    bms,
+                    &timeline,
+                    resolution,
+                    |m| &m.bga,
+                )),
+                layer_events: bga_events(events(
+                    bms,
+                    &timeline,
+                    resolution,
+                    |m| &m.bga_layer,
+                )),
+                poor_events: bga_events(events(
+                    bms,
+                    &timeline,
+                    resolution,
+                    |m| &m.bga_poor,
+                )),
+                extra: Default::default(),
+            },
+            scroll_events: Some(
+                events(bms, &timeline, resolution, |m| &m.scroll)
+                    .into_iter()
+                    .filter_map(|(y, id)| {
+                        Some(ScrollEvent {
+                            y: y as f64,
+                            rate: *bms.scroll.get(&id)?,
+                        })
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .filter(|e| !e.is_empty()),
+            mine_channels: (!mines.is_empty()).then(|| {
+                vec![MineChannel {
+                    name: name(0),
+                    notes: mines,
+                }]
+            }),
+            key_channels: (!keys.is_empty()).then(|| {
+                keys.into_iter()
+                    .map(|(wav, notes)| KeyChannel {
+                        name: name(wav),
+                        notes,
+                    })
+                    .collect()
+            }),
+            extra: Default::default(),
+        }
+    }
+}
+
+/// 出現順にidを割り当てる
+struct Ids<K> {
+    ids: HashMap<K, usize>,
+    keys: Vec<K>,
+}
+
+impl<K: Clone + Eq + std::hash::Hash> Ids<K> {
+    fn new() -> Self {
+        Ids {
+            ids: HashMap::new(),
+            keys: vec![],
+        }
+    }
+    /// 1から始まるid
+    fn id(&mut self, key: K) -> usize {
+        let next = self.keys.len() + 1;
+        *self.ids.entry(key.clone()).or_insert_with(|| {
+            self.keys.push(key);
+            next
+        })
+    }
+    fn max(&self) -> usize {
+        self.keys.len()
+    }
+    fn iter(&self) -> impl Iterator<Item = (usize, &K)> {
+        self.keys.iter().enumerate().map(|(i, k)| (i + 1, k))
+    }
+}
+
+/// メインデータのチャンネル
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Lane {
+    Bgm,
+    Bga,
+    BgaLayer,
+    BgaPoor,
+    ExBpm,
+    Stop,
+    Scroll,
+    Note(usize),
+    Long(usize),
+    Invisible(usize),
+    /// 値はダメージの2倍
+    Landmine(usize),
+}
+
+impl Lane {
+    fn value(self, row: &[usize], base62: bool) -> MainDataValue<'static> {
+        let ids = || Objects::from_ids(row, base62);
+        match self {
+            Lane::Bgm => MainDataValue::Bgm(ids()),
+            Lane::Bga => MainDataValue::Bga(ids()),
+            Lane::BgaLayer => MainDataValue::BgaLayer(ids()),
+            Lane::BgaPoor => MainDataValue::BgaPoor(ids()),
+            Lane::ExBpm => MainDataValue::ExBpm(ids()),
+            Lane::Stop => MainDataValue::Stop(ids()),
+            Lane::Scroll => MainDataValue::Scroll(ids()),
+            Lane::Note(ch) => MainDataValue::Note(ch, ids()),
+            Lane::Long(ch) => MainDataValue::LongNote(ch, ids()),
+            Lane::Invisible(ch) => MainDataValue::InvisibleNote(ch, ids()),
+            Lane::Landmine(ch) => MainDataValue::Landmine(
+                ch,
+                row.iter().map(|&n| n as f64 / 2.).collect(),
+            ),
+        }
+    }
+}
+
+/// 小節線で区切った小節
+///
+/// ロングノートの終端がu32を超えることがあるため、位置はu64で扱う
+struct Measures {
+    /// 小節の開始位置（パルス数）
+    starts: Vec<u64>,
+    /// 最後の小節線より後の小節の長さ
+    length: u64,
+}
+
+impl Measures {
+    fn new(lines: Option<&Vec<BarLine>>, resolution: u32) -> Measures {
+        let mut starts = vec![0];
+        starts.extend(lines.into_iter().flatten().map(|l| u64::from(l.y)));
+        starts.sort();
+        starts.dedup();
+        Measures {
+            starts,
+            length: u64::from(resolution) * 4,
+        }
+    }
+    /// (小節, 小節内の位置, 小節の長さ)
+    fn locate(&self, y: u64) -> (usize, u64, u64) {
+        let i = self.starts.partition_point(|&s| s <= y) - 1;
+        if i + 1 < self.starts.len() {
+            (i, y - self.starts[i], self.starts[i + 1] - self.starts[i])
+        }
+        else {
+            let offset = y - self.starts[i];
+            (
+                i + (offset / self.length) as usize,
+                offset % self.length,
+                self.length,
+            )
+        }
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+impl RawBms<'static> {
+    /// BmsonからBMSへ変換
+    ///
+    /// 鍵盤の配置は`mode_hint`から決め、分からない場合は7鍵とする
+    ///
+    /// 続行フラグはBMSで表せないため、全て音声の最初から再生する
+    ///
+    /// 小節数と1行のオブジェクト数は[`ParseLimits`]の初期値までに抑え、
+    /// 1000小節目以降のオブジェクトは捨て、細かすぎる位置は丸める
+    ///
+    /// [`ParseLimits`]: crate::bms::ParseLimits
+    pub fn from_bmson(bmson: &Bmson) -> RawBms<'static> {
+        let info = &bmson.info;
+        let resolution = info.resolution.max(1);
+        let mode =
+            KeyMode::from_mode_hint(&info.mode_hint).unwrap_or_else(|| {
+                log::warn!("{}は7鍵として変換します", info.mode_hint);
+                KeyMode::Beat7K
+            });
+        let lanes = mode.lanes();
+        let note_channel = |x: Option<u32>| {
+            x.filter(|x| lanes.contains(x))
+                .and_then(|x| mode.bms_channel(x))
+        };
+
+        let mut wavs = Ids::new();
+        let mut bmps = Ids::new();
+        let mut bpms = Ids::new();
+        let mut stops = Ids::new();
+        let mut scrolls = Ids::new();
+        let mut events = vec![];
+
+        let sound_notes = bmson
+            .sound_channels
+            .iter()
+            .flatten()
+            .flat_map(|sc| {
+                let wav = wavs.id(sc.name.clone());
+                sc.notes.iter().map(move |n| (wav, n))
+            })
+            .collect::<Vec<_>>();
+        // 終端フラグのノートはロングノートの終点にあるものだけ終端音にする
+        let ln_ends = sound_notes
+            .iter()
+            .filter(|(_, n)| 0 < n.l && n.up != Some(true))
+            .map(|(_, n)| (n.x, u64::from(n.y) + u64::from(n.l)))
+            .collect::<HashSet<_>>();
+        let mut end_sounds = HashMap::new();
+        for (wav, n) in &sound_notes {
+            let y = u64::from(n.y);
+            if n.up == Some(true) && ln_ends.contains(&(n.x, y)) {
+                end_sounds.insert((n.x, y), *wav);
+            }
+        }
+        for (wav, n) in sound_notes {
+            let y = u64::from(n.y);
+            let Some(ch) = note_channel(n.x)
+            else {
+                events.push((y, Lane::Bgm, wav));
+                continue;
+            };
+            if n.up == Some(true) && end_sounds.contains_key(&(n.x, y)) {
+                continue;
+            }
+            if 0 < n.l {
+                let end = y + u64::from(n.l);
+                let end_wav = end_sounds.get(&(n.x, end)).copied();
+                events.push((y, Lane::Long(ch + LONG_OFFSET), wav));
+                events.push((
+                    end,
+                    Lane::Long(ch + LONG_OFFSET),
+                    end_wav.unwrap_or(wav),
+                ));
+            }
+            else {
+                events.push((y, Lane::Note(ch), wav));
+            }
+        }
+        for kc in bmson.key_channels.iter().flatten() {
+            let wav = wavs.id(kc.name.clone());
+            for n in &kc.notes {
+                if let Some(ch) = note_channel(n.x) {
+                    events.push((
+                        n.y.into(),
+                        Lane::Invisible(ch + INVISIBLE_OFFSET),
+                        wav,
+                    ));
+                }
+            }
+        }
+        for mc in bmson.mine_channels.iter().flatten() {
+            for n in &mc.notes {
+                if let Some(ch) = note_channel(n.x) {
+                    let damage = ((n.damage * 2.).round() as usize)
+                        .clamp(1, MAX_BASE_36);
+                    events.push((
+                        n.y.into(),
+                        Lane::Landmine(ch + LANDMINE_OFFSET),
+                        damage,
+                    ));
+                }
+            }
+        }
+        for e in bmson.bpm_events.iter().flatten() {
+            events.push((e.y.into(), Lane::ExBpm, bpms.id(e.bpm.to_bits())));
+        }
+        for e in bmson.stop_events.iter().flatten() {
+            events.push((e.y.into(), Lane::Stop, stops.id(e.duration)));
+        }
+        for e in bmson.scroll_events.iter().flatten() {
+            let y = e.y.round().max(0.) as u64;
+            events.push((y, Lane::Scroll, scrolls.id(e.rate.to_bits())));
+        }
+        for h in &bmson.bga.bga_header {
+            bmps.id(h.id);
+        }
+        for (lane, bga_events) in [
+            (Lane::Bga, &bmson.bga.bga_events),
+            (Lane::BgaLayer, &bmson.bga.layer_events),
+            (Lane::BgaPoor, &bmson.bga.poor_events),
+        ] {
+            for e in bga_events {
+                events.push((e.y.into(), lane, bmps.id(e.id)));
+            }
+        }
+
+        let base62 = [
+            wavs.max(),
+            bmps.max(),
+            bpms.max(),
+            stops.max(),
+            scrolls.max(),
+        ]
+        .into_iter()
+        .any(|n| MAX_BASE_36 < n);
+        let id = |n| Channel::from_number(n, base62);
+
+        let mut commands: Vec<Command<'static>> = vec![];
+        let double = matches!(mode, KeyMode::Beat10K | KeyMode::Beat14K);
+        commands.push(Command::Player(if double { 3 } else { 1 }));
+        if !info.genre.is_empty() {
+            commands.push(Command::Genre(info.genre.clone().into()));
+        }
+        commands.push(Command::Title(info.title.clone().into()));
+        if !info.subtitle.is_empty() {
+            commands.push(Command::SubTitle(info.subtitle.clone().into()));
+        }
+        if !info.artist.is_empty() {
+            commands.push(Command::Artist(info.artist.clone().into()));
+        }
+        for s in info.subartists.iter().flatten() {
+            commands.push(Command::SubArtist(s.clone().into()));
+        }
+        commands.push(Command::Bpm(info.init_bpm));
+        commands.push(Command::PlayLevel(info.level as i32));
+        if let Some((n, _)) = CHART_NAMES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(&info.chart_name))
+        {
+            commands.push(Command::Difficulty(*n));
+        }
+        commands.push(Command::DefExRank(info.judge_rank));
+        commands.push(Command::Total(info.total));
+        for (image, command) in [
+            (&info.eyecatch_image, Command::StageFile as fn(_) -> _),
+            (&info.banner_image, Command::Banner),
+            (&info.title_image, Command::BackBmp),
+            (&info.preview_music, Command::Preview),
+        ] {
+            if let Some(file) = image {
+                commands.push(command(file.clone().into()));
+            }
+        }
+        if events.iter().any(|e| matches!(e.1, Lane::Long(_))) {
+            commands.push(Command::LnType(1));
+        }
+        if let Some(n) = match info.ln_type {
+            Some(LongNoteType::LongNote) => Some(1),
+            Some(LongNoteType::ChargeNote) => Some(2),
+            Some(LongNoteType::HellChargeNote) => Some(3),
+            _ => None,
+        } {
+            commands.push(Command::LnMode(n));
+        }
+        if base62 {
+            commands.push(Command::Base62);
+        }
+
+        for (n, name) in wavs.iter().filter(|(_, name)| !name.is_empty()) {
+            commands.push(Command::Wav(id(n), name.clone().into()));
+        }
+        let names = bmson
+            .bga
+            .bga_header
+            .iter()
+            .map(|h| (h.id, &h.name))
+            .collect::<HashMap<_, _>>();
+        for (n, bmp) in bmps.iter() {
+            match names.get(bmp) {
+                Some(name) => {
+                    commands.push(Command::Bmp(id(n), name.to_string().into()))
+                }
+                None => log::warn!("画像{}が定義されていません", bmp),
+            }
+        }
+        for (n, bpm) in bpms.iter() {
+            commands.push(Command::ExBpm(id(n), f64::from_bits(*bpm)));
+        }
+        for (n, duration) in stops.iter() {
+            // 停止時間の1は192分音符
+            let value = *duration as f64 * 48. / resolution as f64;
+            commands.push(Command::Stop(id(n), value));
+        }
+        for (n, rate) in scrolls.iter() {
+            commands.push(Command::Scroll(id(n), f64::from_bits(*rate)));
+        }
+
+        let limits = crate::bms::ParseLimits::default();
+        let measures = Measures::new(bmson.lines.as_ref(), resolution);
+        let mut lengths = BTreeMap::new();
+        // (小節, チャンネル) -> [(小節内の位置, 値)]
+        let mut rows = BTreeMap::<_, Vec<_>>::new();
+        let mut dropped = 0;
+        for (y, lane, value) in events {
+            let (m, offset, length) = measures.locate(y);
+            if limits.max_measures <= m {
+                dropped += 1;
+                continue;
+            }
+            lengths.insert(m, length);
+            rows.entry((m, lane)).or_default().push((offset, value));
+        }
+        if 0 < dropped {
+            log::warn!(
+                "{}小節目以降の{dropped}個のオブジェクトは変換できません",
+                limits.max_measures
+            );
+        }
+        for (&m, &length) in &lengths {
+            if length != measures.length {
+                let length = length as f64 / measures.length as f64;
+                commands
+                    .push(Command::MainData(m, MainDataValue::Length(length)));
+            }
+        }
+        let max_len = limits.max_objects_per_line as u64;
+        for ((m, lane), objects) in rows {
+            let length = lengths[&m];
+            // 1行の長さを抑え、収まらない位置は丸める
+            let unit = objects.iter().fold(length, |g, o| gcd(g, o.0));
+            let len = (length / unit).min(max_len);
+            if max_len < length / unit {
+                log::warn!("{m}小節目のオブジェクトの位置を丸めます");
+            }
+            let mut objects = objects
+                .into_iter()
+                .map(|(offset, value)| {
+                    let i = u128::from(offset) * u128::from(len)
+                        / u128::from(length);
+                    (i as u64, value)
+                })
+                .collect::<Vec<_>>();
+            objects.sort_by_key(|o| o.0);
+            // 同じ位置のオブジェクトは別の行に分ける
+            let mut layers: Vec<Vec<(u64, usize)>> = vec![];
+            for o in objects {
+                match layers.iter_mut().find(|l| l.last().unwrap().0 != o.0) {
+                    Some(layer) => layer.push(o),
+                    None => layers.push(vec![o]),
+                }
+            }
+            for layer in layers {
+                let unit = layer.iter().fold(len, |g, o| gcd(g, o.0));
+                let mut row = vec![0; (len / unit) as usize];
+                for (offset, value) in layer {
+                    row[(offset / unit) as usize] = value;
+                }
+                commands.push(Command::MainData(m, lane.value(&row, base62)));
+            }
+        }
+        RawBms::from_commands(commands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(clippy::type_complexity)]
+    fn notes<'a>(
+        bms: &Bms<'a>,
+    ) -> Vec<(usize, BmsNoteKind, Option<&'a str>, f64, f64, Option<f64>)> {
+        let timeline = bms.timeline();
+        bms.notes(&timeline)
+            .into_iter()
+            .map(|n| {
+                let wav = bms.wav.get(&n.wav).copied();
+                let end = n.end.map(|e| e.beat);
+                (n.channel, n.kind, wav, n.beat, n.time, end)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn convert() {
+        let raw = RawBms::parse(
+            r"
+#TITLE タイトル
+#ARTIST 制作者
+#BPM 120
+#PLAYLEVEL 7
+#DIFFICULTY 3
+#RANK 2
+#WAV01 a.wav
+#WAV02 b.wav
+#WAV03 c.wav
+#BMP01 a.bmp
+#BPM01 240
+#STOP01 48
+#00102:0.75
+#00101:03
+#00104:01
+#00108:0001
+#00109:01
+#00111:0102
+#00119:0001
+#00151:01000002
+#00251:0000
+#00241:01
+#002D1:0A
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let bmson = Bmson::from_bms(&bms, 240);
+        assert_eq!(bmson.info.title, "タイトル");
+        assert_eq!(bmson.info.mode_hint, "beat-14k");
+        assert_eq!(bmson.info.chart_name, "HYPER");
+        assert_eq!(bmson.info.judge_rank, 75.);
+        assert_eq!(
+            bmson
+                .lines
+                .as_ref()
+                .unwrap()
+                .iter()
+                .map(|l| l.y)
+                .collect::<Vec<_>>(),
+            vec![0, 960, 1680, 2640]
+        );
+        assert_eq!(bmson.bpm_events.as_ref().unwrap()[0].y, 1320);
+        // 四分音符1つ分の停止
+        assert_eq!(bmson.stop_events.as_ref().unwrap()[0].duration, 240);
+        let channels = bmson.sound_channels.as_ref().unwrap();
+        let b = channels.iter().find(|c| c.name == "b.wav").unwrap();
+        // ロングノートの終端音
+        assert_eq!(b.notes.len(), 2);
+        assert_eq!(b.notes[1].up, Some(true));
+        assert_eq!(bmson.key_channels.as_ref().unwrap()[0].notes[0].x, Some(9));
+        assert_eq!(
+            bmson.mine_channels.as_ref().unwrap()[0].notes[0].damage,
+            5.
+        );
+
+        let raw = RawBms::from_bmson(&bmson);
+        let converted = raw.make_bms(rand::rng());
+        assert_eq!(notes(&converted), notes(&bms));
+        assert_eq!(converted.bmp.values().collect::<Vec<_>>(), vec![&"a.bmp"]);
+        assert_eq!(
+            Bmson::from_bms(&converted, 240).sound_channels,
+            bmson.sound_channels
+        );
+    }
+
+    #[test]
+    fn out_of_range() {
+        let note = |x, y, l| Note {
+            x: Some(x),
+            y,
+            l,
+            c: false,
+            t: None,
+            up: None,
+            extra: Default::default(),
+        };
+        let bmson = |resolution, lines: Vec<u32>, notes| Bmson {
+            info: BmsonInfo {
+                resolution,
+                ..Default::default()
+            },
+            lines: Some(lines.into_iter().map(|y| BarLine { y }).collect()),
+            sound_channels: Some(vec![SoundChannel {
+                name: "a.wav".to_string(),
+                notes,
+                extra: Default::default(),
+            }]),
+            ..Default::default()
+        };
+        let limits = crate::bms::ParseLimits::default();
+
+        // 長い小節の細かい位置は丸める
+        let huge = bmson(
+            u32::MAX,
+            vec![0, 4000000000],
+            vec![note(1, 1, 0), note(2, 2, 0), note(3, u32::MAX - 10, 100)],
+        );
+        let raw = RawBms::from_bmson(&huge);
+        let bms = raw.make_bms(rand::rng());
+        let written = bms.to_string();
+        assert!(RawBms::try_parse(&written, &limits).is_ok());
+        let timeline = bms.timeline();
+        assert_eq!(bms.notes(&timeline).len(), 3);
+
+        // 1000小節目以降は捨てる
+        let far = bmson(240, vec![], vec![note(1, 0, 0), note(1, u32::MAX, 0)]);
+        let raw = RawBms::from_bmson(&far);
+        let bms = raw.make_bms(rand::rng());
+        assert!(RawBms::try_parse(&bms.to_string(), &limits).is_ok());
+        let timeline = bms.timeline();
+        assert_eq!(bms.notes(&timeline).len(), 1);
+    }
+}
@@ -0,0 +1,302 @@
+use super::*;
+
+/// バージョン0.21以前のBmson
+///
+/// `version`が無く、`bpmNotes`・`stopNotes`・`soundChannel`などのキーを使う
+///
+/// [`LegacyBmson::upgrade`]で現在の[`Bmson`]に変換する
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyBmson {
+    /// 譜面の情報
+    #[serde(default)]
+    pub info: LegacyBmsonInfo,
+    /// 小節線
+    #[serde(default)]
+    pub lines: Vec<LegacyEventNote>,
+    /// BPMイベント
+    #[serde(default)]
+    pub bpm_notes: Vec<LegacyEventNote>,
+    /// 譜面停止イベント
+    #[serde(default)]
+    pub stop_notes: Vec<LegacyEventNote>,
+    /// 音声チャンネル
+    #[serde(default)]
+    pub sound_channel: Vec<LegacySoundChannel>,
+    /// BGA情報
+    #[serde(default)]
+    pub bga: LegacyBga,
+}
+
+/// バージョン0.21以前のヘッダー
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyBmsonInfo {
+    /// タイトル
+    #[serde(default)]
+    pub title: String,
+    /// アーティスト
+    #[serde(default)]
+    pub artist: String,
+    /// ジャンル
+    #[serde(default)]
+    pub genre: String,
+    /// 判定幅
+    #[serde(default = "default_judge_rank")]
+    pub judge_rank: f64,
+    /// ゲージ増加の総数
+    #[serde(default = "default_total")]
+    pub total: f64,
+    /// 初期BPM
+    #[serde(rename = "initBPM", default = "default_init_bpm")]
+    pub init_bpm: f64,
+    /// レベル
+    #[serde(default)]
+    pub level: u32,
+}
+fn default_init_bpm() -> f64 {
+    BmsonInfo::default().init_bpm
+}
+impl Default for LegacyBmsonInfo {
+    fn default() -> Self {
+        LegacyBmsonInfo {
+            title: String::new(),
+            artist: String::new(),
+            genre: String::new(),
+            judge_rank: default_judge_rank(),
+            total: default_total(),
+            init_bpm: default_init_bpm(),
+            level: 0,
+        }
+    }
+}
+
+/// バージョン0.21以前のイベント
+///
+/// `v`はBPMイベントではBPM、譜面停止イベントでは停止時間（パルス数）
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LegacyEventNote {
+    /// イベント時刻（パルス数）
+    pub y: u32,
+    /// イベントの値
+    #[serde(default)]
+    pub v: f64,
+}
+
+/// バージョン0.21以前のサウンドチャンネル
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LegacySoundChannel {
+    /// ファイル名
+    pub name: String,
+    /// ノーツ
+    #[serde(default)]
+    pub notes: Vec<LegacySoundNote>,
+}
+
+/// バージョン0.21以前のサウンドノート
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LegacySoundNote {
+    /// 演奏レーン
+    pub x: Option<u32>,
+    /// 演奏時刻（パルス数）
+    pub y: u32,
+    /// 長さ（パルス数）
+    #[serde(default)]
+    pub l: u32,
+    /// 続行フラグ
+    #[serde(default)]
+    pub c: bool,
+}
+
+/// バージョン0.21以前のBGA情報
+#[derive(Deserialize, Serialize, Clone, Default, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LegacyBga {
+    /// 画像データ
+    #[serde(default)]
+    pub bga_header: Vec<LegacyBgaHeader>,
+    /// BGAイベント
+    #[serde(default)]
+    pub bga_notes: Vec<BgaEvent>,
+    /// レイヤーイベント
+    #[serde(default)]
+    pub layer_notes: Vec<BgaEvent>,
+    /// POORイベント
+    #[serde(default)]
+    pub poor_notes: Vec<BgaEvent>,
+}
+
+/// バージョン0.21以前の画像ファイル
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LegacyBgaHeader {
+    /// 画像のID
+    #[serde(rename = "ID")]
+    pub id: u32,
+    /// 画像ファイル
+    pub name: String,
+}
+
+impl LegacyBmson {
+    /// 文字列から0.21以前のBmsonを解析
+    pub fn parse(source: &str) -> serde_json::Result<LegacyBmson> {
+        serde_json::from_str(source)
+    }
+    /// Jsonが0.21以前のBmsonかどうか
+    ///
+    /// `version`が無く、0.21以前のキーを含んでいれば`true`
+    pub fn detect(value: &serde_json::Value) -> bool {
+        let Some(object) = value.as_object()
+        else {
+            return false;
+        };
+        !object.contains_key("version")
+            && (["bpmNotes", "stopNotes", "soundChannel"]
+                .iter()
+                .any(|key| object.contains_key(*key))
+                || object
+                    .get("info")
+                    .and_then(|info| info.as_object())
+                    .is_some_and(|info| info.contains_key("initBPM")))
+    }
+    /// 現在のBmsonへ変換
+    ///
+    /// 分解能は0.21の固定値である240になる
+    pub fn upgrade(self) -> Bmson {
+        let info = BmsonInfo {
+            title: self.info.title,
+            artist: self.info.artist,
+            genre: self.info.genre,
+            level: self.info.level,
+            init_bpm: self.info.init_bpm,
+            judge_rank: self.info.judge_rank,
+            total: self.info.total,
+            resolution: 240,
+            ..Default::default()
+        };
+        Bmson {
+            version: SUPPORTED_VERSIONS[0].to_string(),
+            info,
+            lines: Some(
+                self.lines.into_iter().map(|l| BarLine { y: l.y }).collect(),
+            ),
+            bpm_events: Some(
+                self.bpm_notes
+                    .into_iter()
+                    .map(|e| BpmEvent { y: e.y, bpm: e.v })
+                    .collect(),
+            ),
+            stop_events: Some(
+                self.stop_notes
+                    .into_iter()
+                    .map(|e| StopEvent {
+                        y: e.y,
+                        duration: e.v.max(0.) as u32,
+                    })
+                    .collect(),
+            ),
+            sound_channels: Some(
+                self.sound_channel
+                    .into_iter()
+                    .map(|sc| SoundChannel {
+                        name: sc.name,
+                        notes: sc
+                            .notes
+                            .into_iter()
+                            .map(|n| Note {
+                                x: n.x,
+                                y: n.y,
+                                l: n.l,
+                                c: n.c,
+                                t: None,
+                                up: None,
+                                extra: Default::default(),
+                            })
+                            .collect(),
+                        extra: Default::default(),
+                    })
+                    .collect(),
+            ),
+            bga: Bga {
+                bga_header: self
+                    .bga
+                    .bga_header
+                    .into_iter()
+                    .map(|h| BgaHeader {
+                        id: h.id,
+                        name: h.name,
+                    })
+                    .collect(),
+                bga_events: self.bga.bga_notes,
+                layer_events: self.bga.layer_notes,
+                poor_events: self.bga.poor_notes,
+                extra: Default::default(),
+            },
+            ..Default::default()
+        }
+    }
+}
+impl From<LegacyBmson> for Bmson {
+    fn from(legacy: LegacyBmson) -> Self {
+        legacy.upgrade()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrade() {
+        let source = r#"
+        {
+          "info": {
+            "title": "タイトル",
+            "artist": "制作者",
+            "genre": "ジャンル",
+            "judgeRank": 80,
+            "total": 300,
+            "initBPM": 150,
+            "level": 7
+          },
+          "lines": [{ "y": 0 }, { "y": 960 }],
+          "bpmNotes": [{ "y": 960, "v": 300 }],
+          "stopNotes": [{ "y": 480, "v": 240 }],
+          "soundChannel": [
+            {
+              "name": "a.wav",
+              "notes": [{ "x": 1, "y": 0, "l": 0, "c": false }]
+            }
+          ],
+          "bga": {
+            "bgaHeader": [{ "ID": 1, "name": "a.bmp" }],
+            "bgaNotes": [{ "y": 0, "id": 1 }],
+            "layerNotes": [],
+            "poorNotes": []
+          }
+        }
+        "#;
+        let bmson = Bmson::parse(source).unwrap();
+        assert_eq!(bmson.version, "1.0.0");
+        assert_eq!(bmson.info.title, "タイトル");
+        assert_eq!(bmson.info.init_bpm, 150.);
+        assert_eq!(bmson.info.judge_rank, 80.);
+        assert_eq!(bmson.info.resolution, 240);
+        assert_eq!(
+            bmson.bpm_events,
+            Some(vec![BpmEvent { y: 960, bpm: 300. }])
+        );
+        assert_eq!(
+            bmson.stop_events,
+            Some(vec![StopEvent {
+                y: 480,
+                duration: 240
+            }])
+        );
+        assert_eq!(bmson.sound_channels.unwrap()[0].notes.len(), 1);
+        assert_eq!(bmson.bga.bga_header[0].name, "a.bmp");
+        assert_eq!(Bmson::parse_info(source).unwrap().init_bpm, 150.);
+
+        // 現在のBmsonの解析に失敗した場合はそのエラーを返す
+        assert!(Bmson::parse(r#"{ "version": "1.0.0" }"#).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use super::*;
+
+/// パルス数と時刻を相互に変換するタイムライン
+///
+/// 時刻はすべてミリ秒で、パルス数0を0msとする
+///
+/// 停止イベントは、その位置のBPMで停止時間（パルス数）を換算する
+#[derive(Clone, Debug, PartialEq)]
+pub struct BmsonTimeline {
+    resolution: f64,
+    points: Vec<TimingPoint>,
+}
+
+/// BPMか停止が変化する位置
+#[derive(Clone, Debug, PartialEq)]
+struct TimingPoint {
+    /// 位置（パルス数）
+    y: f64,
+    /// この位置に到達した時刻
+    time: f64,
+    /// この位置以降のBPM
+    bpm: f64,
+    /// この位置での停止時間
+    stop: f64,
+}
+
+/// 時刻が確定したサウンドノート
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimedNote<'a> {
+    /// [`Bmson::sound_channels`]内のインデックス
+    pub channel: usize,
+    /// ノート
+    pub note: &'a Note,
+    /// 演奏時刻
+    pub time: f64,
+    /// 終端の時刻
+    ///
+    /// 普通のノートでは`time`と同じ
+    pub end_time: f64,
+}
+
+impl BmsonTimeline {
+    pub fn new(bmson: &Bmson) -> BmsonTimeline {
+        enum Event {
+            Bpm(f64),
+            Stop(u32),
+        }
+        let mut events = vec![];
+        for e in bmson.bpm_events.iter().flatten() {
+            if e.bpm > 0. {
+                events.push((e.y, Event::Bpm(e.bpm)));
+            }
+            else {
+                log::warn!("{}パルスの0以下のBPMを無視しました", e.y);
+            }
+        }
+        for e in bmson.stop_events.iter().flatten() {
+            events.push((e.y, Event::Stop(e.duration)));
+        }
+        // 同じ位置ではBPM変化を先に適用する
+        events.sort_by_key(|(y, e)| (*y, matches!(e, Event::Stop(_))));
+
+        let resolution = bmson.info.resolution.max(1) as f64;
+        let init_bpm = if bmson.info.init_bpm > 0. {
+            bmson.info.init_bpm
+        }
+        else {
+            log::warn!("0以下の初期BPMを無視しました");
+            BmsonInfo::default().init_bpm
+        };
+        let mut points = vec![TimingPoint {
+            y: 0.,
+            time: 0.,
+            bpm: init_bpm,
+            stop: 0.,
+        }];
+        for (y, e) in events {
+            let y = y as f64;
+            let last = points.last().unwrap();
+            if last.y < y {
+                let time = last.time
+                    + last.stop
+                    + pulses_to_ms(y - last.y, last.bpm, resolution);
+                let bpm = last.bpm;
+                points.push(TimingPoint {
+                    y,
+                    time,
+                    bpm,
+                    stop: 0.,
+                });
+            }
+            let last = points.last_mut().unwrap();
+            match e {
+                Event::Bpm(bpm) => last.bpm = bpm,
+                Event::Stop(duration) => {
+                    last.stop +=
+                        pulses_to_ms(duration as f64, last.bpm, resolution)
+                }
+            }
+        }
+        BmsonTimeline { resolution, points }
+    }
+    /// 分解能（四分音符1つに対応するパルス数）
+    pub fn resolution(&self) -> f64 {
+        self.resolution
+    }
+    fn point_at_pulse(&self, y: f64) -> &TimingPoint {
+        let i = self.points.partition_point(|p| p.y <= y);
+        &self.points[i.saturating_sub(1)]
+    }
+    /// パルス数から時刻へ変換
+    ///
+    /// 停止位置では停止が始まる時刻を返す
+    pub fn pulse_to_ms(&self, y: f64) -> f64 {
+        let p = self.point_at_pulse(y);
+        let stop = if p.y < y { p.stop } else { 0. };
+        p.time + stop + pulses_to_ms(y - p.y, p.bpm, self.resolution)
+    }
+    /// 時刻からパルス数へ変換
+    ///
+    /// 停止中の時刻では停止位置を返す
+    pub fn ms_to_pulse(&self, ms: f64) -> f64 {
+        let i = self.points.partition_point(|p| p.time <= ms);
+        let p = &self.points[i.saturating_sub(1)];
+        let elapsed = if ms < p.time {
+            ms - p.time
+        }
+        else {
+            (ms - p.time - p.stop).max(0.)
+        };
+        p.y + elapsed * p.bpm * self.resolution / 60000.
+    }
+    /// その位置のBPM
+    pub fn bpm_at(&self, y: f64) -> f64 {
+        self.point_at_pulse(y).bpm
+    }
+    /// 停止する位置
+    ///
+    /// (パルス数, 時刻, 停止時間)
+    pub fn stops(&self) -> Vec<(f64, f64, f64)> {
+        self.points
+            .iter()
+            .filter(|p| p.stop > 0.)
+            .map(|p| (p.y, p.time, p.stop))
+            .collect()
+    }
+    /// ノートの演奏時刻
+    pub fn note_time(&self, note: &Note) -> f64 {
+        self.pulse_to_ms(note.y as f64)
+    }
+    /// ノートの終端の時刻
+    pub fn note_end_time(&self, note: &Note) -> f64 {
+        self.pulse_to_ms(note.y as f64 + note.l as f64)
+    }
+    /// 全てのサウンドノートを時刻順に並べたもの
+    pub fn notes<'a>(&self, bmson: &'a Bmson) -> Vec<TimedNote<'a>> {
+        let mut notes = bmson
+            .sound_channels
+            .iter()
+            .flatten()
+            .enumerate()
+            .flat_map(|(channel, sc)| {
+                sc.notes.iter().map(move |note| TimedNote {
+                    channel,
+                    note,
+                    time: self.note_time(note),
+                    end_time: self.note_end_time(note),
+                })
+            })
+            .collect::<Vec<_>>();
+        notes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        notes
+    }
+    /// 小節線の時刻
+    pub fn bar_lines(&self, bmson: &Bmson) -> Vec<f64> {
+        bmson
+            .lines
+            .iter()
+            .flatten()
+            .map(|l| self.pulse_to_ms(l.y as f64))
+            .collect()
+    }
+    /// BGAイベントの時刻
+    ///
+    /// (時刻, 画像のID)
+    pub fn bga_events(&self, events: &[BgaEvent]) -> Vec<(f64, u32)> {
+        events
+            .iter()
+            .map(|e| (self.pulse_to_ms(e.y as f64), e.id))
+            .collect()
+    }
+    /// スクロール速度イベントの時刻
+    ///
+    /// (時刻, スクロール速度倍率)
+    pub fn scroll_events(&self, bmson: &Bmson) -> Vec<(f64, f64)> {
+        bmson
+            .scroll_events
+            .iter()
+            .flatten()
+            .map(|e| (self.pulse_to_ms(e.y), e.rate))
+            .collect()
+    }
+}
+
+fn pulses_to_ms(pulses: f64, bpm: f64, resolution: f64) -> f64 {
+    pulses * 60000. / (bpm * resolution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bmson(bpm_events: Vec<BpmEvent>, stop_events: Vec<StopEvent>) -> Bmson {
+        Bmson {
+            info: BmsonInfo {
+                init_bpm: 120.,
+                resolution: 240,
+                ..Default::default()
+            },
+            bpm_events: Some(bpm_events),
+            stop_events: Some(stop_events),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn constant_bpm() {
+        let timeline = bmson(vec![], vec![]).timeline();
+        assert_eq!(timeline.pulse_to_ms(0.), 0.);
+        assert_eq!(timeline.pulse_to_ms(240.), 500.);
+        assert_eq!(timeline.pulse_to_ms(960.), 2000.);
+        assert_eq!(timeline.ms_to_pulse(2000.), 960.);
+    }
+
+    #[test]
+    fn bpm_change() {
+        let timeline =
+            bmson(vec![BpmEvent { y: 960, bpm: 240. }], vec![]).timeline();
+        assert_eq!(timeline.pulse_to_ms(960.), 2000.);
+        assert_eq!(timeline.pulse_to_ms(1200.), 2250.);
+        assert_eq!(timeline.ms_to_pulse(2250.), 1200.);
+        assert_eq!(timeline.bpm_at(959.), 120.);
+        assert_eq!(timeline.bpm_at(960.), 240.);
+    }
+
+    #[test]
+    fn stop() {
+        let timeline = bmson(
+            vec![BpmEvent { y: 480, bpm: 240. }],
+            vec![StopEvent {
+                y: 480,
+                duration: 240,
+            }],
+        )
+        .timeline();
+        // 停止時間は変化後のBPMで換算する
+        assert_eq!(timeline.pulse_to_ms(480.), 1000.);
+        assert_eq!(timeline.pulse_to_ms(720.), 1500.);
+        assert_eq!(timeline.ms_to_pulse(1100.), 480.);
+        assert_eq!(timeline.ms_to_pulse(1250.), 480.);
+        assert_eq!(timeline.ms_to_pulse(1500.), 720.);
+    }
+
+    #[test]
+    fn long_note_end() {
+        let timeline = bmson(vec![], vec![]).timeline();
+        let note = Note {
+            x: Some(1),
+            y: 480,
+            l: 240,
+            c: false,
+            t: None,
+            up: None,
+            extra: Default::default(),
+        };
+        assert_eq!(timeline.note_end_time(&note), 1500.);
+        // 終端がu32を超えても溢れない
+        let note = Note {
+            y: u32::MAX,
+            l: u32::MAX,
+            ..note
+        };
+        assert_eq!(
+            timeline.note_end_time(&note),
+            timeline.pulse_to_ms(2. * u32::MAX as f64)
+        );
+    }
+}
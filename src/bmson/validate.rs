@@ -0,0 +1,431 @@
+use super::*;
+use std::collections::{HashMap, HashSet};
+
+/// 対応しているBmsonのバージョン
+pub const SUPPORTED_VERSIONS: &[&str] = &["1.0.0"];
+
+/// [`Bmson::validate`]で見つかった問題
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationIssue {
+    /// 分解能が0
+    ZeroResolution,
+    /// 初期BPMが0以下
+    NonPositiveInitBpm(f64),
+    /// BPMイベントのBPMが0以下
+    NonPositiveBpm { y: u32, bpm: f64 },
+    /// BPMイベントが時刻順に並んでいない
+    ///
+    /// 最初に順番が崩れたイベントのインデックス
+    UnsortedBpmEvents(usize),
+    /// 停止イベントが時刻順に並んでいない
+    ///
+    /// 最初に順番が崩れたイベントのインデックス
+    UnsortedStopEvents(usize),
+    /// プレイ方法に存在しないレーンのノート
+    LaneOutOfRange { channel: usize, x: u32, y: u32 },
+    /// 同じレーンのロングノートに重なっているノート
+    OverlappingLongNote { x: u32, y: u32 },
+    /// 終端のパルス数がu32に収まらないロングノート
+    LongNoteEndOutOfRange { x: u32, y: u32 },
+    /// `generic-nkeys`のレーン数が[`MAX_GENERIC_KEYS`]より多い
+    TooManyKeys(u32),
+    /// 画像データに無いIDを参照しているBGAイベント
+    UndefinedBgaId { y: u32, id: u32 },
+    /// 画像データのIDの重複
+    DuplicateBgaHeaderId(u32),
+}
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ValidationIssue::*;
+        match self {
+            ZeroResolution => write!(f, "分解能が0です"),
+            NonPositiveInitBpm(bpm) => {
+                write!(f, "初期BPMが0以下です ({bpm})")
+            }
+            NonPositiveBpm { y, bpm } => {
+                write!(f, "{y}パルスのBPMが0以下です ({bpm})")
+            }
+            UnsortedBpmEvents(i) => {
+                write!(f, "{i}番目のBPMイベントが時刻順になっていません")
+            }
+            UnsortedStopEvents(i) => {
+                write!(f, "{i}番目の停止イベントが時刻順になっていません")
+            }
+            LaneOutOfRange { channel, x, y } => write!(
+                f,
+                "{channel}番目のサウンドチャンネルの{y}パルスのノートのレーン{x}は存在しません"
+            ),
+            OverlappingLongNote { x, y } => write!(
+                f,
+                "レーン{x}の{y}パルスのノートがロングノートに重なっています"
+            ),
+            LongNoteEndOutOfRange { x, y } => write!(
+                f,
+                "レーン{x}の{y}パルスのロングノートの終端が範囲外です"
+            ),
+            TooManyKeys(n) => write!(f, "レーン数{n}は多すぎます"),
+            UndefinedBgaId { y, id } => {
+                write!(f, "{y}パルスのBGAイベントのID{id}は定義されていません")
+            }
+            DuplicateBgaHeaderId(id) => {
+                write!(f, "画像データのID{id}が重複しています")
+            }
+        }
+    }
+}
+
+/// 厳密な解析のエラー
+#[derive(Debug)]
+pub enum BmsonError {
+    /// Jsonとして解析できない
+    Json(serde_json::Error),
+    /// 対応していないバージョン
+    UnsupportedVersion(String),
+    /// 検証で問題が見つかった
+    Invalid(Vec<ValidationIssue>),
+}
+impl std::fmt::Display for BmsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BmsonError::Json(e) => e.fmt(f),
+            BmsonError::UnsupportedVersion(v) => {
+                write!(f, "対応していないバージョンです ({v})")
+            }
+            BmsonError::Invalid(issues) => {
+                write!(f, "{}個の問題が見つかりました", issues.len())?;
+                for issue in issues {
+                    write!(f, "\n{issue}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+impl std::error::Error for BmsonError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BmsonError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<serde_json::Error> for BmsonError {
+    fn from(e: serde_json::Error) -> Self {
+        BmsonError::Json(e)
+    }
+}
+
+/// `generic-nkeys`のプレイ方法で扱うレーン数の上限
+pub const MAX_GENERIC_KEYS: u32 = 1024;
+
+/// `generic-nkeys`のプレイ方法のレーン数
+fn generic_keys(mode_hint: &str) -> Option<u32> {
+    mode_hint
+        .strip_prefix("generic-")?
+        .strip_suffix("keys")?
+        .parse()
+        .ok()
+}
+
+/// プレイ方法のヒントで、ノートを置けるレーンか
+///
+/// 分からないヒントでは`None`
+pub(crate) fn mode_hint_has_lane(mode_hint: &str, x: u32) -> Option<bool> {
+    Some(match mode_hint {
+        "beat-5k" => matches!(x, 1..=5 | 8),
+        "beat-7k" => (1..=8).contains(&x),
+        "beat-10k" => matches!(x, 1..=5 | 8..=13 | 16),
+        "beat-14k" => (1..=16).contains(&x),
+        "popn-5k" => (1..=5).contains(&x),
+        "popn-9k" => (1..=9).contains(&x),
+        "keyboard-24k" => (1..=26).contains(&x),
+        "keyboard-48k" => (1..=52).contains(&x),
+        hint => {
+            let n = generic_keys(hint).filter(|&n| n <= MAX_GENERIC_KEYS)?;
+            (1..=n).contains(&x)
+        }
+    })
+}
+
+impl Bmson {
+    /// 譜面の内容を検証する
+    ///
+    /// 問題が無ければ空の`Vec`を返す
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        use ValidationIssue::*;
+        let mut issues = vec![];
+
+        if self.info.resolution == 0 {
+            issues.push(ZeroResolution);
+        }
+        if self.info.init_bpm <= 0. {
+            issues.push(NonPositiveInitBpm(self.info.init_bpm));
+        }
+
+        let bpm_events = self.bpm_events.as_deref().unwrap_or_default();
+        for e in bpm_events {
+            if e.bpm <= 0. {
+                issues.push(NonPositiveBpm { y: e.y, bpm: e.bpm });
+            }
+        }
+        if let Some(i) = unsorted_index(bpm_events.iter().map(|e| e.y)) {
+            issues.push(UnsortedBpmEvents(i));
+        }
+        let stop_events = self.stop_events.as_deref().unwrap_or_default();
+        if let Some(i) = unsorted_index(stop_events.iter().map(|e| e.y)) {
+            issues.push(UnsortedStopEvents(i));
+        }
+
+        let mode_hint = self.info.mode_hint.as_str();
+        if let Some(n) =
+            generic_keys(mode_hint).filter(|&n| n > MAX_GENERIC_KEYS)
+        {
+            issues.push(TooManyKeys(n));
+        }
+        let mut lane_notes = HashMap::<u32, Vec<&Note>>::new();
+        for (channel, sc) in self.sound_channels.iter().flatten().enumerate() {
+            for note in &sc.notes {
+                let Some(x) = note.x.filter(|&x| x != 0)
+                else {
+                    continue;
+                };
+                if mode_hint_has_lane(mode_hint, x) == Some(false) {
+                    issues.push(LaneOutOfRange {
+                        channel,
+                        x,
+                        y: note.y,
+                    });
+                }
+                lane_notes.entry(x).or_default().push(note);
+            }
+        }
+        let mut lane_notes = lane_notes.into_iter().collect::<Vec<_>>();
+        lane_notes.sort_by_key(|(x, _)| *x);
+        for (x, mut notes) in lane_notes {
+            notes.sort_by_key(|n| (n.y, std::cmp::Reverse(n.l)));
+            let mut ln_end = None;
+            for note in notes {
+                if ln_end.is_some_and(|end| u64::from(note.y) < end) {
+                    issues.push(OverlappingLongNote { x, y: note.y });
+                    continue;
+                }
+                if note.l > 0 {
+                    let end = u64::from(note.y) + u64::from(note.l);
+                    if end > u64::from(u32::MAX) {
+                        issues.push(LongNoteEndOutOfRange { x, y: note.y });
+                    }
+                    ln_end = Some(end);
+                }
+            }
+        }
+
+        let mut ids = HashSet::new();
+        for header in &self.bga.bga_header {
+            if !ids.insert(header.id) {
+                issues.push(DuplicateBgaHeaderId(header.id));
+            }
+        }
+        for e in self
+            .bga
+            .bga_events
+            .iter()
+            .chain(&self.bga.layer_events)
+            .chain(&self.bga.poor_events)
+        {
+            if !ids.contains(&e.id) {
+                issues.push(UndefinedBgaId { y: e.y, id: e.id });
+            }
+        }
+
+        issues
+    }
+    /// 文字列からBmsonを厳密に解析
+    ///
+    /// 対応していないバージョンや、[`Bmson::validate`]で問題が見つかった場合はエラーになる
+    ///
+    /// バージョン0.21以前のBmsonも`version`が空の対応していないバージョンとして扱う
+    pub fn parse_strict(source: &str) -> Result<Bmson, BmsonError> {
+        let legacy = || {
+            serde_json::from_str::<serde_json::Value>(source)
+                .is_ok_and(|value| LegacyBmson::detect(&value))
+        };
+        let bmson: Bmson = match serde_json::from_str(source) {
+            Ok(bmson) => bmson,
+            Err(_) if legacy() => {
+                return Err(BmsonError::UnsupportedVersion(String::new()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !SUPPORTED_VERSIONS.contains(&bmson.version.as_str()) {
+            return Err(BmsonError::UnsupportedVersion(bmson.version));
+        }
+        let issues = bmson.validate();
+        if !issues.is_empty() {
+            return Err(BmsonError::Invalid(issues));
+        }
+        Ok(bmson)
+    }
+}
+
+fn unsorted_index(ys: impl Iterator<Item = u32>) -> Option<usize> {
+    let mut last = 0;
+    for (i, y) in ys.enumerate() {
+        if y < last {
+            return Some(i);
+        }
+        last = y;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(x: u32, y: u32, l: u32) -> Note {
+        Note {
+            x: Some(x),
+            y,
+            l,
+            c: false,
+            t: None,
+            up: None,
+            extra: Default::default(),
+        }
+    }
+
+    #[test]
+    fn issues() {
+        let bmson = Bmson {
+            version: "1.0.0".to_string(),
+            bpm_events: Some(vec![
+                BpmEvent { y: 960, bpm: 150. },
+                BpmEvent { y: 480, bpm: 0. },
+            ]),
+            sound_channels: Some(vec![SoundChannel {
+                name: "a.wav".to_string(),
+                notes: vec![
+                    note(1, 0, 480),
+                    note(1, 240, 0),
+                    note(1, 480, 0),
+                    note(9, 0, 0),
+                ],
+                extra: Default::default(),
+            }]),
+            bga: Bga {
+                bga_header: vec![
+                    BgaHeader {
+                        id: 1,
+                        name: "a.bmp".to_string(),
+                    },
+                    BgaHeader {
+                        id: 1,
+                        name: "b.bmp".to_string(),
+                    },
+                ],
+                bga_events: vec![BgaEvent { y: 0, id: 2 }],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            bmson.validate(),
+            vec![
+                ValidationIssue::NonPositiveBpm { y: 480, bpm: 0. },
+                ValidationIssue::UnsortedBpmEvents(1),
+                ValidationIssue::LaneOutOfRange {
+                    channel: 0,
+                    x: 9,
+                    y: 0
+                },
+                ValidationIssue::OverlappingLongNote { x: 1, y: 240 },
+                ValidationIssue::DuplicateBgaHeaderId(1),
+                ValidationIssue::UndefinedBgaId { y: 0, id: 2 },
+            ]
+        );
+        assert!(matches!(
+            Bmson::parse_strict(&bmson.to_string().unwrap()),
+            Err(BmsonError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn mode_hint() {
+        let bmson = |mode_hint: &str, notes| Bmson {
+            version: "1.0.0".to_string(),
+            info: BmsonInfo {
+                mode_hint: mode_hint.to_string(),
+                ..Default::default()
+            },
+            sound_channels: Some(vec![SoundChannel {
+                name: "a.wav".to_string(),
+                notes,
+                extra: Default::default(),
+            }]),
+            ..Default::default()
+        };
+        assert_eq!(
+            bmson("beat-5k", vec![note(6, 0, 0), note(8, 0, 0)]).validate(),
+            vec![ValidationIssue::LaneOutOfRange {
+                channel: 0,
+                x: 6,
+                y: 0
+            }]
+        );
+        assert_eq!(
+            bmson("generic-20keys", vec![note(20, 0, 0), note(21, 0, 0)])
+                .validate(),
+            vec![ValidationIssue::LaneOutOfRange {
+                channel: 0,
+                x: 21,
+                y: 0
+            }]
+        );
+        assert_eq!(
+            bmson("generic-4294967295keys", vec![note(21, 0, 0)]).validate(),
+            vec![ValidationIssue::TooManyKeys(u32::MAX)]
+        );
+        assert_eq!(
+            bmson(
+                "beat-7k",
+                vec![note(1, u32::MAX - 10, 100), note(1, u32::MAX, 0)]
+            )
+            .validate(),
+            vec![
+                ValidationIssue::LongNoteEndOutOfRange {
+                    x: 1,
+                    y: u32::MAX - 10
+                },
+                ValidationIssue::OverlappingLongNote { x: 1, y: u32::MAX },
+            ]
+        );
+    }
+
+    #[test]
+    fn version() {
+        let bmson = Bmson {
+            version: "2.0.0".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Bmson::parse_strict(&bmson.to_string().unwrap()),
+            Err(BmsonError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            Bmson::parse_strict(r#"{"info": {"initBPM": 120}}"#),
+            Err(BmsonError::UnsupportedVersion(v)) if v.is_empty()
+        ));
+        assert!(matches!(
+            Bmson::parse_strict(r#"{"info": {}}"#),
+            Err(BmsonError::Json(_))
+        ));
+        let bmson = Bmson {
+            version: "1.0.0".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Bmson::parse_strict(&bmson.to_string().unwrap()).unwrap(),
+            bmson
+        );
+    }
+}
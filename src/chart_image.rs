@@ -0,0 +1,484 @@
+use crate::bms::{Bms, BmsNoteKind};
+use crate::transform::KeyMode;
+use std::fmt::Write;
+
+/// 譜面画像の設定
+///
+/// 長さの単位はピクセル
+#[derive(Clone, Debug, PartialEq)]
+pub struct SvgOptions {
+    /// 1拍の高さ
+    pub beat_height: f64,
+    /// 1レーンの幅
+    pub lane_width: f64,
+    /// 1列に並べる拍数
+    ///
+    /// これより長い小節は1列に1小節だけ並べる
+    pub beats_per_column: f64,
+    /// 列の間隔
+    ///
+    /// BPM・停止の注釈はここに書く
+    pub column_gap: f64,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        SvgOptions {
+            beat_height: 24.,
+            lane_width: 10.,
+            beats_per_column: 16.,
+            column_gap: 56.,
+        }
+    }
+}
+
+const BACKGROUND: &str = "#000";
+const LANE: &str = "#111";
+const MEASURE_LINE: &str = "#888";
+const TEXT: &str = "#ccc";
+const SCRATCH: &str = "#e04040";
+const WHITE_KEY: &str = "#e0e0e0";
+const BLUE_KEY: &str = "#4080ff";
+const POPN_KEYS: [&str; 9] = [
+    "#e0e0e0", "#f0e040", "#40d040", "#4080ff", "#e04040", "#4080ff",
+    "#40d040", "#f0e040", "#e0e0e0",
+];
+const LANDMINE: &str = "#a02020";
+const BPM: &str = "#40c040";
+const STOP: &str = "#e0a020";
+const NOTE_HEIGHT: f64 = 4.;
+const MARGIN: f64 = 16.;
+/// 列の左に小節番号を書く幅
+const NUMBER_WIDTH: f64 = 24.;
+
+/// 描画するノーツ
+struct Note {
+    lane: u32,
+    beat: f64,
+    /// ロングノートの終点の位置
+    end: Option<f64>,
+    landmine: bool,
+}
+
+/// 描画に必要な譜面の情報
+///
+/// 位置は全て拍数
+struct Chart {
+    mode: KeyMode,
+    /// 各小節の開始位置と、最後に譜面の終わりの位置
+    measures: Vec<f64>,
+    notes: Vec<Note>,
+    /// (位置, BPM)
+    bpm: Vec<(f64, f64)>,
+    /// (位置, 停止時間)
+    stops: Vec<(f64, f64)>,
+}
+
+/// 左から順に、レーン番号と色
+fn lane_layout(mode: KeyMode) -> Vec<(u32, &'static str)> {
+    let scratch = mode.scratch_lanes();
+    if mode == KeyMode::Popn9K {
+        return mode.lanes().into_iter().zip(POPN_KEYS).collect();
+    }
+    let mut lanes = mode.lanes();
+    // 1Pのスクラッチは左端、2Pのスクラッチは右端
+    lanes.sort_by_key(|l| match scratch.iter().position(|s| s == l) {
+        Some(0) => 0,
+        Some(_) => u32::MAX,
+        None => *l,
+    });
+    lanes
+        .into_iter()
+        .map(|l| {
+            let color = if scratch.contains(&l) {
+                SCRATCH
+            }
+            // 片側の鍵盤の偶数番目が青
+            else if (l - 1) % 8 % 2 == 1 {
+                BLUE_KEY
+            }
+            else {
+                WHITE_KEY
+            };
+            (l, color)
+        })
+        .collect()
+}
+
+impl Chart {
+    fn svg(&self, options: &SvgOptions) -> String {
+        let lanes = lane_layout(self.mode);
+        let lane_x = |lane: u32| {
+            lanes
+                .iter()
+                .position(|(l, _)| *l == lane)
+                .map(|i| i as f64 * options.lane_width)
+        };
+        let column_width = lanes.len() as f64 * options.lane_width;
+
+        // 各小節の(列, 列内の開始位置)
+        let mut placement = vec![];
+        let (mut column, mut offset) = (0, 0.);
+        for w in self.measures.windows(2) {
+            let length = w[1] - w[0];
+            if 0. < offset && options.beats_per_column < offset + length {
+                column += 1;
+                offset = 0.;
+            }
+            placement.push((column, offset));
+            offset += length;
+        }
+        let columns = placement.last().map_or(1, |p| p.0 + 1);
+        let column_height = self
+            .measures
+            .windows(2)
+            .map(|w| w[1] - w[0])
+            .fold(options.beats_per_column, f64::max);
+        let column_step = NUMBER_WIDTH + column_width + options.column_gap;
+        let width = MARGIN * 2. + columns as f64 * column_step;
+        let height = column_height * options.beat_height + MARGIN * 2.;
+
+        // 位置から、列の左端のx座標とy座標へ変換
+        let position = |beat: f64| -> Option<(f64, f64)> {
+            let m = self.measures.partition_point(|&s| s <= beat);
+            let m = m.checked_sub(1)?.min(placement.len().checked_sub(1)?);
+            let (column, offset) = placement[m];
+            let x = MARGIN + NUMBER_WIDTH + column as f64 * column_step;
+            let y = MARGIN
+                + (column_height - offset - (beat - self.measures[m]))
+                    * options.beat_height;
+            Some((x, y))
+        };
+
+        let mut svg = String::new();
+        writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="sans-serif" font-size="10">"#
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"<rect width="{width}" height="{height}" fill="{BACKGROUND}"/>"#
+        )
+        .unwrap();
+        for (m, w) in self.measures.windows(2).enumerate() {
+            let (Some((x, bottom)), length) = (position(w[0]), w[1] - w[0])
+            else {
+                continue;
+            };
+            let top = bottom - length * options.beat_height;
+            writeln!(
+                svg,
+                r#"<rect x="{x}" y="{top}" width="{column_width}" height="{}" fill="{LANE}"/>"#,
+                length * options.beat_height
+            )
+            .unwrap();
+            for i in 1..lanes.len() {
+                let lx = x + i as f64 * options.lane_width;
+                writeln!(
+                    svg,
+                    r#"<line x1="{lx}" y1="{top}" x2="{lx}" y2="{bottom}" stroke="{BACKGROUND}" stroke-width="0.5"/>"#
+                )
+                .unwrap();
+            }
+            writeln!(
+                svg,
+                r#"<line x1="{x}" y1="{bottom}" x2="{}" y2="{bottom}" stroke="{MEASURE_LINE}"/>"#,
+                x + column_width
+            )
+            .unwrap();
+            writeln!(
+                svg,
+                r#"<text x="{}" y="{}" fill="{TEXT}" text-anchor="end">{m:03}</text>"#,
+                x - 2.,
+                bottom - 2.
+            )
+            .unwrap();
+        }
+
+        for note in &self.notes {
+            let (Some(lx), Some((x, y))) =
+                (lane_x(note.lane), position(note.beat))
+            else {
+                continue;
+            };
+            let color = lanes
+                .iter()
+                .find(|(l, _)| *l == note.lane)
+                .map_or(WHITE_KEY, |(_, c)| *c);
+            if let Some(end) = note.end {
+                // 小節をまたぐロングノートは小節ごとに描く
+                let mut start = note.beat;
+                while start < end {
+                    let m = self.measures.partition_point(|&s| s <= start);
+                    let measure_end =
+                        self.measures.get(m).copied().unwrap_or(f64::INFINITY);
+                    let segment_end = end.min(measure_end);
+                    if let Some((x, y)) = position(start) {
+                        let h = (segment_end - start) * options.beat_height;
+                        writeln!(
+                            svg,
+                            r#"<rect x="{}" y="{}" width="{}" height="{h}" fill="{color}" fill-opacity="0.5"/>"#,
+                            x + lx + 2.,
+                            y - h,
+                            options.lane_width - 4.
+                        )
+                        .unwrap();
+                    }
+                    start = segment_end;
+                }
+                if let Some((x, y)) = position(end) {
+                    writeln!(
+                        svg,
+                        r#"<rect x="{}" y="{}" width="{}" height="{NOTE_HEIGHT}" fill="{color}"/>"#,
+                        x + lx + 1.,
+                        y - NOTE_HEIGHT / 2.,
+                        options.lane_width - 2.
+                    )
+                    .unwrap();
+                }
+            }
+            let color = if note.landmine { LANDMINE } else { color };
+            writeln!(
+                svg,
+                r#"<rect x="{}" y="{}" width="{}" height="{NOTE_HEIGHT}" fill="{color}"/>"#,
+                x + lx + 1.,
+                y - NOTE_HEIGHT / 2.,
+                options.lane_width - 2.
+            )
+            .unwrap();
+        }
+
+        let annotations =
+            self.bpm
+                .iter()
+                .map(|(beat, bpm)| (*beat, BPM, format!("{bpm}")))
+                .chain(self.stops.iter().map(|(beat, ms)| {
+                    (*beat, STOP, format!("STOP {ms:.0}ms"))
+                }));
+        for (beat, color, text) in annotations {
+            if let Some((x, y)) = position(beat) {
+                writeln!(
+                    svg,
+                    r#"<line x1="{x}" y1="{y}" x2="{}" y2="{y}" stroke="{color}"/>"#,
+                    x + column_width + 2.
+                )
+                .unwrap();
+                writeln!(
+                    svg,
+                    r#"<text x="{}" y="{}" fill="{color}">{text}</text>"#,
+                    x + column_width + 4.,
+                    y + 3.
+                )
+                .unwrap();
+            }
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+/// BMSの譜面全体をSVG画像にする
+///
+/// 小節を下から上へ積み重ね、列がいっぱいになったら右の列へ移る
+///
+/// 不可視ノーツは描かない
+pub fn bms_to_svg(bms: &Bms, options: &SvgOptions) -> String {
+    let timeline = bms.timeline();
+    let notes = bms.notes(&timeline);
+    let mode = KeyMode::detect(&notes);
+    let notes = notes
+        .iter()
+        .filter(|n| n.kind != BmsNoteKind::Invisible)
+        .filter_map(|n| {
+            Some(Note {
+                lane: mode.bms_lane(n.channel)?,
+                beat: n.beat,
+                end: n.end.as_ref().map(|e| e.beat),
+                landmine: matches!(n.kind, BmsNoteKind::Landmine(_)),
+            })
+        })
+        .collect();
+    Chart {
+        mode,
+        measures: (0..=timeline.measure_count())
+            .map(|m| timeline.measure_start(m))
+            .collect(),
+        notes,
+        bpm: timeline
+            .bpm_changes()
+            .into_iter()
+            .map(|(beat, _, bpm)| (beat, bpm))
+            .collect(),
+        stops: timeline
+            .stops()
+            .into_iter()
+            .map(|(beat, _, stop)| (beat, stop))
+            .collect(),
+    }
+    .svg(options)
+}
+
+/// Bmsonの譜面全体をSVG画像にする
+///
+/// 小節線が無い場合は4拍ごとに小節を区切る
+/// 小節数は[`ParseLimits::default`](crate::bms::ParseLimits::default)の`max_measures`まで
+///
+/// `mode_hint`が不明な場合は、使われているレーンから7鍵か14鍵とする
+#[cfg(feature = "bmson")]
+pub fn bmson_to_svg(bmson: &crate::Bmson, options: &SvgOptions) -> String {
+    let timeline = bmson.timeline();
+    let resolution = timeline.resolution();
+    let beat = |y: f64| y / resolution;
+    let mut notes = timeline
+        .notes(bmson)
+        .into_iter()
+        .filter_map(|n| {
+            Some(Note {
+                lane: n.note.x.filter(|&x| x != 0)?,
+                beat: beat(n.note.y as f64),
+                end: (0 < n.note.l)
+                    .then(|| beat(n.note.y as f64 + n.note.l as f64)),
+                landmine: false,
+            })
+        })
+        .collect::<Vec<_>>();
+    for mine in bmson.mine_channels.iter().flatten().flat_map(|c| &c.notes) {
+        if let Some(lane) = mine.x.filter(|&x| x != 0) {
+            notes.push(Note {
+                lane,
+                beat: beat(mine.y as f64),
+                end: None,
+                landmine: true,
+            });
+        }
+    }
+    let mode =
+        KeyMode::from_mode_hint(&bmson.info.mode_hint).unwrap_or_else(|| {
+            if notes.iter().any(|n| 8 < n.lane) {
+                KeyMode::Beat14K
+            }
+            else {
+                KeyMode::Beat7K
+            }
+        });
+    let last = notes
+        .iter()
+        .map(|n| n.end.unwrap_or(n.beat))
+        .fold(0., f64::max);
+    let mut measures = bmson
+        .lines
+        .iter()
+        .flatten()
+        .map(|l| beat(l.y as f64))
+        .collect::<Vec<_>>();
+    if measures.is_empty() {
+        // 極端に遠いノーツがあっても小節を作りすぎない
+        let max_measures = crate::bms::ParseLimits::default().max_measures;
+        let count = ((last / 4.) as usize).min(max_measures - 1) + 1;
+        measures = (0..=count).map(|m| m as f64 * 4.).collect();
+    }
+    measures.insert(0, 0.);
+    measures.sort_by(f64::total_cmp);
+    measures.dedup();
+    let mut bpm = vec![(0., bmson.info.init_bpm)];
+    bpm.extend(
+        bmson
+            .bpm_events
+            .iter()
+            .flatten()
+            .map(|e| (beat(e.y as f64), e.bpm)),
+    );
+    Chart {
+        mode,
+        measures,
+        notes,
+        bpm,
+        stops: timeline
+            .stops()
+            .into_iter()
+            .map(|(y, _, stop)| (beat(y), stop))
+            .collect(),
+    }
+    .svg(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawBms;
+
+    #[test]
+    fn svg() {
+        let raw = RawBms::parse(
+            r"
+#BPM 120
+#STOP01 48
+#00111:01
+#00116:0001
+#00118:01
+#00151:01000000
+#00251:0001
+#001D1:00000002
+#00203:00F0
+#00209:01
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let options = SvgOptions::default();
+        let svg = bms_to_svg(&bms, &options);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.ends_with("</svg>\n"));
+        // 3小節が1列に並ぶ
+        assert_eq!(svg.matches(r##"fill="#111""##).count(), 3);
+        assert!(svg.contains(">002</text>"));
+        // ロングノートは小節ごとに描く
+        assert_eq!(svg.matches(r#"fill-opacity="0.5""#).count(), 2);
+        assert_eq!(svg.matches(&format!(r#"fill="{LANDMINE}""#)).count(), 1);
+        assert!(svg.contains(">120</text>"));
+        assert!(svg.contains(">240</text>"));
+        assert!(svg.contains(">STOP 500ms</text>"));
+
+        #[cfg(feature = "bmson")]
+        {
+            let svg = bmson_to_svg(&crate::Bmson::default(), &options);
+            // 小節線が無いので4拍の小節が1つ
+            assert_eq!(svg.matches(r##"fill="#111""##).count(), 1);
+
+            // 極端に遠いノーツでも小節数は上限まで
+            use crate::bmson::{BmsonInfo, Note, SoundChannel};
+            let bmson = crate::Bmson {
+                info: BmsonInfo {
+                    init_bpm: 120.,
+                    resolution: 1,
+                    ..Default::default()
+                },
+                sound_channels: Some(vec![SoundChannel {
+                    name: "a.wav".into(),
+                    notes: vec![Note {
+                        x: Some(1),
+                        y: u32::MAX,
+                        l: 0,
+                        c: false,
+                        t: None,
+                        up: None,
+                        extra: Default::default(),
+                    }],
+                    extra: Default::default(),
+                }]),
+                ..Default::default()
+            };
+            let svg = bmson_to_svg(&bmson, &options);
+            let max_measures = crate::bms::ParseLimits::default().max_measures;
+            assert_eq!(svg.matches(r##"fill="#111""##).count(), max_measures);
+        }
+
+        assert_eq!(
+            lane_layout(KeyMode::Beat14K)
+                .iter()
+                .map(|l| l.0)
+                .collect::<Vec<_>>(),
+            vec![8, 1, 2, 3, 4, 5, 6, 7, 9, 10, 11, 12, 13, 14, 15, 16]
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use crate::bms::{Bms, BmsNoteKind};
+use crate::transform::KeyMode;
+
+/// 縦連打とみなす間隔（ミリ秒）
+pub const JACK_INTERVAL: f64 = 250.;
+/// トリルとみなす間隔（ミリ秒）
+pub const TRILL_INTERVAL: f64 = 200.;
+
+/// 難易度の推定に使うノーツ
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayNote {
+    /// 時刻
+    pub time: f64,
+    /// レーン番号
+    pub lane: u32,
+    /// スクラッチかどうか
+    pub scratch: bool,
+    /// ロングノートの終点の時刻
+    pub end: Option<f64>,
+}
+
+/// 難易度の内訳
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DifficultyFeatures {
+    /// ノーツ数
+    pub notes: usize,
+    /// 最初のノーツから最後のノーツまでの長さ（ミリ秒）
+    pub duration: f64,
+    /// 1秒あたりの平均ノーツ数
+    pub average_nps: f64,
+    /// 1秒間のノーツ数の最大値
+    pub peak_nps: f64,
+    /// 同時に押すノーツ数の平均
+    pub chord_density: f64,
+    /// 縦連打になっているノーツの割合
+    pub jack_ratio: f64,
+    /// トリルになっているノーツの割合
+    pub trill_ratio: f64,
+    /// スクラッチのノーツの割合
+    pub scratch_ratio: f64,
+    /// ロングノートを押している時間の割合
+    pub ln_coverage: f64,
+    /// BPMの変動係数
+    ///
+    /// 時間で重み付けしたBPMの標準偏差を平均で割ったもの
+    pub bpm_variability: f64,
+}
+
+/// 推定した難易度
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Difficulty {
+    /// 難易度の値
+    ///
+    /// 譜面同士を比べるための目安で、`#PLAYLEVEL`とは尺度が異なる
+    pub value: f64,
+    /// 内訳
+    pub features: DifficultyFeatures,
+}
+
+/// ノーツとBPM変化から難易度を推定する
+///
+/// `bpm_changes`は(時刻, BPM)を時刻順に並べたもの
+///
+/// 時刻が有限でないノーツと、0以下や有限でないBPMは無視する
+pub fn estimate(notes: &[PlayNote], bpm_changes: &[(f64, f64)]) -> Difficulty {
+    let mut notes = notes
+        .iter()
+        .filter(|n| n.time.is_finite())
+        .collect::<Vec<_>>();
+    notes.sort_by(|a, b| a.time.total_cmp(&b.time).then(a.lane.cmp(&b.lane)));
+    let (Some(first), Some(last)) = (notes.first(), notes.last())
+    else {
+        return Difficulty::default();
+    };
+    let (start, end) = (first.time, last.time);
+    let duration = end - start;
+    let count = notes.len() as f64;
+    let seconds = (duration / 1000.).max(1.);
+
+    let mut peak = 0;
+    let mut j = 0;
+    for i in 0..notes.len() {
+        while notes[i].time - notes[j].time >= 1000. {
+            j += 1;
+        }
+        peak = peak.max(i + 1 - j);
+    }
+
+    let chords = notes.chunk_by(|a, b| a.time == b.time).collect::<Vec<_>>();
+
+    let mut jacks = 0;
+    let mut last_time = std::collections::HashMap::new();
+    for n in &notes {
+        if let Some(t) = last_time.insert(n.lane, n.time)
+            && n.time - t <= JACK_INTERVAL
+        {
+            jacks += 1;
+        }
+    }
+
+    let mut trills = 0;
+    for w in chords.windows(3) {
+        if let [[a], [b], [c]] = w
+            && a.lane == c.lane
+            && a.lane != b.lane
+            && c.time - a.time <= TRILL_INTERVAL * 2.
+        {
+            trills += 1;
+        }
+    }
+
+    let scratches = notes.iter().filter(|n| n.scratch).count();
+
+    let mut holds = notes
+        .iter()
+        .filter_map(|n| n.end.filter(|e| e.is_finite()).map(|e| (n.time, e)))
+        .collect::<Vec<_>>();
+    holds.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut held = 0.;
+    let mut current: Option<(f64, f64)> = None;
+    for (s, e) in holds {
+        match &mut current {
+            Some(c) if s <= c.1 => c.1 = c.1.max(e),
+            _ => {
+                if let Some(c) = current {
+                    held += c.1 - c.0;
+                }
+                current = Some((s, e));
+            }
+        }
+    }
+    if let Some(c) = current {
+        held += c.1 - c.0;
+    }
+
+    let features = DifficultyFeatures {
+        notes: notes.len(),
+        duration,
+        average_nps: count / seconds,
+        peak_nps: peak as f64,
+        chord_density: count / chords.len() as f64,
+        jack_ratio: jacks as f64 / count,
+        trill_ratio: trills as f64 / count,
+        scratch_ratio: scratches as f64 / count,
+        ln_coverage: if 0. < duration {
+            (held / duration).min(1.)
+        }
+        else {
+            0.
+        },
+        bpm_variability: bpm_variability(bpm_changes, start, end),
+    };
+    Difficulty {
+        value: difficulty_value(&features),
+        features,
+    }
+}
+
+fn bpm_variability(bpm_changes: &[(f64, f64)], start: f64, end: f64) -> f64 {
+    let bpm_changes = bpm_changes
+        .iter()
+        .filter(|(time, bpm)| time.is_finite() && 0. < *bpm && bpm.is_finite())
+        .collect::<Vec<_>>();
+    // (BPM, 長さ)
+    let mut sections = vec![];
+    for (i, &&(time, bpm)) in bpm_changes.iter().enumerate() {
+        let next = bpm_changes.get(i + 1).map_or(end, |c| c.0);
+        let length = next.min(end) - time.max(start);
+        if 0. < length {
+            sections.push((bpm, length));
+        }
+    }
+    let total = sections.iter().map(|s| s.1).sum::<f64>();
+    if total <= 0. {
+        return 0.;
+    }
+    let mean = sections.iter().map(|(b, l)| b * l).sum::<f64>() / total;
+    if mean <= 0. {
+        return 0.;
+    }
+    let variance = sections
+        .iter()
+        .map(|(b, l)| (b - mean).powi(2) * l)
+        .sum::<f64>()
+        / total;
+    variance.sqrt() / mean
+}
+
+/// 内訳から難易度の値を計算する
+///
+/// 平均と最大の密度を基本に、縦連打・トリル・スクラッチ・ロングノート・
+/// BPM変化・同時押しの多さで補正する
+fn difficulty_value(f: &DifficultyFeatures) -> f64 {
+    let density = f.average_nps * 0.4 + f.peak_nps * 0.6;
+    let technique = 1.
+        + f.jack_ratio * 0.3
+        + f.trill_ratio * 0.2
+        + f.scratch_ratio * 0.3
+        + f.ln_coverage * 0.3
+        + f.bpm_variability * 0.5;
+    let chord = 1. + (f.chord_density - 1.) * 0.1;
+    density * technique * chord
+}
+
+/// BMSの難易度を推定する
+///
+/// 不可視ノーツと地雷は含まない
+pub fn estimate_bms(bms: &Bms) -> Difficulty {
+    let timeline = bms.timeline();
+    let notes = bms.notes(&timeline);
+    let mode = KeyMode::detect(&notes);
+    let scratch = mode.scratch_lanes();
+    let notes = notes
+        .iter()
+        .filter(|n| matches!(n.kind, BmsNoteKind::Normal | BmsNoteKind::Long))
+        .filter_map(|n| {
+            let lane = mode.bms_lane(n.channel)?;
+            Some(PlayNote {
+                time: n.time,
+                lane,
+                scratch: scratch.contains(&lane),
+                end: n.end.as_ref().map(|e| e.time),
+            })
+        })
+        .collect::<Vec<_>>();
+    let bpm_changes = timeline
+        .bpm_changes()
+        .into_iter()
+        .map(|(_, time, bpm)| (time, bpm))
+        .collect::<Vec<_>>();
+    estimate(&notes, &bpm_changes)
+}
+
+/// Bmsonの難易度を推定する
+///
+/// `mode_hint`が不明な場合はスクラッチを区別しない
+#[cfg(feature = "bmson")]
+pub fn estimate_bmson(bmson: &crate::Bmson) -> Difficulty {
+    let timeline = bmson.timeline();
+    let scratch = KeyMode::from_mode_hint(&bmson.info.mode_hint)
+        .map(|m| m.scratch_lanes())
+        .unwrap_or_default();
+    let notes = timeline
+        .notes(bmson)
+        .into_iter()
+        .filter(|n| n.note.up != Some(true))
+        .filter_map(|n| {
+            let lane = n.note.x.filter(|&x| x != 0)?;
+            Some(PlayNote {
+                time: n.time,
+                lane,
+                scratch: scratch.contains(&lane),
+                end: (n.time < n.end_time).then_some(n.end_time),
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut bpm_changes = vec![(0., bmson.info.init_bpm)];
+    for e in bmson.bpm_events.iter().flatten() {
+        bpm_changes.push((timeline.pulse_to_ms(e.y as f64), e.bpm));
+    }
+    bpm_changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+    estimate(&notes, &bpm_changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawBms;
+
+    #[test]
+    fn features() {
+        // 1小節が2000msで、1文字が125ms
+        let raw = RawBms::parse(
+            r"
+#BPM 120
+#00111:01000100000000000000000000000000
+#00112:00010000000000000000000000000000
+#00113:00000000000000000101000000000000
+#00116:00000000000000000100000000000000
+#00152:00000000000000000000000000000101
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let difficulty = estimate_bms(&bms);
+        let f = &difficulty.features;
+        assert_eq!(f.notes, 7);
+        assert_eq!(f.duration, 1750.);
+        assert_eq!(f.peak_nps, 4.);
+        assert_eq!(f.chord_density, 7. / 6.);
+        // 11と13の縦連打
+        assert_eq!(f.jack_ratio, 2. / 7.);
+        // 11・12・11のトリル
+        assert_eq!(f.trill_ratio, 1. / 7.);
+        assert_eq!(f.scratch_ratio, 1. / 7.);
+        assert_eq!(f.ln_coverage, 125. / 1750.);
+        assert_eq!(f.bpm_variability, 0.);
+        assert!(0. < difficulty.value);
+
+        let note = |time| PlayNote {
+            time,
+            lane: 1,
+            scratch: false,
+            end: None,
+        };
+        let difficulty =
+            estimate(&[note(0.), note(2000.)], &[(0., 120.), (1000., 240.)]);
+        assert_eq!(difficulty.features.bpm_variability, 1. / 3.);
+
+        assert_eq!(estimate(&[], &[]), Difficulty::default());
+
+        // 0以下や有限でないBPM・時刻があってもNaNにならない
+        for bpm in [0., -120., f64::INFINITY, f64::NAN] {
+            let difficulty = estimate(&[note(0.), note(2000.)], &[(0., bpm)]);
+            assert_eq!(difficulty.features.bpm_variability, 0.);
+            assert!(difficulty.value.is_finite());
+        }
+        let difficulty =
+            estimate(&[note(0.), note(f64::INFINITY)], &[(0., 120.)]);
+        assert_eq!(difficulty.features.notes, 1);
+        assert!(difficulty.value.is_finite());
+        let raw =
+            RawBms::parse("#BPM 120\n#STOP01 inf\n#00111:0101\n#00109:01");
+        let difficulty = estimate_bms(&raw.make_bms(rand::rng()));
+        assert!(difficulty.value.is_finite());
+        assert!(difficulty.features.duration.is_finite());
+
+        #[cfg(feature = "bmson")]
+        {
+            let mut bmson = crate::Bmson::default();
+            bmson.info.init_bpm = 0.;
+            bmson.bpm_events =
+                Some(vec![crate::bmson::BpmEvent { y: 240, bpm: -1. }]);
+            bmson.sound_channels = Some(vec![crate::bmson::SoundChannel {
+                name: "a.wav".into(),
+                notes: [0, 960]
+                    .map(|y| crate::bmson::Note {
+                        x: Some(1),
+                        y,
+                        l: 0,
+                        c: false,
+                        t: None,
+                        up: None,
+                        extra: Default::default(),
+                    })
+                    .into(),
+                extra: Default::default(),
+            }]);
+            let difficulty = estimate_bmson(&bmson);
+            assert_eq!(difficulty.features.notes, 2);
+            assert!(difficulty.value.is_finite());
+        }
+    }
+}
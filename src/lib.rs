@@ -37,7 +37,8 @@
 //!
 //! // 書き込み
 //!
-//! // !!!開発中!!!
+//! // ランダム要素を確定させたBMSの文字列へ
+//! let bms_string = bms.to_string();
 //! ```
 //! # Bmsonファイル
 //! ```
@@ -109,3 +110,31 @@ pub use bms::RawBms;
 pub mod bmson;
 #[cfg(feature = "bmson")]
 pub use bmson::Bmson;
+
+/// レーンオプションやダブルプレイへの変換など、譜面の変換
+pub mod transform;
+
+/// 譜面の難易度の推定
+pub mod difficulty;
+
+/// SCROLL・SPEEDを含めた譜面の表示位置の計算
+pub mod scroll;
+
+/// 譜面全体を小節ごとに並べたSVG画像
+pub mod chart_image;
+
+/// キー音を合成して音声ファイルを書き出す
+///
+/// WAV・OGG・FLACの音声ファイルに対応
+#[cfg(feature = "audio")]
+pub mod audio;
+
+/// 譜面ファイルの読み込み
+///
+/// 文字コードの推測とハッシュ値の計算をする
+#[cfg(feature = "load")]
+pub mod load;
+
+/// 譜面フォルダを読み込み、曲ごとにまとめた一覧を作る
+#[cfg(feature = "library")]
+pub mod library;
@@ -0,0 +1,415 @@
+use crate::load::{ChartFile, ChartFormat};
+use crate::{Bmson, RawBms};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// `#DIFFICULTY`の値と、難易度名に含まれる単語の対応
+const DIFFICULTY_NAMES: [(i32, &[&str]); 5] = [
+    (1, &["BEGINNER", "EASY", "LIGHT"]),
+    (2, &["NORMAL", "STANDARD"]),
+    (3, &["HYPER", "HARD"]),
+    (4, &["ANOTHER", "EX"]),
+    (5, &["INSANE", "BLACK", "LEGGENDARIA", "発狂"]),
+];
+
+/// 難易度名を囲む括弧
+const BRACKETS: [(char, char); 6] = [
+    ('[', ']'),
+    ('(', ')'),
+    ('（', '）'),
+    ('【', '】'),
+    ('<', '>'),
+    ('-', '-'),
+];
+
+/// 譜面1つのヘッダーの情報
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChartEntry {
+    pub path: PathBuf,
+    pub format: ChartFormat,
+    /// ファイルのMD5
+    pub md5: String,
+    /// ファイルのSHA-256
+    pub sha256: String,
+    pub title: String,
+    pub subtitle: String,
+    pub artist: String,
+    pub subartist: String,
+    pub genre: String,
+    pub level: Option<i32>,
+    /// 難易度
+    ///
+    /// 書かれていない場合は難易度名から推測する
+    ///
+    /// [`Bms::difficulty`](crate::Bms::difficulty)と同じく1から5
+    pub difficulty: Option<i32>,
+    /// タイトルの末尾の`[ANOTHER]`などや、Bmsonの`chart_name`
+    pub chart_name: String,
+    /// 初期BPM
+    pub bpm: Option<f64>,
+    pub stage_file: Option<String>,
+    pub banner: Option<String>,
+    pub preview: Option<String>,
+}
+
+impl ChartEntry {
+    /// 難易度名を除いたタイトル
+    pub fn song_title(&self) -> &str {
+        split_difficulty(&self.title)
+            .filter(|(title, _)| !title.is_empty())
+            .map_or(self.title.trim(), |(title, _)| title)
+    }
+}
+
+/// 同じ曲の譜面をまとめたもの
+#[derive(Clone, Debug, PartialEq)]
+pub struct Song {
+    /// 難易度名を除いたタイトル
+    pub title: String,
+    pub artist: String,
+    /// 最初の譜面があるフォルダ
+    pub folder: PathBuf,
+    /// 難易度、レベル、パスの順に並べた譜面
+    pub charts: Vec<ChartEntry>,
+}
+
+/// [`scan`]の結果
+#[derive(Debug, Default)]
+pub struct Library {
+    pub songs: Vec<Song>,
+    /// 読み込めなかったファイルやフォルダ
+    pub errors: Vec<(PathBuf, ScanError)>,
+}
+
+/// 譜面ファイルやフォルダを読み込めなかった理由
+#[derive(Debug)]
+pub enum ScanError {
+    Io(std::io::Error),
+    /// Bmsonとして解析できない
+    Bmson(serde_json::Error),
+}
+impl std::fmt::Display for ScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanError::Io(e) => e.fmt(f),
+            ScanError::Bmson(e) => e.fmt(f),
+        }
+    }
+}
+impl std::error::Error for ScanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScanError::Io(e) => Some(e),
+            ScanError::Bmson(e) => Some(e),
+        }
+    }
+}
+impl From<std::io::Error> for ScanError {
+    fn from(e: std::io::Error) -> Self {
+        ScanError::Io(e)
+    }
+}
+impl From<serde_json::Error> for ScanError {
+    fn from(e: serde_json::Error) -> Self {
+        ScanError::Bmson(e)
+    }
+}
+
+/// 末尾の括弧で囲まれた難易度名を分ける
+///
+/// (難易度名より前, 難易度名)
+fn split_difficulty(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_end();
+    BRACKETS.iter().find_map(|&(open, close)| {
+        let rest = s.strip_suffix(close)?;
+        let i = rest.rfind(open)?;
+        let (before, name) = (&rest[..i], rest[i + open.len_utf8()..].trim());
+        // `-`はタイトルの途中にも使われるので、前に空白が必要
+        if name.is_empty()
+            || open == '-' && !before.is_empty() && !before.ends_with(' ')
+        {
+            return None;
+        }
+        Some((before.trim_end(), name))
+    })
+}
+
+/// 難易度名から`#DIFFICULTY`の値を推測する
+fn difficulty_from_name(name: &str) -> Option<i32> {
+    let name = name.to_uppercase();
+    let words = name
+        .split(|c: char| !c.is_alphanumeric())
+        .collect::<Vec<_>>();
+    DIFFICULTY_NAMES
+        .iter()
+        .rev()
+        .find(|(_, keys)| {
+            keys.iter().any(|key| {
+                words.contains(key) || !key.is_ascii() && name.contains(key)
+            })
+        })
+        .map(|(n, _)| *n)
+}
+
+/// 譜面ファイルのヘッダーを読み込む
+///
+/// BMSのメインデータとBmsonの`info`以外は読み飛ばす
+///
+/// BMSの分岐は全て1を選ぶ
+pub fn load_chart(path: impl AsRef<Path>) -> Result<ChartEntry, ScanError> {
+    let file = ChartFile::open(path)?;
+    let mut entry = ChartEntry {
+        path: file.path,
+        format: file.format,
+        md5: file.md5,
+        sha256: file.sha256,
+        title: String::new(),
+        subtitle: String::new(),
+        artist: String::new(),
+        subartist: String::new(),
+        genre: String::new(),
+        level: None,
+        difficulty: None,
+        chart_name: String::new(),
+        bpm: None,
+        stage_file: None,
+        banner: None,
+        preview: None,
+    };
+    match file.format {
+        ChartFormat::Bms => {
+            let raw = RawBms::parse_header(&file.text);
+            let bms = raw.make_bms_with(|_| 1);
+            entry.title = bms.title.unwrap_or_default().to_string();
+            entry.subtitle = bms.sub_title.join(" ");
+            entry.artist = bms.artist.unwrap_or_default().to_string();
+            entry.subartist = bms.sub_artist.join(" ");
+            entry.genre = bms.genre.unwrap_or_default().to_string();
+            entry.level = bms.play_level;
+            entry.difficulty = bms.difficulty;
+            entry.bpm = bms.bpm;
+            entry.stage_file = bms.stage_file.map(str::to_string);
+            entry.banner = bms.banner.map(str::to_string);
+            entry.preview = bms.preview.map(str::to_string);
+            // 難易度名はタイトルかサブタイトルの末尾に書かれる
+            entry.chart_name = [&entry.title, &entry.subtitle]
+                .into_iter()
+                .find_map(|s| split_difficulty(s))
+                .map_or("", |(_, name)| name)
+                .to_string();
+        }
+        ChartFormat::Bmson => {
+            let info = Bmson::parse_info(&file.text)?;
+            entry.title = info.title;
+            entry.subtitle = info.subtitle;
+            entry.artist = info.artist;
+            entry.subartist = info.subartists.unwrap_or_default().join(" ");
+            entry.genre = info.genre;
+            entry.level = i32::try_from(info.level).ok();
+            entry.chart_name = info.chart_name;
+            entry.bpm = Some(info.init_bpm);
+            entry.stage_file = info.eyecatch_image;
+            entry.banner = info.banner_image;
+            entry.preview = info.preview_music;
+        }
+    }
+    if entry.difficulty.is_none() {
+        entry.difficulty = difficulty_from_name(&entry.chart_name);
+    }
+    Ok(entry)
+}
+
+/// 譜面を曲ごとにまとめる
+///
+/// 同じフォルダにある譜面と、難易度名を除いたタイトルとアーティストが
+/// 同じ譜面を同じ曲とする
+pub fn group_songs(mut charts: Vec<ChartEntry>) -> Vec<Song> {
+    charts.sort_by(|a, b| a.path.cmp(&b.path));
+    // Union-Find
+    let mut parent = (0..charts.len()).collect::<Vec<_>>();
+    fn root(parent: &mut [usize], i: usize) -> usize {
+        let mut r = i;
+        while parent[r] != r {
+            r = parent[r];
+        }
+        parent[i] = r;
+        r
+    }
+    let mut folders = HashMap::new();
+    let mut titles = HashMap::new();
+    for (i, chart) in charts.iter().enumerate() {
+        let mut keys = vec![];
+        if let Some(&j) = folders.get(&chart.path.parent()) {
+            keys.push(j);
+        }
+        folders.entry(chart.path.parent()).or_insert(i);
+        let title = (chart.song_title(), chart.artist.trim());
+        if !title.0.is_empty() {
+            if let Some(&j) = titles.get(&title) {
+                keys.push(j);
+            }
+            titles.entry(title).or_insert(i);
+        }
+        for j in keys {
+            let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+            parent[a.max(b)] = a.min(b);
+        }
+    }
+
+    let mut songs: Vec<Song> = vec![];
+    let mut song_index = HashMap::new();
+    for (i, chart) in charts.into_iter().enumerate() {
+        let r = root(&mut parent, i);
+        match song_index.get(&r) {
+            Some(&s) => {
+                let song: &mut Song = &mut songs[s];
+                if song.title.is_empty() {
+                    song.title = chart.song_title().to_string();
+                }
+                if song.artist.is_empty() {
+                    song.artist = chart.artist.trim().to_string();
+                }
+                song.charts.push(chart);
+            }
+            None => {
+                song_index.insert(r, songs.len());
+                songs.push(Song {
+                    title: chart.song_title().to_string(),
+                    artist: chart.artist.trim().to_string(),
+                    folder: chart
+                        .path
+                        .parent()
+                        .map(Path::to_path_buf)
+                        .unwrap_or_default(),
+                    charts: vec![chart],
+                });
+            }
+        }
+    }
+    for song in &mut songs {
+        song.charts.sort_by(|a, b| {
+            (
+                a.difficulty.unwrap_or(i32::MAX),
+                a.level.unwrap_or(i32::MAX),
+            )
+                .cmp(&(
+                    b.difficulty.unwrap_or(i32::MAX),
+                    b.level.unwrap_or(i32::MAX),
+                ))
+                .then_with(|| a.path.cmp(&b.path))
+        });
+    }
+    songs
+}
+
+/// フォルダ以下の譜面ファイルを全て読み込み、曲ごとにまとめる
+///
+/// 読み込めなかったファイルは[`Library::errors`]に入れて続ける
+///
+/// シンボリックリンクのフォルダはたどらない
+pub fn scan(root: impl AsRef<Path>) -> Library {
+    let mut library = Library::default();
+    let mut charts = vec![];
+    let mut dirs = vec![root.as_ref().to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                library.errors.push((dir, e.into()));
+                continue;
+            }
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    library.errors.push((dir.clone(), e.into()));
+                    continue;
+                }
+            };
+            if std::fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir()) {
+                dirs.push(path);
+            }
+            else if path.is_file() && ChartFormat::from_path(&path).is_some()
+            {
+                match load_chart(&path) {
+                    Ok(chart) => charts.push(chart),
+                    Err(e) => library.errors.push((path, e)),
+                }
+            }
+        }
+    }
+    library.errors.sort_by(|a, b| a.0.cmp(&b.0));
+    library.songs = group_songs(charts);
+    library
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan() {
+        assert_eq!(
+            split_difficulty("Song [ANOTHER]"),
+            Some(("Song", "ANOTHER"))
+        );
+        assert_eq!(split_difficulty("Song -7KEYS-"), Some(("Song", "7KEYS")));
+        assert_eq!(split_difficulty("Re-Re-"), None);
+        assert_eq!(difficulty_from_name("SP ANOTHER"), Some(4));
+        assert_eq!(difficulty_from_name("BLACK ANOTHER"), Some(5));
+        assert_eq!(difficulty_from_name("EXTRA"), None);
+
+        let dir = std::env::temp_dir().join("bms-utils-library-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("song/extra")).unwrap();
+        std::fs::create_dir_all(dir.join("other")).unwrap();
+        std::fs::write(
+            dir.join("song/b.bms"),
+            "#TITLE Song [ANOTHER]\n#ARTIST A\n#PLAYLEVEL 10\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("song/a.bms"),
+            "#TITLE Song\n#SUBTITLE [HYPER]\n#ARTIST A\n#DIFFICULTY 3\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("song/extra/c.bmson"),
+            r#"{"version": "1.0.0", "info": {"title": "Song", "artist": "A",
+            "genre": "", "chart_name": "INSANE", "level": 12,
+            "init_bpm": 150}, "sound_channels": []}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("other/d.bms"), "#TITLE Other\n").unwrap();
+        std::fs::write(dir.join("other/e.bmson"), "{").unwrap();
+        // 上のフォルダへのリンクはたどらない
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, dir.join("song/extra/loop")).unwrap();
+
+        let library = super::scan(&dir);
+        assert_eq!(library.errors.len(), 1);
+        assert_eq!(library.errors[0].0, dir.join("other/e.bmson"));
+        assert_eq!(library.songs.len(), 2);
+        let other = &library.songs[0];
+        assert_eq!(other.title, "Other");
+        assert_eq!(other.folder, dir.join("other"));
+        let song = &library.songs[1];
+        assert_eq!((song.title.as_str(), song.artist.as_str()), ("Song", "A"));
+        let charts = song
+            .charts
+            .iter()
+            .map(|c| (c.chart_name.as_str(), c.difficulty, c.level))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            charts,
+            vec![
+                ("HYPER", Some(3), None),
+                ("ANOTHER", Some(4), Some(10)),
+                ("INSANE", Some(5), Some(12)),
+            ]
+        );
+        assert_eq!(song.charts[0].md5.len(), 32);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+use std::path::{Component, Path, PathBuf};
+
+/// 譜面ファイルの形式
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChartFormat {
+    /// .bms .bme .bml .pms
+    Bms,
+    /// .bmson
+    Bmson,
+}
+
+impl ChartFormat {
+    /// 拡張子から形式を決める
+    ///
+    /// 大文字と小文字は区別しない
+    pub fn from_path(path: impl AsRef<Path>) -> Option<ChartFormat> {
+        let extension = path.as_ref().extension()?.to_str()?;
+        match extension.to_ascii_lowercase().as_str() {
+            "bms" | "bme" | "bml" | "pms" => Some(ChartFormat::Bms),
+            "bmson" => Some(ChartFormat::Bmson),
+            _ => None,
+        }
+    }
+}
+
+/// 文字コード
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TextEncoding {
+    Utf8,
+    ShiftJis,
+}
+
+impl TextEncoding {
+    /// 文字コードの名前
+    pub fn name(self) -> &'static str {
+        match self {
+            TextEncoding::Utf8 => "UTF-8",
+            TextEncoding::ShiftJis => "Shift_JIS",
+        }
+    }
+    /// 文字列をこの文字コードのバイト列にする
+    ///
+    /// 表せない文字は`&#...;`の形式になる
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::ShiftJis => {
+                encoding_rs::SHIFT_JIS.encode(text).0.into_owned()
+            }
+        }
+    }
+}
+
+/// バイト列を文字列にした結果
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decoded {
+    pub text: String,
+    /// 推測した文字コード
+    pub encoding: TextEncoding,
+    /// 文字コードとして不正なバイト列を含んでいた
+    ///
+    /// 不正な部分は置換文字になる
+    pub malformed: bool,
+}
+
+/// UTF-8として正しければUTF-8、そうでなければShift_JISとして読む
+///
+/// UTF-8のBOMは取り除く
+pub fn decode(bytes: &[u8]) -> Decoded {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => Decoded {
+            text: text.to_string(),
+            encoding: TextEncoding::Utf8,
+            malformed: false,
+        },
+        Err(_) => {
+            let (text, malformed) =
+                encoding_rs::SHIFT_JIS.decode_without_bom_handling(bytes);
+            Decoded {
+                text: text.into_owned(),
+                encoding: TextEncoding::ShiftJis,
+                malformed,
+            }
+        }
+    }
+}
+
+/// バイト列のMD5を16進数の文字列にする
+pub fn md5_hex(bytes: &[u8]) -> String {
+    use md5::Digest;
+    format!("{:x}", md5::Md5::digest(bytes))
+}
+
+/// バイト列のSHA-256を16進数の文字列にする
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    use sha2::Digest;
+    format!("{:x}", sha2::Sha256::digest(bytes))
+}
+
+/// 読み込んだ譜面ファイル
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChartFile {
+    pub path: PathBuf,
+    pub format: ChartFormat,
+    /// 文字列にしたファイルの内容
+    pub text: String,
+    pub encoding: TextEncoding,
+    /// 文字コードとして不正なバイト列を含んでいた
+    pub malformed: bool,
+    /// ファイルのMD5
+    pub md5: String,
+    /// ファイルのSHA-256
+    pub sha256: String,
+}
+
+impl ChartFile {
+    /// 譜面ファイルを読み込む
+    ///
+    /// 拡張子が譜面ファイルのものでない場合は`ErrorKind::InvalidInput`を返す
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<ChartFile> {
+        let path = path.as_ref();
+        let Some(format) = ChartFormat::from_path(path)
+        else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "譜面ファイルではありません",
+            ));
+        };
+        let bytes = std::fs::read(path)?;
+        let decoded = decode(&bytes);
+        Ok(ChartFile {
+            path: path.to_path_buf(),
+            format,
+            text: decoded.text,
+            encoding: decoded.encoding,
+            malformed: decoded.malformed,
+            md5: md5_hex(&bytes),
+            sha256: sha256_hex(&bytes),
+        })
+    }
+}
+
+/// 音声ファイルを探すときに試す拡張子
+///
+/// `audio`で読み込める形式と同じで、mp3は含めない
+pub const SOUND_EXTENSIONS: &[&str] = &["wav", "ogg", "flac"];
+
+/// 画像・動画ファイルを探すときに試す拡張子
+pub const IMAGE_EXTENSIONS: &[&str] = &[
+    "bmp", "png", "jpg", "jpeg", "gif", "mpg", "mpeg", "mp4", "avi", "wmv",
+    "webm",
+];
+
+/// 譜面から参照されたファイルを探す
+///
+/// `name`は`dir`からの相対パスで、区切りには`\`も使える
+///
+/// 大文字と小文字は区別しない
+/// 見つからない場合は拡張子を`extensions`のものに変えて探す
+///
+/// 絶対パスや`dir`の外を指す名前では探さない
+pub fn find_file(
+    dir: impl AsRef<Path>,
+    name: &str,
+    extensions: &[&str],
+) -> Option<PathBuf> {
+    let name = name.replace('\\', "/");
+    let mut components = Path::new(&name).components().peekable();
+    let mut path = dir.as_ref().to_path_buf();
+    // `dir`から辿った深さ
+    let mut depth = 0usize;
+    while let Some(component) = components.next() {
+        let component = match component {
+            Component::Normal(c) => c.to_str()?,
+            Component::CurDir => continue,
+            Component::ParentDir => {
+                depth = depth.checked_sub(1)?;
+                path.pop();
+                continue;
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        };
+        let entries = std::fs::read_dir(&path)
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .collect::<Vec<_>>();
+        let file_name = |p: &PathBuf| {
+            p.file_name().and_then(|n| n.to_str()).map(str::to_string)
+        };
+        let exact = entries.iter().find(|p| {
+            file_name(p).is_some_and(|n| n.eq_ignore_ascii_case(component))
+        });
+        if let Some(exact) = exact {
+            path = exact.clone();
+            depth += 1;
+            continue;
+        }
+        if components.peek().is_some() {
+            return None;
+        }
+        let stem = Path::new(component).file_stem()?.to_str()?;
+        return extensions.iter().find_map(|extension| {
+            entries
+                .iter()
+                .find(|p| {
+                    p.file_stem()
+                        .and_then(|s| s.to_str())
+                        .is_some_and(|s| s.eq_ignore_ascii_case(stem))
+                        && p.extension()
+                            .and_then(|e| e.to_str())
+                            .is_some_and(|e| e.eq_ignore_ascii_case(extension))
+                })
+                .cloned()
+        });
+    }
+    path.is_file().then_some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        assert_eq!(ChartFormat::from_path("a/b.BME"), Some(ChartFormat::Bms));
+        assert_eq!(ChartFormat::from_path("b.bmson"), Some(ChartFormat::Bmson));
+        assert_eq!(ChartFormat::from_path("b.wav"), None);
+
+        let utf8 = decode("\u{FEFF}#TITLE テスト".as_bytes());
+        assert_eq!(utf8.text, "#TITLE テスト");
+        assert_eq!(utf8.encoding, TextEncoding::Utf8);
+        let sjis = TextEncoding::ShiftJis.encode("#TITLE テスト");
+        let decoded = decode(&sjis);
+        assert_eq!(decoded.text, "#TITLE テスト");
+        assert_eq!(decoded.encoding, TextEncoding::ShiftJis);
+        assert!(!decoded.malformed);
+
+        assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn find() {
+        let dir = std::env::temp_dir().join("bms-utils-find-file-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("Sounds")).unwrap();
+        std::fs::write(dir.join("Sounds/Kick.OGG"), b"").unwrap();
+        std::fs::write(dir.join("bg.png"), b"").unwrap();
+
+        assert_eq!(
+            find_file(&dir, "sounds\\kick.ogg", SOUND_EXTENSIONS),
+            Some(dir.join("Sounds/Kick.OGG"))
+        );
+        assert_eq!(
+            find_file(&dir, "sounds/kick.wav", SOUND_EXTENSIONS),
+            Some(dir.join("Sounds/Kick.OGG"))
+        );
+        assert_eq!(
+            find_file(&dir, "BG.bmp", IMAGE_EXTENSIONS),
+            Some(dir.join("bg.png"))
+        );
+        assert_eq!(find_file(&dir, "bg.bmp", SOUND_EXTENSIONS), None);
+        assert_eq!(find_file(&dir, "sounds", SOUND_EXTENSIONS), None);
+        assert_eq!(find_file(&dir, "snare.wav", SOUND_EXTENSIONS), None);
+        assert_eq!(
+            find_file(&dir, "./sounds/../bg.png", IMAGE_EXTENSIONS),
+            Some(dir.join("bg.png"))
+        );
+        // `dir`の外は探さない
+        let sounds = dir.join("Sounds");
+        assert_eq!(find_file(&sounds, "../Sounds/kick.ogg", &[]), None);
+        assert_eq!(find_file(&sounds, "./../kick.ogg", &[]), None);
+        assert_eq!(find_file(&dir, "sounds/../../kick.ogg", &[]), None);
+        assert_eq!(
+            find_file(&dir, &sounds.join("kick.ogg").to_string_lossy(), &[]),
+            None
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use crate::bms::timeline::objects;
+use crate::bms::{Bms, BmsTimeline, MainData};
+use std::collections::HashMap;
+
+/// 表示位置の計算方法
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScrollMode {
+    /// BPM・停止・SCROLLに従ってノーツが流れる
+    Normal,
+    /// BPM・停止・SCROLLを無視して、一定の速さでノーツが流れる
+    ///
+    /// 指定したBPMで1拍進む時間を、表示位置の1とする
+    Constant(f64),
+}
+
+/// 表示位置の傾きが変わる点
+#[derive(Clone, Debug, PartialEq)]
+struct Knot {
+    time: f64,
+    position: f64,
+    /// 1msあたりに進む表示位置
+    slope: f64,
+}
+
+/// 時刻から譜面の表示位置を求めるタイムライン
+///
+/// 表示位置は拍数にSCROLLの倍率を掛けて積み重ねたもので、
+/// 停止中は変化しない
+///
+/// SPEEDは表示位置ではなく、判定ラインからの距離の倍率として扱う
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScrollTimeline {
+    knots: Vec<Knot>,
+    /// (時刻, SPEEDの倍率)
+    speeds: Vec<(f64, f64)>,
+}
+
+impl ScrollTimeline {
+    /// 拍数で表した位置から作成する
+    ///
+    /// `changes`はBPM・停止・SCROLLが変化する位置
+    ///
+    /// `scrolls`は(位置, SCROLLの倍率)を位置順に並べたもの
+    fn new(
+        mode: ScrollMode,
+        mut changes: Vec<f64>,
+        scrolls: &[(f64, f64)],
+        time: impl Fn(f64) -> f64,
+        bpm: impl Fn(f64) -> f64,
+        stop: impl Fn(f64) -> f64,
+    ) -> ScrollTimeline {
+        if let ScrollMode::Constant(bpm) = mode {
+            return ScrollTimeline {
+                knots: vec![Knot {
+                    time: 0.,
+                    position: 0.,
+                    slope: bpm / 60000.,
+                }],
+                speeds: vec![],
+            };
+        }
+        let scroll = |beat: f64| {
+            let i = scrolls.partition_point(|s| s.0 <= beat);
+            i.checked_sub(1).map_or(1., |i| scrolls[i].1)
+        };
+        changes.push(0.);
+        changes.extend(scrolls.iter().map(|s| s.0));
+        changes.sort_by(f64::total_cmp);
+        changes.dedup();
+
+        let mut knots: Vec<Knot> = vec![];
+        let mut position = 0.;
+        let mut last = 0.;
+        for beat in changes.into_iter().filter(|b| 0. <= *b) {
+            position += (beat - last) * scroll(last);
+            last = beat;
+            let slope = scroll(beat) * bpm(beat) / 60000.;
+            let time = time(beat);
+            let stop = stop(beat);
+            if 0. < stop {
+                knots.push(Knot {
+                    time,
+                    position,
+                    slope: 0.,
+                });
+            }
+            knots.push(Knot {
+                time: time + stop,
+                position,
+                slope,
+            });
+        }
+        ScrollTimeline {
+            knots,
+            speeds: vec![],
+        }
+    }
+    /// その時刻の表示位置
+    pub fn position(&self, time: f64) -> f64 {
+        let i = self.knots.partition_point(|k| k.time <= time);
+        let k = &self.knots[i.saturating_sub(1)];
+        k.position + (time - k.time) * k.slope
+    }
+    /// その時刻のSPEEDの倍率
+    ///
+    /// 前後のSPEEDの間は時刻で線形補間する
+    pub fn speed(&self, time: f64) -> f64 {
+        let i = self.speeds.partition_point(|s| s.0 <= time);
+        match (i.checked_sub(1).map(|i| self.speeds[i]), self.speeds.get(i)) {
+            (None, _) => 1.,
+            (Some((_, s)), None) => s,
+            (Some((t0, s0)), Some(&(t1, s1))) => {
+                s0 + (s1 - s0) * (time - t0) / (t1 - t0)
+            }
+        }
+    }
+    /// 時刻`now`に、時刻`time`のオブジェクトを表示する判定ラインからの距離
+    ///
+    /// 表示位置の差にSPEEDの倍率を掛けたもの
+    pub fn distance(&self, now: f64, time: f64) -> f64 {
+        (self.position(time) - self.position(now)) * self.speed(now)
+    }
+}
+
+/// チャンネルのidを値に置き換え、(位置, 値)を位置順に並べる
+fn value_changes<'a>(
+    bms: &Bms<'a>,
+    timeline: &BmsTimeline,
+    rows: impl for<'b> Fn(&'b MainData<'a>) -> &'b Vec<Vec<usize>>,
+    values: &HashMap<usize, f64>,
+) -> Vec<(f64, f64)> {
+    let mut changes = vec![];
+    for (m, measure) in bms.main_data.iter().enumerate() {
+        for row in rows(measure) {
+            for (f, id) in objects(row) {
+                match values.get(id) {
+                    Some(v) => changes.push((timeline.beat(m, f), *v)),
+                    None => log::warn!("{}が定義されていません", id),
+                }
+            }
+        }
+    }
+    changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+    changes
+}
+
+impl Bms<'_> {
+    /// SCROLL・SPEEDを含めた表示位置のタイムラインを作成
+    pub fn scroll_timeline(
+        &self,
+        timeline: &BmsTimeline,
+        mode: ScrollMode,
+    ) -> ScrollTimeline {
+        let scrolls =
+            value_changes(self, timeline, |m| &m.scroll, &self.scroll);
+        let stops = timeline.stops();
+        let mut beats = timeline
+            .bpm_changes()
+            .into_iter()
+            .map(|c| c.0)
+            .collect::<Vec<_>>();
+        beats.extend(stops.iter().map(|s| s.0));
+        let mut scroll = ScrollTimeline::new(
+            mode,
+            beats,
+            &scrolls,
+            |b| timeline.beat_to_ms(b),
+            |b| timeline.bpm_at(b),
+            |b| stops.iter().find(|s| s.0 == b).map_or(0., |s| s.2),
+        );
+        scroll.speeds =
+            value_changes(self, timeline, |m| &m.speed, &self.speed)
+                .into_iter()
+                .map(|(beat, speed)| (timeline.beat_to_ms(beat), speed))
+                .collect();
+        scroll
+    }
+}
+
+#[cfg(feature = "bmson")]
+impl crate::Bmson {
+    /// スクロール速度イベントを含めた表示位置のタイムラインを作成
+    ///
+    /// 表示位置は四分音符を1とする
+    pub fn scroll_timeline(
+        &self,
+        timeline: &crate::bmson::BmsonTimeline,
+        mode: ScrollMode,
+    ) -> ScrollTimeline {
+        let resolution = timeline.resolution();
+        let stops = timeline.stops();
+        let mut scrolls = self
+            .scroll_events
+            .iter()
+            .flatten()
+            .map(|e| (e.y / resolution, e.rate))
+            .collect::<Vec<_>>();
+        scrolls.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut beats = self
+            .bpm_events
+            .iter()
+            .flatten()
+            .map(|e| e.y as f64 / resolution)
+            .collect::<Vec<_>>();
+        beats.extend(stops.iter().map(|s| s.0 / resolution));
+        ScrollTimeline::new(
+            mode,
+            beats,
+            &scrolls,
+            |b| timeline.pulse_to_ms(b * resolution),
+            |b| timeline.bpm_at(b * resolution),
+            |b| {
+                stops
+                    .iter()
+                    .find(|s| s.0 == b * resolution)
+                    .map_or(0., |s| s.2)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawBms;
+
+    #[test]
+    fn position() {
+        let raw = RawBms::parse(
+            r"
+#BPM 120
+#STOP01 96
+#SCROLL01 2
+#SCROLL02 0.5
+#SPEED01 1
+#SPEED02 3
+#001SC:01
+#00109:0001
+#002SC:02
+#001SP:0102
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let timeline = bms.timeline();
+        let scroll = bms.scroll_timeline(&timeline, ScrollMode::Normal);
+        // 1拍500ms
+        assert_eq!(scroll.position(1000.), 2.);
+        // 1小節からSCROLL 2
+        assert_eq!(scroll.position(2500.), 6.);
+        // 1小節の中央で1000msの停止
+        assert_eq!(scroll.position(3000.), 8.);
+        assert_eq!(scroll.position(3500.), 8.);
+        assert_eq!(scroll.position(4000.), 8.);
+        assert_eq!(scroll.position(5000.), 12.);
+        // 2小節からSCROLL 0.5
+        assert_eq!(scroll.position(6000.), 13.);
+        assert_eq!(scroll.position(-500.), -1.);
+
+        // 1小節の最初から中央までSPEEDが1から3へ変化する
+        assert_eq!(scroll.speed(1000.), 1.);
+        assert_eq!(scroll.speed(2500.), 2.);
+        assert_eq!(scroll.speed(3000.), 3.);
+        assert_eq!(scroll.distance(0., 1000.), 2.);
+        assert_eq!(scroll.distance(3000., 5000.), 12.);
+
+        let constant =
+            bms.scroll_timeline(&timeline, ScrollMode::Constant(60.));
+        assert_eq!(constant.position(3500.), 3.5);
+    }
+
+    #[cfg(feature = "bmson")]
+    #[test]
+    fn bmson_position() {
+        use crate::bmson::{ScrollEvent, StopEvent};
+        let mut bmson = crate::Bmson::default();
+        bmson.info.init_bpm = 120.;
+        bmson.info.resolution = 240;
+        bmson.stop_events = Some(vec![StopEvent {
+            y: 240,
+            duration: 240,
+        }]);
+        bmson.scroll_events = Some(vec![ScrollEvent { y: 480., rate: 2. }]);
+        let timeline = bmson.timeline();
+        let scroll = bmson.scroll_timeline(&timeline, ScrollMode::Normal);
+        // 1拍目で500msの停止
+        assert_eq!(scroll.position(500.), 1.);
+        assert_eq!(scroll.position(1000.), 1.);
+        assert_eq!(scroll.position(1500.), 2.);
+        // 2拍目からSCROLL 2
+        assert_eq!(scroll.position(2000.), 4.);
+        assert_eq!(scroll.speed(2000.), 1.);
+    }
+}
@@ -0,0 +1,626 @@
+use crate::bms::{BmsNote, Channel};
+use rand::Rng;
+use rand::seq::{IndexedRandom, SliceRandom};
+use std::collections::HashMap;
+
+mod double;
+mod long_note;
+pub use double::{battle, flip_sides, merge_double};
+#[cfg(feature = "bmson")]
+pub use long_note::set_long_note_type;
+pub use long_note::{
+    ln_object_to_long_notes, ln_type_2_to_1, long_notes_to_ln_object,
+};
+
+/// 鍵盤の配置
+///
+/// レーン番号はBmsonの`x`と同じで、
+/// beatでは1から7が1Pの鍵盤、8が1Pのスクラッチ、
+/// 9から15が2Pの鍵盤、16が2Pのスクラッチ
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyMode {
+    Beat5K,
+    Beat7K,
+    Beat10K,
+    Beat14K,
+    /// pop'nの9ボタン
+    Popn9K,
+}
+
+/// 片側の鍵盤
+struct Side {
+    keys: &'static [u32],
+    scratch: Option<u32>,
+}
+
+const BEAT_5K_1P: Side = Side {
+    keys: &[1, 2, 3, 4, 5],
+    scratch: Some(8),
+};
+const BEAT_5K_2P: Side = Side {
+    keys: &[9, 10, 11, 12, 13],
+    scratch: Some(16),
+};
+const BEAT_7K_1P: Side = Side {
+    keys: &[1, 2, 3, 4, 5, 6, 7],
+    scratch: Some(8),
+};
+const BEAT_7K_2P: Side = Side {
+    keys: &[9, 10, 11, 12, 13, 14, 15],
+    scratch: Some(16),
+};
+const POPN_9K: Side = Side {
+    keys: &[1, 2, 3, 4, 5, 6, 7, 8, 9],
+    scratch: None,
+};
+
+/// BMSのチャンネルとレーン番号の対応
+const BEAT_CHANNELS: [(&str, u32); 16] = [
+    ("11", 1),
+    ("12", 2),
+    ("13", 3),
+    ("14", 4),
+    ("15", 5),
+    ("18", 6),
+    ("19", 7),
+    ("16", 8),
+    ("21", 9),
+    ("22", 10),
+    ("23", 11),
+    ("24", 12),
+    ("25", 13),
+    ("28", 14),
+    ("29", 15),
+    ("26", 16),
+];
+const POPN_CHANNELS: [(&str, u32); 9] = [
+    ("11", 1),
+    ("12", 2),
+    ("13", 3),
+    ("14", 4),
+    ("15", 5),
+    ("22", 6),
+    ("23", 7),
+    ("24", 8),
+    ("25", 9),
+];
+
+impl KeyMode {
+    /// Bmsonの`mode_hint`から配置を決める
+    pub fn from_mode_hint(mode_hint: &str) -> Option<KeyMode> {
+        Some(match mode_hint {
+            "beat-5k" => KeyMode::Beat5K,
+            "beat-7k" => KeyMode::Beat7K,
+            "beat-10k" => KeyMode::Beat10K,
+            "beat-14k" => KeyMode::Beat14K,
+            "popn-9k" => KeyMode::Popn9K,
+            _ => return None,
+        })
+    }
+    /// Bmsonの`mode_hint`の値
+    pub fn mode_hint(self) -> &'static str {
+        match self {
+            KeyMode::Beat5K => "beat-5k",
+            KeyMode::Beat7K => "beat-7k",
+            KeyMode::Beat10K => "beat-10k",
+            KeyMode::Beat14K => "beat-14k",
+            KeyMode::Popn9K => "popn-9k",
+        }
+    }
+    /// 使われているチャンネルから配置を推測する
+    ///
+    /// 2Pのスクラッチを使わず、22から25のチャンネルのみを使う場合はpop'nとする
+    pub fn detect(notes: &[BmsNote]) -> KeyMode {
+        let used = |channels: &[&str]| {
+            notes.iter().any(|n| {
+                channels
+                    .iter()
+                    .any(|c| Channel::new(c).to_base_36() == n.channel)
+            })
+        };
+        let seven = used(&["18", "19", "28", "29"]);
+        if used(&["21", "26", "28", "29"]) {
+            if seven {
+                KeyMode::Beat14K
+            }
+            else {
+                KeyMode::Beat10K
+            }
+        }
+        else if used(&["22", "23", "24", "25"]) && !used(&["16"]) {
+            KeyMode::Popn9K
+        }
+        else if used(&["22", "23", "24", "25"]) {
+            if seven {
+                KeyMode::Beat14K
+            }
+            else {
+                KeyMode::Beat10K
+            }
+        }
+        else if seven {
+            KeyMode::Beat7K
+        }
+        else {
+            KeyMode::Beat5K
+        }
+    }
+    fn sides(self) -> &'static [Side] {
+        match self {
+            KeyMode::Beat5K => &[BEAT_5K_1P],
+            KeyMode::Beat7K => &[BEAT_7K_1P],
+            KeyMode::Beat10K => &[BEAT_5K_1P, BEAT_5K_2P],
+            KeyMode::Beat14K => &[BEAT_7K_1P, BEAT_7K_2P],
+            KeyMode::Popn9K => &[POPN_9K],
+        }
+    }
+    fn channels(self) -> &'static [(&'static str, u32)] {
+        match self {
+            KeyMode::Popn9K => &POPN_CHANNELS,
+            _ => &BEAT_CHANNELS,
+        }
+    }
+    /// レーン番号の最大値
+    pub fn lane_count(self) -> u32 {
+        match self {
+            KeyMode::Beat5K | KeyMode::Beat7K => 8,
+            KeyMode::Beat10K | KeyMode::Beat14K => 16,
+            KeyMode::Popn9K => 9,
+        }
+    }
+    /// 鍵盤とスクラッチのレーン番号
+    pub fn lanes(self) -> Vec<u32> {
+        self.sides()
+            .iter()
+            .flat_map(|s| s.keys.iter().copied().chain(s.scratch))
+            .collect()
+    }
+    /// スクラッチのレーン番号
+    pub fn scratch_lanes(self) -> Vec<u32> {
+        self.sides().iter().filter_map(|s| s.scratch).collect()
+    }
+    /// BMSのチャンネルからレーン番号へ変換
+    pub fn bms_lane(self, channel: usize) -> Option<u32> {
+        let lanes = self.lanes();
+        self.channels()
+            .iter()
+            .find(|(c, _)| Channel::new(c).to_base_36() == channel)
+            .map(|(_, lane)| *lane)
+            .filter(|lane| lanes.contains(lane))
+    }
+    /// レーン番号からBMSのチャンネルへ変換
+    pub fn bms_channel(self, lane: u32) -> Option<usize> {
+        self.channels()
+            .iter()
+            .find(|(_, l)| *l == lane)
+            .map(|(c, _)| Channel::new(c).to_base_36())
+    }
+}
+
+/// レーンオプション
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LaneOption {
+    /// 鍵盤を左右反転
+    Mirror,
+    /// 鍵盤をランダムに並べ替える
+    Random,
+    /// 鍵盤をずらす
+    ///
+    /// 左右反転する場合もある
+    RRandom,
+    /// ノーツごとにランダムな鍵盤へ移す
+    ///
+    /// ロングノートの押している間はそのレーンを使わない
+    SRandom,
+    /// 縦連打が出来ないようにノーツごとにランダムな鍵盤へ移す
+    ///
+    /// 前のノーツから[`H_RANDOM_THRESHOLD`]ミリ秒以内のレーンは、
+    /// 他に置ける鍵盤が無い場合のみ使う
+    HRandom,
+    /// スクラッチに置けるノーツをスクラッチへ移す
+    ///
+    /// 前のスクラッチのノーツから[`H_RANDOM_THRESHOLD`]ミリ秒以内は移さない
+    ///
+    /// スクラッチが無い配置では何もしない
+    AllScratch,
+}
+
+/// 縦連打とみなす間隔（ミリ秒）
+pub const H_RANDOM_THRESHOLD: f64 = 100.;
+
+/// レーンオプションで使った並べ替え
+///
+/// リプレイではこれを保存して同じ譜面を再現する
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Permutation {
+    /// レーンごとの並べ替え
+    ///
+    /// レーン`x`のノーツはレーン`lanes[x - 1]`へ移る
+    Lanes(Vec<u32>),
+    /// ノーツごとの移動先のレーン
+    ///
+    /// 並び順は[`Permutation::apply_bms`]・
+    /// [`Permutation::apply_bmson`]を参照
+    ///
+    /// ノーツの数より短い場合、足りない分のノーツは移動しない
+    Notes(Vec<u32>),
+}
+
+/// 並べ替えに使うノーツの情報
+struct LaneNote {
+    lane: u32,
+    time: f64,
+    end: f64,
+}
+
+impl LaneOption {
+    fn permutation(
+        self,
+        mode: KeyMode,
+        notes: &[LaneNote],
+        rng: &mut impl rand::RngCore,
+    ) -> Permutation {
+        let mut lanes = (1..=mode.lane_count()).collect::<Vec<_>>();
+        let mut set = |from: &[u32], to: &[u32]| {
+            for (f, t) in from.iter().zip(to) {
+                lanes[*f as usize - 1] = *t;
+            }
+        };
+        match self {
+            LaneOption::Mirror => {
+                for side in mode.sides() {
+                    let mut keys = side.keys.to_vec();
+                    keys.reverse();
+                    set(side.keys, &keys);
+                }
+            }
+            LaneOption::Random => {
+                for side in mode.sides() {
+                    let mut keys = side.keys.to_vec();
+                    keys.shuffle(rng);
+                    set(side.keys, &keys);
+                }
+            }
+            LaneOption::RRandom => {
+                for side in mode.sides() {
+                    let mut keys = side.keys.to_vec();
+                    let mirror = rng.random_bool(0.5);
+                    let shift = if mirror {
+                        rng.random_range(0..keys.len())
+                    }
+                    else {
+                        rng.random_range(1..keys.len())
+                    };
+                    keys.rotate_left(shift);
+                    if mirror {
+                        keys.reverse();
+                    }
+                    set(side.keys, &keys);
+                }
+            }
+            LaneOption::SRandom
+            | LaneOption::HRandom
+            | LaneOption::AllScratch => {
+                return Permutation::Notes(self.shuffle(mode, notes, rng));
+            }
+        }
+        Permutation::Lanes(lanes)
+    }
+    fn shuffle(
+        self,
+        mode: KeyMode,
+        notes: &[LaneNote],
+        rng: &mut impl rand::RngCore,
+    ) -> Vec<u32> {
+        let mut result = notes.iter().map(|n| n.lane).collect::<Vec<_>>();
+        let mut order = (0..notes.len()).collect::<Vec<_>>();
+        order.sort_by(|&a, &b| notes[a].time.total_cmp(&notes[b].time));
+        for side in mode.sides() {
+            let lanes = side.keys.iter().copied().chain(side.scratch);
+            // (ロングノートが終わる時刻, 最後のノーツの時刻)
+            let mut state = lanes
+                .map(|l| (l, (f64::NEG_INFINITY, f64::NEG_INFINITY)))
+                .collect::<HashMap<_, _>>();
+            let side_notes = order
+                .iter()
+                .copied()
+                .filter(|&i| state.contains_key(&notes[i].lane))
+                .collect::<Vec<_>>();
+            for group in
+                side_notes.chunk_by(|&a, &b| notes[a].time == notes[b].time)
+            {
+                let time = notes[group[0]].time;
+                let free = |(end, last): (f64, f64), threshold: f64| {
+                    end < time && threshold <= time - last
+                };
+                let mut used = vec![];
+                let mut targets = vec![];
+                for &i in group {
+                    if Some(notes[i].lane) == side.scratch {
+                        used.extend(side.scratch);
+                    }
+                    else {
+                        targets.push(i);
+                    }
+                }
+                if self == LaneOption::AllScratch {
+                    if let Some(scratch) = side.scratch
+                        && used.is_empty()
+                        && free(state[&scratch], H_RANDOM_THRESHOLD)
+                        && let Some(&i) = targets.choose(rng)
+                    {
+                        result[i] = scratch;
+                    }
+                }
+                else {
+                    targets.shuffle(rng);
+                    for &i in &targets {
+                        let candidates = |threshold| {
+                            side.keys
+                                .iter()
+                                .copied()
+                                .filter(|l| {
+                                    !used.contains(l)
+                                        && free(state[l], threshold)
+                                })
+                                .collect::<Vec<_>>()
+                        };
+                        let mut lanes = vec![];
+                        if self == LaneOption::HRandom {
+                            lanes = candidates(H_RANDOM_THRESHOLD);
+                        }
+                        if lanes.is_empty() {
+                            lanes = candidates(0.);
+                        }
+                        if let Some(&lane) = lanes.choose(rng) {
+                            result[i] = lane;
+                        }
+                        used.push(result[i]);
+                    }
+                }
+                for &i in group {
+                    let s = state.get_mut(&result[i]).unwrap();
+                    s.0 = s.0.max(notes[i].end);
+                    s.1 = time;
+                }
+            }
+        }
+        result
+    }
+    /// BMSのノーツにレーンオプションを適用する
+    ///
+    /// 使った並べ替えを返す
+    pub fn apply_bms(
+        self,
+        mode: KeyMode,
+        notes: &mut [BmsNote],
+        rng: &mut impl rand::RngCore,
+    ) -> Permutation {
+        let lane_notes = bms_lane_notes(mode, notes)
+            .map(|(_, n)| n)
+            .collect::<Vec<_>>();
+        let permutation = self.permutation(mode, &lane_notes, rng);
+        permutation.apply_bms(mode, notes);
+        permutation
+    }
+    /// Bmsonのノーツにレーンオプションを適用する
+    ///
+    /// 使った並べ替えを返す
+    #[cfg(feature = "bmson")]
+    pub fn apply_bmson(
+        self,
+        mode: KeyMode,
+        bmson: &mut crate::Bmson,
+        rng: &mut impl rand::RngCore,
+    ) -> Permutation {
+        let timeline = bmson.timeline();
+        let lanes = mode.lanes();
+        let lane_notes = bmson_lane_notes(&lanes, bmson)
+            .map(|n| LaneNote {
+                lane: n.x.unwrap(),
+                time: timeline.note_time(n),
+                end: timeline.note_end_time(n),
+            })
+            .collect::<Vec<_>>();
+        let permutation = self.permutation(mode, &lane_notes, rng);
+        permutation.apply_bmson(mode, bmson);
+        permutation
+    }
+}
+
+fn bms_lane_notes(
+    mode: KeyMode,
+    notes: &[BmsNote],
+) -> impl Iterator<Item = (usize, LaneNote)> {
+    notes.iter().enumerate().filter_map(move |(i, n)| {
+        Some((
+            i,
+            LaneNote {
+                lane: mode.bms_lane(n.channel)?,
+                time: n.time,
+                end: n.end.as_ref().map_or(n.time, |e| e.time),
+            },
+        ))
+    })
+}
+
+/// 並べ替えの対象になるBmsonのノーツ
+///
+/// ロングノートの終端音は含まない
+#[cfg(feature = "bmson")]
+fn bmson_lane_notes<'a>(
+    lanes: &[u32],
+    bmson: &'a crate::Bmson,
+) -> impl Iterator<Item = &'a crate::bmson::Note> {
+    bmson
+        .sound_channels
+        .iter()
+        .flatten()
+        .flat_map(|sc| &sc.notes)
+        .filter(|n| {
+            n.x.is_some_and(|x| lanes.contains(&x)) && n.up != Some(true)
+        })
+}
+
+impl Permutation {
+    fn lane(&self, index: usize, lane: u32) -> u32 {
+        match self {
+            Permutation::Lanes(lanes) => {
+                lanes.get(lane as usize - 1).copied().unwrap_or(lane)
+            }
+            Permutation::Notes(lanes) => {
+                lanes.get(index).copied().unwrap_or(lane)
+            }
+        }
+    }
+    /// BMSのノーツに並べ替えを適用する
+    ///
+    /// ノーツごとの並べ替えでは、配置に含まれるチャンネルのノーツを
+    /// `notes`の順に並べたものに対応する
+    pub fn apply_bms(&self, mode: KeyMode, notes: &mut [BmsNote]) {
+        let lanes = bms_lane_notes(mode, notes)
+            .enumerate()
+            .map(|(j, (i, n))| (i, self.lane(j, n.lane)))
+            .collect::<Vec<_>>();
+        for (i, lane) in lanes {
+            if let Some(channel) = mode.bms_channel(lane) {
+                notes[i].channel = channel;
+            }
+        }
+    }
+    /// Bmsonのノーツに並べ替えを適用する
+    ///
+    /// ノーツごとの並べ替えでは、配置に含まれるレーンのノーツを
+    /// サウンドチャンネルの順に並べたものに対応する
+    ///
+    /// ロングノートの終端音は、そのロングノートと同じレーンへ移る
+    #[cfg(feature = "bmson")]
+    pub fn apply_bmson(&self, mode: KeyMode, bmson: &mut crate::Bmson) {
+        let lanes = mode.lanes();
+        // (元のレーン, 終点) -> 移動先のレーン
+        let mut ends = HashMap::new();
+        let new_lanes = bmson_lane_notes(&lanes, bmson)
+            .enumerate()
+            .map(|(i, n)| {
+                let lane = self.lane(i, n.x.unwrap());
+                if 0 < n.l {
+                    let end = u64::from(n.y) + u64::from(n.l);
+                    ends.insert((n.x.unwrap(), end), lane);
+                }
+                lane
+            })
+            .collect::<Vec<_>>();
+        let mut new_lanes = new_lanes.into_iter();
+        for note in bmson
+            .sound_channels
+            .iter_mut()
+            .flatten()
+            .flat_map(|sc| &mut sc.notes)
+        {
+            let Some(x) = note.x.filter(|x| lanes.contains(x))
+            else {
+                continue;
+            };
+            if note.up == Some(true) {
+                if let Some(&lane) = ends.get(&(x, u64::from(note.y))) {
+                    note.x = Some(lane);
+                }
+            }
+            else if let Some(lane) = new_lanes.next() {
+                note.x = Some(lane);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawBms;
+    use rand::SeedableRng;
+
+    fn lanes(mode: KeyMode, notes: &[BmsNote]) -> Vec<u32> {
+        notes
+            .iter()
+            .map(|n| mode.bms_lane(n.channel).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn lane_options() {
+        let raw = RawBms::parse(
+            r"
+#00111:01010101
+#00112:0001
+#00116:01
+#00119:01
+#00153:0100000001000000
+#00114:0000000001
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let timeline = bms.timeline();
+        let notes = bms.notes(&timeline);
+        let mode = KeyMode::detect(&notes);
+        assert_eq!(mode, KeyMode::Beat7K);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+
+        let mut mirror = notes.clone();
+        let permutation =
+            LaneOption::Mirror.apply_bms(mode, &mut mirror, &mut rng);
+        assert_eq!(
+            permutation,
+            Permutation::Lanes(vec![7, 6, 5, 4, 3, 2, 1, 8])
+        );
+        assert_eq!(lanes(mode, &mirror), vec![7, 5, 8, 1, 7, 7, 6, 7, 4]);
+
+        for option in [LaneOption::Random, LaneOption::RRandom] {
+            let mut random = notes.clone();
+            let permutation = option.apply_bms(mode, &mut random, &mut rng);
+            let Permutation::Lanes(lanes) = &permutation
+            else {
+                panic!();
+            };
+            let mut sorted = lanes.clone();
+            sorted.sort();
+            assert_eq!(sorted, (1..=8).collect::<Vec<_>>());
+            assert_eq!(lanes[7], 8);
+            // 同じ並べ替えで再現できる
+            let mut replay = notes.clone();
+            permutation.apply_bms(mode, &mut replay);
+            assert_eq!(replay, random);
+        }
+
+        for _ in 0..20 {
+            let mut random = notes.clone();
+            LaneOption::SRandom.apply_bms(mode, &mut random, &mut rng);
+            let lanes = lanes(mode, &random);
+            // スクラッチは動かない
+            assert_eq!(lanes[2], 8);
+            // 同時に押すノーツは別のレーン
+            assert!(lanes[0] != lanes[1] && lanes[0] != lanes[3]);
+            assert!(lanes[1] != lanes[3] && lanes[5] != lanes[6]);
+            // ロングノートを押している間は同じレーンを使わない
+            assert!(lanes[4..7].iter().all(|&l| l != lanes[1]));
+        }
+
+        // 足りない分のノーツは動かない
+        let mut short = notes.clone();
+        Permutation::Notes(vec![2]).apply_bms(mode, &mut short);
+        assert_eq!(lanes(mode, &short)[0], 2);
+        assert_eq!(lanes(mode, &short)[1..], lanes(mode, &notes)[1..]);
+        let mut empty = notes.clone();
+        Permutation::Notes(vec![]).apply_bms(mode, &mut empty);
+        assert_eq!(empty, notes);
+
+        let mut all_scratch = notes.clone();
+        let permutation =
+            LaneOption::AllScratch.apply_bms(mode, &mut all_scratch, &mut rng);
+        let lanes = lanes(mode, &all_scratch);
+        // 前のスクラッチから100ミリ秒以内のノーツは移さない
+        assert_eq!(lanes.iter().filter(|&&l| l == 8).count(), 4);
+        let mut replay = notes.clone();
+        permutation.apply_bms(mode, &mut replay);
+        assert_eq!(replay, all_scratch);
+    }
+}
@@ -0,0 +1,299 @@
+use super::*;
+use crate::bms::notes::{
+    INVISIBLE_OFFSET, LANDMINE_OFFSET, LONG_OFFSET, NOTE_CHANNELS,
+};
+use crate::bms::{Bms, MainData, PlayType};
+
+/// 1Pの鍵盤と2Pの鍵盤の対応（左右反転）
+const BATTLE_CHANNELS: [(&str, &str); 9] = [
+    ("11", "29"),
+    ("12", "28"),
+    ("13", "25"),
+    ("14", "24"),
+    ("15", "23"),
+    ("18", "22"),
+    ("19", "21"),
+    ("16", "26"),
+    ("17", "27"),
+];
+
+/// 1Pと2Pのチャンネルの差
+const SIDE_OFFSET: usize =
+    Channel::new("21").to_base_36() - Channel::new("11").to_base_36();
+
+fn is_1p(channel: usize) -> bool {
+    (Channel::new("11").to_base_36()..Channel::new("21").to_base_36())
+        .contains(&channel)
+}
+
+fn is_2p(channel: usize, offset: usize) -> bool {
+    channel.checked_sub(offset + SIDE_OFFSET).is_some_and(is_1p)
+}
+
+/// ノーツのチャンネルを全て置き換える
+///
+/// `f`は11から2Zのチャンネルを受け取り、移動先のチャンネルを返す
+///
+/// 不可視ノーツ・ロングノート・地雷のチャンネルも同様に置き換える
+fn remap_channels(
+    measure: &mut MainData,
+    f: impl Fn(usize) -> Option<usize>,
+    copy: bool,
+) {
+    fn remap<T: Clone>(
+        map: &mut HashMap<usize, Vec<T>>,
+        offset: usize,
+        f: &impl Fn(usize) -> Option<usize>,
+        copy: bool,
+    ) {
+        let mut channels = std::mem::take(map).into_iter().collect::<Vec<_>>();
+        channels.sort_by_key(|(c, _)| *c);
+        for (channel, rows) in channels {
+            let to = channel
+                .checked_sub(offset)
+                .filter(|c| NOTE_CHANNELS.contains(c))
+                .and_then(f);
+            match to {
+                Some(to) => {
+                    if copy {
+                        map.entry(channel).or_default().extend(rows.clone());
+                    }
+                    map.entry(to + offset).or_default().extend(rows);
+                }
+                None => map.entry(channel).or_default().extend(rows),
+            }
+        }
+    }
+    remap(&mut measure.notes, 0, &f, copy);
+    remap(&mut measure.invisible_notes, INVISIBLE_OFFSET, &f, copy);
+    remap(&mut measure.long_notes, LONG_OFFSET, &f, copy);
+    remap(&mut measure.landmine, LANDMINE_OFFSET, &f, copy);
+}
+
+/// 2Pのノーツを全て削除する
+fn clear_2p(measure: &mut MainData) {
+    measure.notes.retain(|c, _| !is_2p(*c, 0));
+    measure
+        .invisible_notes
+        .retain(|c, _| !is_2p(*c, INVISIBLE_OFFSET));
+    measure.long_notes.retain(|c, _| !is_2p(*c, LONG_OFFSET));
+    measure.landmine.retain(|c, _| !is_2p(*c, LANDMINE_OFFSET));
+}
+
+/// 1Pの譜面を左右反転して2Pへ複製し、バトル譜面にする
+///
+/// 元々あった2Pのノーツは削除される
+#[allow(deprecated)]
+pub fn battle(bms: &mut Bms) {
+    let channels = BATTLE_CHANNELS.map(|(p1, p2)| {
+        (Channel::new(p1).to_base_36(), Channel::new(p2).to_base_36())
+    });
+    for measure in &mut bms.main_data {
+        clear_2p(measure);
+        remap_channels(
+            measure,
+            |c| channels.iter().find(|(p1, _)| *p1 == c).map(|(_, p2)| *p2),
+            true,
+        );
+    }
+    bms.player = Some(PlayType::BattlePlay);
+}
+
+/// ダブルプレイの譜面の1Pと2Pを入れ替える
+///
+/// スクラッチはスクラッチ、フットペダルはフットペダルへ移る
+pub fn flip_sides(bms: &mut Bms) {
+    for measure in &mut bms.main_data {
+        remap_channels(
+            measure,
+            |c| {
+                if is_1p(c) {
+                    Some(c + SIDE_OFFSET)
+                }
+                else if c >= SIDE_OFFSET && is_1p(c - SIDE_OFFSET) {
+                    Some(c - SIDE_OFFSET)
+                }
+                else {
+                    None
+                }
+            },
+            false,
+        );
+    }
+}
+
+/// 2つのシングルプレイの譜面を1つのダブルプレイの譜面にまとめる
+///
+/// `p2`の1Pのノーツを2Pのチャンネルへ移して`p1`へ加える
+///
+/// 小節の長さ・BPM・停止・BGMなどは`p1`のものを使う
+///
+/// `p2`の音声ファイルのidが`p1`と衝突する場合は、空いているidへ付け替える
+#[allow(deprecated)]
+pub fn merge_double<'a>(mut p1: Bms<'a>, mut p2: Bms<'a>) -> Bms<'a> {
+    const MAX_ID: usize = 36 * 36 - 1;
+    let mut ids = HashMap::<usize, usize>::new();
+    let mut next_id = 1;
+    let mut map_id = |id: usize, p1: &mut Bms<'a>| -> usize {
+        if id == 0 {
+            return 0;
+        }
+        if let Some(&mapped) = ids.get(&id) {
+            return mapped;
+        }
+        let name = p2.wav.get(&id).copied();
+        let ln_object = p2.ln_object.contains(&id);
+        let used = p1.wav.contains_key(&id) || p1.ln_object.contains(&id);
+        let mut mapped = id;
+        if used
+            && (p1.wav.get(&id).copied() != name
+                || p1.ln_object.contains(&id) != ln_object)
+        {
+            while next_id < MAX_ID
+                && (p1.wav.contains_key(&next_id)
+                    || p1.ln_object.contains(&next_id))
+            {
+                next_id += 1;
+            }
+            if next_id < MAX_ID {
+                mapped = next_id;
+            }
+            else {
+                log::warn!("音声ファイルのidが足りません");
+            }
+        }
+        if let Some(name) = name {
+            p1.wav.insert(mapped, name);
+        }
+        if ln_object {
+            p1.ln_object.insert(mapped);
+        }
+        ids.insert(id, mapped);
+        mapped
+    };
+
+    if p1.main_data.len() < p2.main_data.len() {
+        p1.main_data
+            .resize_with(p2.main_data.len(), Default::default);
+    }
+    for measure in &mut p1.main_data {
+        clear_2p(measure);
+    }
+    for (m, mut measure) in
+        std::mem::take(&mut p2.main_data).into_iter().enumerate()
+    {
+        clear_2p(&mut measure);
+        remap_channels(
+            &mut measure,
+            |c| is_1p(c).then_some(c + SIDE_OFFSET),
+            false,
+        );
+        for (map, offset) in [
+            (measure.notes, 0),
+            (measure.invisible_notes, INVISIBLE_OFFSET),
+            (measure.long_notes, LONG_OFFSET),
+        ] {
+            for (channel, rows) in map {
+                if !is_2p(channel, offset) {
+                    continue;
+                }
+                let rows = rows
+                    .into_iter()
+                    .map(|row| {
+                        row.into_iter().map(|id| map_id(id, &mut p1)).collect()
+                    })
+                    .collect::<Vec<_>>();
+                let target = &mut p1.main_data[m];
+                let target = match offset {
+                    0 => &mut target.notes,
+                    INVISIBLE_OFFSET => &mut target.invisible_notes,
+                    _ => &mut target.long_notes,
+                };
+                target.entry(channel).or_default().extend(rows);
+            }
+        }
+        for (channel, rows) in measure.landmine {
+            if is_2p(channel, LANDMINE_OFFSET) {
+                p1.main_data[m]
+                    .landmine
+                    .entry(channel)
+                    .or_default()
+                    .extend(rows);
+            }
+        }
+    }
+    p1.player = Some(PlayType::DoublePlay);
+    p1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawBms;
+
+    fn channels(bms: &Bms) -> Vec<(usize, Vec<usize>)> {
+        let mut channels = bms.main_data[1]
+            .notes
+            .iter()
+            .map(|(c, rows)| (*c, rows.concat()))
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels
+    }
+
+    #[test]
+    fn double() {
+        let ch = |c| Channel::new(c).to_base_36();
+        let raw = RawBms::parse(
+            r"
+#WAV01 a.wav
+#WAV02 b.wav
+#00111:01
+#00116:02
+#00122:01
+",
+        );
+        let mut bms = raw.make_bms(rand::rng());
+        battle(&mut bms);
+        assert_eq!(
+            channels(&bms),
+            vec![
+                (ch("11"), vec![1]),
+                (ch("16"), vec![2]),
+                (ch("26"), vec![2]),
+                (ch("29"), vec![1]),
+            ]
+        );
+
+        flip_sides(&mut bms);
+        assert_eq!(
+            channels(&bms),
+            vec![
+                (ch("16"), vec![2]),
+                (ch("19"), vec![1]),
+                (ch("21"), vec![1]),
+                (ch("26"), vec![2]),
+            ]
+        );
+
+        let raw_p2 = RawBms::parse(
+            r"
+#WAV01 a.wav
+#WAV02 c.wav
+#00113:0102
+",
+        );
+        let bms = raw.make_bms(rand::rng());
+        let p2 = raw_p2.make_bms(rand::rng());
+        let merged = merge_double(bms, p2);
+        assert_eq!(
+            channels(&merged),
+            vec![
+                (ch("11"), vec![1]),
+                (ch("16"), vec![2]),
+                (ch("23"), vec![1, 3]),
+            ]
+        );
+        assert_eq!(merged.wav[&3], "c.wav");
+    }
+}
@@ -0,0 +1,397 @@
+use super::*;
+use crate::bms::notes::{LONG_OFFSET, NOTE_CHANNELS, merge_rows};
+use crate::bms::{Bms, MainData};
+use std::collections::HashSet;
+
+/// メインデータ上のオブジェクトの位置
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Object {
+    measure: usize,
+    index: usize,
+    len: usize,
+    id: usize,
+}
+
+/// チャンネルの全てのオブジェクトを小節順に並べる
+///
+/// 同じ小節の複数行は1行にまとめる
+fn channel_objects<'a>(
+    main_data: &[MainData<'a>],
+    rows: impl for<'b> Fn(&'b MainData<'a>) -> Option<&'b Vec<Vec<usize>>>,
+) -> Vec<Object> {
+    let mut objects = vec![];
+    for (measure, data) in main_data.iter().enumerate() {
+        let row = rows(data).map(|r| merge_rows(r)).unwrap_or_default();
+        for (index, &id) in row.iter().enumerate() {
+            if id != 0 {
+                objects.push(Object {
+                    measure,
+                    index,
+                    len: row.len(),
+                    id,
+                });
+            }
+        }
+    }
+    objects
+}
+
+/// オブジェクトを1つだけ含む行を追加する
+fn place<'a>(
+    main_data: &mut Vec<MainData<'a>>,
+    rows: impl for<'b> Fn(&'b mut MainData<'a>) -> &'b mut Vec<Vec<usize>>,
+    object: Object,
+) {
+    if main_data.len() <= object.measure {
+        main_data.resize_with(object.measure + 1, Default::default);
+    }
+    let mut row = vec![0; object.len];
+    row[object.index] = object.id;
+    rows(&mut main_data[object.measure]).push(row);
+}
+
+/// 各チャンネルの複数行を、できるだけ短い1行にまとめる
+fn compact(map: &mut HashMap<usize, Vec<Vec<usize>>>) {
+    fn gcd(a: usize, b: usize) -> usize {
+        if b == 0 { a } else { gcd(b, a % b) }
+    }
+    map.retain(|_, rows| {
+        let row = merge_rows(rows);
+        let step = row
+            .iter()
+            .enumerate()
+            .filter(|(_, id)| **id != 0)
+            .fold(row.len(), |g, (i, _)| gcd(g, i));
+        if step == 0 || row.iter().all(|id| *id == 0) {
+            return false;
+        }
+        *rows = vec![row.into_iter().step_by(step).collect()];
+        true
+    });
+}
+
+/// 使われていない音声ファイルのidを大きい方から探す
+fn unused_id(bms: &Bms) -> Option<usize> {
+    let mut used = bms.wav.keys().copied().collect::<HashSet<_>>();
+    used.extend(bms.ln_object.iter().copied());
+    for measure in &bms.main_data {
+        for row in measure
+            .notes
+            .values()
+            .chain(measure.invisible_notes.values())
+            .chain(measure.long_notes.values())
+            .chain([&measure.bgm])
+            .flatten()
+        {
+            used.extend(row.iter().copied());
+        }
+    }
+    (1..36 * 36).rev().find(|id| !used.contains(id))
+}
+
+/// ロングノートのチャンネル（51から6Z）のロングノートを、
+/// `#LNOBJ`で終点を指定する形式に変換する
+///
+/// 始点は通常のノーツのチャンネルへ移り、終点は`#LNOBJ`のidに置き換わる
+///
+/// `#LNOBJ`のidは、音声ファイルが定義されていない既存のidか、
+/// 使われていないidを使う
+pub fn long_notes_to_ln_object(bms: &mut Bms) {
+    if bms.ln_type == Some(2) {
+        ln_type_2_to_1(bms);
+    }
+    let ln_object = match bms
+        .ln_object
+        .iter()
+        .copied()
+        .filter(|id| !bms.wav.contains_key(id))
+        .min()
+    {
+        Some(id) => id,
+        None => {
+            let Some(id) = unused_id(bms)
+            else {
+                log::warn!("LNOBJに使えるidがありません");
+                return;
+            };
+            bms.ln_object.insert(id);
+            id
+        }
+    };
+    for channel in NOTE_CHANNELS {
+        let long_channel = channel + LONG_OFFSET;
+        let objects = channel_objects(&bms.main_data, |m| {
+            m.long_notes.get(&long_channel)
+        });
+        if objects.is_empty() {
+            continue;
+        }
+        for pair in objects.chunks(2) {
+            place(
+                &mut bms.main_data,
+                |m| m.notes.entry(channel).or_default(),
+                pair[0],
+            );
+            match pair.get(1) {
+                Some(&end) => place(
+                    &mut bms.main_data,
+                    |m| m.notes.entry(channel).or_default(),
+                    Object {
+                        id: ln_object,
+                        ..end
+                    },
+                ),
+                None => log::warn!(
+                    "{}小節のロングノートに終点がありません",
+                    pair[0].measure
+                ),
+            }
+        }
+        for measure in &mut bms.main_data {
+            measure.long_notes.remove(&long_channel);
+        }
+    }
+    for measure in &mut bms.main_data {
+        compact(&mut measure.notes);
+    }
+}
+
+/// `#LNOBJ`で終点を指定したロングノートを、
+/// ロングノートのチャンネル（51から6Z）の形式に変換する
+///
+/// 終点のidは`#LNOBJ`のidのまま残るため、終点の音も変わらない
+pub fn ln_object_to_long_notes(bms: &mut Bms) {
+    if bms.ln_object.is_empty() {
+        return;
+    }
+    if bms.ln_type == Some(2) {
+        ln_type_2_to_1(bms);
+    }
+    for channel in NOTE_CHANNELS {
+        let objects =
+            channel_objects(&bms.main_data, |m| m.notes.get(&channel));
+        if !objects.iter().any(|o| bms.ln_object.contains(&o.id)) {
+            continue;
+        }
+        let mut notes = vec![];
+        let mut long_notes = vec![];
+        // 直前のノーツだけが始点になる
+        let mut last = None;
+        for object in objects {
+            if !bms.ln_object.contains(&object.id) {
+                notes.extend(last.replace(object));
+                continue;
+            }
+            match last.take() {
+                Some(start) => long_notes.extend([start, object]),
+                None => log::warn!(
+                    "{}小節のLNOBJに対応する始点がありません",
+                    object.measure
+                ),
+            }
+        }
+        notes.extend(last);
+        for measure in &mut bms.main_data {
+            measure.notes.remove(&channel);
+        }
+        for object in notes {
+            place(
+                &mut bms.main_data,
+                |m| m.notes.entry(channel).or_default(),
+                object,
+            );
+        }
+        for object in long_notes {
+            place(
+                &mut bms.main_data,
+                |m| m.long_notes.entry(channel + LONG_OFFSET).or_default(),
+                object,
+            );
+        }
+    }
+    for measure in &mut bms.main_data {
+        compact(&mut measure.notes);
+        compact(&mut measure.long_notes);
+    }
+    bms.ln_object.clear();
+    bms.ln_type = Some(1);
+}
+
+/// 非推奨の`#LNTYPE 2`のロングノートを`#LNTYPE 1`の形式に変換する
+///
+/// 終点のidは始点と同じidになる
+pub fn ln_type_2_to_1(bms: &mut Bms) {
+    if bms.ln_type != Some(2) {
+        return;
+    }
+    let measures = bms.main_data.len();
+    for channel in NOTE_CHANNELS {
+        let long_channel = channel + LONG_OFFSET;
+        if !bms
+            .main_data
+            .iter()
+            .any(|m| m.long_notes.contains_key(&long_channel))
+        {
+            continue;
+        }
+        let mut long_notes = vec![];
+        let mut start: Option<Object> = None;
+        for measure in 0..=measures {
+            let row = bms
+                .main_data
+                .get(measure)
+                .and_then(|m| m.long_notes.get(&long_channel))
+                .map(|rows| merge_rows(rows))
+                .unwrap_or_default();
+            if row.is_empty()
+                && let Some(s) = start.take()
+            {
+                long_notes.push(s);
+                long_notes.push(Object {
+                    measure,
+                    index: 0,
+                    len: 1,
+                    id: s.id,
+                });
+            }
+            for (index, &id) in row.iter().enumerate() {
+                let object = Object {
+                    measure,
+                    index,
+                    len: row.len(),
+                    id,
+                };
+                match (id, start) {
+                    (0, Some(s)) => {
+                        long_notes.push(s);
+                        long_notes.push(Object { id: s.id, ..object });
+                        start = None;
+                    }
+                    (1.., None) => start = Some(object),
+                    _ => (),
+                }
+            }
+        }
+        for measure in &mut bms.main_data {
+            measure.long_notes.remove(&long_channel);
+        }
+        for object in long_notes {
+            place(
+                &mut bms.main_data,
+                |m| m.long_notes.entry(long_channel).or_default(),
+                object,
+            );
+        }
+    }
+    for measure in &mut bms.main_data {
+        compact(&mut measure.long_notes);
+    }
+    bms.ln_type = Some(1);
+}
+
+/// Bmsonのロングノートの種類をノーツごとに変更する
+///
+/// `f`はロングノートを受け取り、新しい種類を返す
+///
+/// `None`を返したノーツは変更しない
+#[cfg(feature = "bmson")]
+pub fn set_long_note_type(
+    bmson: &mut crate::Bmson,
+    mut f: impl FnMut(&crate::bmson::Note) -> Option<crate::bmson::LongNoteType>,
+) {
+    for note in bmson
+        .sound_channels
+        .iter_mut()
+        .flatten()
+        .flat_map(|sc| &mut sc.notes)
+        .filter(|n| 0 < n.l)
+    {
+        if let Some(t) = f(note) {
+            note.t = Some(t);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RawBms;
+
+    type Summary = Vec<(usize, f64, Option<(usize, f64)>)>;
+
+    fn summary(bms: &Bms) -> Summary {
+        let timeline = bms.timeline();
+        bms.notes(&timeline)
+            .iter()
+            .map(|n| (n.wav, n.beat, n.end.as_ref().map(|e| (e.wav, e.beat))))
+            .collect()
+    }
+
+    #[test]
+    fn convert() {
+        let raw = RawBms::parse(
+            r"
+#WAV01 a.wav
+#WAV02 b.wav
+#00112:01
+#00151:02000002
+#00251:0201
+",
+        );
+        let mut bms = raw.make_bms(rand::rng());
+        let expected = vec![
+            (2, 4., Some((2, 7.))),
+            (1, 4., None),
+            (2, 8., Some((1, 10.))),
+        ];
+        assert_eq!(summary(&bms), expected);
+
+        long_notes_to_ln_object(&mut bms);
+        assert_eq!(bms.ln_object, HashSet::from([1295]));
+        assert!(bms.main_data.iter().all(|m| m.long_notes.is_empty()));
+        let lnobj = |notes: Summary| {
+            notes
+                .into_iter()
+                .map(|(wav, beat, end)| {
+                    (wav, beat, end.map(|(_, beat)| (1295, beat)))
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(summary(&bms), lnobj(expected.clone()));
+        // 書き出して読み込んでも同じ
+        let written = bms.to_string();
+        let raw = RawBms::parse(&written);
+        assert_eq!(summary(&raw.make_bms(rand::rng())), lnobj(expected));
+
+        ln_object_to_long_notes(&mut bms);
+        assert!(bms.ln_object.is_empty());
+        assert_eq!(
+            summary(&bms),
+            vec![
+                (2, 4., Some((1295, 7.))),
+                (1, 4., None),
+                (2, 8., Some((1295, 10.))),
+            ]
+        );
+
+        let raw = RawBms::parse(
+            r"
+#LNTYPE 2
+#00151:00010101
+#00251:0101000001
+",
+        );
+        let mut bms = raw.make_bms(rand::rng());
+        let before = summary(&bms)
+            .into_iter()
+            .map(|(_, beat, end)| (beat, end.map(|e| e.1)))
+            .collect::<Vec<_>>();
+        ln_type_2_to_1(&mut bms);
+        assert_eq!(bms.ln_type, Some(1));
+        let after = summary(&bms)
+            .into_iter()
+            .map(|(_, beat, end)| (beat, end.map(|e| e.1)))
+            .collect::<Vec<_>>();
+        assert_eq!(before, after);
+    }
+}